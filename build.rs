@@ -7,11 +7,121 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also turns the query tables and signal definitions in `queries/`
+//! into Rust, see `generate_queries`.
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+// Column values of the ecu column, indexes are polling::ECU_BMS etc.
+const ECUS: [(&str, &str); 8] = [
+    ("bms", "ECU_BMS"),
+    ("tpms", "ECU_TPMS"),
+    ("hvac", "ECU_HVAC"),
+    ("adas", "ECU_ADAS"),
+    ("iccu", "ECU_ICCU"),
+    ("vcms", "ECU_VCMS"),
+    ("dash", "ECU_DASH"),
+    ("igpm", "ECU_IGPM"),
+];
+
+fn parse_hex(field: &str) -> Option<u32> {
+    u32::from_str_radix(field.strip_prefix("0x")?, 16).ok()
+}
+
+// One signal of the signals column, a subset of a DBC signal definition:
+// `name=start|length(scale,offset)unit`. Bits are numbered from the most
+// significant bit of the first data byte after the DID, values are big
+// endian, and an `s` after the length makes the raw value signed, e.g.
+// `battery_current=80|16s(0.1,0)A`.
+fn parse_signal(token: &str) -> Option<String> {
+    let (name, definition) = token.split_once('=')?;
+    let (start, rest) = definition.split_once('|')?;
+    let (length, rest) = rest.split_once('(')?;
+    let (factors, unit) = rest.split_once(')')?;
+    let (scale, offset) = factors.split_once(',')?;
+    let (length, signed) = match length.strip_suffix('s') {
+        Some(length) => (length, true),
+        None => (length, false),
+    };
+    let start: u16 = start.parse().ok()?;
+    let length: u8 = length.parse().ok().filter(|length| (1..=32).contains(length))?;
+    let scale: f32 = scale.parse().ok()?;
+    let offset: f32 = offset.parse().ok()?;
+    Some(format!(
+        "crate::signals::SignalDefinition {{ name: {:?}, start: {}, length: {}, signed: {}, scale: {:?}, offset: {:?} }} /* {} */",
+        name, start, length, signed, scale, offset, unit,
+    ))
+}
+
+// Each queries/<profile>.csv becomes $OUT_DIR/queries/<profile>.rs, a
+// polling::QueryDefinition slice expression the vehicle profile includes,
+// and $OUT_DIR/signals/<profile>.rs, the signals::SignalDefinition slices
+// for each query in the same order. Rows are `ecu, did, interval,
+// forwarding id, signals` with the signals separated by spaces (see
+// parse_signal), lines starting with # are comments.
+fn generate_queries(out: &Path) {
+    fs::create_dir_all(out.join("queries")).unwrap();
+    fs::create_dir_all(out.join("signals")).unwrap();
+    for entry in fs::read_dir("queries").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|extension| extension != "csv") {
+            continue;
+        }
+        let mut generated = String::from("&[\n");
+        let mut signals = String::from("&[\n");
+        for (number, line) in fs::read_to_string(&path).unwrap().lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fail = |problem: &str| -> ! {
+                panic!("{}:{}: {}", path.display(), number + 1, problem)
+            };
+            let fields: Vec<&str> = line.splitn(5, ',').map(str::trim).collect();
+            if fields.len() < 4 {
+                fail("expected ecu, did, interval, forwarding id");
+            }
+            let ecu = ECUS
+                .iter()
+                .find(|(name, _)| *name == fields[0])
+                .unwrap_or_else(|| fail("unknown ECU"))
+                .1;
+            let did = parse_hex(fields[1])
+                .filter(|&did| did <= 0xFFFF)
+                .unwrap_or_else(|| fail("DID has to be 2 bytes of hex"));
+            let interval: u16 = fields[2]
+                .parse()
+                .unwrap_or_else(|_| fail("interval has to be whole seconds"));
+            let forwarding_id = parse_hex(fields[3])
+                .filter(|&id| id <= 0x7FF)
+                .unwrap_or_else(|| fail("forwarding ID has to be an 11-bit hex ID"));
+            generated += &format!(
+                "    crate::polling::QueryDefinition {{ ecu: crate::polling::{}, did: [{:#04X}, {:#04X}], interval: {}, forwarding_id: {:#05X} }},\n",
+                ecu,
+                did >> 8,
+                did & 0xFF,
+                interval,
+                forwarding_id,
+            );
+            signals += "    &[\n";
+            for token in fields.get(4).unwrap_or(&"").split_whitespace() {
+                let signal = parse_signal(token).unwrap_or_else(|| fail("signals have to look like name=start|length(scale,offset)unit"));
+                signals += &format!("        {},\n", signal);
+            }
+            signals += "    ],\n";
+        }
+        generated += "]\n";
+        signals += "]\n";
+        let name = path.file_stem().unwrap();
+        fs::write(out.join("queries").join(name).with_extension("rs"), generated).unwrap();
+        fs::write(out.join("signals").join(name).with_extension("rs"), signals).unwrap();
+    }
+    println!("cargo:rerun-if-changed=queries");
+}
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -28,4 +138,6 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    generate_queries(out);
 }
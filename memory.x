@@ -1,15 +1,16 @@
 MEMORY {
+    /* BOOT2 and the first 24K of flash belong to the bootloader (see bootloader/memory.x) */
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    FLASH : ORIGIN = 0x10007000, LENGTH = 512K
+    DFU : ORIGIN = 0x10087000, LENGTH = 516K
+    /* Persistent data, see src/storage.rs */
+    STORAGE : ORIGIN = 0x101F0000, LENGTH = 64K
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
-EXTERN(BOOT2_FIRMWARE)
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
 
-SECTIONS {
-    /* ### Boot loader */
-    .boot2 ORIGIN(BOOT2) :
-    {
-        KEEP(*(.boot2));
-    } > BOOT2
-} INSERT BEFORE .text;
\ No newline at end of file
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
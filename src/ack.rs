@@ -0,0 +1,78 @@
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::protocol::{Message, MessageType, Source};
+
+// With CAP_ACK negotiated, critical messages are held on to after they're forwarded until the host echoes their source
+// and sequence number back in an ACK command. Whatever isn't acknowledged within ACK_TIMEOUT goes out again with the
+// same sequence number, up to MAX_RETRANSMITS times, so the host has to expect repeats of messages it already has.
+
+const ACK_TIMEOUT: Duration = Duration::from_millis(250);
+const MAX_RETRANSMITS: u8 = 3;
+
+struct Unacknowledged {
+    message: Message,
+    sequence: u8,
+    sent_at: Instant,
+    retransmits: u8,
+}
+
+static UNACKNOWLEDGED: Mutex<CriticalSectionRawMutex, RefCell<Vec<Unacknowledged, 4>>> = Mutex::new(RefCell::new(Vec::new()));
+
+// DTCs, error reports and command responses
+pub fn needs_ack(message_type: MessageType) -> bool {
+    matches!(message_type, MessageType::Dtc | MessageType::ControllerError | MessageType::CommandResponse)
+}
+
+// Call once a message that needs an ACK has been forwarded for the first time
+pub fn sent(message: &Message, sequence: u8) {
+    UNACKNOWLEDGED.lock(|unacknowledged| {
+        let mut unacknowledged = unacknowledged.borrow_mut();
+        if unacknowledged.is_full() {
+            let oldest = unacknowledged.remove(0);
+            warn!("Too many unacknowledged messages, giving up on {:x} sequence {}", oldest.message.id.as_raw(), oldest.sequence);
+        }
+        unacknowledged.push(Unacknowledged { message: message.clone(), sequence, sent_at: Instant::now(), retransmits: 0 }).ok();
+    });
+}
+
+pub fn acknowledge(source: Source, sequence: u8) {
+    UNACKNOWLEDGED.lock(|unacknowledged| {
+        unacknowledged.borrow_mut().retain(|pending| pending.message.source != source || pending.sequence != sequence);
+    });
+}
+
+// Resolves with the next message that has to be sent again and the sequence number it has to go out with
+pub async fn next_retransmit() -> (Message, u8) {
+    loop {
+        let due_at = UNACKNOWLEDGED.lock(|unacknowledged| {
+            unacknowledged.borrow().iter().map(|pending| pending.sent_at + ACK_TIMEOUT).min()
+        });
+        match due_at {
+            Some(due_at) => Timer::at(due_at).await,
+            None => core::future::pending().await,
+        }
+        let due = UNACKNOWLEDGED.lock(|unacknowledged| {
+            let mut unacknowledged = unacknowledged.borrow_mut();
+            // Acknowledged while we were waiting
+            let position = unacknowledged.iter().position(|pending| pending.sent_at.elapsed() >= ACK_TIMEOUT)?;
+            if unacknowledged[position].retransmits >= MAX_RETRANSMITS {
+                let dropped = unacknowledged.remove(position);
+                warn!("No ACK for {:x} sequence {}, giving up", dropped.message.id.as_raw(), dropped.sequence);
+                return None;
+            }
+            let pending = &mut unacknowledged[position];
+            pending.retransmits += 1;
+            pending.sent_at = Instant::now();
+            Some((pending.message.clone(), pending.sequence))
+        });
+        if let Some(due) = due {
+            return due;
+        }
+    }
+}
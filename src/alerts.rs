@@ -0,0 +1,149 @@
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, CONFIG};
+use crate::protocol::{Message, MessageType, Source};
+use crate::FORWARDING_QUEUE;
+
+pub const ALERT_FORWARDING_ID: u16 = 0x790;
+pub const MAX_ALERT_RULES: usize = 4;
+
+// Decoded signal samples the alert rules are evaluated against
+pub static SIGNAL_CHANNEL: Channel<CriticalSectionRawMutex, (Signal, f32), 8> = Channel::new();
+
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub enum Signal {
+    // Max - min cell voltage (V)
+    CellVoltageDelta,
+    // 12 V auxiliary battery voltage (V)
+    AuxBatteryVoltage,
+}
+impl Signal {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::CellVoltageDelta),
+            1 => Some(Self::AuxBatteryVoltage),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub enum Direction {
+    Above,
+    Below,
+}
+impl Direction {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::Above),
+            1 => Some(Self::Below),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub signal: Signal,
+    pub direction: Direction,
+    // The alert raises once the value is past `on_threshold` for `debounce_on` and only clears once it's back past
+    // `off_threshold` for `debounce_off`, so a noisy signal sitting on the threshold doesn't chatter
+    pub on_threshold: f32,
+    pub off_threshold: f32,
+    #[serde(with = "config::milliseconds")]
+    pub debounce_on: Duration,
+    #[serde(with = "config::milliseconds")]
+    pub debounce_off: Duration,
+    // Minimum time between two raises of the same alert being reported
+    #[serde(with = "config::milliseconds")]
+    pub min_repeat: Duration,
+}
+impl AlertRule {
+    fn past_on_threshold(&self, value: f32) -> bool {
+        match self.direction {
+            Direction::Above => value > self.on_threshold,
+            Direction::Below => value < self.on_threshold,
+        }
+    }
+    fn past_off_threshold(&self, value: f32) -> bool {
+        match self.direction {
+            Direction::Above => value < self.off_threshold,
+            Direction::Below => value > self.off_threshold,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum RuleState {
+    Inactive,
+    Raising(Instant),
+    Active,
+    Clearing(Instant),
+}
+
+struct AlertEngine {
+    states: [RuleState; MAX_ALERT_RULES],
+    last_raised: [Option<Instant>; MAX_ALERT_RULES],
+    // Whether the host was told about the current raise, a raise held back by min_repeat doesn't get its clear reported
+    // either
+    reported: [bool; MAX_ALERT_RULES],
+}
+impl AlertEngine {
+    fn new() -> Self {
+        Self {
+            states: [RuleState::Inactive; MAX_ALERT_RULES],
+            last_raised: [None; MAX_ALERT_RULES],
+            reported: [false; MAX_ALERT_RULES],
+        }
+    }
+    // Returns the rules whose reported state changed as (rule index, now active)
+    fn update(&mut self, rules: &[AlertRule], signal: Signal, value: f32, now: Instant) -> Vec<(u8, bool), MAX_ALERT_RULES> {
+        let mut changes = Vec::new();
+        for (i, rule) in rules.iter().enumerate().filter(|(_, rule)| rule.signal == signal) {
+            self.states[i] = match self.states[i] {
+                RuleState::Inactive if rule.past_on_threshold(value) => RuleState::Raising(now),
+                RuleState::Raising(_) if !rule.past_on_threshold(value) => RuleState::Inactive,
+                RuleState::Raising(since) if now - since >= rule.debounce_on => {
+                    let rate_limited = self.last_raised[i].is_some_and(|last| now - last < rule.min_repeat);
+                    self.reported[i] = !rate_limited;
+                    if !rate_limited {
+                        self.last_raised[i] = Some(now);
+                        changes.push((i as u8, true)).unwrap();
+                    }
+                    RuleState::Active
+                },
+                RuleState::Active if rule.past_off_threshold(value) => RuleState::Clearing(now),
+                RuleState::Clearing(_) if !rule.past_off_threshold(value) => RuleState::Active,
+                RuleState::Clearing(since) if now - since >= rule.debounce_off => {
+                    if self.reported[i] {
+                        changes.push((i as u8, false)).unwrap();
+                    }
+                    RuleState::Inactive
+                },
+                state => state,
+            };
+        }
+        changes
+    }
+}
+
+#[embassy_executor::task]
+pub async fn alert_task() {
+    let mut engine = AlertEngine::new();
+    loop {
+        let (signal, value) = SIGNAL_CHANNEL.receive().await;
+        let rules = CONFIG.lock().await.alert_rules;
+        for (rule, active) in engine.update(&rules, signal, value, Instant::now()) {
+            info!("Alert rule {} ({}) {} at {}", rule, signal, if active { "raised" } else { "cleared" }, value);
+            let mut forward_data: Vec<u8, 64> = Vec::new();
+            forward_data.extend_from_slice(&[rule, active as u8]).unwrap();
+            forward_data.extend_from_slice(&value.to_be_bytes()).unwrap();
+            FORWARDING_QUEUE.send(Message::new(ALERT_FORWARDING_ID, MessageType::Alert, Source::Firmware, forward_data)).await;
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::protocol::{Message, MessageType, Source};
+
+// Small forwarded messages are packed into one FD frame per period instead of going out one frame each. Each entry is
+// [forwarding ID (2 bytes), message type, payload length, payload...]
+pub const BATCH_FORWARDING_ID: u16 = 0x7D0;
+pub const ENTRY_HEADER_LENGTH: usize = 4;
+// How long the first entry can sit in a batch waiting for company
+const BATCH_PERIOD: Duration = Duration::from_millis(100);
+
+pub struct Batcher {
+    entries: Vec<u8, 64>,
+    started_at: Option<Instant>,
+}
+impl Batcher {
+    pub fn new() -> Self {
+        Self { entries: Vec::new(), started_at: None }
+    }
+
+    // Adds the message to the batch if there's room, given the space a batch frame has for its payload. Returns
+    // whatever has to be sent right away: the message itself if it's too big to ever be batched, or the current batch
+    // if the message didn't fit and started a new one.
+    pub fn add(&mut self, message: Message, capacity: usize) -> Option<Message> {
+        let capacity = capacity.min(self.entries.capacity());
+        let entry_length = ENTRY_HEADER_LENGTH + message.payload.len();
+        if entry_length > capacity {
+            return Some(message);
+        }
+        let full = if self.entries.len() + entry_length > capacity { self.take() } else { None };
+
+        self.entries.extend_from_slice(&message.id.as_raw().to_be_bytes()).unwrap();
+        self.entries.extend_from_slice(&[message.message_type as u8, message.payload.len() as u8]).unwrap();
+        self.entries.extend_from_slice(&message.payload).unwrap();
+        self.started_at.get_or_insert_with(Instant::now);
+        full
+    }
+
+    pub fn take(&mut self) -> Option<Message> {
+        let started_at = self.started_at.take()?;
+        let entries = core::mem::take(&mut self.entries);
+        Some(Message::new(BATCH_FORWARDING_ID, MessageType::Batch, Source::Firmware, entries).at(started_at))
+    }
+
+    // Resolves once the current batch is due to go out, never while it's empty
+    pub async fn wait(&self) {
+        match self.started_at {
+            Some(started_at) => Timer::at(started_at + BATCH_PERIOD).await,
+            None => core::future::pending().await,
+        }
+    }
+}
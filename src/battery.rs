@@ -0,0 +1,85 @@
+use defmt::*;
+use embassy_rp::adc::{Adc, Async, Channel};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Ticker};
+use heapless::Vec;
+use portable_atomic::{AtomicU16, Ordering};
+
+use crate::protocol::{Message, MessageType, Source};
+use crate::FORWARDING_QUEUE;
+
+// The 12 V supply rail, sampled through a resistor divider on ADC2 and smoothed, forwarded as [millivolts (2 bytes),
+// 0x01 if the DC-DC converter (or alternator) is charging]. A drop from charging to resting voltage means the car was
+// switched off, which starts the parked timers in power.rs and the sender's slow polling without waiting for the BMS to
+// say so.
+pub const SUPPLY_FORWARDING_ID: u16 = 0x7A3;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+// Every this many samples
+const FORWARD_EVERY: u32 = 10;
+// 100k over 22k
+const DIVIDER_RATIO: f32 = 122.0 / 22.0;
+const ADC_REFERENCE: f32 = 3.3;
+const ADC_FULL_SCALE: f32 = 4095.0;
+// Exponential moving average, about a second to settle at the sample rate
+const FILTER_ALPHA: f32 = 0.1;
+// A resting lead-acid battery sits around 12.6 V, charging pushes it above 13.5 V. The gap keeps it from flapping.
+const CHARGING_ABOVE: f32 = 13.2;
+const RESTING_BELOW: f32 = 12.9;
+
+// Latest filtered reading in millivolts, 0 until the first sample
+static SUPPLY_MILLIVOLTS: AtomicU16 = AtomicU16::new(0);
+
+pub fn supply_millivolts() -> u16 {
+    SUPPLY_MILLIVOLTS.load(Ordering::Relaxed)
+}
+
+#[embassy_executor::task]
+pub async fn supply_task(
+    adc: &'static Mutex<CriticalSectionRawMutex, Adc<'static, Async>>,
+    mut channel: Channel<'static>,
+    car_off_since: &'static Mutex<CriticalSectionRawMutex, Option<Instant>>,
+) {
+    let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+    let mut filtered: Option<f32> = None;
+    let mut charging = false;
+    let mut samples: u32 = 0;
+    loop {
+        ticker.next().await;
+        let raw = match adc.lock().await.read(&mut channel).await {
+            Ok(raw) => raw,
+            Err(err) => {
+                error!("Unable to read the supply voltage: {}", err);
+                continue;
+            },
+        };
+        let volts = raw as f32 * ADC_REFERENCE / ADC_FULL_SCALE * DIVIDER_RATIO;
+        let volts = match filtered {
+            Some(previous) => previous + FILTER_ALPHA * (volts - previous),
+            None => volts,
+        };
+        filtered = Some(volts);
+        SUPPLY_MILLIVOLTS.store((volts * 1000.0) as u16, Ordering::Relaxed);
+
+        if !charging && volts > CHARGING_ABOVE {
+            charging = true;
+        }
+        else if charging && volts < RESTING_BELOW {
+            charging = false;
+            info!("Supply dropped to {} V, car switched off", volts);
+            let mut car_off_since = car_off_since.lock().await;
+            if car_off_since.is_none() {
+                *car_off_since = Some(Instant::now());
+            }
+        }
+
+        samples = samples.wrapping_add(1);
+        if samples % FORWARD_EVERY == 0 {
+            let mut forward_data: Vec<u8, 64> = Vec::new();
+            forward_data.extend_from_slice(&supply_millivolts().to_be_bytes()).unwrap();
+            forward_data.push(charging as u8).unwrap();
+            FORWARDING_QUEUE.send(Message::new(SUPPLY_FORWARDING_ID, MessageType::Supply, Source::Sensors, forward_data)).await;
+        }
+    }
+}
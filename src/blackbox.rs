@@ -0,0 +1,141 @@
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+use embedded_can::Id;
+use heapless::{Deque, Vec};
+use mcp25xxfd::frame::Frame;
+
+use crate::protocol::{Message, MessageType, Source, MAX_MESSAGE_LENGTH};
+use crate::storage::{self, FlashMutex};
+use crate::FORWARDING_QUEUE;
+
+// The last ENTRY_COUNT frames sent or received on the vehicle bus, kept in RAM and written to BLACKBOX_SECTOR on a
+// panic, hard fault or bus-off so the traffic leading up to it survives the reset. The host asks for the stored dump
+// with a command and gets it back in parts: [reason, uptime at the dump (ms, 4 bytes), part, part count], followed by
+// [milliseconds since boot (4 bytes), 0x01 if transmitted, ID (4 bytes, bit 31 set for extended IDs), length, data
+// (8 bytes)] for each frame, oldest first.
+pub const BLACKBOX_FORWARDING_ID: u16 = 0x7B8;
+
+const ENTRY_COUNT: usize = 32;
+const ENTRY_LENGTH: usize = 18;
+const BLACKBOX_STORE_MAGIC: u32 = 0x4242_5831; // "BBX1"
+// [magic (4 bytes), reason, uptime (4 bytes), entry count]
+const HEADER_LENGTH: usize = 10;
+const ENTRIES_PER_MESSAGE: usize = (MAX_MESSAGE_LENGTH - 7) / ENTRY_LENGTH;
+
+#[derive(Clone, Copy, Format)]
+pub enum Reason {
+    Panic = 1,
+    HardFault = 2,
+    ObdBusOff = 3,
+    CommaBusOff = 4,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    timestamp: u32,
+    transmitted: bool,
+    raw_id: u32,
+    length: u8,
+    data: [u8; 8],
+}
+
+// Taken from the crash handlers as well, so a blocking critical section rather than an async mutex
+static ENTRIES: Mutex<CriticalSectionRawMutex, RefCell<Deque<Entry, ENTRY_COUNT>>> = Mutex::new(RefCell::new(Deque::new()));
+static DUMP_REQUESTS: Signal<CriticalSectionRawMutex, Reason> = Signal::new();
+
+fn record(transmitted: bool, frame: &Frame) {
+    let raw_id = match frame.id() {
+        Id::Standard(id) => id.as_raw() as u32,
+        Id::Extended(id) => id.as_raw() | 0x8000_0000,
+    };
+    // FD frames only keep their first 8 bytes
+    let length = frame.data().len().min(8);
+    let mut entry = Entry { timestamp: Instant::now().as_millis() as u32, transmitted, raw_id, length: length as u8, data: [0; 8] };
+    entry.data[..length].copy_from_slice(&frame.data()[..length]);
+    ENTRIES.lock(|entries| {
+        let mut entries = entries.borrow_mut();
+        if entries.is_full() {
+            entries.pop_front();
+        }
+        entries.push_back(entry).ok().unwrap();
+    });
+}
+
+pub fn received(frame: &Frame) {
+    record(false, frame);
+}
+pub fn transmitted(frame: &Frame) {
+    record(true, frame);
+}
+
+fn snapshot(reason: Reason) -> [u8; HEADER_LENGTH + ENTRY_COUNT * ENTRY_LENGTH] {
+    let mut buf = [0xFFu8; HEADER_LENGTH + ENTRY_COUNT * ENTRY_LENGTH];
+    buf[..4].copy_from_slice(&BLACKBOX_STORE_MAGIC.to_be_bytes());
+    buf[4] = reason as u8;
+    buf[5..9].copy_from_slice(&(Instant::now().as_millis() as u32).to_be_bytes());
+    ENTRIES.lock(|entries| {
+        let entries = entries.borrow();
+        buf[9] = entries.len() as u8;
+        for (entry, out) in entries.iter().zip(buf[HEADER_LENGTH..].chunks_exact_mut(ENTRY_LENGTH)) {
+            out[..4].copy_from_slice(&entry.timestamp.to_be_bytes());
+            out[4] = entry.transmitted as u8;
+            out[5..9].copy_from_slice(&entry.raw_id.to_be_bytes());
+            out[9] = entry.length;
+            out[10..].copy_from_slice(&entry.data);
+        }
+    });
+    buf
+}
+
+// From the crash handlers, with the rest of the firmware stopped
+pub fn dump_blocking(reason: Reason) {
+    if storage::crash_write_sector(storage::BLACKBOX_SECTOR, &snapshot(reason)).is_err() {
+        error!("Unable to dump blackbox");
+    }
+}
+
+// From tasks, the dump is written by dump_task
+pub fn request_dump(reason: Reason) {
+    DUMP_REQUESTS.signal(reason);
+}
+
+#[embassy_executor::task]
+pub async fn dump_task(flash: &'static FlashMutex) {
+    loop {
+        let reason = DUMP_REQUESTS.wait().await;
+        warn!("Dumping blackbox after {}", reason);
+        if let Err(err) = storage::write_sector(flash, storage::BLACKBOX_SECTOR, &snapshot(reason)) {
+            error!("Unable to dump blackbox: {}", err);
+        }
+    }
+}
+
+// Forwards the stored dump, returns how many frames it held (0 if there isn't one)
+pub async fn send(flash: &FlashMutex) -> u8 {
+    let mut buf = [0u8; HEADER_LENGTH + ENTRY_COUNT * ENTRY_LENGTH];
+    if let Err(err) = storage::read(flash, storage::BLACKBOX_SECTOR, &mut buf) {
+        error!("Unable to read blackbox: {}", err);
+        return 0;
+    }
+    if u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) != BLACKBOX_STORE_MAGIC {
+        // Erased, never dumped
+        return 0;
+    }
+    let count = (buf[9] as usize).min(ENTRY_COUNT);
+    let entries = &buf[HEADER_LENGTH..HEADER_LENGTH + count * ENTRY_LENGTH];
+    let parts = count.div_ceil(ENTRIES_PER_MESSAGE).max(1);
+    for part in 0..parts {
+        let mut forward_data: Vec<u8, MAX_MESSAGE_LENGTH> = Vec::new();
+        forward_data.extend_from_slice(&buf[4..9]).unwrap();
+        forward_data.extend_from_slice(&[part as u8, parts as u8]).unwrap();
+        let chunk = entries.chunks(ENTRIES_PER_MESSAGE * ENTRY_LENGTH).nth(part).unwrap_or(&[]);
+        forward_data.extend_from_slice(chunk).unwrap();
+        FORWARDING_QUEUE.send(Message::new(BLACKBOX_FORWARDING_ID, MessageType::Blackbox, Source::Firmware, forward_data)).await;
+    }
+    count as u8
+}
@@ -0,0 +1,129 @@
+// Pin mappings for each board revision. The ioniq-v2 board in hardware/ is the default, the pico feature selects a
+// breadboard build on a Raspberry Pi Pico with two MCP2518FD breakouts. The Pico keeps GPIO 23-25 for itself, so the
+// transceiver STBY lines and the status LED move, and GPIO 29 so there's no thermistor input.
+//
+// main takes the pins out of the peripherals with take_pins!(p), which leaves the rest of the peripherals usable.
+
+#[cfg(not(feature = "pico"))]
+pub mod pins {
+    use embassy_rp::peripherals::*;
+
+    pub const NAME: &str = "ioniq-v2";
+
+    pub type SpiSclk = PIN_18;
+    pub type SpiMosi = PIN_19;
+    pub type SpiMiso = PIN_20;
+    pub type ObdCs = PIN_21;
+    pub type ObdInt = PIN_14;
+    pub type ObdStby = PIN_24;
+    pub type CommaCs = PIN_22;
+    pub type CommaInt = PIN_15;
+    pub type CommaStby = PIN_25;
+    pub type I2cScl = PIN_1;
+    pub type I2cSda = PIN_0;
+    pub type StatusLed = PIN_16;
+    pub type Strap0 = PIN_26;
+    pub type Strap1 = PIN_27;
+    pub type GpsRx = PIN_9;
+    pub type SupplySense = PIN_28;
+    pub type NtcSense = PIN_29;
+
+    macro_rules! take_pins {
+        ($p:ident) => {
+            $crate::board::Pins {
+                spi_sclk: $p.PIN_18,
+                spi_mosi: $p.PIN_19,
+                spi_miso: $p.PIN_20,
+                obd_cs: $p.PIN_21,
+                obd_int: $p.PIN_14,
+                obd_stby: $p.PIN_24,
+                comma_cs: $p.PIN_22,
+                comma_int: $p.PIN_15,
+                comma_stby: $p.PIN_25,
+                i2c_scl: $p.PIN_1,
+                i2c_sda: $p.PIN_0,
+                status_led: $p.PIN_16,
+                strap0: $p.PIN_26,
+                strap1: $p.PIN_27,
+                gps_rx: $p.PIN_9,
+                supply_sense: $p.PIN_28,
+                ntc_sense: $p.PIN_29,
+            }
+        };
+    }
+    pub(crate) use take_pins;
+}
+
+#[cfg(feature = "pico")]
+pub mod pins {
+    use embassy_rp::peripherals::*;
+
+    pub const NAME: &str = "Pico breadboard";
+
+    pub type SpiSclk = PIN_18;
+    pub type SpiMosi = PIN_19;
+    pub type SpiMiso = PIN_16;
+    pub type ObdCs = PIN_17;
+    pub type ObdInt = PIN_14;
+    pub type ObdStby = PIN_12;
+    pub type CommaCs = PIN_20;
+    pub type CommaInt = PIN_15;
+    pub type CommaStby = PIN_13;
+    pub type I2cScl = PIN_5;
+    pub type I2cSda = PIN_4;
+    // The Pico's onboard LED
+    pub type StatusLed = PIN_25;
+    pub type Strap0 = PIN_26;
+    pub type Strap1 = PIN_27;
+    pub type GpsRx = PIN_9;
+    pub type SupplySense = PIN_28;
+
+    macro_rules! take_pins {
+        ($p:ident) => {
+            $crate::board::Pins {
+                spi_sclk: $p.PIN_18,
+                spi_mosi: $p.PIN_19,
+                spi_miso: $p.PIN_16,
+                obd_cs: $p.PIN_17,
+                obd_int: $p.PIN_14,
+                obd_stby: $p.PIN_12,
+                comma_cs: $p.PIN_20,
+                comma_int: $p.PIN_15,
+                comma_stby: $p.PIN_13,
+                i2c_scl: $p.PIN_5,
+                i2c_sda: $p.PIN_4,
+                status_led: $p.PIN_25,
+                strap0: $p.PIN_26,
+                strap1: $p.PIN_27,
+                gps_rx: $p.PIN_9,
+                supply_sense: $p.PIN_28,
+            }
+        };
+    }
+    pub(crate) use take_pins;
+}
+
+pub use pins::NAME;
+pub(crate) use pins::take_pins;
+
+// Both controllers share SPI0, the environment sensor (BME280 or SHT4x) and optional LPS22 barometer are on I2C0. The strap pins select a bus profile, see strap.rs. An optional GPS module's TX goes to UART1 RX, and the 12 V supply is divided down onto ADC2. An external thermistor can go on ADC3, see ntc.rs.
+pub struct Pins {
+    pub spi_sclk: pins::SpiSclk,
+    pub spi_mosi: pins::SpiMosi,
+    pub spi_miso: pins::SpiMiso,
+    pub obd_cs: pins::ObdCs,
+    pub obd_int: pins::ObdInt,
+    pub obd_stby: pins::ObdStby,
+    pub comma_cs: pins::CommaCs,
+    pub comma_int: pins::CommaInt,
+    pub comma_stby: pins::CommaStby,
+    pub i2c_scl: pins::I2cScl,
+    pub i2c_sda: pins::I2cSda,
+    pub status_led: pins::StatusLed,
+    pub strap0: pins::Strap0,
+    pub strap1: pins::Strap1,
+    pub gps_rx: pins::GpsRx,
+    pub supply_sense: pins::SupplySense,
+    #[cfg(not(feature = "pico"))]
+    pub ntc_sense: pins::NtcSense,
+}
@@ -0,0 +1,132 @@
+use defmt::*;
+use embassy_boot_rp::{AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig, State};
+use embassy_rp::watchdog::Watchdog;
+use embassy_time::{Duration, Instant, Timer};
+use embassy_rp::pac;
+use portable_atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::storage::FlashMutex;
+use crate::supervisor;
+
+// Conditions a newly swapped-in firmware image has to reach before it's marked as good
+pub static OBD_BUS_UP: AtomicBool = AtomicBool::new(false);
+pub static COMMA_BUS_UP: AtomicBool = AtomicBool::new(false);
+// Set by the host's heartbeat command, see commands::Command::Heartbeat
+pub static HOST_HEARTBEAT_SEEN: AtomicBool = AtomicBool::new(false);
+
+// Why the chip last came out of reset, reported in the heartbeat so unexpected reboots in the car can be told apart
+// from power cycles
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum ResetReason {
+    PowerOn = 0,
+    // RUN pin pulled low
+    RunPin = 1,
+    // Rescue or restart through the debug port
+    Debugger = 2,
+    // Watchdog timed out, the firmware hung
+    Watchdog = 3,
+    // The firmware reset itself on purpose, e.g. to revert a configuration or firmware image
+    Software = 4,
+    // Panic or hard fault, see crash.rs
+    Crash = 5,
+}
+static RESET_REASON: AtomicU8 = AtomicU8::new(ResetReason::PowerOn as u8);
+// Software resets through SCB don't show up in the reset registers, so reset() leaves the reason in a watchdog scratch
+// register, which survives everything but power-on and RUN pin resets: magic in the upper bytes, reason in the lowest
+const RESET_SCRATCH_MAGIC: u32 = 0x5253_5400; // "RST"
+
+// Call once, first thing at boot
+pub fn read_reset_reason() {
+    let chip_reset = pac::VREG_AND_CHIP_RESET.chip_reset().read();
+    let watchdog_reason = pac::WATCHDOG.reason().read();
+    let scratch = pac::WATCHDOG.scratch7().read();
+    pac::WATCHDOG.scratch7().write_value(0);
+    let reason = if scratch & 0xFFFF_FF00 == RESET_SCRATCH_MAGIC && scratch as u8 == ResetReason::Crash as u8 {
+        ResetReason::Crash
+    }
+    else if scratch & 0xFFFF_FF00 == RESET_SCRATCH_MAGIC || watchdog_reason.force() {
+        ResetReason::Software
+    }
+    else if watchdog_reason.timer() {
+        ResetReason::Watchdog
+    }
+    else if chip_reset.had_psm_restart() {
+        ResetReason::Debugger
+    }
+    else if chip_reset.had_run() {
+        ResetReason::RunPin
+    }
+    else {
+        ResetReason::PowerOn
+    };
+    info!("Reset reason: {}", reason);
+    RESET_REASON.store(reason as u8, Ordering::Relaxed);
+}
+
+pub fn reset_reason() -> u8 {
+    RESET_REASON.load(Ordering::Relaxed)
+}
+
+// Resets the chip, recording why for the next boot
+pub fn reset(reason: ResetReason) -> ! {
+    pac::WATCHDOG.scratch7().write_value(RESET_SCRATCH_MAGIC | reason as u32);
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+// If a new image isn't confirmed within this time we reset and the bootloader reverts to the previous image
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(120);
+// The bootloader leaves the watchdog running, so it has to be fed from here on
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+
+#[embassy_executor::task]
+pub async fn boot_confirm_task(flash: &'static FlashMutex, mut watchdog: Watchdog) {
+    watchdog.start(WATCHDOG_TIMEOUT);
+
+    let config = FirmwareUpdaterConfig::from_linkerfile_blocking(flash, flash);
+    let mut aligned = AlignedBuffer([0; 1]);
+    let mut updater = BlockingFirmwareUpdater::new(config, &mut aligned.0);
+
+    let mut unconfirmed = match updater.get_state() {
+        Ok(State::Swap) => {
+            info!("Running a newly swapped firmware image, waiting for buses and host before confirming it");
+            true
+        },
+        Ok(_) => false,
+        Err(err) => {
+            error!("Unable to read bootloader state: {}", err);
+            false
+        },
+    };
+
+    let start = Instant::now();
+    loop {
+        if unconfirmed {
+            let obd_bus_up = OBD_BUS_UP.load(Ordering::Relaxed);
+            let comma_bus_up = COMMA_BUS_UP.load(Ordering::Relaxed);
+            let host_heartbeat_seen = HOST_HEARTBEAT_SEEN.load(Ordering::Relaxed);
+
+            if obd_bus_up && comma_bus_up && host_heartbeat_seen {
+                match updater.mark_booted() {
+                    Ok(()) => info!("Firmware image confirmed after {} ms", start.elapsed().as_millis()),
+                    Err(err) => error!("Unable to confirm firmware image: {}", err),
+                }
+                unconfirmed = false;
+            }
+            else if start.elapsed() > CONFIRMATION_TIMEOUT {
+                error!(
+                    "Firmware image not confirmed (OBD bus up: {}, comma bus up: {}, host heartbeat: {}), reverting",
+                    obd_bus_up, comma_bus_up, host_heartbeat_seen,
+                );
+                Timer::after_millis(100).await;
+                watchdog.trigger_reset();
+            }
+        }
+        if let Some(task) = supervisor::stalled() {
+            supervisor::record_stall(task);
+            // Stop feeding, the watchdog resets the chip within WATCHDOG_TIMEOUT
+            core::future::pending::<()>().await;
+        }
+        watchdog.feed();
+        Timer::after_millis(500).await;
+    }
+}
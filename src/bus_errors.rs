@@ -0,0 +1,116 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use heapless::{Deque, Vec};
+
+use crate::mcp::ErrorCounters;
+use crate::protocol::{Message, MessageType, Source, MAX_MESSAGE_LENGTH};
+use crate::FORWARDING_QUEUE;
+
+// The last EVENT_COUNT error events on either bus, for tracking down intermittent wiring problems that are gone by the
+// time anyone looks at the bus health frames. The host asks for them with a command and gets [milliseconds since boot
+// (4 bytes), bus (0 = OBD, 1 = comma), kind, C1TREC state flags (EWARN, RXWARN, TXWARN, RXBP, TXBP, TXBO), C1BDIAG1
+// error flags (2 bytes), TEC, REC] for each, oldest first. For arbitration lost the error flags are the mask of TX
+// FIFOs (bit 0 is the TXQ) that gave up instead.
+pub const BUS_ERRORS_FORWARDING_ID: u16 = 0x7B9;
+
+const EVENT_COUNT: usize = 32;
+const EVENT_LENGTH: usize = 11;
+const EVENTS_PER_MESSAGE: usize = MAX_MESSAGE_LENGTH / EVENT_LENGTH;
+
+// C1TREC bits 19-21 as they appear in ErrorCounters::state_flags
+const STATE_ERROR_PASSIVE: u8 = 0b01_1000;
+const STATE_BUS_OFF: u8 = 0b10_0000;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    // Error flags from the diagnostic registers since the last bus health check (stuff, form, CRC, bit, ACK errors)
+    Errors = 1,
+    // Went error passive or bus-off, or recovered from either
+    StateChanged = 2,
+    // A frame was abandoned after losing arbitration on every attempt
+    ArbitrationLost = 3,
+}
+
+#[derive(Clone, Copy)]
+struct Event {
+    timestamp: u32,
+    bus: u8,
+    kind: Kind,
+    state_flags: u8,
+    error_flags: u16,
+    tec: u8,
+    rec: u8,
+}
+
+static EVENTS: Mutex<CriticalSectionRawMutex, RefCell<Deque<Event, EVENT_COUNT>>> = Mutex::new(RefCell::new(Deque::new()));
+// Error passive and bus-off bits from the previous check of each bus
+static LAST_STATE: Mutex<CriticalSectionRawMutex, RefCell<[u8; 2]>> = Mutex::new(RefCell::new([0; 2]));
+
+fn push(event: Event) {
+    EVENTS.lock(|events| {
+        let mut events = events.borrow_mut();
+        if events.is_full() {
+            events.pop_front();
+        }
+        events.push_back(event).ok().unwrap();
+    });
+}
+
+// From bus_health_task, with the counters it just read
+pub fn check(bus: u8, counters: &ErrorCounters) {
+    let event = |kind| Event {
+        timestamp: Instant::now().as_millis() as u32,
+        bus,
+        kind,
+        state_flags: counters.state_flags,
+        error_flags: counters.error_flags,
+        tec: counters.tec,
+        rec: counters.rec,
+    };
+    let state = counters.state_flags & (STATE_ERROR_PASSIVE | STATE_BUS_OFF);
+    let previous = LAST_STATE.lock(|last_state| core::mem::replace(&mut last_state.borrow_mut()[bus as usize & 1], state));
+    if state != previous {
+        push(event(Kind::StateChanged));
+    }
+    if counters.error_flags != 0 {
+        push(event(Kind::Errors));
+    }
+}
+
+pub fn arbitration_lost(bus: u8, fifos: u32) {
+    push(Event {
+        timestamp: Instant::now().as_millis() as u32,
+        bus,
+        kind: Kind::ArbitrationLost,
+        state_flags: 0,
+        error_flags: fifos as u16,
+        tec: 0,
+        rec: 0,
+    });
+}
+
+// Forwards the logged events, returns how many there were
+pub async fn send(clear: bool) -> u8 {
+    let events: Vec<Event, EVENT_COUNT> = EVENTS.lock(|events| {
+        let mut events = events.borrow_mut();
+        let copy = events.iter().copied().collect();
+        if clear {
+            events.clear();
+        }
+        copy
+    });
+    for chunk in events.chunks(EVENTS_PER_MESSAGE) {
+        let mut forward_data: Vec<u8, MAX_MESSAGE_LENGTH> = Vec::new();
+        for event in chunk {
+            forward_data.extend_from_slice(&event.timestamp.to_be_bytes()).unwrap();
+            forward_data.extend_from_slice(&[event.bus, event.kind as u8, event.state_flags]).unwrap();
+            forward_data.extend_from_slice(&event.error_flags.to_be_bytes()).unwrap();
+            forward_data.extend_from_slice(&[event.tec, event.rec]).unwrap();
+        }
+        FORWARDING_QUEUE.send(Message::new(BUS_ERRORS_FORWARDING_ID, MessageType::BusErrors, Source::Firmware, forward_data)).await;
+    }
+    events.len() as u8
+}
@@ -0,0 +1,93 @@
+use core::cell::RefCell;
+
+use critical_section::{CriticalSection, RestoreState};
+use embassy_time::{Duration, Timer};
+use heapless::{Deque, Vec};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::boot;
+use crate::protocol::{Message, MessageType, Source};
+use crate::FORWARDING_QUEUE;
+
+// defmt logger for units installed without a debug probe, enabled with the can-log feature in place of defmt-rtt. The
+// encoded defmt stream (rzCOBS frames separated by zero bytes) goes out on LOG_FORWARDING_ID in chunks and the host
+// feeds it to defmt-decoder with the firmware's ELF, just like it would the RTT channel. Records that don't fit in the
+// buffer are dropped, the decoder picks up again at the next frame.
+pub const LOG_FORWARDING_ID: u16 = 0x7B7;
+const BUFFER_SIZE: usize = 1024;
+const CHUNK_SIZE: usize = 60;
+// Collect a few records per message rather than sending each one on its own
+const DRAIN_INTERVAL: Duration = Duration::from_millis(250);
+
+static BUFFER: critical_section::Mutex<RefCell<Deque<u8, BUFFER_SIZE>>> = critical_section::Mutex::new(RefCell::new(Deque::new()));
+// Bytes that didn't fit in BUFFER since boot
+static OVERRUN: AtomicU32 = AtomicU32::new(0);
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+static mut RESTORE_STATE: RestoreState = RestoreState::invalid();
+static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+
+fn buffer(bytes: &[u8]) {
+    // Only called between acquire() and release(), inside their critical section
+    let cs = unsafe { CriticalSection::new() };
+    let mut buffer = BUFFER.borrow_ref_mut(cs);
+    for &byte in bytes {
+        if buffer.push_back(byte).is_err() {
+            OVERRUN.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[defmt::global_logger]
+struct CanLogger;
+unsafe impl defmt::Logger for CanLogger {
+    fn acquire() {
+        let restore = unsafe { critical_section::acquire() };
+        if TAKEN.swap(true, Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        unsafe {
+            RESTORE_STATE = restore;
+            (*core::ptr::addr_of_mut!(ENCODER)).start_frame(buffer);
+        }
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        (*core::ptr::addr_of_mut!(ENCODER)).end_frame(buffer);
+        TAKEN.store(false, Ordering::Relaxed);
+        critical_section::release(RESTORE_STATE);
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        (*core::ptr::addr_of_mut!(ENCODER)).write(bytes, buffer);
+    }
+}
+
+#[embassy_executor::task]
+pub async fn drain_task() {
+    let mut reported_overrun = 0;
+    loop {
+        Timer::after(DRAIN_INTERVAL).await;
+        // Keep everything from boot until there's somewhere to send it
+        if !boot::COMMA_BUS_UP.load(Ordering::Relaxed) {
+            continue;
+        }
+        loop {
+            let chunk: Vec<u8, CHUNK_SIZE> = critical_section::with(|cs| {
+                let mut buffer = BUFFER.borrow_ref_mut(cs);
+                core::iter::from_fn(|| buffer.pop_front()).take(CHUNK_SIZE).collect()
+            });
+            if chunk.is_empty() {
+                break;
+            }
+            FORWARDING_QUEUE.send(Message::new(LOG_FORWARDING_ID, MessageType::Log, Source::Firmware, chunk)).await;
+        }
+        let overrun = OVERRUN.load(Ordering::Relaxed);
+        if overrun != reported_overrun {
+            defmt::warn!("Log buffer overran, {} bytes dropped since boot", overrun);
+            reported_overrun = overrun;
+        }
+    }
+}
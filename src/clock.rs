@@ -0,0 +1,28 @@
+use embassy_time::Instant;
+use portable_atomic::{AtomicBool, AtomicU64, Ordering};
+
+// Wall clock time, once the host has told us what it is. Kept as an offset from the monotonic clock so timestamps
+// taken before a sync can still be converted.
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+static SYNCED: AtomicBool = AtomicBool::new(false);
+// Set once the host has synced the clock, GPS time only fills in until then
+static HOST_SYNCED: AtomicBool = AtomicBool::new(false);
+
+pub fn sync(unix_micros: u64) {
+    OFFSET.store(unix_micros.wrapping_sub(Instant::now().as_micros()), Ordering::Relaxed);
+    SYNCED.store(true, Ordering::Relaxed);
+    HOST_SYNCED.store(true, Ordering::Relaxed);
+}
+
+pub fn sync_from_gps(unix_micros: u64) {
+    if HOST_SYNCED.load(Ordering::Relaxed) {
+        return;
+    }
+    OFFSET.store(unix_micros.wrapping_sub(Instant::now().as_micros()), Ordering::Relaxed);
+    SYNCED.store(true, Ordering::Relaxed);
+}
+
+// Microseconds since the Unix epoch at the given instant
+pub fn wall_clock(at: Instant) -> Option<u64> {
+    SYNCED.load(Ordering::Relaxed).then(|| OFFSET.load(Ordering::Relaxed).wrapping_add(at.as_micros()))
+}
@@ -0,0 +1,372 @@
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Duration;
+use embedded_can::{ExtendedId, Id, StandardId};
+use heapless::Vec;
+use portable_atomic::Ordering;
+
+use crate::config::{self, BitRates, DataBitRate, EnvironmentOffsets, ForwardingIds, NominalBitRate};
+use crate::session::{self, Session};
+use crate::mcp;
+use crate::{ack, blackbox, boot, bus_errors, clock, factory_reset, gateway};
+use crate::log_level::{self, debug};
+use crate::polling::{self, QueryRequest, ECU_COUNT, QUERY_COUNT};
+use crate::storage::FlashMutex;
+use crate::protocol::{Message, MessageType, Source};
+use crate::subscriptions::{CaptureRequest, Subscription, CAPTURE_REQUESTS, MAX_CAPTURE_FILTERS, SUBSCRIPTION_REQUESTS};
+use crate::PRIORITY_FORWARDING_CHANNEL;
+
+// Control frames sent to us by the comma device
+pub const COMMAND_ID: u16 = 0x6F0;
+// Replies to commands, first byte echoes the command
+pub const COMMAND_RESPONSE_ID: u16 = 0x6F1;
+
+// Command frame payloads drained from the comma controller by its interrupt task
+pub const COMMAND_CHANNEL_DEPTH: usize = 8;
+pub static COMMAND_CHANNEL: Channel<CriticalSectionRawMutex, Vec<u8, 64>, COMMAND_CHANNEL_DEPTH> = Channel::new();
+
+#[derive(Format)]
+pub enum Command {
+    // [0x01, TTL seconds (2 bytes), ID (4 bytes, bit 31 set for extended IDs)...]
+    Subscribe(Subscription),
+    // [0x02]
+    Unsubscribe,
+    // [0x03, protocol version, capabilities (2 bytes), max payload]
+    Hello {
+        version: u8,
+        capabilities: u16,
+        max_payload: u8,
+    },
+    // [0x04, bus (0 = OBD, 1 = comma), nominal rate (0 = 500k, 1 = 250k, 2 = 125k), data rate (0 = 2M, 1 = 5M)]
+    SetBitRates {
+        bus: u8,
+        bit_rates: BitRates,
+    },
+    // [0x05, slot, ID (4 bytes, bit 31 set for extended IDs), mask (4 bytes, ID bits that have to match)]
+    // [0x06, slot] removes it again
+    Capture(CaptureRequest),
+    // [0x07, query index, 0x01 to poll it or 0x00 to stop]
+    // [0x08, query index, interval seconds (2 bytes, 0 = every cycle)]
+    // [0x09, ECU index, DID (2 bytes)] reads a DID once
+    // [0x0E, query index, max interval seconds (2 bytes, 0 = off)] backs the query off while its response is stable
+    Query(QueryRequest),
+    // [0x0A, query index (0xFF for the environment sensor), forwarding ID (2 bytes)]
+    SetForwardingId {
+        target: u8,
+        id: u16,
+    },
+    // [0x0B, source, sequence] acknowledges a forwarded message, never answered
+    Ack {
+        source: Source,
+        sequence: u8,
+    },
+    // [0x0C, microseconds since the Unix epoch (8 bytes)]
+    SyncClock(u64),
+    // [0x0D, ECU index, request length, request (up to 7 bytes)] sends a diagnostic request to the vehicle, see
+    // gateway.rs
+    Gateway {
+        ecu: u8,
+        request: Vec<u8, gateway::MAX_REQUEST_LENGTH>,
+    },
+    // [0x0F, ECU index, 0x01 to poll its queries or 0x00 to stop], e.g. for a car without TPMS
+    SetEcuPolled {
+        ecu: u8,
+        polled: bool,
+    },
+    // [0x10, temperature (2 bytes signed, 0.01 °C), pressure (2 bytes signed, Pa), humidity (2 bytes signed,
+    // 0.01 %RH)] calibrates the environment sensor, the offsets are added to its readings
+    SetEnvironmentOffsets(EnvironmentOffsets),
+    // [0x11] asks for a factory reset challenge, see factory_reset.rs
+    FactoryResetChallenge,
+    // [0x12, response (factory_reset::RESPONSE_LENGTH bytes)] wipes the stored configuration, DTCs and crash log and
+    // restarts
+    FactoryReset([u8; factory_reset::RESPONSE_LENGTH]),
+    // [0x13, level (0 = trace, 1 = debug, 2 = info)] sets the log verbosity until the next reset
+    SetLogLevel(log_level::Level),
+    // [0x14] forwards the stored blackbox dump, see blackbox.rs
+    SendBlackbox,
+    // [0x15, 0x01 to clear the log afterwards] forwards the logged bus error events, see bus_errors.rs
+    SendBusErrors {
+        clear: bool,
+    },
+    // [0x16] the host's periodic heartbeat, never answered. A newly swapped firmware image isn't confirmed until one
+    // arrives, see boot.rs
+    Heartbeat,
+}
+
+// 4 byte IDs with bit 31 set for extended IDs
+fn parse_id(raw_id: u32) -> Option<Id> {
+    if raw_id & 0x8000_0000 != 0 {
+        Some(ExtendedId::new(raw_id & 0x1FFF_FFFF)?.into())
+    }
+    else {
+        Some(StandardId::new(u16::try_from(raw_id).ok()?)?.into())
+    }
+}
+impl Command {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match *data.first()? {
+            0x01 => {
+                let ttl = u16::from_be_bytes([*data.get(1)?, *data.get(2)?]);
+                let mut ids = Vec::new();
+                for raw_id in data.get(3..)?.chunks_exact(4) {
+                    let raw_id = u32::from_be_bytes([raw_id[0], raw_id[1], raw_id[2], raw_id[3]]);
+                    if raw_id == 0 {
+                        // Frame padding
+                        break;
+                    }
+                    ids.push(parse_id(raw_id)?).ok()?;
+                }
+                Some(Self::Subscribe(Subscription { ids, ttl: Duration::from_secs(ttl as u64) }))
+            },
+            0x02 => Some(Self::Unsubscribe),
+            0x03 => Some(Self::Hello {
+                version: *data.get(1)?,
+                capabilities: u16::from_be_bytes([*data.get(2)?, *data.get(3)?]),
+                max_payload: *data.get(4)?,
+            }),
+            0x04 => Some(Self::SetBitRates {
+                bus: *data.get(1)?,
+                bit_rates: BitRates {
+                    nominal: NominalBitRate::from_code(*data.get(2)?)?,
+                    data: DataBitRate::from_code(*data.get(3)?)?,
+                },
+            }),
+            0x05 => {
+                let slot = *data.get(1)?;
+                if slot >= MAX_CAPTURE_FILTERS {
+                    return None;
+                }
+                let id = parse_id(u32::from_be_bytes(data.get(2..6)?.try_into().ok()?))?;
+                let mask_bits = u32::from_be_bytes(data.get(6..10)?.try_into().ok()?);
+                // Mask bits are in the same ID space as the filter's ID
+                let mask_id: Id = match id {
+                    Id::Standard(_) => StandardId::new((mask_bits & 0x7FF) as u16)?.into(),
+                    Id::Extended(_) => ExtendedId::new(mask_bits & 0x1FFF_FFFF)?.into(),
+                };
+                Some(Self::Capture(CaptureRequest::Add { slot, id, mask: mcp::partial_mask(mask_id) }))
+            },
+            0x06 => {
+                let slot = *data.get(1)?;
+                if slot >= MAX_CAPTURE_FILTERS {
+                    return None;
+                }
+                Some(Self::Capture(CaptureRequest::Remove { slot }))
+            },
+            0x07 | 0x08 | 0x0E => {
+                let index = *data.get(1)?;
+                if index as usize >= QUERY_COUNT {
+                    return None;
+                }
+                Some(Self::Query(match data[0] {
+                    0x07 => QueryRequest::SetEnabled { index, enabled: *data.get(2)? != 0 },
+                    0x0E => QueryRequest::SetAdaptive { index, max_interval: u16::from_be_bytes([*data.get(2)?, *data.get(3)?]) },
+                    _ => QueryRequest::SetInterval { index, interval: u16::from_be_bytes([*data.get(2)?, *data.get(3)?]) },
+                }))
+            },
+            0x09 => {
+                let ecu = *data.get(1)?;
+                if ecu >= ECU_COUNT {
+                    return None;
+                }
+                Some(Self::Query(QueryRequest::ReadDid { ecu, did: [*data.get(2)?, *data.get(3)?] }))
+            },
+            0x0A => {
+                let target = *data.get(1)?;
+                if target as usize >= QUERY_COUNT && target != ForwardingIds::ENVIRONMENT_TARGET {
+                    return None;
+                }
+                Some(Self::SetForwardingId { target, id: u16::from_be_bytes([*data.get(2)?, *data.get(3)?]) })
+            },
+            0x0B => Some(Self::Ack { source: Source::from_code(*data.get(1)?)?, sequence: *data.get(2)? }),
+            0x0C => Some(Self::SyncClock(u64::from_be_bytes(data.get(1..9)?.try_into().ok()?))),
+            0x0D => {
+                let ecu = *data.get(1)?;
+                if ecu >= ECU_COUNT {
+                    return None;
+                }
+                let length = *data.get(2)? as usize;
+                Some(Self::Gateway { ecu, request: Vec::from_slice(data.get(3..3 + length)?).ok()? })
+            },
+            0x0F => {
+                let ecu = *data.get(1)?;
+                if ecu >= ECU_COUNT {
+                    return None;
+                }
+                Some(Self::SetEcuPolled { ecu, polled: *data.get(2)? != 0 })
+            },
+            0x10 => Some(Self::SetEnvironmentOffsets(EnvironmentOffsets::from_bytes(data.get(1..)?)?)),
+            0x11 => Some(Self::FactoryResetChallenge),
+            0x12 => Some(Self::FactoryReset(data.get(1..1 + factory_reset::RESPONSE_LENGTH)?.try_into().ok()?)),
+            0x13 => Some(Self::SetLogLevel(log_level::Level::from_code(*data.get(1)?)?)),
+            0x14 => Some(Self::SendBlackbox),
+            0x15 => Some(Self::SendBusErrors { clear: data.get(1).is_some_and(|&clear| clear == 0x01) }),
+            0x16 => Some(Self::Heartbeat),
+            _ => None,
+        }
+    }
+}
+
+async fn respond(command: u8, data: &[u8]) {
+    let mut response: Vec<u8, 64> = Vec::new();
+    response.push(command).unwrap();
+    response.extend_from_slice(data).unwrap();
+    PRIORITY_FORWARDING_CHANNEL.send(Message::new(COMMAND_RESPONSE_ID, MessageType::CommandResponse, Source::Firmware, response)).await;
+}
+
+#[embassy_executor::task]
+pub async fn command_task(flash: &'static FlashMutex) {
+    loop {
+        let command = COMMAND_CHANNEL.receive().await;
+        handle_command(&command, flash).await;
+    }
+}
+
+async fn handle_command(data: &[u8], flash: &FlashMutex) {
+    match Command::parse(data) {
+        Some(command) => {
+            debug!("Received command: {}", command);
+            match command {
+                Command::Subscribe(subscription) => SUBSCRIPTION_REQUESTS.signal(Some(subscription)),
+                Command::Unsubscribe => SUBSCRIPTION_REQUESTS.signal(None),
+                Command::Capture(request) => {
+                    if CAPTURE_REQUESTS.try_send(request).is_err() {
+                        warn!("Dropping capture filter change, too many pending");
+                    }
+                },
+                Command::Gateway { ecu, request } => {
+                    // [0x0D, 0x01 if queued for the sender], refused unless the gateway is on and the request is
+                    // read-only
+                    let queued = if !config::CONFIG.lock().await.gateway_enabled {
+                        warn!("Gateway disabled, refusing request");
+                        false
+                    }
+                    else if !gateway::allowed(&request) {
+                        warn!("Refusing gateway request {:x}", request);
+                        false
+                    }
+                    else {
+                        polling::QUERY_REQUESTS.try_send(QueryRequest::Gateway { ecu, request }).is_ok()
+                    };
+                    respond(0x0D, &[queued as u8]).await;
+                },
+                Command::Query(request) => {
+                    // [command, 0x01 if queued for the sender]
+                    let queued = polling::QUERY_REQUESTS.try_send(request).is_ok();
+                    if !queued {
+                        warn!("Dropping query request, too many pending");
+                    }
+                    respond(data[0], &[queued as u8]).await;
+                },
+                Command::SetForwardingId { target, id } => {
+                    let mut config = config::CONFIG.lock().await;
+                    let forwarding_ids = config.forwarding_ids.with(target, id).unwrap();
+                    // [0x0A, target, 0x01 if applied], rejected if the new map has a conflict
+                    let applied = match forwarding_ids.conflict() {
+                        Some(conflict) => {
+                            warn!("Rejecting forwarding ID {:x} for {}, {:x} would conflict", id, target, conflict);
+                            false
+                        },
+                        None => {
+                            config.forwarding_ids = forwarding_ids;
+                            true
+                        },
+                    };
+                    drop(config);
+                    if applied {
+                        config::save(flash).await;
+                    }
+                    respond(0x0A, &[target, applied as u8]).await;
+                },
+                Command::SetEcuPolled { ecu, polled } => {
+                    config::CONFIG.lock().await.set_ecu_polled(ecu, polled);
+                    config::save(flash).await;
+                    // [0x0F, ECU index, 0x01 if polled]
+                    respond(0x0F, &[ecu, polled as u8]).await;
+                },
+                Command::SetEnvironmentOffsets(offsets) => {
+                    config::CONFIG.lock().await.environment_offsets = offsets;
+                    // [0x10, 0x01 if stored]
+                    let stored = config::save(flash).await;
+                    respond(0x10, &[stored as u8]).await;
+                },
+                Command::FactoryResetChallenge => match factory_reset::challenge().await {
+                    // [0x11, challenge (4 bytes)], or just [0x11] if this build can't be factory reset
+                    Some(challenge) => respond(0x11, &challenge).await,
+                    None => {
+                        warn!("Factory reset requested, but no key was built in");
+                        respond(0x11, &[]).await;
+                    },
+                },
+                Command::FactoryReset(response) => {
+                    // [0x12, 0x01 if wiping], the device restarts right after
+                    if factory_reset::verify(&response).await {
+                        respond(0x12, &[0x01]).await;
+                        factory_reset::wipe(flash).await;
+                    }
+                    warn!("Refusing factory reset, wrong or stale challenge response");
+                    respond(0x12, &[0x00]).await;
+                },
+                Command::SetLogLevel(level) => {
+                    info!("Log level set to {}", level);
+                    log_level::set(level);
+                    // [0x13, level]
+                    respond(0x13, &[level as u8]).await;
+                },
+                Command::SendBlackbox => {
+                    let count = blackbox::send(flash).await;
+                    // [0x14, frames in the dump], 0 if nothing has been dumped
+                    respond(0x14, &[count]).await;
+                },
+                Command::SendBusErrors { clear } => {
+                    let count = bus_errors::send(clear).await;
+                    // [0x15, events sent]
+                    respond(0x15, &[count]).await;
+                },
+                Command::Ack { source, sequence } => ack::acknowledge(source, sequence),
+                Command::Heartbeat => boot::HOST_HEARTBEAT_SEEN.store(true, Ordering::Relaxed),
+                Command::SyncClock(unix_micros) => {
+                    clock::sync(unix_micros);
+                    // [0x0C]
+                    respond(0x0C, &[]).await;
+                },
+                Command::Hello { version, capabilities, max_payload } => {
+                    let session = Session::negotiate(version, capabilities, max_payload);
+                    session::start(session);
+                    // [0x03, our version, our capabilities, negotiated version, negotiated capabilities, negotiated max payload]
+                    let mut response: Vec<u8, 8> = Vec::new();
+                    response.push(session::PROTOCOL_VERSION).unwrap();
+                    response.extend_from_slice(&session::SUPPORTED_CAPABILITIES.to_be_bytes()).unwrap();
+                    response.push(session.version).unwrap();
+                    response.extend_from_slice(&session.capabilities.to_be_bytes()).unwrap();
+                    response.push(session.max_payload).unwrap();
+                    respond(0x03, &response).await;
+                },
+                Command::SetBitRates { bus, bit_rates } => {
+                    let changes = match bus {
+                        0 => {
+                            config::CONFIG.lock().await.obd_bit_rates = bit_rates;
+                            &config::OBD_BIT_RATE_CHANGES
+                        },
+                        1 => {
+                            config::CONFIG.lock().await.comma_bit_rates = bit_rates;
+                            &config::COMMA_BIT_RATE_CHANGES
+                        },
+                        _ => {
+                            warn!("Bit rate change for unknown bus {}", bus);
+                            respond(0x04, &[bus, 0x00]).await;
+                            return;
+                        },
+                    };
+                    config::save(flash).await;
+                    // [0x04, bus, 0x01 if applied], sent first since changing the comma link's rate drops it until
+                    // the host switches too
+                    respond(0x04, &[bus, 0x01]).await;
+                    changes.signal(bit_rates);
+                },
+            }
+        },
+        None => warn!("Ignoring malformed command: {:x}", data),
+    }
+}
@@ -0,0 +1,1062 @@
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use mcp25xxfd::registers::OperationMode;
+use portable_atomic::{AtomicBool, Ordering};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::alerts::{self, AlertRule, Direction, MAX_ALERT_RULES};
+use crate::boot;
+use crate::forwarding::{BackpressurePolicy, RateLimit};
+use crate::id_filter::IdList;
+use crate::polling::{ECU_COUNT, QUERIES, QUERY_COUNT};
+use crate::protocol::crc16;
+use crate::sensor::{SENSOR_COUNT, SENSOR_ENVIRONMENT, SENSOR_MOTION};
+use crate::smoothing::Smoothing;
+use crate::storage::{self, FlashMutex};
+use crate::vehicle::{Vehicle, VehicleProfile};
+
+// Runtime device configuration, starts out with the compile-time defaults
+pub static CONFIG: Mutex<CriticalSectionRawMutex, DeviceConfig> = Mutex::new(DeviceConfig::DEFAULT);
+
+// Bit rates changed at runtime, picked up by each bus's bit_rate_task
+pub static OBD_BIT_RATE_CHANGES: Signal<CriticalSectionRawMutex, BitRates> = Signal::new();
+pub static COMMA_BIT_RATE_CHANGES: Signal<CriticalSectionRawMutex, BitRates> = Signal::new();
+
+// How actively a controller takes part in its bus
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub enum BusMode {
+    Normal,
+    // Receives and ACKs but never transmits, a middle ground for cautious deployments
+    Restricted,
+    // No ACKs and no transmits, for observing an unknown vehicle bus before enabling active querying
+    ListenOnly,
+    // Internal loopback with simulated ECUs answering the queries, for bench testing without a vehicle
+    Loopback,
+    // Listen-only with every frame on the bus forwarded raw, see sniffer.rs
+    Sniffer,
+}
+impl BusMode {
+    // Codes used by the config service
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::Normal),
+            1 => Some(Self::Restricted),
+            2 => Some(Self::ListenOnly),
+            3 => Some(Self::Loopback),
+            4 => Some(Self::Sniffer),
+            _ => None,
+        }
+    }
+    pub fn operation_mode(self) -> OperationMode {
+        match self {
+            Self::Normal => OperationMode::Normal,
+            Self::Restricted => OperationMode::RestrictedOperation,
+            Self::ListenOnly => OperationMode::ListenOnly,
+            Self::Loopback => OperationMode::InternalLoopback,
+            Self::Sniffer => OperationMode::ListenOnly,
+        }
+    }
+    pub fn can_transmit(self) -> bool {
+        matches!(self, Self::Normal | Self::Loopback)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub enum NominalBitRate {
+    Kbps500,
+    Kbps250,
+    Kbps125,
+}
+impl NominalBitRate {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::Kbps500),
+            1 => Some(Self::Kbps250),
+            2 => Some(Self::Kbps125),
+            _ => None,
+        }
+    }
+    pub fn bits_per_second(self) -> u32 {
+        match self {
+            Self::Kbps500 => 500_000,
+            Self::Kbps250 => 250_000,
+            Self::Kbps125 => 125_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub enum DataBitRate {
+    Mbps2,
+    Mbps5,
+}
+impl DataBitRate {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::Mbps2),
+            1 => Some(Self::Mbps5),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub struct BitRates {
+    pub nominal: NominalBitRate,
+    // Only used for the data phase of FD frames with bit-rate switching
+    pub data: DataBitRate,
+}
+impl BitRates {
+    pub const DEFAULT: Self = Self {
+        nominal: NominalBitRate::Kbps500,
+        data: DataBitRate::Mbps2,
+    };
+}
+
+// Diagnostic addresses of an ECU: 11-bit request ID and 29-bit target address, the responses are derived from these
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub struct EcuAddress {
+    pub request_id: u16,
+    pub target: u8,
+}
+
+// Indexed by polling::ECU_BMS etc.
+const DEFAULT_ECUS: [EcuAddress; ECU_COUNT as usize] = Vehicle::ECUS;
+
+// (ECU index, DID) for each periodic query, starting out as polling::QUERIES
+const DEFAULT_QUERIES: [(u8, [u8; 2]); QUERY_COUNT] = {
+    let mut queries = [(0, [0; 2]); QUERY_COUNT];
+    let mut index = 0;
+    while index < QUERY_COUNT {
+        queries[index] = (QUERIES[index].ecu, QUERIES[index].did);
+        index += 1;
+    }
+    queries
+};
+
+// CAN IDs that decoded vehicle data is forwarded to the comma device on
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub struct ForwardingIds {
+    // Indexed like polling::QUERIES
+    pub queries: [u16; QUERY_COUNT],
+    pub environment: u16,
+}
+impl ForwardingIds {
+    pub const DEFAULT: Self = {
+        let mut queries = [0; QUERY_COUNT];
+        let mut index = 0;
+        while index < QUERY_COUNT {
+            queries[index] = QUERIES[index].forwarding_id;
+            index += 1;
+        }
+        Self { queries, environment: 0x7A0 }
+    };
+    // Commands, the multiplexed stream, the config service, the diagnostic server, errors, DTCs, alerts, motion, GPS and supply voltage telemetry, diagnostics, one-shot reads and gateway responses, batches and decoded signals, and raw frames
+    const RESERVED: [(u16, u16); 9] = [
+        (0x6F0, 0x6F6),
+        (0x700, 0x700),
+        (0x780, 0x785),
+        (0x790, 0x790),
+        (0x7A1, 0x7A3),
+        (0x7B0, 0x7B9),
+        (0x7C0, 0x7C1),
+        (0x7D0, 0x7D1),
+        (0x7F0, 0x7F1),
+    ];
+
+    // Host commands pick an ID by its query index, or this for the environment sensor
+    pub const ENVIRONMENT_TARGET: u8 = 0xFF;
+
+    pub fn get(&self, target: u8) -> Option<u16> {
+        match target {
+            Self::ENVIRONMENT_TARGET => Some(self.environment),
+            index => self.queries.get(index as usize).copied(),
+        }
+    }
+    // Copy of the map with the target's ID changed, None if there's no such target
+    pub fn with(&self, target: u8, id: u16) -> Option<Self> {
+        let mut forwarding_ids = *self;
+        match target {
+            Self::ENVIRONMENT_TARGET => forwarding_ids.environment = id,
+            index => *forwarding_ids.queries.get_mut(index as usize)? = id,
+        }
+        Some(forwarding_ids)
+    }
+
+    // Returns the first ID that isn't a valid 11-bit ID, is used twice or collides with one of the fixed IDs
+    pub fn conflict(&self) -> Option<u16> {
+        let ids = || self.queries.iter().copied().chain([self.environment]);
+        ids().enumerate().find_map(|(index, id)| {
+            let reserved = Self::RESERVED.iter().any(|&(first, last)| (first..=last).contains(&id));
+            let duplicate = ids().skip(index + 1).any(|other| other == id);
+            (id > 0x7FF || reserved || duplicate).then_some(id)
+        })
+    }
+}
+
+// Durations in the stored configuration are milliseconds
+pub mod milliseconds {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        (duration.as_millis() as u32).serialize(serializer)
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u32::deserialize(deserializer)? as u64))
+    }
+}
+
+// Environment readings are only forwarded once one of them has moved by more than its deadband since the last one
+// forwarded, or once max_silence has passed without any
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub struct EnvironmentDeadbands {
+    // Pa
+    pub pressure: f32,
+    // °C
+    pub temperature: f32,
+    // %RH
+    pub humidity: f32,
+    #[serde(with = "milliseconds")]
+    pub max_silence: Duration,
+}
+impl EnvironmentDeadbands {
+    pub const DEFAULT: Self = Self {
+        pressure: 50.0,
+        temperature: 0.5,
+        humidity: 2.0,
+        max_silence: Duration::from_secs(30),
+    };
+}
+
+// Per-unit corrections added to the environment sensor readings before they're forwarded, set in the field against a reference
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub struct EnvironmentOffsets {
+    // Pa
+    pub pressure: f32,
+    // °C
+    pub temperature: f32,
+    // %RH
+    pub humidity: f32,
+}
+impl EnvironmentOffsets {
+    pub const DEFAULT: Self = Self { pressure: 0.0, temperature: 0.0, humidity: 0.0 };
+
+    // [temperature (2 bytes signed, 0.01 °C), pressure (2 bytes signed, Pa), humidity (2 bytes signed, 0.01 %RH)], the
+    // encoding used by the offset command
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let i16_at = |offset: usize| Some(i16::from_be_bytes([*bytes.get(offset)?, *bytes.get(offset + 1)?]) as f32);
+        Some(Self {
+            temperature: i16_at(0)? / 100.0,
+            pressure: i16_at(2)?,
+            humidity: i16_at(4)? / 100.0,
+        })
+    }
+}
+
+// Milliseconds between samples of each sensor, indexed by sensor::SENSOR_ENVIRONMENT etc. 0 pauses the sensor.
+const DEFAULT_SENSOR_INTERVALS: [u32; SENSOR_COUNT as usize] = {
+    let mut intervals = [0; SENSOR_COUNT as usize];
+    intervals[SENSOR_ENVIRONMENT as usize] = 1000;
+    intervals[SENSOR_MOTION as usize] = 10;
+    intervals
+};
+
+// Indexed by the alert rule number the alerts are forwarded with
+const DEFAULT_ALERT_RULES: [AlertRule; MAX_ALERT_RULES] = [
+    AlertRule {
+        signal: alerts::Signal::CellVoltageDelta,
+        direction: Direction::Above,
+        on_threshold: 0.05,
+        off_threshold: 0.03,
+        debounce_on: Duration::from_secs(10),
+        debounce_off: Duration::from_secs(30),
+        min_repeat: Duration::from_secs(10 * 60),
+    },
+    AlertRule {
+        signal: alerts::Signal::AuxBatteryVoltage,
+        direction: Direction::Below,
+        on_threshold: 11.8,
+        off_threshold: 12.2,
+        debounce_on: Duration::from_secs(30),
+        debounce_off: Duration::from_secs(10),
+        min_repeat: Duration::from_secs(10 * 60),
+    },
+    AlertRule {
+        signal: alerts::Signal::AuxBatteryVoltage,
+        direction: Direction::Above,
+        on_threshold: 15.0,
+        off_threshold: 14.7,
+        debounce_on: Duration::from_secs(5),
+        debounce_off: Duration::from_secs(10),
+        min_repeat: Duration::from_secs(10 * 60),
+    },
+    AlertRule {
+        signal: alerts::Signal::CellVoltageDelta,
+        direction: Direction::Above,
+        on_threshold: 0.2,
+        off_threshold: 0.15,
+        debounce_on: Duration::from_secs(2),
+        debounce_off: Duration::from_secs(30),
+        min_repeat: Duration::from_secs(60),
+    },
+];
+
+// The MCP25xxFD allows up to 0.85 * SYSCLK / 2, which is 8.5 MHz with the 20 MHz clock (the 20 MHz maximum needs the
+// PLL). The minimum is the clock a controller that stops responding is retried at.
+pub const MIN_SPI_FREQUENCY: u32 = 250_000;
+pub const MAX_SPI_FREQUENCY: u32 = 8_500_000;
+
+#[derive(Clone)]
+pub struct DeviceConfig {
+    pub alert_rules: [AlertRule; MAX_ALERT_RULES],
+    pub obd_bit_rates: BitRates,
+    pub comma_bit_rates: BitRates,
+    // Read once at startup
+    pub ecus: [EcuAddress; ECU_COUNT as usize],
+    // Bit per ECU index, the queries of ECUs that are cleared aren't sent
+    pub polled_ecus: u8,
+    pub queries: [(u8, [u8; 2]); QUERY_COUNT],
+    pub obd_mode: BusMode,
+    // Probe the vehicle bus for its nominal bit rate at startup instead of trusting obd_bit_rates
+    pub obd_detect_bit_rate: bool,
+    // SPI clock used once both controllers are configured, see MAX_SPI_FREQUENCY
+    pub spi_frequency: u32,
+    // What to drop once the comma link falls behind and the forwarding queue fills up
+    pub forwarding_backpressure: BackpressurePolicy,
+    // Applied to each forwarding ID separately
+    pub forwarding_rate_limit: RateLimit,
+    pub forwarding_ids: ForwardingIds,
+    // Let the comma device send its own diagnostic requests to the vehicle, see gateway.rs
+    pub gateway_enabled: bool,
+    // IDs that gateway requests may not be sent on and raw frames may not be forwarded from, see id_filter.rs
+    pub id_list: IdList,
+    pub environment_deadbands: EnvironmentDeadbands,
+    pub environment_offsets: EnvironmentOffsets,
+    // See DEFAULT_SENSOR_INTERVALS
+    pub sensor_intervals: [u32; SENSOR_COUNT as usize],
+    // Indexed like sensor_intervals
+    pub sensor_smoothing: [Smoothing; SENSOR_COUNT as usize],
+}
+impl DeviceConfig {
+    pub const DEFAULT: Self = Self {
+        alert_rules: DEFAULT_ALERT_RULES,
+        obd_bit_rates: BitRates::DEFAULT,
+        comma_bit_rates: BitRates::DEFAULT,
+        ecus: DEFAULT_ECUS,
+        polled_ecus: 0xFF,
+        queries: DEFAULT_QUERIES,
+        obd_mode: if cfg!(feature = "loopback") { BusMode::Loopback } else { BusMode::Normal },
+        obd_detect_bit_rate: false,
+        spi_frequency: MAX_SPI_FREQUENCY,
+        forwarding_backpressure: BackpressurePolicy::DropOldest,
+        forwarding_rate_limit: RateLimit { per_second: 100, burst: 20 },
+        forwarding_ids: ForwardingIds::DEFAULT,
+        gateway_enabled: false,
+        id_list: IdList::DEFAULT,
+        environment_deadbands: EnvironmentDeadbands::DEFAULT,
+        environment_offsets: EnvironmentOffsets::DEFAULT,
+        sensor_intervals: DEFAULT_SENSOR_INTERVALS,
+        sensor_smoothing: [Smoothing::NONE; SENSOR_COUNT as usize],
+    };
+
+    pub fn ecu_polled(&self, ecu: u8) -> bool {
+        self.polled_ecus & (1 << ecu) != 0
+    }
+    pub fn set_ecu_polled(&mut self, ecu: u8, polled: bool) {
+        if polled {
+            self.polled_ecus |= 1 << ecu;
+        }
+        else {
+            self.polled_ecus &= !(1 << ecu);
+        }
+    }
+}
+
+// Part of the configuration that survives a reboot. There are two slots so a new configuration that keeps the buses
+// from coming up can be rolled back to the last known good one: each save goes to the slot that doesn't hold the
+// last known good configuration as a trial, and the first boot that reaches normal CAN operation with it confirms it.
+// A trial that doesn't get there in time, or within a few boots, is rejected and the device restarts on the other slot.
+//
+// Each slot is [magic (4 bytes), schema version (2 bytes), generation (4 bytes), length (2 bytes), CRC-16 over the data
+// (2 bytes), postcard-encoded data], followed at state_offset() by state bytes that start out erased and are
+// programmed to 0 without erasing the sector: [confirmed, rejected, a byte per trial boot (MAX_TRIAL_BOOTS)]
+#[derive(Serialize, Deserialize)]
+struct StoredConfig {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    polled_ecus: u8,
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+    id_list: IdList,
+    environment_offsets: EnvironmentOffsets,
+    sensor_intervals: [u32; SENSOR_COUNT as usize],
+    sensor_smoothing: [Smoothing; SENSOR_COUNT as usize],
+    alert_rules: [AlertRule; MAX_ALERT_RULES],
+    gateway_enabled: bool,
+    forwarding_rate_limit: RateLimit,
+    forwarding_backpressure: BackpressurePolicy,
+    obd_mode: BusMode,
+    obd_detect_bit_rate: bool,
+    spi_frequency: u32,
+    environment_deadbands: EnvironmentDeadbands,
+}
+
+// Bumped whenever StoredConfig changes, with a migration from the previous version in decode()
+const CONFIG_SCHEMA_VERSION: u16 = 12;
+
+// Schema 1, from before the ECU query switches
+#[derive(Deserialize)]
+struct StoredConfigV1 {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+}
+impl StoredConfigV1 {
+    fn migrate(self) -> StoredConfigV2 {
+        StoredConfigV2 {
+            obd_bit_rates: self.obd_bit_rates,
+            comma_bit_rates: self.comma_bit_rates,
+            ecus: self.ecus,
+            polled_ecus: 0xFF,
+            queries: self.queries,
+            forwarding_ids: self.forwarding_ids,
+        }
+    }
+}
+
+// Schema 2, from before the ID allow/deny list
+#[derive(Deserialize)]
+struct StoredConfigV2 {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    polled_ecus: u8,
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+}
+impl StoredConfigV2 {
+    fn migrate(self) -> StoredConfigV3 {
+        StoredConfigV3 {
+            obd_bit_rates: self.obd_bit_rates,
+            comma_bit_rates: self.comma_bit_rates,
+            ecus: self.ecus,
+            polled_ecus: self.polled_ecus,
+            queries: self.queries,
+            forwarding_ids: self.forwarding_ids,
+            id_list: IdList::DEFAULT,
+        }
+    }
+}
+
+// Schema 3, from before the environment sensor offsets
+#[derive(Deserialize)]
+struct StoredConfigV3 {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    polled_ecus: u8,
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+    id_list: IdList,
+}
+impl StoredConfigV3 {
+    fn migrate(self) -> StoredConfigV4 {
+        StoredConfigV4 {
+            obd_bit_rates: self.obd_bit_rates,
+            comma_bit_rates: self.comma_bit_rates,
+            ecus: self.ecus,
+            polled_ecus: self.polled_ecus,
+            queries: self.queries,
+            forwarding_ids: self.forwarding_ids,
+            id_list: self.id_list,
+            environment_offsets: EnvironmentOffsets::DEFAULT,
+        }
+    }
+}
+
+// Schema 4, from before the sensor intervals
+#[derive(Deserialize)]
+struct StoredConfigV4 {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    polled_ecus: u8,
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+    id_list: IdList,
+    environment_offsets: EnvironmentOffsets,
+}
+impl StoredConfigV4 {
+    fn migrate(self) -> StoredConfigV5 {
+        StoredConfigV5 {
+            obd_bit_rates: self.obd_bit_rates,
+            comma_bit_rates: self.comma_bit_rates,
+            ecus: self.ecus,
+            polled_ecus: self.polled_ecus,
+            queries: self.queries,
+            forwarding_ids: self.forwarding_ids,
+            id_list: self.id_list,
+            environment_offsets: self.environment_offsets,
+            sensor_intervals: DEFAULT_SENSOR_INTERVALS,
+        }
+    }
+}
+
+// Schema 5, from before sensor smoothing
+#[derive(Deserialize)]
+struct StoredConfigV5 {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    polled_ecus: u8,
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+    id_list: IdList,
+    environment_offsets: EnvironmentOffsets,
+    sensor_intervals: [u32; SENSOR_COUNT as usize],
+}
+impl StoredConfigV5 {
+    fn migrate(self) -> StoredConfigV6 {
+        StoredConfigV6 {
+            obd_bit_rates: self.obd_bit_rates,
+            comma_bit_rates: self.comma_bit_rates,
+            ecus: self.ecus,
+            polled_ecus: self.polled_ecus,
+            queries: self.queries,
+            forwarding_ids: self.forwarding_ids,
+            id_list: self.id_list,
+            environment_offsets: self.environment_offsets,
+            sensor_intervals: self.sensor_intervals,
+            sensor_smoothing: [Smoothing::NONE; SENSOR_COUNT as usize],
+        }
+    }
+}
+
+// Schema 6, from before the alert rules were stored
+#[derive(Deserialize)]
+struct StoredConfigV6 {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    polled_ecus: u8,
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+    id_list: IdList,
+    environment_offsets: EnvironmentOffsets,
+    sensor_intervals: [u32; SENSOR_COUNT as usize],
+    sensor_smoothing: [Smoothing; SENSOR_COUNT as usize],
+}
+impl StoredConfigV6 {
+    fn migrate(self) -> StoredConfigV7 {
+        StoredConfigV7 {
+            obd_bit_rates: self.obd_bit_rates,
+            comma_bit_rates: self.comma_bit_rates,
+            ecus: self.ecus,
+            polled_ecus: self.polled_ecus,
+            queries: self.queries,
+            forwarding_ids: self.forwarding_ids,
+            id_list: self.id_list,
+            environment_offsets: self.environment_offsets,
+            sensor_intervals: self.sensor_intervals,
+            sensor_smoothing: self.sensor_smoothing,
+            alert_rules: DEFAULT_ALERT_RULES,
+        }
+    }
+}
+
+// Schema 7, from before the gateway switch and forwarding limits were stored
+#[derive(Deserialize)]
+struct StoredConfigV7 {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    polled_ecus: u8,
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+    id_list: IdList,
+    environment_offsets: EnvironmentOffsets,
+    sensor_intervals: [u32; SENSOR_COUNT as usize],
+    sensor_smoothing: [Smoothing; SENSOR_COUNT as usize],
+    alert_rules: [AlertRule; MAX_ALERT_RULES],
+}
+impl StoredConfigV7 {
+    fn migrate(self) -> StoredConfigV8 {
+        StoredConfigV8 {
+            obd_bit_rates: self.obd_bit_rates,
+            comma_bit_rates: self.comma_bit_rates,
+            ecus: self.ecus,
+            polled_ecus: self.polled_ecus,
+            queries: self.queries,
+            forwarding_ids: self.forwarding_ids,
+            id_list: self.id_list,
+            environment_offsets: self.environment_offsets,
+            sensor_intervals: self.sensor_intervals,
+            sensor_smoothing: self.sensor_smoothing,
+            alert_rules: self.alert_rules,
+            gateway_enabled: DeviceConfig::DEFAULT.gateway_enabled,
+            forwarding_rate_limit: DeviceConfig::DEFAULT.forwarding_rate_limit,
+            forwarding_backpressure: DeviceConfig::DEFAULT.forwarding_backpressure,
+        }
+    }
+}
+
+// Schema 8, from before the OBD bus mode was stored
+#[derive(Deserialize)]
+struct StoredConfigV8 {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    polled_ecus: u8,
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+    id_list: IdList,
+    environment_offsets: EnvironmentOffsets,
+    sensor_intervals: [u32; SENSOR_COUNT as usize],
+    sensor_smoothing: [Smoothing; SENSOR_COUNT as usize],
+    alert_rules: [AlertRule; MAX_ALERT_RULES],
+    gateway_enabled: bool,
+    forwarding_rate_limit: RateLimit,
+    forwarding_backpressure: BackpressurePolicy,
+}
+impl StoredConfigV8 {
+    fn migrate(self) -> StoredConfigV9 {
+        StoredConfigV9 {
+            obd_bit_rates: self.obd_bit_rates,
+            comma_bit_rates: self.comma_bit_rates,
+            ecus: self.ecus,
+            polled_ecus: self.polled_ecus,
+            queries: self.queries,
+            forwarding_ids: self.forwarding_ids,
+            id_list: self.id_list,
+            environment_offsets: self.environment_offsets,
+            sensor_intervals: self.sensor_intervals,
+            sensor_smoothing: self.sensor_smoothing,
+            alert_rules: self.alert_rules,
+            gateway_enabled: self.gateway_enabled,
+            forwarding_rate_limit: self.forwarding_rate_limit,
+            forwarding_backpressure: self.forwarding_backpressure,
+            obd_mode: DeviceConfig::DEFAULT.obd_mode,
+        }
+    }
+}
+
+// Schema 9, from before bit rate detection could be switched on
+#[derive(Deserialize)]
+struct StoredConfigV9 {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    polled_ecus: u8,
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+    id_list: IdList,
+    environment_offsets: EnvironmentOffsets,
+    sensor_intervals: [u32; SENSOR_COUNT as usize],
+    sensor_smoothing: [Smoothing; SENSOR_COUNT as usize],
+    alert_rules: [AlertRule; MAX_ALERT_RULES],
+    gateway_enabled: bool,
+    forwarding_rate_limit: RateLimit,
+    forwarding_backpressure: BackpressurePolicy,
+    obd_mode: BusMode,
+}
+impl StoredConfigV9 {
+    fn migrate(self) -> StoredConfigV10 {
+        StoredConfigV10 {
+            obd_bit_rates: self.obd_bit_rates,
+            comma_bit_rates: self.comma_bit_rates,
+            ecus: self.ecus,
+            polled_ecus: self.polled_ecus,
+            queries: self.queries,
+            forwarding_ids: self.forwarding_ids,
+            id_list: self.id_list,
+            environment_offsets: self.environment_offsets,
+            sensor_intervals: self.sensor_intervals,
+            sensor_smoothing: self.sensor_smoothing,
+            alert_rules: self.alert_rules,
+            gateway_enabled: self.gateway_enabled,
+            forwarding_rate_limit: self.forwarding_rate_limit,
+            forwarding_backpressure: self.forwarding_backpressure,
+            obd_mode: self.obd_mode,
+            obd_detect_bit_rate: DeviceConfig::DEFAULT.obd_detect_bit_rate,
+        }
+    }
+}
+
+// Schema 10, from before the SPI clock was stored
+#[derive(Deserialize)]
+struct StoredConfigV10 {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    polled_ecus: u8,
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+    id_list: IdList,
+    environment_offsets: EnvironmentOffsets,
+    sensor_intervals: [u32; SENSOR_COUNT as usize],
+    sensor_smoothing: [Smoothing; SENSOR_COUNT as usize],
+    alert_rules: [AlertRule; MAX_ALERT_RULES],
+    gateway_enabled: bool,
+    forwarding_rate_limit: RateLimit,
+    forwarding_backpressure: BackpressurePolicy,
+    obd_mode: BusMode,
+    obd_detect_bit_rate: bool,
+}
+impl StoredConfigV10 {
+    fn migrate(self) -> StoredConfigV11 {
+        StoredConfigV11 {
+            obd_bit_rates: self.obd_bit_rates,
+            comma_bit_rates: self.comma_bit_rates,
+            ecus: self.ecus,
+            polled_ecus: self.polled_ecus,
+            queries: self.queries,
+            forwarding_ids: self.forwarding_ids,
+            id_list: self.id_list,
+            environment_offsets: self.environment_offsets,
+            sensor_intervals: self.sensor_intervals,
+            sensor_smoothing: self.sensor_smoothing,
+            alert_rules: self.alert_rules,
+            gateway_enabled: self.gateway_enabled,
+            forwarding_rate_limit: self.forwarding_rate_limit,
+            forwarding_backpressure: self.forwarding_backpressure,
+            obd_mode: self.obd_mode,
+            obd_detect_bit_rate: self.obd_detect_bit_rate,
+            spi_frequency: DeviceConfig::DEFAULT.spi_frequency,
+        }
+    }
+}
+
+// Schema 11, from before the environment deadbands were stored
+#[derive(Deserialize)]
+struct StoredConfigV11 {
+    obd_bit_rates: BitRates,
+    comma_bit_rates: BitRates,
+    ecus: [EcuAddress; ECU_COUNT as usize],
+    polled_ecus: u8,
+    queries: [(u8, [u8; 2]); QUERY_COUNT],
+    forwarding_ids: ForwardingIds,
+    id_list: IdList,
+    environment_offsets: EnvironmentOffsets,
+    sensor_intervals: [u32; SENSOR_COUNT as usize],
+    sensor_smoothing: [Smoothing; SENSOR_COUNT as usize],
+    alert_rules: [AlertRule; MAX_ALERT_RULES],
+    gateway_enabled: bool,
+    forwarding_rate_limit: RateLimit,
+    forwarding_backpressure: BackpressurePolicy,
+    obd_mode: BusMode,
+    obd_detect_bit_rate: bool,
+    spi_frequency: u32,
+}
+impl StoredConfigV11 {
+    fn migrate(self) -> StoredConfig {
+        StoredConfig {
+            obd_bit_rates: self.obd_bit_rates,
+            comma_bit_rates: self.comma_bit_rates,
+            ecus: self.ecus,
+            polled_ecus: self.polled_ecus,
+            queries: self.queries,
+            forwarding_ids: self.forwarding_ids,
+            id_list: self.id_list,
+            environment_offsets: self.environment_offsets,
+            sensor_intervals: self.sensor_intervals,
+            sensor_smoothing: self.sensor_smoothing,
+            alert_rules: self.alert_rules,
+            gateway_enabled: self.gateway_enabled,
+            forwarding_rate_limit: self.forwarding_rate_limit,
+            forwarding_backpressure: self.forwarding_backpressure,
+            obd_mode: self.obd_mode,
+            obd_detect_bit_rate: self.obd_detect_bit_rate,
+            spi_frequency: self.spi_frequency,
+            environment_deadbands: EnvironmentDeadbands::DEFAULT,
+        }
+    }
+}
+
+fn decode(version: u16, data: &[u8]) -> Option<StoredConfig> {
+    match version {
+        1 => postcard::from_bytes::<StoredConfigV1>(data).ok().map(|stored| stored.migrate().migrate().migrate().migrate().migrate().migrate().migrate().migrate().migrate().migrate().migrate()),
+        2 => postcard::from_bytes::<StoredConfigV2>(data).ok().map(|stored| stored.migrate().migrate().migrate().migrate().migrate().migrate().migrate().migrate().migrate().migrate()),
+        3 => postcard::from_bytes::<StoredConfigV3>(data).ok().map(|stored| stored.migrate().migrate().migrate().migrate().migrate().migrate().migrate().migrate().migrate()),
+        4 => postcard::from_bytes::<StoredConfigV4>(data).ok().map(|stored| stored.migrate().migrate().migrate().migrate().migrate().migrate().migrate().migrate()),
+        5 => postcard::from_bytes::<StoredConfigV5>(data).ok().map(|stored| stored.migrate().migrate().migrate().migrate().migrate().migrate().migrate()),
+        6 => postcard::from_bytes::<StoredConfigV6>(data).ok().map(|stored| stored.migrate().migrate().migrate().migrate().migrate().migrate()),
+        7 => postcard::from_bytes::<StoredConfigV7>(data).ok().map(|stored| stored.migrate().migrate().migrate().migrate().migrate()),
+        8 => postcard::from_bytes::<StoredConfigV8>(data).ok().map(|stored| stored.migrate().migrate().migrate().migrate()),
+        9 => postcard::from_bytes::<StoredConfigV9>(data).ok().map(|stored| stored.migrate().migrate().migrate()),
+        10 => postcard::from_bytes::<StoredConfigV10>(data).ok().map(|stored| stored.migrate().migrate()),
+        11 => postcard::from_bytes::<StoredConfigV11>(data).ok().map(StoredConfigV11::migrate),
+        CONFIG_SCHEMA_VERSION => postcard::from_bytes(data).ok(),
+        // Written by newer firmware, better the defaults than a misread
+        _ => None,
+    }
+}
+
+// Only changes with the slot layout, StoredConfig changes go through CONFIG_SCHEMA_VERSION
+const CONFIG_STORE_MAGIC: u32 = 0x4346_4753; // "CFGS"
+const CONFIG_HEADER_LENGTH: usize = 14;
+// Single slot layouts from before schema versions: [magic, length (2 bytes), CRC-16 (2 bytes), data] in the first
+// slot, schemas 1 and 2 respectively. Treated as confirmed, generation 0.
+const LEGACY_STORE_MAGICS: [(u32, u16); 2] = [(0x4346_4731, 1), (0x4346_4732, 2)]; // "CFG1", "CFG2"
+const LEGACY_HEADER_LENGTH: usize = 8;
+const CONFIG_STORE_SIZE: usize = 512;
+// Slots from before schema 7, when the alert rules made it outgrow them, are half the size
+const SMALL_STORE_SIZE: usize = 256;
+const STATE_CONFIRMED: usize = 0;
+const STATE_REJECTED: usize = 1;
+const STATE_TRIAL_BOOTS: usize = 2;
+const MAX_TRIAL_BOOTS: usize = 3;
+// A trial configuration has this long after boot to get both buses up
+const TRIAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Set when the newest stored configuration was rejected and an older one (or the defaults, if all of them were rejected
+// or corrupted) is in use, reported in the heartbeat
+pub static CONFIG_REVERTED: AtomicBool = AtomicBool::new(false);
+// A slot failed its length, CRC or sanity checks since boot
+static CORRUPTED: AtomicBool = AtomicBool::new(false);
+// (sector, generation) of the trial configuration this boot is running with, if it is
+static TRIAL: Mutex<CriticalSectionRawMutex, Option<(u32, u32)>> = Mutex::new(None);
+
+struct Slot {
+    sector: u32,
+    state_offset: usize,
+    generation: u32,
+    confirmed: bool,
+    rejected: bool,
+    trial_boots: usize,
+    stored: StoredConfig,
+}
+
+fn read_slot(flash: &FlashMutex, sector: u32) -> Option<Slot> {
+    let mut buf = [0u8; CONFIG_STORE_SIZE + STATE_TRIAL_BOOTS + MAX_TRIAL_BOOTS];
+    if let Err(err) = storage::read(flash, sector, &mut buf) {
+        error!("Unable to read stored configuration: {}", err);
+        return None;
+    }
+    let magic = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let legacy_version = LEGACY_STORE_MAGICS.iter().find(|&&(legacy_magic, _)| legacy_magic == magic).map(|&(_, version)| version);
+    // (schema version, generation, header length, data length, CRC)
+    let (version, generation, header_length, length, crc) = match legacy_version {
+        Some(version) => (version, 0, LEGACY_HEADER_LENGTH, u16::from_be_bytes([buf[4], buf[5]]), u16::from_be_bytes([buf[6], buf[7]])),
+        None if magic == CONFIG_STORE_MAGIC => (
+            u16::from_be_bytes([buf[4], buf[5]]),
+            u32::from_be_bytes([buf[6], buf[7], buf[8], buf[9]]),
+            CONFIG_HEADER_LENGTH,
+            u16::from_be_bytes([buf[10], buf[11]]),
+            u16::from_be_bytes([buf[12], buf[13]]),
+        ),
+        // Erased or never written
+        None => return None,
+    };
+    let state_offset = state_offset(version);
+    let Some(data) = buf[..state_offset].get(header_length..header_length + length as usize) else {
+        warn!("Stored configuration at {:x} has a bad length", sector);
+        CORRUPTED.store(true, Ordering::Relaxed);
+        return None;
+    };
+    if crc16(data.iter().copied()) != crc {
+        warn!("Stored configuration at {:x} is corrupted", sector);
+        CORRUPTED.store(true, Ordering::Relaxed);
+        return None;
+    }
+    let Some(stored) = decode(version, data) else {
+        warn!("Unable to decode stored configuration at {:x} (schema {}, expected up to {})", sector, version, CONFIG_SCHEMA_VERSION);
+        CORRUPTED.store(true, Ordering::Relaxed);
+        return None;
+    };
+    if version != CONFIG_SCHEMA_VERSION {
+        info!("Migrating stored configuration at {:x} from schema {}", sector, version);
+    }
+    if stored.queries.iter().any(|&(ecu, _)| ecu >= ECU_COUNT) || stored.ecus.iter().any(|ecu| ecu.request_id > 0x7FF) {
+        warn!("Stored ECU addresses or query table at {:x} are invalid", sector);
+        CORRUPTED.store(true, Ordering::Relaxed);
+        return None;
+    }
+    let state = &buf[state_offset..];
+    Some(Slot {
+        sector,
+        state_offset,
+        generation,
+        confirmed: legacy_version.is_some() || state[STATE_CONFIRMED] == 0,
+        rejected: state[STATE_REJECTED] == 0,
+        trial_boots: state[STATE_TRIAL_BOOTS..].iter().filter(|&&byte| byte == 0).count(),
+        stored,
+    })
+}
+
+// Where a slot's state bytes are, right after its data
+fn state_offset(version: u16) -> usize {
+    if version < 7 { SMALL_STORE_SIZE } else { CONFIG_STORE_SIZE }
+}
+
+// Programs one of a slot's state bytes
+fn mark(flash: &FlashMutex, slot: &Slot, state: usize) {
+    if let Err(err) = storage::write(flash, slot.sector + (slot.state_offset + state) as u32, &[0]) {
+        error!("Unable to update configuration state at {:x}: {}", slot.sector, err);
+    }
+}
+
+// Both slots, newest first
+fn read_slots(flash: &FlashMutex) -> [Option<Slot>; 2] {
+    let mut slots = storage::CONFIG_SECTORS.map(|sector| read_slot(flash, sector));
+    let generation = |slot: &Option<Slot>| slot.as_ref().map(|slot| slot.generation);
+    if generation(&slots[1]) > generation(&slots[0]) {
+        slots.swap(0, 1);
+    }
+    slots
+}
+
+// Replaces the compile-time defaults with the newest usable configuration in flash, if there is one
+pub async fn load(flash: &FlashMutex) {
+    for slot in read_slots(flash).into_iter().flatten() {
+        if slot.rejected {
+            CONFIG_REVERTED.store(true, Ordering::Relaxed);
+            continue;
+        }
+        if !slot.confirmed {
+            if slot.trial_boots >= MAX_TRIAL_BOOTS {
+                warn!("Stored configuration generation {} never got the buses up, rejecting it", slot.generation);
+                mark(flash, &slot, STATE_REJECTED);
+                CONFIG_REVERTED.store(true, Ordering::Relaxed);
+                continue;
+            }
+            mark(flash, &slot, STATE_TRIAL_BOOTS + slot.trial_boots);
+            *TRIAL.lock().await = Some((slot.sector, slot.generation));
+            info!("Trying stored configuration generation {} (boot {} of {})", slot.generation, slot.trial_boots + 1, MAX_TRIAL_BOOTS);
+        }
+        let stored = slot.stored;
+        let mut config = CONFIG.lock().await;
+        config.obd_bit_rates = stored.obd_bit_rates;
+        config.comma_bit_rates = stored.comma_bit_rates;
+        config.ecus = stored.ecus;
+        config.polled_ecus = stored.polled_ecus;
+        config.id_list = stored.id_list;
+        config.queries = stored.queries;
+        config.forwarding_ids = stored.forwarding_ids;
+        config.environment_offsets = stored.environment_offsets;
+        config.sensor_intervals = stored.sensor_intervals;
+        config.sensor_smoothing = stored.sensor_smoothing;
+        config.alert_rules = stored.alert_rules;
+        config.gateway_enabled = stored.gateway_enabled;
+        // Configs committed before zero limits were rejected could still hold one, which would stop all forwarding
+        if stored.forwarding_rate_limit.per_second > 0 && stored.forwarding_rate_limit.burst > 0 {
+            config.forwarding_rate_limit = stored.forwarding_rate_limit;
+        }
+        config.forwarding_backpressure = stored.forwarding_backpressure;
+        // Bench builds always loop back, whatever the unit was last set to
+        if !cfg!(feature = "loopback") {
+            config.obd_mode = stored.obd_mode;
+        }
+        config.obd_detect_bit_rate = stored.obd_detect_bit_rate;
+        config.spi_frequency = stored.spi_frequency;
+        config.environment_deadbands = stored.environment_deadbands;
+        info!("Loaded stored configuration generation {}", slot.generation);
+        return;
+    }
+    if CORRUPTED.load(Ordering::Relaxed) {
+        CONFIG_REVERTED.store(true, Ordering::Relaxed);
+    }
+    if CONFIG_REVERTED.load(Ordering::Relaxed) {
+        warn!("No usable stored configuration left, using the defaults");
+    }
+}
+
+fn stored(config: &DeviceConfig) -> StoredConfig {
+    StoredConfig {
+        obd_bit_rates: config.obd_bit_rates,
+        comma_bit_rates: config.comma_bit_rates,
+        ecus: config.ecus,
+        polled_ecus: config.polled_ecus,
+        queries: config.queries,
+        forwarding_ids: config.forwarding_ids,
+        id_list: config.id_list,
+        environment_offsets: config.environment_offsets,
+        sensor_intervals: config.sensor_intervals,
+        sensor_smoothing: config.sensor_smoothing,
+        alert_rules: config.alert_rules,
+        gateway_enabled: config.gateway_enabled,
+        forwarding_rate_limit: config.forwarding_rate_limit,
+        forwarding_backpressure: config.forwarding_backpressure,
+        obd_mode: config.obd_mode,
+        obd_detect_bit_rate: config.obd_detect_bit_rate,
+        spi_frequency: config.spi_frequency,
+        environment_deadbands: config.environment_deadbands,
+    }
+}
+
+// CRC of the running configuration as it would be stored, so two units (or a unit and a saved config file) can be
+// compared without reading every key
+pub async fn hash() -> u16 {
+    let stored = stored(&*CONFIG.lock().await);
+    let mut buf = [0u8; CONFIG_STORE_SIZE];
+    match postcard::to_slice(&stored, &mut buf) {
+        Ok(data) => crc16(data.iter().copied()),
+        Err(_) => 0,
+    }
+}
+
+// Returns whether the configuration made it to flash. It's only a trial until a boot with it confirms it.
+pub async fn save(flash: &FlashMutex) -> bool {
+    let stored = stored(&*CONFIG.lock().await);
+    let slots = read_slots(flash);
+    let newest_generation = slots[0].as_ref().map_or(0, |slot| slot.generation);
+    // Keep the last known good configuration, or failing that the newest one
+    let keep = slots.iter().flatten().find(|slot| slot.confirmed && !slot.rejected).or(slots[0].as_ref());
+    let sector = match keep {
+        Some(keep) => *storage::CONFIG_SECTORS.iter().find(|&&sector| sector != keep.sector).unwrap(),
+        None => storage::CONFIG_SECTORS[0],
+    };
+
+    let mut buf = [0u8; CONFIG_STORE_SIZE];
+    let length = match postcard::to_slice(&stored, &mut buf[CONFIG_HEADER_LENGTH..]) {
+        Ok(data) => data.len(),
+        Err(_) => {
+            error!("Configuration doesn't fit in its flash sector");
+            return false;
+        },
+    };
+    let crc = crc16(buf[CONFIG_HEADER_LENGTH..CONFIG_HEADER_LENGTH + length].iter().copied());
+    buf[..4].copy_from_slice(&CONFIG_STORE_MAGIC.to_be_bytes());
+    buf[4..6].copy_from_slice(&CONFIG_SCHEMA_VERSION.to_be_bytes());
+    buf[6..10].copy_from_slice(&(newest_generation + 1).to_be_bytes());
+    buf[10..12].copy_from_slice(&(length as u16).to_be_bytes());
+    buf[12..14].copy_from_slice(&crc.to_be_bytes());
+    if let Err(err) = storage::write_sector(flash, sector, &buf) {
+        error!("Unable to store configuration: {}", err);
+        return false;
+    }
+    CONFIG_REVERTED.store(false, Ordering::Relaxed);
+    true
+}
+
+// Confirms the trial configuration this boot is running with once both buses are up, or rejects it and restarts on
+// the last known good one
+#[embassy_executor::task]
+pub async fn trial_task(flash: &'static FlashMutex) {
+    let Some((sector, generation)) = *TRIAL.lock().await else {
+        return;
+    };
+    let deadline = Instant::now() + TRIAL_TIMEOUT;
+    while Instant::now() < deadline {
+        if boot::OBD_BUS_UP.load(Ordering::Relaxed) && boot::COMMA_BUS_UP.load(Ordering::Relaxed) {
+            // Only if a save since boot hasn't replaced it
+            if let Some(slot) = read_slot(flash, sector).filter(|slot| slot.generation == generation) {
+                mark(flash, &slot, STATE_CONFIRMED);
+                info!("Stored configuration generation {} confirmed", generation);
+            }
+            return;
+        }
+        Timer::after_millis(500).await;
+    }
+    error!("Buses didn't come up with stored configuration generation {}, reverting", generation);
+    if let Some(slot) = read_slot(flash, sector).filter(|slot| slot.generation == generation) {
+        mark(flash, &slot, STATE_REJECTED);
+    }
+    Timer::after_millis(100).await;
+    boot::reset(boot::ResetReason::Software);
+}
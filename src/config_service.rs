@@ -0,0 +1,361 @@
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Duration;
+use heapless::Vec;
+use micromath::F32Ext;
+
+use crate::config::{self, BitRates, BusMode, DataBitRate, DeviceConfig, EcuAddress, NominalBitRate, CONFIG};
+use crate::forwarding::{BackpressurePolicy, RateLimit};
+use crate::id_filter::IdRule;
+use crate::log_level::debug;
+use crate::polling::{ECU_COUNT, QUERY_COUNT};
+use crate::protocol::{Message, MessageType, Source};
+use crate::{alerts, sensor, smoothing};
+use crate::storage::FlashMutex;
+use crate::PRIORITY_FORWARDING_CHANNEL;
+
+// Get/set/commit/reset access to the configuration on its own CAN ID, so an installed unit can be reconfigured from the
+// comma device or a laptop with a CAN adapter. Requests are [operation, key, index, value...] and fit in classic
+// frames, apart from ID rule sets, which take an FD frame. Every request is answered on CONFIG_RESPONSE_ID with
+// [operation, key, index, status, value...], where gets and sets return the (new) value.
+// Sets only change the running configuration, commit stores it to flash. ECU addresses, queries, the OBD bus mode, bit
+// rate detection and the SPI clock are only read at startup, so those take a commit and a reboot to apply.
+
+pub const CONFIG_REQUEST_ID: u16 = 0x6F3;
+pub const CONFIG_RESPONSE_ID: u16 = 0x6F4;
+
+// Request payloads drained from the comma controller by its interrupt task
+pub static CONFIG_REQUESTS: Channel<CriticalSectionRawMutex, Vec<u8, 64>, 4> = Channel::new();
+
+const OP_GET: u8 = 0x01;
+const OP_SET: u8 = 0x02;
+// Store the running configuration in flash
+const OP_COMMIT: u8 = 0x03;
+// Go back to the compile-time defaults, in flash too
+const OP_RESET: u8 = 0x04;
+
+// Keys and their values. Keys without an index ignore it.
+// [nominal rate, data rate], see commands.rs for the codes
+const KEY_OBD_BIT_RATES: u8 = 0x01;
+const KEY_COMMA_BIT_RATES: u8 = 0x02;
+// Index is the ECU: [request ID (2 bytes), 29-bit target address]
+const KEY_ECU_ADDRESS: u8 = 0x03;
+// Index is the query: [ECU index, DID (2 bytes)]
+const KEY_QUERY: u8 = 0x04;
+// Index is the query, or 0xFF for the environment sensor: [forwarding ID (2 bytes)]
+const KEY_FORWARDING_ID: u8 = 0x05;
+// [0x01 if enabled]
+const KEY_GATEWAY_ENABLED: u8 = 0x06;
+// [messages per second (2 bytes), burst (2 bytes)]
+const KEY_RATE_LIMIT: u8 = 0x07;
+// [0 = drop oldest, 1 = drop newest, 2 = drop lowest priority]
+const KEY_BACKPRESSURE: u8 = 0x08;
+// Index is the ECU: [0x01 if its queries are sent]
+const KEY_ECU_POLLED: u8 = 0x09;
+// [0x01 for an allow list, 0x00 for a deny list]
+const KEY_ID_LIST_MODE: u8 = 0x0A;
+// Index is the rule: [ID (4 bytes, bit 31 set for extended IDs), mask (4 bytes)], an empty value clears it
+const KEY_ID_RULE: u8 = 0x0B;
+// Index is 0 for temperature (0.01 °C), 1 for pressure (Pa) or 2 for humidity (0.01 %RH): [offset (2 bytes signed)]
+const KEY_ENVIRONMENT_OFFSET: u8 = 0x0C;
+// Index is the sensor, see sensor.rs: [sampling interval (ms, 4 bytes)], 0 pauses it
+const KEY_SENSOR_INTERVAL: u8 = 0x0D;
+// Index is the sensor: [0 = none, 1 = moving average, 2 = median, window (readings)]
+const KEY_SENSOR_SMOOTHING: u8 = 0x0E;
+// Index is the alert rule, see alerts.rs: [signal (0 = cell voltage delta, 1 = 12 V battery), 0 to alert above the
+// threshold or 1 below it]
+const KEY_ALERT_SIGNAL: u8 = 0x0F;
+// Index is the alert rule: [on threshold (2 bytes signed, mV), off threshold (2 bytes signed, mV)]
+const KEY_ALERT_THRESHOLDS: u8 = 0x10;
+// Index is the alert rule: [debounce on (s), debounce off (s), minimum time between raises (s, 2 bytes)]
+const KEY_ALERT_TIMING: u8 = 0x11;
+// [0 = normal, 1 = restricted, 2 = listen-only, 3 = loopback, 4 = sniffer], see config::BusMode
+const KEY_OBD_MODE: u8 = 0x12;
+// [0x01 to detect the vehicle bus's nominal bit rate at startup], a bus profile strap turns it off again
+const KEY_OBD_DETECT_BIT_RATE: u8 = 0x13;
+// [Hz (4 bytes)], between config::MIN_SPI_FREQUENCY and config::MAX_SPI_FREQUENCY
+const KEY_SPI_FREQUENCY: u8 = 0x14;
+// Index is 0 for temperature (0.01 °C), 1 for pressure (Pa), 2 for humidity (0.01 %RH) or 3 for the longest time
+// without forwarding (s): [deadband (2 bytes)]
+const KEY_ENVIRONMENT_DEADBAND: u8 = 0x15;
+
+const STATUS_OK: u8 = 0x00;
+const STATUS_UNKNOWN: u8 = 0x01;
+const STATUS_INVALID: u8 = 0x02;
+const STATUS_FLASH_ERROR: u8 = 0x03;
+
+fn get(config: &DeviceConfig, key: u8, index: u8) -> Option<Vec<u8, 8>> {
+    let mut value: Vec<u8, 8> = Vec::new();
+    match key {
+        KEY_OBD_BIT_RATES => value.extend_from_slice(&[config.obd_bit_rates.nominal as u8, config.obd_bit_rates.data as u8]),
+        KEY_COMMA_BIT_RATES => value.extend_from_slice(&[config.comma_bit_rates.nominal as u8, config.comma_bit_rates.data as u8]),
+        KEY_ECU_ADDRESS => {
+            let ecu = config.ecus.get(index as usize)?;
+            let [high, low] = ecu.request_id.to_be_bytes();
+            value.extend_from_slice(&[high, low, ecu.target])
+        },
+        KEY_QUERY => {
+            let (ecu, did) = config.queries.get(index as usize)?;
+            value.extend_from_slice(&[*ecu, did[0], did[1]])
+        },
+        KEY_FORWARDING_ID => value.extend_from_slice(&config.forwarding_ids.get(index)?.to_be_bytes()),
+        KEY_GATEWAY_ENABLED => value.extend_from_slice(&[config.gateway_enabled as u8]),
+        KEY_RATE_LIMIT => {
+            let per_second = (config.forwarding_rate_limit.per_second.min(u16::MAX as u32) as u16).to_be_bytes();
+            let burst = (config.forwarding_rate_limit.burst.min(u16::MAX as u32) as u16).to_be_bytes();
+            value.extend_from_slice(&[per_second[0], per_second[1], burst[0], burst[1]])
+        },
+        KEY_BACKPRESSURE => value.extend_from_slice(&[config.forwarding_backpressure as u8]),
+        KEY_ECU_POLLED if index < ECU_COUNT => value.extend_from_slice(&[config.ecu_polled(index) as u8]),
+        KEY_ID_LIST_MODE => value.extend_from_slice(&[config.id_list.allow as u8]),
+        KEY_ID_RULE => match config.id_list.rules.get(index as usize)? {
+            Some(rule) => value.extend_from_slice(&rule.id.to_be_bytes()).and_then(|_| value.extend_from_slice(&rule.mask.to_be_bytes())),
+            None => Ok(()),
+        },
+        KEY_ENVIRONMENT_OFFSET => {
+            let offsets = config.environment_offsets;
+            let offset = match index {
+                0 => offsets.temperature * 100.0,
+                1 => offsets.pressure,
+                2 => offsets.humidity * 100.0,
+                _ => return None,
+            };
+            value.extend_from_slice(&(offset.round() as i16).to_be_bytes())
+        },
+        KEY_SENSOR_INTERVAL => value.extend_from_slice(&config.sensor_intervals.get(index as usize)?.to_be_bytes()),
+        KEY_SENSOR_SMOOTHING => {
+            let smoothing = config.sensor_smoothing.get(index as usize)?;
+            value.extend_from_slice(&[smoothing.method as u8, smoothing.window])
+        },
+        KEY_ALERT_SIGNAL => {
+            let rule = config.alert_rules.get(index as usize)?;
+            value.extend_from_slice(&[rule.signal as u8, rule.direction as u8])
+        },
+        KEY_ALERT_THRESHOLDS => {
+            let rule = config.alert_rules.get(index as usize)?;
+            let on_threshold = ((rule.on_threshold * 1000.0).round() as i16).to_be_bytes();
+            let off_threshold = ((rule.off_threshold * 1000.0).round() as i16).to_be_bytes();
+            value.extend_from_slice(&[on_threshold[0], on_threshold[1], off_threshold[0], off_threshold[1]])
+        },
+        KEY_ALERT_TIMING => {
+            let rule = config.alert_rules.get(index as usize)?;
+            let seconds = |duration: Duration| duration.as_secs().min(u8::MAX as u64) as u8;
+            let min_repeat = (rule.min_repeat.as_secs().min(u16::MAX as u64) as u16).to_be_bytes();
+            value.extend_from_slice(&[seconds(rule.debounce_on), seconds(rule.debounce_off), min_repeat[0], min_repeat[1]])
+        },
+        KEY_OBD_MODE => value.extend_from_slice(&[config.obd_mode as u8]),
+        KEY_OBD_DETECT_BIT_RATE => value.extend_from_slice(&[config.obd_detect_bit_rate as u8]),
+        KEY_SPI_FREQUENCY => value.extend_from_slice(&config.spi_frequency.to_be_bytes()),
+        KEY_ENVIRONMENT_DEADBAND => {
+            let deadbands = config.environment_deadbands;
+            let deadband = match index {
+                0 => (deadbands.temperature * 100.0).round() as u16,
+                1 => deadbands.pressure.round() as u16,
+                2 => (deadbands.humidity * 100.0).round() as u16,
+                3 => deadbands.max_silence.as_secs().min(u16::MAX as u64) as u16,
+                _ => return None,
+            };
+            value.extend_from_slice(&deadband.to_be_bytes())
+        },
+        _ => return None,
+    }.unwrap();
+    Some(value)
+}
+
+fn bit_rates(value: &[u8]) -> Option<BitRates> {
+    Some(BitRates {
+        nominal: NominalBitRate::from_code(*value.first()?)?,
+        data: DataBitRate::from_code(*value.get(1)?)?,
+    })
+}
+
+// Applies the value, or returns the status to answer with if it can't be
+fn set(config: &mut DeviceConfig, key: u8, index: u8, value: &[u8]) -> Result<(), u8> {
+    let u16_at = |offset: usize| -> Result<u16, u8> {
+        Ok(u16::from_be_bytes([*value.get(offset).ok_or(STATUS_INVALID)?, *value.get(offset + 1).ok_or(STATUS_INVALID)?]))
+    };
+    match key {
+        KEY_OBD_BIT_RATES => {
+            config.obd_bit_rates = bit_rates(value).ok_or(STATUS_INVALID)?;
+            config::OBD_BIT_RATE_CHANGES.signal(config.obd_bit_rates);
+        },
+        KEY_COMMA_BIT_RATES => {
+            config.comma_bit_rates = bit_rates(value).ok_or(STATUS_INVALID)?;
+            config::COMMA_BIT_RATE_CHANGES.signal(config.comma_bit_rates);
+        },
+        KEY_ECU_ADDRESS => {
+            let request_id = u16_at(0)?;
+            let target = *value.get(2).ok_or(STATUS_INVALID)?;
+            if index >= ECU_COUNT || request_id > 0x7FF {
+                return Err(STATUS_INVALID);
+            }
+            config.ecus[index as usize] = EcuAddress { request_id, target };
+        },
+        KEY_QUERY => {
+            let ecu = *value.first().ok_or(STATUS_INVALID)?;
+            if index as usize >= QUERY_COUNT || ecu >= ECU_COUNT {
+                return Err(STATUS_INVALID);
+            }
+            config.queries[index as usize] = (ecu, u16_at(1)?.to_be_bytes());
+        },
+        KEY_FORWARDING_ID => {
+            let forwarding_ids = config.forwarding_ids.with(index, u16_at(0)?).ok_or(STATUS_INVALID)?;
+            if let Some(conflict) = forwarding_ids.conflict() {
+                warn!("Rejecting forwarding ID change, {:x} would conflict", conflict);
+                return Err(STATUS_INVALID);
+            }
+            config.forwarding_ids = forwarding_ids;
+        },
+        KEY_GATEWAY_ENABLED => config.gateway_enabled = *value.first().ok_or(STATUS_INVALID)? != 0,
+        KEY_RATE_LIMIT => {
+            let rate_limit = RateLimit { per_second: u16_at(0)? as u32, burst: u16_at(2)? as u32 };
+            // Either being zero would stop every forwarding ID for good
+            if rate_limit.per_second == 0 || rate_limit.burst == 0 {
+                return Err(STATUS_INVALID);
+            }
+            config.forwarding_rate_limit = rate_limit;
+        },
+        KEY_BACKPRESSURE => {
+            config.forwarding_backpressure = match value.first() {
+                Some(0) => BackpressurePolicy::DropOldest,
+                Some(1) => BackpressurePolicy::DropNewest,
+                Some(2) => BackpressurePolicy::DropLowestPriority,
+                _ => return Err(STATUS_INVALID),
+            };
+        },
+        KEY_ECU_POLLED => {
+            if index >= ECU_COUNT {
+                return Err(STATUS_INVALID);
+            }
+            config.set_ecu_polled(index, *value.first().ok_or(STATUS_INVALID)? != 0);
+        },
+        KEY_ID_LIST_MODE => config.id_list.allow = *value.first().ok_or(STATUS_INVALID)? != 0,
+        KEY_ID_RULE => {
+            let rule = match value {
+                [] => None,
+                _ if value.len() == 8 => Some(IdRule {
+                    id: u32::from_be_bytes(value[..4].try_into().unwrap()),
+                    mask: u32::from_be_bytes(value[4..].try_into().unwrap()),
+                }),
+                _ => return Err(STATUS_INVALID),
+            };
+            *config.id_list.rules.get_mut(index as usize).ok_or(STATUS_INVALID)? = rule;
+        },
+        KEY_ENVIRONMENT_OFFSET => {
+            let offset = u16_at(0)? as i16 as f32;
+            match index {
+                0 => config.environment_offsets.temperature = offset / 100.0,
+                1 => config.environment_offsets.pressure = offset,
+                2 => config.environment_offsets.humidity = offset / 100.0,
+                _ => return Err(STATUS_INVALID),
+            }
+        },
+        KEY_SENSOR_INTERVAL => {
+            let interval = u32::from_be_bytes(value.try_into().map_err(|_| STATUS_INVALID)?);
+            if interval != 0 && (interval as u64) < sensor::MIN_INTERVAL.as_millis() {
+                return Err(STATUS_INVALID);
+            }
+            *config.sensor_intervals.get_mut(index as usize).ok_or(STATUS_INVALID)? = interval;
+        },
+        KEY_SENSOR_SMOOTHING => {
+            let method = smoothing::Method::from_code(*value.first().ok_or(STATUS_INVALID)?).ok_or(STATUS_INVALID)?;
+            let window = *value.get(1).ok_or(STATUS_INVALID)?;
+            if window == 0 || window as usize > smoothing::MAX_WINDOW {
+                return Err(STATUS_INVALID);
+            }
+            *config.sensor_smoothing.get_mut(index as usize).ok_or(STATUS_INVALID)? = smoothing::Smoothing { method, window };
+        },
+        KEY_ALERT_SIGNAL => {
+            let signal = alerts::Signal::from_code(*value.first().ok_or(STATUS_INVALID)?).ok_or(STATUS_INVALID)?;
+            let direction = alerts::Direction::from_code(*value.get(1).ok_or(STATUS_INVALID)?).ok_or(STATUS_INVALID)?;
+            let rule = config.alert_rules.get_mut(index as usize).ok_or(STATUS_INVALID)?;
+            rule.signal = signal;
+            rule.direction = direction;
+        },
+        KEY_ALERT_THRESHOLDS => {
+            let (on_threshold, off_threshold) = (u16_at(0)? as i16 as f32 / 1000.0, u16_at(2)? as i16 as f32 / 1000.0);
+            let rule = config.alert_rules.get_mut(index as usize).ok_or(STATUS_INVALID)?;
+            rule.on_threshold = on_threshold;
+            rule.off_threshold = off_threshold;
+        },
+        KEY_ALERT_TIMING => {
+            let debounce_on = *value.first().ok_or(STATUS_INVALID)?;
+            let debounce_off = *value.get(1).ok_or(STATUS_INVALID)?;
+            let min_repeat = u16_at(2)?;
+            let rule = config.alert_rules.get_mut(index as usize).ok_or(STATUS_INVALID)?;
+            rule.debounce_on = Duration::from_secs(debounce_on as u64);
+            rule.debounce_off = Duration::from_secs(debounce_off as u64);
+            rule.min_repeat = Duration::from_secs(min_repeat as u64);
+        },
+        KEY_OBD_MODE => config.obd_mode = BusMode::from_code(*value.first().ok_or(STATUS_INVALID)?).ok_or(STATUS_INVALID)?,
+        KEY_OBD_DETECT_BIT_RATE => config.obd_detect_bit_rate = *value.first().ok_or(STATUS_INVALID)? != 0,
+        KEY_SPI_FREQUENCY => {
+            let frequency = u32::from_be_bytes(value.try_into().map_err(|_| STATUS_INVALID)?);
+            if !(config::MIN_SPI_FREQUENCY..=config::MAX_SPI_FREQUENCY).contains(&frequency) {
+                return Err(STATUS_INVALID);
+            }
+            config.spi_frequency = frequency;
+        },
+        KEY_ENVIRONMENT_DEADBAND => {
+            let deadband = u16_at(0)?;
+            match index {
+                0 => config.environment_deadbands.temperature = deadband as f32 / 100.0,
+                1 => config.environment_deadbands.pressure = deadband as f32,
+                2 => config.environment_deadbands.humidity = deadband as f32 / 100.0,
+                3 => config.environment_deadbands.max_silence = Duration::from_secs(deadband as u64),
+                _ => return Err(STATUS_INVALID),
+            }
+        },
+        _ => return Err(STATUS_UNKNOWN),
+    }
+    Ok(())
+}
+
+async fn handle_request(request: &[u8], flash: &FlashMutex) {
+    let (Some(&operation), key, index) = (request.first(), request.get(1).copied().unwrap_or(0), request.get(2).copied().unwrap_or(0)) else {
+        return;
+    };
+    let mut value: Vec<u8, 8> = Vec::new();
+    let status = match operation {
+        OP_GET => match get(&*CONFIG.lock().await, key, index) {
+            Some(current) => {
+                value = current;
+                STATUS_OK
+            },
+            None => STATUS_UNKNOWN,
+        },
+        OP_SET => {
+            let mut config = CONFIG.lock().await;
+            match set(&mut config, key, index, request.get(3..).unwrap_or(&[])) {
+                Ok(()) => {
+                    value = get(&config, key, index).unwrap();
+                    STATUS_OK
+                },
+                Err(status) => status,
+            }
+        },
+        OP_COMMIT => if config::save(flash).await { STATUS_OK } else { STATUS_FLASH_ERROR },
+        OP_RESET => {
+            *CONFIG.lock().await = DeviceConfig::DEFAULT;
+            config::OBD_BIT_RATE_CHANGES.signal(BitRates::DEFAULT);
+            config::COMMA_BIT_RATE_CHANGES.signal(BitRates::DEFAULT);
+            if config::save(flash).await { STATUS_OK } else { STATUS_FLASH_ERROR }
+        },
+        _ => STATUS_UNKNOWN,
+    };
+    debug!("Config request {:x}: status {}", request, status);
+
+    let mut response: Vec<u8, 64> = Vec::new();
+    response.extend_from_slice(&[operation, key, index, status]).unwrap();
+    response.extend_from_slice(&value).unwrap();
+    PRIORITY_FORWARDING_CHANNEL.send(Message::new(CONFIG_RESPONSE_ID, MessageType::CommandResponse, Source::Firmware, response)).await;
+}
+
+#[embassy_executor::task]
+pub async fn config_service_task(flash: &'static FlashMutex) {
+    loop {
+        let request = CONFIG_REQUESTS.receive().await;
+        handle_request(&request, flash).await;
+    }
+}
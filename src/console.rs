@@ -0,0 +1,166 @@
+use core::fmt::Write;
+
+use defmt::*;
+use embassy_futures::join::join;
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::Builder;
+use embedded_can::{ExtendedId, Id, StandardId};
+use heapless::{String, Vec};
+use static_cell::StaticCell;
+
+use crate::config::CONFIG;
+use crate::polling::{self, QueryRequest, QUERY_COUNT};
+use crate::stats;
+
+// Line-based command shell on the USB serial port for bench debugging, so poking at the device doesn't take a
+// recompile or a comma device. Everything it does goes through the same channels as host commands.
+const HELP: &str = "\
+stats                   frame, ISO-TP and queue counters\r\n\
+filters                 ID allow/deny list\r\n\
+send <id> <bytes>       send a raw frame on the vehicle bus, hex (ids above 7ff are extended)\r\n\
+rate <query> <ms>       poll a query at most this often, rounded up to whole seconds (0 = every cycle)\r\n";
+
+const MAX_PACKET_SIZE: u16 = 64;
+const MAX_LINE: usize = 64;
+
+static CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+static STATE: StaticCell<State> = StaticCell::new();
+
+type Output = String<1024>;
+
+fn parse_hex(field: &str) -> Option<u32> {
+    u32::from_str_radix(field.trim_start_matches("0x"), 16).ok()
+}
+
+fn send(args: &mut core::str::SplitWhitespace, out: &mut Output) -> Option<()> {
+    let raw_id = parse_hex(args.next()?)?;
+    let id: Id = if raw_id > 0x7FF { ExtendedId::new(raw_id)?.into() } else { StandardId::new(raw_id as u16)?.into() };
+    let mut data: Vec<u8, 8> = Vec::new();
+    for byte in args {
+        data.push(u8::from_str_radix(byte, 16).ok()?).ok()?;
+    }
+    let queued = polling::QUERY_REQUESTS.try_send(QueryRequest::Raw { id, data }).is_ok();
+    let _ = write!(out, "{}\r\n", if queued { "queued" } else { "sender busy, try again" });
+    Some(())
+}
+
+fn rate(args: &mut core::str::SplitWhitespace, out: &mut Output) -> Option<()> {
+    let index: u8 = args.next()?.parse().ok()?;
+    let millis: u32 = args.next()?.parse().ok()?;
+    if index as usize >= QUERY_COUNT {
+        return None;
+    }
+    let interval = millis.div_ceil(1000).min(u16::MAX as u32) as u16;
+    let queued = polling::QUERY_REQUESTS.try_send(QueryRequest::SetInterval { index, interval }).is_ok();
+    let _ = write!(out, "{}\r\n", if queued { "ok" } else { "sender busy, try again" });
+    Some(())
+}
+
+async fn run(line: &str, out: &mut Output) {
+    let mut args = line.split_whitespace();
+    let handled = match args.next() {
+        None => Some(()),
+        Some("help") => out.push_str(HELP).ok(),
+        Some("stats") => stats::summary(out).ok(),
+        Some("filters") => {
+            let id_list = CONFIG.lock().await.id_list;
+            let _ = write!(out, "ID {} list\r\n", if id_list.allow { "allow" } else { "deny" });
+            for (index, rule) in id_list.rules.iter().enumerate() {
+                if let Some(rule) = rule {
+                    let _ = write!(out, "  {}: id {:08x} mask {:08x}\r\n", index, rule.id, rule.mask);
+                }
+            }
+            Some(())
+        },
+        Some("send") => send(&mut args, out),
+        Some("rate") => rate(&mut args, out),
+        Some(_) => {
+            let _ = out.push_str("unknown command, try help\r\n");
+            Some(())
+        },
+    };
+    if handled.is_none() {
+        let _ = out.push_str("bad arguments, try help\r\n");
+    }
+}
+
+async fn write_all(class: &mut CdcAcmClass<'static, Driver<'static, USB>>, data: &[u8]) -> Result<(), EndpointError> {
+    // A full-size packet would need an empty one after it to end the transfer, so stay one byte short
+    for chunk in data.chunks(MAX_PACKET_SIZE as usize - 1) {
+        class.write_packet(chunk).await?;
+    }
+    Ok(())
+}
+
+async fn session(class: &mut CdcAcmClass<'static, Driver<'static, USB>>) -> Result<(), EndpointError> {
+    write_all(class, b"rp2040-canbus console, try help\r\n> ").await?;
+    let mut line: Vec<u8, MAX_LINE> = Vec::new();
+    let mut packet = [0u8; MAX_PACKET_SIZE as usize];
+    loop {
+        let length = class.read_packet(&mut packet).await?;
+        for &byte in &packet[..length] {
+            match byte {
+                b'\r' | b'\n' => {
+                    let mut out = Output::new();
+                    let _ = out.push_str("\r\n");
+                    match core::str::from_utf8(&line) {
+                        Ok(line) => run(line, &mut out).await,
+                        Err(_) => {
+                            let _ = out.push_str("not UTF-8\r\n");
+                        },
+                    }
+                    let _ = out.push_str("> ");
+                    write_all(class, out.as_bytes()).await?;
+                    line.clear();
+                },
+                // Backspace or delete
+                0x08 | 0x7F => {
+                    if line.pop().is_some() {
+                        write_all(class, b"\x08 \x08").await?;
+                    }
+                },
+                _ => {
+                    if line.push(byte).is_ok() {
+                        write_all(class, &[byte]).await?;
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn console_task(driver: Driver<'static, USB>) {
+    // pid.codes test VID/PID
+    let mut config = embassy_usb::Config::new(0x1209, 0x0001);
+    config.manufacturer = Some("rp2040-canbus");
+    config.product = Some("rp2040-canbus console");
+    config.max_power = 100;
+    config.max_packet_size_0 = MAX_PACKET_SIZE as u8;
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; 256]),
+        BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        CONTROL_BUF.init([0; 64]),
+    );
+    let mut class = CdcAcmClass::new(&mut builder, STATE.init(State::new()), MAX_PACKET_SIZE);
+    let mut usb = builder.build();
+
+    let console = async {
+        loop {
+            class.wait_connection().await;
+            info!("Console connected");
+            let _ = session(&mut class).await;
+            info!("Console disconnected");
+        }
+    };
+    join(usb.run(), console).await;
+}
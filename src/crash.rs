@@ -0,0 +1,91 @@
+use core::fmt::Write;
+
+use cortex_m_rt::{exception, ExceptionFrame};
+use defmt::*;
+use embassy_time::Timer;
+use heapless::{String, Vec};
+use portable_atomic::Ordering;
+
+use crate::{blackbox, boot, supervisor};
+use crate::protocol::{Message, MessageType, Source, MAX_MESSAGE_LENGTH};
+use crate::storage::{self, FlashMutex};
+use crate::FORWARDING_QUEUE;
+
+// Panics and hard faults are written to CRASH_SECTOR before resetting, and the record is forwarded once after the next
+// boot: [kind, task, PC (4 bytes), LR (4 bytes), message...]. The task is the supervisor::Task that last checked in
+// (supervisor::NO_TASK if none has), which is the one that crashed unless it was one of the unsupervised tasks. PC and
+// LR can be symbolicated against the ELF to find where. Panics have no meaningful PC, so it's 0, and send the panic
+// location and message instead.
+pub const CRASH_FORWARDING_ID: u16 = 0x7B5;
+
+const CRASH_STORE_MAGIC: u32 = 0x4352_5348; // "CRSH"
+// [magic (4 bytes), kind, task, PC (4 bytes), LR (4 bytes), message length, message]
+const HEADER_LENGTH: usize = 15;
+const MAX_CRASH_MESSAGE: usize = MAX_MESSAGE_LENGTH - 10;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Panic = 1,
+    HardFault = 2,
+}
+
+fn record(kind: Kind, pc: u32, lr: u32, message: &str) {
+    let mut buf = [0xFFu8; HEADER_LENGTH + MAX_CRASH_MESSAGE];
+    let length = message.len().min(MAX_CRASH_MESSAGE);
+    buf[..4].copy_from_slice(&CRASH_STORE_MAGIC.to_be_bytes());
+    buf[4] = kind as u8;
+    buf[5] = supervisor::last_checked_in();
+    buf[6..10].copy_from_slice(&pc.to_be_bytes());
+    buf[10..14].copy_from_slice(&lr.to_be_bytes());
+    buf[14] = length as u8;
+    buf[HEADER_LENGTH..HEADER_LENGTH + length].copy_from_slice(&message.as_bytes()[..length]);
+    if storage::crash_write_sector(storage::CRASH_SECTOR, &buf).is_err() {
+        error!("Unable to record crash");
+    }
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    error!("{}", Display2Format(info));
+    let mut message: String<MAX_CRASH_MESSAGE> = String::new();
+    // Cut short if it doesn't fit
+    let _ = write!(message, "{}", info);
+    record(Kind::Panic, 0, cortex_m::register::lr::read(), &message);
+    blackbox::dump_blocking(blackbox::Reason::Panic);
+    boot::reset(boot::ResetReason::Crash);
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    error!("Hard fault at {:x}", frame.pc());
+    record(Kind::HardFault, frame.pc(), frame.lr(), "");
+    blackbox::dump_blocking(blackbox::Reason::HardFault);
+    boot::reset(boot::ResetReason::Crash);
+}
+
+// Forwards the crash from the previous run, if there was one, once the comma bus is up, then clears it
+#[embassy_executor::task]
+pub async fn report_task(flash: &'static FlashMutex) {
+    let mut buf = [0u8; HEADER_LENGTH + MAX_CRASH_MESSAGE];
+    if let Err(err) = storage::read(flash, storage::CRASH_SECTOR, &mut buf) {
+        error!("Unable to read crash record: {}", err);
+        return;
+    }
+    if u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) != CRASH_STORE_MAGIC {
+        // Erased, no crash
+        return;
+    }
+    let length = (buf[14] as usize).min(MAX_CRASH_MESSAGE);
+    warn!("Previous run crashed: {=[u8]:a}", buf[HEADER_LENGTH..HEADER_LENGTH + length]);
+    while !boot::COMMA_BUS_UP.load(Ordering::Relaxed) {
+        Timer::after_secs(1).await;
+    }
+    let mut forward_data: Vec<u8, MAX_MESSAGE_LENGTH> = Vec::new();
+    forward_data.extend_from_slice(&buf[4..14]).unwrap();
+    forward_data.extend_from_slice(&buf[HEADER_LENGTH..HEADER_LENGTH + length]).unwrap();
+    FORWARDING_QUEUE.send(Message::new(CRASH_FORWARDING_ID, MessageType::CrashReport, Source::Firmware, forward_data)).await;
+    if let Err(err) = storage::erase(flash, storage::CRASH_SECTOR) {
+        error!("Unable to clear crash record: {}", err);
+    }
+}
@@ -0,0 +1,229 @@
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_can::StandardId;
+use heapless::Vec;
+use mcp25xxfd::frame::Frame;
+
+use crate::log_level::debug;
+use crate::{boot, config, errors, memory, stats, supervisor, tx_events, CanController, FORWARDING_QUEUE, TRANSMIT_FIFO};
+
+// A minimal UDS server on the comma bus, so a field unit can be interrogated with off-the-shelf diagnostic tooling
+// (a CAN adapter and anything that speaks ISO-TP) instead of the host's own protocol. Requests are single frames on
+// DIAG_REQUEST_ID, responses go out on DIAG_RESPONSE_ID with ISO-TP segmentation and answer to flow control like an ECU
+// would. Replies are sent straight to the controller, not through the forwarder, since tooling expects plain ISO-TP
+// rather than the host framing.
+
+pub const DIAG_REQUEST_ID: u16 = 0x6F5;
+pub const DIAG_RESPONSE_ID: u16 = 0x6F6;
+
+// Request payloads drained from the comma controller by its interrupt task, flow control frames included
+pub static REQUESTS: Channel<CriticalSectionRawMutex, Vec<u8, 64>, 4> = Channel::new();
+
+const SERVICE_SESSION_CONTROL: u8 = 0x10;
+const SERVICE_READ_DATA_BY_IDENTIFIER: u8 = 0x22;
+const SERVICE_TESTER_PRESENT: u8 = 0x3E;
+const NEGATIVE_RESPONSE: u8 = 0x7F;
+
+const NRC_SERVICE_NOT_SUPPORTED: u8 = 0x11;
+const NRC_SUBFUNCTION_NOT_SUPPORTED: u8 = 0x12;
+const NRC_INCORRECT_LENGTH: u8 = 0x13;
+const NRC_REQUEST_OUT_OF_RANGE: u8 = 0x31;
+
+// Data identifiers
+// Firmware version as ASCII, e.g. "0.1.0"
+const DID_SOFTWARE_VERSION: u16 = 0xF195;
+// CRC-16 of the running configuration, see config::hash
+const DID_CONFIG_HASH: u16 = 0xFD00;
+// [uptime seconds (4 bytes), ISO-TP transfers completed (4 bytes), ISO-TP transfers aborted (4 bytes), forwarding queue
+// drops (4 bytes), stack bytes never used (4 bytes)]
+const DID_STATS: u16 = 0xFD01;
+// [count, then (seconds since boot (4 bytes), class, module, code, context (4 bytes)) for each, oldest first], see
+// errors.rs
+const DID_RECENT_ERRORS: u16 = 0xFD02;
+// [reset reason (see boot::ResetReason), task that stalled before the reset (see supervisor::Task, 0xFF if none)]
+const DID_RESET_REASON: u16 = 0xFD03;
+
+// Longest response a DID produces, ISO-TP itself allows up to 4095 bytes
+const MAX_RESPONSE_LENGTH: usize = 128;
+// How long to wait for the tester's flow control after a first frame (N_Bs)
+const FLOW_CONTROL_TIMEOUT: Duration = Duration::from_secs(1);
+// Default session timings reported by session control: P2 50 ms, P2* 5000 ms (in 10 ms steps)
+const SESSION_TIMINGS: [u8; 4] = [0x00, 0x32, 0x01, 0xF4];
+// Unused bytes at the end of a frame
+const PADDING: u8 = 0xAA;
+
+fn negative(service: u8, code: u8) -> Vec<u8, MAX_RESPONSE_LENGTH> {
+    Vec::from_slice(&[NEGATIVE_RESPONSE, service, code]).unwrap()
+}
+
+async fn read_did(did: u16, data: &mut Vec<u8, MAX_RESPONSE_LENGTH>) -> bool {
+    match did {
+        DID_SOFTWARE_VERSION => data.extend_from_slice(env!("CARGO_PKG_VERSION").as_bytes()).unwrap(),
+        DID_CONFIG_HASH => data.extend_from_slice(&config::hash().await.to_be_bytes()).unwrap(),
+        DID_STATS => {
+            let (completed, aborted) = stats::isotp_transfers();
+            data.extend_from_slice(&(Instant::now().as_secs() as u32).to_be_bytes()).unwrap();
+            data.extend_from_slice(&completed.to_be_bytes()).unwrap();
+            data.extend_from_slice(&aborted.to_be_bytes()).unwrap();
+            data.extend_from_slice(&FORWARDING_QUEUE.dropped().to_be_bytes()).unwrap();
+            data.extend_from_slice(&memory::stack_margin().to_be_bytes()).unwrap();
+        },
+        DID_RECENT_ERRORS => {
+            let recent = errors::recent();
+            data.push(recent.len() as u8).unwrap();
+            for (seconds, report) in recent {
+                data.extend_from_slice(&seconds.to_be_bytes()).unwrap();
+                data.extend_from_slice(&[report.class as u8, report.module as u8, report.code as u8]).unwrap();
+                data.extend_from_slice(&report.context.to_be_bytes()).unwrap();
+            }
+        },
+        DID_RESET_REASON => data.extend_from_slice(&[boot::reset_reason(), supervisor::stalled_before_reset()]).unwrap(),
+        _ => return false,
+    }
+    true
+}
+
+// None for requests that don't get a response at all
+async fn handle(request: &[u8]) -> Option<Vec<u8, MAX_RESPONSE_LENGTH>> {
+    let (&service, parameters) = request.split_first()?;
+    let response = match service {
+        SERVICE_SESSION_CONTROL => match parameters {
+            // Only the default session, there's nothing here that needs another one
+            &[0x01] => {
+                let mut response = Vec::from_slice(&[service + 0x40, 0x01]).unwrap();
+                response.extend_from_slice(&SESSION_TIMINGS).unwrap();
+                response
+            },
+            // Suppress positive response bit
+            &[0x81] => return None,
+            &[_] => negative(service, NRC_SUBFUNCTION_NOT_SUPPORTED),
+            _ => negative(service, NRC_INCORRECT_LENGTH),
+        },
+        SERVICE_TESTER_PRESENT => match parameters {
+            &[0x00] => Vec::from_slice(&[service + 0x40, 0x00]).unwrap(),
+            &[0x80] => return None,
+            &[_] => negative(service, NRC_SUBFUNCTION_NOT_SUPPORTED),
+            _ => negative(service, NRC_INCORRECT_LENGTH),
+        },
+        SERVICE_READ_DATA_BY_IDENTIFIER => match parameters {
+            // One DID per request keeps every response within MAX_RESPONSE_LENGTH
+            &[did_high, did_low] => {
+                let mut response = Vec::from_slice(&[service + 0x40, did_high, did_low]).unwrap();
+                if read_did(u16::from_be_bytes([did_high, did_low]), &mut response).await {
+                    response
+                }
+                else {
+                    negative(service, NRC_REQUEST_OUT_OF_RANGE)
+                }
+            },
+            _ => negative(service, NRC_INCORRECT_LENGTH),
+        },
+        _ => negative(service, NRC_SERVICE_NOT_SUPPORTED),
+    };
+    Some(response)
+}
+
+async fn send_frame(controller: &Mutex<CriticalSectionRawMutex, CanController>, data: &[u8]) -> bool {
+    let mut frame_data = [PADDING; 8];
+    frame_data[..data.len()].copy_from_slice(data);
+    let id = StandardId::new(DIAG_RESPONSE_ID).unwrap();
+    let frame = Frame::new(id, &frame_data).unwrap();
+    match controller.lock().await.transmit::<TRANSMIT_FIFO>(&frame).await {
+        Ok(()) => {
+            tx_events::COMMA_TX.record(id.into());
+            stats::COMMA.transmitted(TRANSMIT_FIFO);
+            true
+        },
+        Err(err) => {
+            error!("Unable to send diagnostic response: {}", err);
+            false
+        },
+    }
+}
+
+// Waits for the tester to clear us to send, returns (block size, separation time) or None to give up on the response
+async fn flow_control() -> Option<(u8, Duration)> {
+    loop {
+        let Ok(frame) = with_timeout(FLOW_CONTROL_TIMEOUT, REQUESTS.receive()).await else {
+            warn!("No flow control from the diagnostic tester");
+            return None;
+        };
+        match frame.as_slice() {
+            // Continue to send
+            &[0x30, block_size, separation_time, ..] => {
+                // 0xF1-0xF9 are 100-900 µs, anything else out of range means the maximum
+                let separation_time = match separation_time {
+                    0x00..=0x7F => Duration::from_millis(separation_time as u64),
+                    0xF1..=0xF9 => Duration::from_micros((separation_time - 0xF0) as u64 * 100),
+                    _ => Duration::from_millis(0x7F),
+                };
+                return Some((block_size, separation_time));
+            },
+            // Wait, another flow control follows
+            &[0x31, ..] => continue,
+            // Overflow, or a new request in the middle of the response
+            _ => return None,
+        }
+    }
+}
+
+async fn send_response(controller: &Mutex<CriticalSectionRawMutex, CanController>, response: &[u8]) {
+    if response.len() <= 7 {
+        let mut frame: Vec<u8, 8> = Vec::new();
+        frame.push(response.len() as u8).unwrap();
+        frame.extend_from_slice(response).unwrap();
+        send_frame(controller, &frame).await;
+        return;
+    }
+
+    let mut frame: Vec<u8, 8> = Vec::new();
+    frame.extend_from_slice(&[0x10 | (response.len() >> 8) as u8, response.len() as u8]).unwrap();
+    frame.extend_from_slice(&response[..6]).unwrap();
+    if !send_frame(controller, &frame).await {
+        return;
+    }
+    let Some((mut block_size, mut separation_time)) = flow_control().await else {
+        return;
+    };
+    let mut sent_in_block = 0;
+    for (index, chunk) in response[6..].chunks(7).enumerate() {
+        if block_size != 0 && sent_in_block == block_size {
+            let Some(next) = flow_control().await else {
+                return;
+            };
+            (block_size, separation_time) = next;
+            sent_in_block = 0;
+        }
+        else if index > 0 {
+            Timer::after(separation_time).await;
+        }
+        let mut frame: Vec<u8, 8> = Vec::new();
+        frame.push(0x20 | ((index + 1) & 0x0F) as u8).unwrap();
+        frame.extend_from_slice(chunk).unwrap();
+        if !send_frame(controller, &frame).await {
+            return;
+        }
+        sent_in_block += 1;
+    }
+}
+
+#[embassy_executor::task]
+pub async fn diag_server_task(controller: &'static Mutex<CriticalSectionRawMutex, CanController>) {
+    loop {
+        let frame = REQUESTS.receive().await;
+        // Only single frame requests, everything this answers to fits in one
+        let request = match frame.as_slice() {
+            &[length @ 1..=7, ref rest @ ..] if rest.len() >= length as usize => &rest[..length as usize],
+            _ => {
+                debug!("Ignoring diagnostic frame {:x}", frame.as_slice());
+                continue;
+            },
+        };
+        if let Some(response) = handle(request).await {
+            send_response(controller, &response).await;
+        }
+    }
+}
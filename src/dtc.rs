@@ -0,0 +1,77 @@
+use defmt::*;
+use heapless::Vec;
+
+use crate::log_level::debug;
+use crate::storage::{self, FlashMutex};
+
+pub const MAX_DTC_ECUS: usize = 4;
+pub const MAX_DTCS: usize = 16;
+
+const DTC_STORE_MAGIC: u32 = 0x4454_4331; // "DTC1"
+// ECU response ID (4 bytes), DTC count, DTCs
+const RECORD_SIZE: usize = 4 + 1 + MAX_DTCS * 2;
+const STORE_SIZE: usize = 4 + MAX_DTC_ECUS * RECORD_SIZE;
+
+pub type DtcList = Vec<u16, MAX_DTCS>;
+
+// Last DTC set seen from each ECU, persisted so that only changes get reported (also across reboots)
+pub struct DtcStore {
+    records: Vec<(u32, DtcList), MAX_DTC_ECUS>,
+}
+impl DtcStore {
+    pub fn load(flash: &FlashMutex) -> Self {
+        let mut store = Self { records: Vec::new() };
+        let mut buf = [0u8; STORE_SIZE];
+        if let Err(err) = storage::read(flash, storage::DTC_SECTOR, &mut buf) {
+            error!("Unable to read stored DTCs: {}", err);
+            return store;
+        }
+        if u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) != DTC_STORE_MAGIC {
+            // Erased or never written
+            return store;
+        }
+        for record in buf[4..].chunks_exact(RECORD_SIZE) {
+            let ecu = u32::from_be_bytes([record[0], record[1], record[2], record[3]]);
+            let count = record[4] as usize;
+            if ecu == 0 || count > MAX_DTCS {
+                continue;
+            }
+            let dtcs = record[5..5 + count * 2].chunks_exact(2).map(|dtc| u16::from_be_bytes([dtc[0], dtc[1]])).collect();
+            store.records.push((ecu, dtcs)).ok();
+        }
+        debug!("Loaded stored DTCs for {} ECUs", store.records.len());
+        store
+    }
+
+    pub fn save(&self, flash: &FlashMutex) {
+        let mut buf = [0u8; STORE_SIZE];
+        buf[..4].copy_from_slice(&DTC_STORE_MAGIC.to_be_bytes());
+        for ((ecu, dtcs), record) in self.records.iter().zip(buf[4..].chunks_exact_mut(RECORD_SIZE)) {
+            record[..4].copy_from_slice(&ecu.to_be_bytes());
+            record[4] = dtcs.len() as u8;
+            for (dtc, bytes) in dtcs.iter().zip(record[5..].chunks_exact_mut(2)) {
+                bytes.copy_from_slice(&dtc.to_be_bytes());
+            }
+        }
+        if let Err(err) = storage::write_sector(flash, storage::DTC_SECTOR, &buf) {
+            error!("Unable to store DTCs: {}", err);
+        }
+    }
+
+    // Replaces the stored set for the ECU, returning the codes that (appeared, cleared) since the last scan
+    pub fn update(&mut self, ecu: u32, dtcs: &[u16]) -> (DtcList, DtcList) {
+        let current: DtcList = dtcs.iter().copied().take(MAX_DTCS).collect();
+        let previous = match self.records.iter_mut().find(|(id, _)| *id == ecu) {
+            Some((_, stored)) => core::mem::replace(stored, current.clone()),
+            None => {
+                if self.records.push((ecu, current.clone())).is_err() {
+                    warn!("No room to store DTCs for ECU {:x}", ecu);
+                }
+                DtcList::new()
+            },
+        };
+        let appeared = current.iter().filter(|dtc| !previous.contains(dtc)).copied().collect();
+        let cleared = previous.iter().filter(|dtc| !current.contains(dtc)).copied().collect();
+        (appeared, cleared)
+    }
+}
@@ -0,0 +1,54 @@
+use heapless::{FnvIndexMap, Vec};
+
+use crate::protocol::crc8;
+
+// Classic automotive end-to-end protection for forwarded frames: every frame gets a CRC-8 byte followed by a
+// rolling alive counter that's tracked separately for each forwarding ID, so the host can spot a stuck or
+// corrupted signal group without relying on the rest of the traffic
+pub const E2E_HEADER_LENGTH: usize = 2;
+
+const COUNTER_SLOTS: usize = 64;
+
+pub struct E2EProtector {
+    // Alive counter and the frame count when it was last used, for each forwarding ID
+    alive_counters: FnvIndexMap<u16, (u8, u32), COUNTER_SLOTS>,
+    frames: u32,
+}
+impl E2EProtector {
+    pub fn new() -> Self {
+        Self { alive_counters: FnvIndexMap::new(), frames: 0 }
+    }
+    // Returns [CRC, counter, payload...], dropping any payload bytes that no longer fit in a 64-byte frame
+    pub fn protect(&mut self, forwarding_id: u16, payload: &[u8]) -> Vec<u8, 64> {
+        self.frames = self.frames.wrapping_add(1);
+        let counter = match self.alive_counters.get_mut(&forwarding_id) {
+            Some((counter, last_used)) => {
+                *counter = counter.wrapping_add(1);
+                *last_used = self.frames;
+                *counter
+            },
+            None => {
+                // More than COUNTER_SLOTS forwarding IDs in use, the one that's been quiet the longest starts over at 0
+                // the next time it's sent
+                if self.alive_counters.len() == COUNTER_SLOTS {
+                    let frames = self.frames;
+                    let least_recent = self.alive_counters.iter()
+                        .max_by_key(|(_, &(_, last_used))| frames.wrapping_sub(last_used))
+                        .map(|(&id, _)| id)
+                        .unwrap();
+                    self.alive_counters.remove(&least_recent);
+                }
+                self.alive_counters.insert(forwarding_id, (0, self.frames)).unwrap();
+                0
+            },
+        };
+        let payload = &payload[..payload.len().min(64 - E2E_HEADER_LENGTH)];
+        // The forwarding ID is included as the data ID so a frame showing up on the wrong ID fails the check
+        let crc = crc8(forwarding_id.to_be_bytes().into_iter().chain([counter]).chain(payload.iter().copied()));
+
+        let mut protected = Vec::new();
+        protected.extend_from_slice(&[crc, counter]).unwrap();
+        protected.extend_from_slice(payload).unwrap();
+        protected
+    }
+}
@@ -0,0 +1,39 @@
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::polling::ECU_COUNT;
+
+// Diagnostic requests that went wrong, per ECU and since boot, so a flaky module shows up in the stats frame rather than
+// only in trace logs. A query an ECU rejected also counts as timed out, since its negative response can't be matched
+// back to the DID it was for.
+pub struct EcuErrors {
+    // Multi-frame responses that stalled partway through or were too long to reassemble
+    pub aborted: AtomicU32,
+    // Periodic queries that went unanswered
+    pub timed_out: AtomicU32,
+    pub negative_responses: AtomicU32,
+}
+impl EcuErrors {
+    const fn new() -> Self {
+        Self {
+            aborted: AtomicU32::new(0),
+            timed_out: AtomicU32::new(0),
+            negative_responses: AtomicU32::new(0),
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed) > 0 || self.timed_out.load(Ordering::Relaxed) > 0 || self.negative_responses.load(Ordering::Relaxed) > 0
+    }
+}
+
+pub static ECUS: [EcuErrors; ECU_COUNT as usize] = [const { EcuErrors::new() }; ECU_COUNT as usize];
+
+pub fn transfer_aborted(ecu: u8) {
+    ECUS[ecu as usize].aborted.fetch_add(1, Ordering::Relaxed);
+}
+pub fn query_timed_out(ecu: u8) {
+    ECUS[ecu as usize].timed_out.fetch_add(1, Ordering::Relaxed);
+}
+pub fn negative_response(ecu: u8) {
+    ECUS[ecu as usize].negative_responses.fetch_add(1, Ordering::Relaxed);
+}
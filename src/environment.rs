@@ -0,0 +1,302 @@
+use bme280_rs::{AsyncBme280, Humidity, Temperature};
+use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+use embassy_rp::adc;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Delay, Duration, Instant};
+use heapless::Vec;
+use micromath::F32Ext;
+use portable_atomic::Ordering;
+
+use crate::errors::Module;
+use crate::i2c_scan::{Device, Inventory};
+use crate::config::EnvironmentOffsets;
+use crate::protocol::{self, Location, MessageType, SensorKind, MAX_MESSAGE_LENGTH};
+use crate::sensor::{sensor_task, ChannelCheck, Sensor, SENSOR_ENVIRONMENT};
+use crate::smoothing::Smoother;
+use crate::{config, heartbeat, lps22, ntc, sht4x, supervisor, weather, I2C0Type, SensorI2c};
+
+// Cabin temperature, humidity and pressure, sampled every second by default, corrected by the unit's calibration
+// offsets, smoothed if configured and forwarded according to config::EnvironmentDeadbands. The temperature and humidity
+// sensor is the one that has to be there, the barometer and thermistor are optional extras. A dead sensor is flagged in
+// the heartbeat until it comes back, and so is a reading that's stuck or impossible.
+//
+// Units with a second BME280 (at 0x77, with the cabin one at 0x76) on a cable to the outside air forward its readings
+// on the same ID from a task of its own, tagged with protocol::Location::Outside. Being off the board, they aren't
+// corrected for its self-heating or by the calibration offsets.
+
+// Readings drift by more than the sensors' resolution well within this, even parked
+const STUCK_AFTER: Duration = Duration::from_secs(10 * 60);
+
+// Whichever temperature and humidity sensor the unit was built with
+enum Hygrometer {
+    Bme280(AsyncBme280<SensorI2c, Delay>),
+    Sht4x(sht4x::Sht4x<SensorI2c>),
+}
+impl Hygrometer {
+    fn kind(&self) -> SensorKind {
+        match self {
+            Self::Bme280(_) => SensorKind::Bme280,
+            Self::Sht4x(_) => SensorKind::Sht4x,
+        }
+    }
+}
+
+fn compensate_temperature(sensor_temp: Temperature) -> Temperature {
+    sensor_temp - 6.0
+}
+fn compensate_humidity(sensor_temp: Temperature, sensor_humidity: Humidity) -> Humidity {
+    // From https://www.renesas.com/ja/document/apn/compensating-temperature-and-relative-humidity-pcb
+    const A: f32 = 6.1162;
+    const M: f32 = 7.5892;
+    const TN: f32 = 240.71;
+
+    let corrected_temp = compensate_temperature(sensor_temp);
+    let sensor_saturation_vapor_pressure = A * f32::powf(10.0,(M * sensor_temp) / (sensor_temp + TN));
+    let corrected_saturation_vapor_pressure = A * f32::powf(10.0,(M * corrected_temp) / (corrected_temp + TN));
+    let vapor_pressure = (sensor_humidity * sensor_saturation_vapor_pressure) / 100.0;
+    (vapor_pressure / corrected_saturation_vapor_pressure) * 100.0
+}
+
+type AdcMutex = Mutex<CriticalSectionRawMutex, adc::Adc<'static, adc::Async>>;
+
+pub struct EnvironmentSensors {
+    location: Location,
+    hygrometer: Hygrometer,
+    // Not worth a fault if it stops answering since the other sensor's pressure takes over
+    barometer: Option<lps22::Lps22<SensorI2c>>,
+    barometer_configured: bool,
+    thermistor: Option<(ntc::Thermistor, &'static AdcMutex)>,
+    // Of the corrected readings, the derived values are worked out from the smoothed ones
+    pressure: Smoother,
+    temperature: Smoother,
+    humidity: Smoother,
+    external_temperature: Smoother,
+    // Of the raw readings
+    pressure_check: ChannelCheck,
+    temperature_check: ChannelCheck,
+    humidity_check: ChannelCheck,
+    external_temperature_check: ChannelCheck,
+    channel_faults: u8,
+    last_forwarded: Option<(protocol::Environment, Instant)>,
+}
+impl EnvironmentSensors {
+    // None without a temperature and humidity sensor, the SHT4x wins if there are both. Takes the thermistor if there is
+    // one.
+    pub fn cabin(
+        i2c_bus: &'static Mutex<CriticalSectionRawMutex, I2C0Type>,
+        inventory: &Inventory,
+        adc: &'static AdcMutex,
+        thermistor: &mut Option<ntc::Thermistor>,
+    ) -> Option<Self> {
+        let hygrometer = match (inventory.find(Device::Sht4x), inventory.find(Device::Bme280)) {
+            (Some(_), _) => Hygrometer::Sht4x(sht4x::Sht4x::new(I2cDevice::new(i2c_bus))),
+            (None, Some(address)) => Hygrometer::Bme280(AsyncBme280::new_with_address(I2cDevice::new(i2c_bus), address, Delay)),
+            (None, None) => return None,
+        };
+        let barometer = inventory.find(Device::Lps22).map(|address| lps22::Lps22::new(I2cDevice::new(i2c_bus), address));
+        Some(Self::new(Location::Cabin, hygrometer, barometer, thermistor.take().map(|thermistor| (thermistor, adc))))
+    }
+
+    // None without BME280s at both addresses
+    pub fn outside(i2c_bus: &'static Mutex<CriticalSectionRawMutex, I2C0Type>, inventory: &Inventory) -> Option<Self> {
+        if !inventory.found(Device::Bme280, 0x76) || !inventory.found(Device::Bme280, 0x77) {
+            return None;
+        }
+        let hygrometer = Hygrometer::Bme280(AsyncBme280::new_with_address(I2cDevice::new(i2c_bus), 0x77, Delay));
+        Some(Self::new(Location::Outside, hygrometer, None, None))
+    }
+
+    fn new(
+        location: Location,
+        hygrometer: Hygrometer,
+        barometer: Option<lps22::Lps22<SensorI2c>>,
+        thermistor: Option<(ntc::Thermistor, &'static AdcMutex)>,
+    ) -> Self {
+        Self {
+            location,
+            hygrometer,
+            barometer,
+            barometer_configured: false,
+            thermistor,
+            pressure: Smoother::new(),
+            temperature: Smoother::new(),
+            humidity: Smoother::new(),
+            external_temperature: Smoother::new(),
+            // The sensors' operating ranges, anything outside them means something's broken
+            pressure_check: ChannelCheck::new(30_000.0..=110_000.0, STUCK_AFTER),
+            temperature_check: ChannelCheck::new(-40.0..=85.0, STUCK_AFTER),
+            humidity_check: ChannelCheck::new(0.0..=100.0, STUCK_AFTER),
+            external_temperature_check: ChannelCheck::new(-40.0..=150.0, STUCK_AFTER),
+            channel_faults: 0,
+            last_forwarded: None,
+        }
+    }
+
+    async fn barometer_pressure(&mut self) -> Option<f32> {
+        let barometer = self.barometer.as_mut()?;
+        if !self.barometer_configured {
+            self.barometer_configured = barometer.init().await.is_ok();
+        }
+        if !self.barometer_configured {
+            return None;
+        }
+        let pressure = barometer.pressure().await.ok();
+        // Set it up again next time in case it was reset
+        self.barometer_configured = pressure.is_some();
+        pressure
+    }
+}
+
+impl Sensor for EnvironmentSensors {
+    const NAME: &'static str = "Environment sensor";
+    const MODULE: Module = Module::Environment;
+    const MESSAGE_TYPE: MessageType = MessageType::Environment;
+    const CONFIG_INDEX: u8 = SENSOR_ENVIRONMENT;
+    type Sample = protocol::Environment;
+
+    fn task(&self) -> supervisor::Task {
+        match self.location {
+            Location::Cabin => supervisor::Task::Environment,
+            Location::Outside => supervisor::Task::OutsideEnvironment,
+        }
+    }
+
+    async fn init(&mut self) -> bool {
+        match &mut self.hygrometer {
+            Hygrometer::Bme280(bme280) => bme280.init().await.is_ok() && bme280.set_sampling_configuration(
+                bme280_rs::Configuration::default()
+                    .with_sensor_mode(bme280_rs::SensorMode::Normal)
+                    .with_standby_time(bme280_rs::StandbyTime::Millis1000)
+                    .with_pressure_oversampling(bme280_rs::Oversampling::Oversample8)
+                    .with_temperature_oversampling(bme280_rs::Oversampling::Oversample8)
+                    .with_humidity_oversampling(bme280_rs::Oversampling::Oversample8)
+                    .with_filter(bme280_rs::Filter::Filter4)
+            ).await.is_ok(),
+            Hygrometer::Sht4x(sht4x) => sht4x.init().await.is_ok(),
+        }
+    }
+
+    async fn sample(&mut self) -> Option<protocol::Environment> {
+        // The SHT4x has no pressure channel
+        let (pressure, temperature, humidity) = match &mut self.hygrometer {
+            Hygrometer::Bme280(bme280) => {
+                let sample = bme280.read_sample().await.ok()?;
+                (sample.pressure, sample.temperature.unwrap_or(0.0), sample.humidity.unwrap_or(0.0))
+            },
+            Hygrometer::Sht4x(sht4x) => {
+                let (temperature, humidity) = sht4x.measure().await.ok()?;
+                (None, temperature, humidity)
+            },
+        };
+        let (pressure, pressure_sensor) = match (self.barometer_pressure().await, pressure) {
+            (Some(pressure), _) => (pressure, SensorKind::Lps22),
+            (None, Some(pressure)) => (pressure, self.hygrometer.kind()),
+            (None, None) => (0.0, SensorKind::None),
+        };
+        let (offsets, smoothing) = {
+            let config = config::CONFIG.lock().await;
+            let offsets = match self.location {
+                Location::Cabin => config.environment_offsets,
+                Location::Outside => EnvironmentOffsets::DEFAULT,
+            };
+            (offsets, config.sensor_smoothing[SENSOR_ENVIRONMENT as usize])
+        };
+        let has_pressure = pressure_sensor != SensorKind::None;
+        let external_temperature = match &mut self.thermistor {
+            Some((thermistor, adc)) => thermistor.read(adc).await,
+            None => None,
+        };
+        let mut faults = 0;
+        if has_pressure && self.pressure_check.check(pressure) {
+            faults |= protocol::Environment::FAULT_PRESSURE;
+        }
+        if self.temperature_check.check(temperature) {
+            faults |= protocol::Environment::FAULT_TEMPERATURE;
+        }
+        if self.humidity_check.check(humidity) {
+            faults |= protocol::Environment::FAULT_HUMIDITY;
+        }
+        if external_temperature.is_some_and(|external_temperature| self.external_temperature_check.check(external_temperature)) {
+            faults |= protocol::Environment::FAULT_EXTERNAL_TEMPERATURE;
+        }
+        self.channel_faults = faults;
+        let pressure = if has_pressure {
+            self.pressure.apply(smoothing, pressure + offsets.pressure)
+        }
+        else {
+            // The offset is for a reading that isn't there, so 0 keeps it obvious to the host
+            self.pressure.reset();
+            self.pressure_check.reset();
+            0.0
+        };
+        let (corrected_temperature, corrected_humidity) = match self.location {
+            Location::Cabin => (compensate_temperature(temperature), compensate_humidity(temperature, humidity)),
+            Location::Outside => (temperature, humidity),
+        };
+        let corrected_temperature = self.temperature.apply(smoothing, corrected_temperature + offsets.temperature);
+        let humidity = self.humidity.apply(smoothing, (corrected_humidity + offsets.humidity).clamp(0.0, 100.0));
+        let external_temperature = match external_temperature {
+            Some(external_temperature) => Some(self.external_temperature.apply(smoothing, external_temperature)),
+            None => {
+                self.external_temperature.reset();
+                self.external_temperature_check.reset();
+                None
+            },
+        };
+        Some(protocol::Environment {
+            pressure,
+            temperature: corrected_temperature,
+            humidity,
+            dew_point: weather::dew_point(corrected_temperature, humidity),
+            pressure_altitude: has_pressure.then(|| weather::pressure_altitude(pressure)),
+            density_altitude: has_pressure.then(|| weather::density_altitude(pressure, corrected_temperature, humidity)),
+            pressure_sensor,
+            temperature_sensor: self.hygrometer.kind(),
+            external_temperature,
+            faults,
+            location: self.location,
+        })
+    }
+
+    async fn encode(&mut self, environment: protocol::Environment, payload: &mut Vec<u8, MAX_MESSAGE_LENGTH>) -> Option<u16> {
+        let deadbands = config::CONFIG.lock().await.environment_deadbands;
+        let changed = match self.last_forwarded {
+            Some((last, forwarded_at)) => {
+                (environment.pressure - last.pressure).abs() > deadbands.pressure
+                    || (environment.temperature - last.temperature).abs() > deadbands.temperature
+                    || (environment.humidity - last.humidity).abs() > deadbands.humidity
+                    || match (environment.external_temperature, last.external_temperature) {
+                        (Some(external), Some(last_external)) => (external - last_external).abs() > deadbands.temperature,
+                        // Connected or disconnected
+                        (external, last_external) => external.is_some() != last_external.is_some(),
+                    }
+                    || environment.faults != last.faults
+                    || forwarded_at.elapsed() >= deadbands.max_silence
+            },
+            None => true,
+        };
+        if !changed {
+            return None;
+        }
+        self.last_forwarded = Some((environment, Instant::now()));
+        let mut buffer = [0u8; 64];
+        payload.extend_from_slice(postcard::to_slice(&environment, &mut buffer).unwrap()).unwrap();
+        Some(config::CONFIG.lock().await.forwarding_ids.environment)
+    }
+
+    fn fault(&mut self, faulted: bool) {
+        let fault = match self.location {
+            Location::Cabin => &heartbeat::ENVIRONMENT_SENSOR_FAULT,
+            Location::Outside => &heartbeat::OUTSIDE_ENVIRONMENT_SENSOR_FAULT,
+        };
+        fault.store(faulted, Ordering::Relaxed);
+    }
+
+    fn channel_faults(&self) -> u8 {
+        self.channel_faults
+    }
+}
+
+sensor_task!(environment_task, EnvironmentSensors);
+sensor_task!(outside_environment_task, EnvironmentSensors);
@@ -0,0 +1,153 @@
+use core::cell::RefCell;
+
+use defmt::{error, Format};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use heapless::{Deque, Vec};
+
+use crate::protocol::{Message, MessageType, Source};
+use crate::FORWARDING_QUEUE;
+
+// Machine-parseable error reports that fit in a single classic frame:
+// [class, module, code, context (4 bytes, meaning depends on the code)]
+pub const ERROR_FORWARDING_ID: u16 = 0x700;
+
+// Everything that can go wrong at runtime that isn't a bug in the firmware. These get reported and the task carries on,
+// so one bad frame or a flaky sensor can't take the whole node down.
+#[derive(Clone, Copy, Format)]
+pub enum Error {
+    // SPI transfer to a CAN controller failed
+    Spi,
+    // The CAN controller rejected a request or reported a fault
+    Controller(&'static str),
+    // A multi-frame transfer from an ECU that doesn't add up
+    IsoTp(IsoTpError),
+    // An ECU answered with a negative response
+    Uds { service: u8, code: u8 },
+    // A sensor didn't respond, or its readings can't be right
+    Sensor,
+    // A channel between tasks was full and something had to be dropped
+    ChannelFull,
+}
+
+#[derive(Clone, Copy, Format)]
+pub enum IsoTpError {
+    // Shorter than its frame type or the transfer needs
+    Truncated,
+    // Longer than we can reassemble
+    TooLong,
+    // Consecutive frame without a first frame
+    Unexpected,
+}
+
+impl From<mcp25xxfd::Error> for Error {
+    fn from(err: mcp25xxfd::Error) -> Self {
+        match err {
+            mcp25xxfd::Error::ControllerError(message) => Self::Controller(message),
+            // Anything else from the driver comes from the SPI transfer itself
+            _ => Self::Spi,
+        }
+    }
+}
+
+impl Error {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Self::Spi => ErrorClass::Spi,
+            Self::Controller(_) => ErrorClass::Controller,
+            Self::IsoTp(_) => ErrorClass::IsoTp,
+            Self::Uds { .. } => ErrorClass::Uds,
+            Self::Sensor => ErrorClass::Sensor,
+            Self::ChannelFull => ErrorClass::Channel,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Format)]
+pub enum ErrorClass {
+    Controller = 1,
+    Spi = 2,
+    IsoTp = 3,
+    Uds = 4,
+    Sensor = 5,
+    Channel = 6,
+}
+
+#[derive(Clone, Copy, Format)]
+pub enum Module {
+    ObdReceive = 1,
+    ObdSender = 2,
+    Environment = 3,
+    BitRate = 4,
+    Motion = 5,
+    Setup = 6,
+    Power = 7,
+}
+
+#[derive(Clone, Copy, Format)]
+pub enum ErrorCode {
+    // Context is the RX FIFO
+    ReceiveFailed = 1,
+    // Context is the raw CAN ID of the frame
+    TransmitFailed = 2,
+    // Context is the raw CAN ID of the ECU's response
+    BadTransfer = 3,
+    // Context is the ECU's response ID (low 2 bytes), the rejected service and the negative response code
+    NegativeResponse = 4,
+    // Context is unused
+    SensorFailed = 5,
+    // Context is the bus (0 = OBD, 1 = comma)
+    ModeChangeFailed = 6,
+    // Context is the raw CAN ID of the frame that was dropped
+    Dropped = 7,
+    // Context is the sensor's channel fault bits, see sensor::ChannelCheck
+    ChannelFaulted = 8,
+    // Context is the bus (0 = OBD, 1 = comma), which is left down
+    SetupFailed = 9,
+    // Context is the bus, which carries on with the configured bit rate or the default addressing
+    DetectionFailed = 10,
+}
+
+#[derive(Clone, Copy, Format)]
+pub struct ErrorReport {
+    pub class: ErrorClass,
+    pub module: Module,
+    pub code: ErrorCode,
+    pub context: u32,
+}
+impl ErrorReport {
+    pub fn new(module: Module, code: ErrorCode, err: &Error, context: u32) -> Self {
+        Self { class: err.class(), module, code, context }
+    }
+
+    pub fn message(&self, source: Source) -> Message {
+        let mut forward_data: Vec<u8, 64> = Vec::new();
+        forward_data.extend_from_slice(&[self.class as u8, self.module as u8, self.code as u8]).unwrap();
+        forward_data.extend_from_slice(&self.context.to_be_bytes()).unwrap();
+        Message::new(ERROR_FORWARDING_ID, MessageType::ControllerError, source, forward_data)
+    }
+}
+
+// The last few reports with the seconds since boot they happened at, for the diagnostic server
+const RECENT_COUNT: usize = 8;
+static RECENT: Mutex<CriticalSectionRawMutex, RefCell<Deque<(u32, ErrorReport), RECENT_COUNT>>> = Mutex::new(RefCell::new(Deque::new()));
+
+// Oldest first
+pub fn recent() -> Vec<(u32, ErrorReport), RECENT_COUNT> {
+    RECENT.lock(|recent| recent.borrow().iter().copied().collect())
+}
+
+// Logs the error and forwards its report
+pub async fn report(source: Source, module: Module, code: ErrorCode, err: Error, context: u32) {
+    error!("{} in {}: {} ({:x})", code, module, err, context);
+    let report = ErrorReport::new(module, code, &err, context);
+    RECENT.lock(|recent| {
+        let mut recent = recent.borrow_mut();
+        if recent.is_full() {
+            recent.pop_front();
+        }
+        recent.push_back((Instant::now().as_secs() as u32, report)).ok().unwrap();
+    });
+    FORWARDING_QUEUE.send(report.message(source)).await;
+}
@@ -0,0 +1,54 @@
+use defmt::*;
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Timer;
+use hmac::{Hmac, Mac};
+use rand_core::RngCore;
+use sha2::Sha256;
+
+use crate::boot;
+use crate::storage::{self, FlashMutex};
+
+// Wiping a unit takes a challenge-response with a per-fleet key baked in at build time (FACTORY_RESET_KEY), so a stray
+// or spoofed frame on the comma bus can't do it. The host asks for a challenge and answers with the first
+// RESPONSE_LENGTH bytes of HMAC-SHA256(key, challenge || "factory reset"). Each challenge is good for one attempt.
+// Firmware built without a key refuses factory resets.
+
+const KEY: Option<&str> = option_env!("FACTORY_RESET_KEY");
+// Truncated so the response fits in a classic frame next to the command byte
+pub const RESPONSE_LENGTH: usize = 6;
+
+static CHALLENGE: Mutex<CriticalSectionRawMutex, Option<[u8; 4]>> = Mutex::new(None);
+
+// None if factory resets are disabled in this build
+pub async fn challenge() -> Option<[u8; 4]> {
+    KEY?;
+    let challenge = RoscRng.next_u32().to_be_bytes();
+    *CHALLENGE.lock().await = Some(challenge);
+    Some(challenge)
+}
+
+// Uses up the outstanding challenge either way
+pub async fn verify(response: &[u8]) -> bool {
+    let (Some(key), Some(challenge)) = (KEY, CHALLENGE.lock().await.take()) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+    mac.update(&challenge);
+    mac.update(b"factory reset");
+    mac.verify_truncated_left(response).is_ok()
+}
+
+// Erases the stored configuration, DTC history, crash log and blackbox, then restarts on the compile-time defaults
+pub async fn wipe(flash: &FlashMutex) -> ! {
+    warn!("Factory reset, erasing stored configuration, DTCs, crash log and blackbox");
+    for sector in storage::CONFIG_SECTORS.into_iter().chain([storage::DTC_SECTOR, storage::CRASH_SECTOR, storage::BLACKBOX_SECTOR]) {
+        if let Err(err) = storage::erase(flash, sector) {
+            error!("Unable to erase {:x}: {}", sector, err);
+        }
+    }
+    // Give the response a chance to go out
+    Timer::after_millis(100).await;
+    boot::reset(boot::ResetReason::Software);
+}
@@ -0,0 +1,225 @@
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+use portable_atomic::{AtomicU32, Ordering};
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+use crate::memory;
+use crate::log_level::debug;
+use crate::protocol::{Message, MessageType};
+
+// Messages waiting for the comma forwarder. Producers never block: once the queue is full the configured policy
+// decides what gets dropped, so a slow or stalled comma link can't hold up receive servicing. The forwarder always takes
+// the oldest message of the highest class queued, so a flood of sensor data can't delay fault reporting.
+pub const QUEUE_DEPTH: usize = 10;
+pub struct ForwardingQueue {
+    queue: Mutex<CriticalSectionRawMutex, RefCell<Deque<Message, QUEUE_DEPTH>>>,
+    queued: Signal<CriticalSectionRawMutex, ()>,
+    dropped: AtomicU32,
+    buckets: Mutex<CriticalSectionRawMutex, RefCell<Vec<Bucket, RATE_LIMITED_IDS>>>,
+    rate_limited: AtomicU32,
+}
+
+// Token bucket for each forwarding ID, so a runaway producer (an error loop, say) can't take over the queue and the
+// comma bus. Raw frames are left alone, their volume is up to the host's subscriptions, capture filters and bus mode.
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub struct RateLimit {
+    // Messages per second an ID can keep up
+    pub per_second: u32,
+    // Messages an ID can send in a row after being quiet
+    pub burst: u32,
+}
+
+// IDs tracked at once, the one that was quiet the longest makes room for a new one
+const RATE_LIMITED_IDS: usize = 16;
+
+struct Bucket {
+    id: u16,
+    tokens: u32,
+    refilled_at: Instant,
+}
+impl Bucket {
+    fn take(&mut self, limit: RateLimit) -> bool {
+        if limit.per_second > 0 {
+            let refill = self.refilled_at.elapsed().as_micros() * limit.per_second as u64 / 1_000_000;
+            if refill > 0 {
+                self.tokens = (self.tokens as u64 + refill).min(limit.burst as u64) as u32;
+                // Carry the fraction of a token that's built up over to the next refill
+                self.refilled_at += Duration::from_micros(refill * 1_000_000 / limit.per_second as u64);
+            }
+        }
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub enum BackpressurePolicy {
+    // Keep the freshest data
+    DropOldest,
+    // Keep what's already queued
+    DropNewest,
+    // Make room by dropping the oldest of the least important queued messages, or the new one if it's less important
+    // than all of them
+    DropLowestPriority,
+}
+
+// Higher classes are forwarded first and survive longer under DropLowestPriority
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum Class {
+    Sensors = 0,
+    ObdData = 1,
+    Diagnostics = 2,
+}
+
+pub fn class(message_type: MessageType) -> Class {
+    match message_type {
+        MessageType::ControllerError
+        | MessageType::SelfTest
+        | MessageType::TxAbandoned
+        | MessageType::Alert
+        | MessageType::Heartbeat
+        | MessageType::CrashReport
+        | MessageType::Dtc
+        | MessageType::BusHealth
+        | MessageType::Statistics
+        | MessageType::Log
+        | MessageType::Blackbox
+        | MessageType::BusErrors => Class::Diagnostics,
+        MessageType::EcuData
+        | MessageType::DidResponse
+        | MessageType::GatewayResponse
+        | MessageType::Signals
+        | MessageType::CommandResponse
+        | MessageType::RawFrame
+        | MessageType::Batch => Class::ObdData,
+        MessageType::Environment | MessageType::Motion | MessageType::Position | MessageType::Supply => Class::Sensors,
+    }
+}
+
+// Deque has no remove(), rotate the message to the front instead
+fn remove(queue: &mut Deque<Message, QUEUE_DEPTH>, position: usize) -> Option<Message> {
+    for _ in 0..position {
+        let front = queue.pop_front().unwrap();
+        queue.push_back(front).ok();
+    }
+    let removed = queue.pop_front();
+    for _ in 0..queue.len() - position {
+        let front = queue.pop_front().unwrap();
+        queue.push_back(front).ok();
+    }
+    removed
+}
+
+impl ForwardingQueue {
+    pub const fn new() -> Self {
+        Self {
+            queue: Mutex::new(RefCell::new(Deque::new())),
+            queued: Signal::new(),
+            dropped: AtomicU32::new(0),
+            buckets: Mutex::new(RefCell::new(Vec::new())),
+            rate_limited: AtomicU32::new(0),
+        }
+    }
+
+    fn within_rate_limit(&self, id: u16, limit: RateLimit) -> bool {
+        self.buckets.lock(|buckets| {
+            let mut buckets = buckets.borrow_mut();
+            let position = match buckets.iter().position(|bucket| bucket.id == id) {
+                Some(position) => position,
+                None => {
+                    if buckets.is_full() {
+                        let quietest = buckets.iter().enumerate().min_by_key(|(_, bucket)| bucket.refilled_at).unwrap().0;
+                        buckets.swap_remove(quietest);
+                    }
+                    buckets.push(Bucket { id, tokens: limit.burst, refilled_at: Instant::now() }).ok();
+                    buckets.len() - 1
+                },
+            };
+            buckets[position].take(limit)
+        })
+    }
+
+    pub async fn send(&self, message: Message) {
+        let (policy, rate_limit) = {
+            let config = CONFIG.lock().await;
+            (config.forwarding_backpressure, config.forwarding_rate_limit)
+        };
+        if message.message_type != MessageType::RawFrame && !self.within_rate_limit(message.id.as_raw(), rate_limit) {
+            self.rate_limited.fetch_add(1, Ordering::Relaxed);
+            debug!("Rate limit reached, dropped message for {:x}", message.id.as_raw());
+            return;
+        }
+        let dropped = self.queue.lock(|queue| {
+            let mut queue = queue.borrow_mut();
+            if !queue.is_full() {
+                queue.push_back(message).ok();
+                memory::FORWARDING_QUEUE_PEAK.record(queue.len());
+                return None;
+            }
+            match policy {
+                BackpressurePolicy::DropOldest => {
+                    let oldest = queue.pop_front();
+                    queue.push_back(message).ok();
+                    oldest
+                },
+                BackpressurePolicy::DropNewest => Some(message),
+                BackpressurePolicy::DropLowestPriority => {
+                    let (position, lowest) = queue.iter()
+                        .map(|queued| class(queued.message_type) as u8)
+                        .enumerate()
+                        .min_by_key(|&(position, queued_class)| (queued_class, position))
+                        .unwrap();
+                    if (class(message.message_type) as u8) < lowest {
+                        return Some(message);
+                    }
+                    let victim = remove(&mut queue, position);
+                    queue.push_back(message).ok();
+                    victim
+                },
+            }
+        });
+        if let Some(dropped) = dropped {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            debug!("Forwarding queue full, dropped message for {:x}", dropped.id.as_raw());
+        }
+        self.queued.signal(());
+    }
+
+    // Only one task can wait on this at a time
+    pub async fn receive(&self) -> Message {
+        loop {
+            let next = self.queue.lock(|queue| {
+                let mut queue = queue.borrow_mut();
+                let (position, _) = queue.iter()
+                    .map(|queued| class(queued.message_type) as u8)
+                    .enumerate()
+                    .max_by_key(|&(position, queued_class)| (queued_class, core::cmp::Reverse(position)))?;
+                remove(&mut queue, position)
+            });
+            if let Some(message) = next {
+                return message;
+            }
+            self.queued.wait().await;
+        }
+    }
+
+    // Messages dropped by the backpressure policy since boot
+    pub fn dropped(&self) -> u32 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Messages dropped by the rate limit since boot
+    pub fn rate_limited(&self) -> u32 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,52 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use embedded_can::Id;
+use heapless::Vec;
+
+// Diagnostic requests from the comma device, sent on the OBD bus through this device. Requests go to one of the ECUs we
+// already talk to (by ECU index, see polling::ECU_COUNT) as ISO-TP single frames, and only read-only services are
+// let through. The whole thing is off unless config::DeviceConfig::gateway_enabled is set.
+
+// [ECU index, UDS/OBD response...]
+pub const GATEWAY_RESPONSE_ID: u16 = 0x7C1;
+// Longest request that fits in a single frame
+pub const MAX_REQUEST_LENGTH: usize = 7;
+// Responses the ECU never sent are forgotten after this long
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// OBD modes 01, 02, 03, 06, 07, 09 and 0A, UDS read DTC information, read data by identifier and tester present
+const ALLOWED_SERVICES: [u8; 10] = [0x01, 0x02, 0x03, 0x06, 0x07, 0x09, 0x0A, 0x19, 0x22, 0x3E];
+
+pub fn allowed(request: &[u8]) -> bool {
+    match request.first() {
+        Some(service) => request.len() <= MAX_REQUEST_LENGTH && ALLOWED_SERVICES.contains(service),
+        None => false,
+    }
+}
+
+// Requests waiting on a response: (ECU response address, ECU index, sent at)
+static PENDING: Mutex<CriticalSectionRawMutex, RefCell<Vec<(Id, u8, Instant), 4>>> = Mutex::new(RefCell::new(Vec::new()));
+
+// Call once the request has been queued for transmission
+pub fn sent(rx_addr: Id, ecu: u8) {
+    PENDING.lock(|pending| {
+        let mut pending = pending.borrow_mut();
+        pending.retain(|(_, _, sent_at)| sent_at.elapsed() <= RESPONSE_TIMEOUT);
+        if pending.is_full() {
+            pending.remove(0);
+        }
+        pending.push((rx_addr, ecu, Instant::now())).ok();
+    });
+}
+
+// Returns the ECU index if a response from this address answers a gateway request
+pub fn take(rx_addr: Id) -> Option<u8> {
+    PENDING.lock(|pending| {
+        let mut pending = pending.borrow_mut();
+        let position = pending.iter().position(|(pending_addr, _, sent_at)| *pending_addr == rx_addr && sent_at.elapsed() <= RESPONSE_TIMEOUT)?;
+        Some(pending.remove(position).1)
+    })
+}
@@ -0,0 +1,164 @@
+use defmt::*;
+use embassy_rp::peripherals::UART1;
+use embassy_rp::uart::{Async, UartRx};
+use heapless::Vec;
+
+use crate::clock;
+use crate::log_level::debug;
+use crate::protocol::{Message, MessageType, Position, Source};
+use crate::FORWARDING_QUEUE;
+
+// NMEA from a u-blox style GPS receiver on UART1 (RX only, the module's defaults are fine). The fix from each GGA
+// sentence is combined with the speed, course and time of the RMC that follows it, and forwarded as a postcard-encoded
+// Position once per RMC. Until the host sets the clock, GPS time does, so timestamps are usable on drives where the host
+// never connects.
+pub const GPS_FORWARDING_ID: u16 = 0x7A2;
+pub const BAUD_RATE: u32 = 9600;
+
+// NMEA sentences are at most 82 characters including the line ending
+const MAX_SENTENCE_LENGTH: usize = 82;
+const KNOTS_TO_METERS_PER_SECOND: f32 = 0.514_444;
+
+#[derive(Default)]
+struct Fix {
+    // 0 = none, 1 = GPS, 2 = differential, see GGA
+    quality: u8,
+    satellites: u8,
+    // 1e-7 degrees
+    latitude: i32,
+    longitude: i32,
+    // m
+    altitude: f32,
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+// The fields between "$" and "*" if the checksum matches
+fn checked_body(line: &[u8]) -> Option<&[u8]> {
+    let line = line.strip_prefix(b"$")?;
+    let star = line.iter().position(|&byte| byte == b'*')?;
+    let (body, checksum) = (&line[..star], &line[star + 1..]);
+    let expected = (hex_digit(*checksum.first()?)? << 4) | hex_digit(*checksum.get(1)?)?;
+    (body.iter().fold(0, |sum, byte| sum ^ byte) == expected).then_some(body)
+}
+
+fn parse<T: core::str::FromStr>(field: &[u8]) -> Option<T> {
+    core::str::from_utf8(field).ok()?.parse().ok()
+}
+
+// [d]ddmm.mmmm and a hemisphere to 1e-7 degrees
+fn coordinate(value: &[u8], hemisphere: &[u8]) -> Option<i32> {
+    let dot = value.iter().position(|&byte| byte == b'.').unwrap_or(value.len());
+    let degrees_length = dot.checked_sub(2)?;
+    let degrees: i64 = parse(&value[..degrees_length])?;
+    let minutes: f64 = parse(&value[degrees_length..])?;
+    let coordinate = degrees * 10_000_000 + (minutes * 10_000_000.0 / 60.0) as i64;
+    match hemisphere {
+        b"N" | b"E" => Some(coordinate as i32),
+        b"S" | b"W" => Some(-coordinate as i32),
+        _ => None,
+    }
+}
+
+// Days since 1970-01-01 for a date in the Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// hhmmss.ss and ddmmyy to microseconds since the Unix epoch
+fn unix_micros(time: &[u8], date: &[u8]) -> Option<u64> {
+    if time.len() < 6 || date.len() != 6 {
+        return None;
+    }
+    let hours: i64 = parse(&time[0..2])?;
+    let minutes: i64 = parse(&time[2..4])?;
+    let seconds: f64 = parse(&time[4..])?;
+    let day: i64 = parse(&date[0..2])?;
+    let month: i64 = parse(&date[2..4])?;
+    let year: i64 = 2000 + parse::<i64>(&date[4..6])?;
+    let days = days_from_civil(year, month, day);
+    let whole_seconds = days * 86_400 + hours * 3600 + minutes * 60;
+    Some(whole_seconds as u64 * 1_000_000 + (seconds * 1_000_000.0) as u64)
+}
+
+async fn handle_sentence(line: &[u8], fix: &mut Fix) {
+    let Some(body) = checked_body(line) else {
+        return;
+    };
+    let fields: Vec<&[u8], 20> = body.split(|&byte| byte == b',').take(20).collect();
+    let field = |index: usize| fields.get(index).copied().unwrap_or(&[]);
+    // Talker ID (GP, GN, GL...) doesn't matter
+    let sentence_type = field(0).get(2..).unwrap_or(&[]);
+    match sentence_type {
+        b"GGA" => {
+            fix.quality = parse(field(6)).unwrap_or(0);
+            fix.satellites = parse(field(7)).unwrap_or(0);
+            if fix.quality > 0 {
+                fix.latitude = coordinate(field(2), field(3)).unwrap_or(fix.latitude);
+                fix.longitude = coordinate(field(4), field(5)).unwrap_or(fix.longitude);
+                fix.altitude = parse(field(9)).unwrap_or(fix.altitude);
+            }
+        },
+        b"RMC" => {
+            let valid = field(2) == b"A";
+            if valid {
+                if let Some(unix_micros) = unix_micros(field(1), field(9)) {
+                    clock::sync_from_gps(unix_micros);
+                }
+            }
+            let position = Position {
+                latitude: fix.latitude,
+                longitude: fix.longitude,
+                altitude: fix.altitude,
+                speed: parse::<f32>(field(7)).unwrap_or(0.0) * KNOTS_TO_METERS_PER_SECOND,
+                course: parse(field(8)).unwrap_or(0.0),
+                fix_quality: if valid { fix.quality } else { 0 },
+                satellites: fix.satellites,
+            };
+            let mut buffer = [0u8; 64];
+            let forward_data = postcard::to_slice(&position, &mut buffer).unwrap();
+            FORWARDING_QUEUE.send(Message::new(GPS_FORWARDING_ID, MessageType::Position, Source::Sensors, forward_data)).await;
+        },
+        _ => {},
+    }
+}
+
+#[embassy_executor::task]
+pub async fn gps_task(mut uart: UartRx<'static, UART1, Async>) {
+    let mut line: Vec<u8, MAX_SENTENCE_LENGTH> = Vec::new();
+    let mut fix = Fix::default();
+    loop {
+        let mut byte = [0u8];
+        if let Err(err) = uart.read(&mut byte).await {
+            // Framing errors and the like while the module powers up, the next sentence starts clean
+            debug!("GPS UART error: {}", err);
+            line.clear();
+            continue;
+        }
+        match byte[0] {
+            b'$' => {
+                line.clear();
+                line.push(b'$').unwrap();
+            },
+            b'\r' | b'\n' => {
+                if !line.is_empty() {
+                    handle_sentence(&line, &mut fix).await;
+                    line.clear();
+                }
+            },
+            // Too long to be a sentence, drop it
+            byte => {
+                if line.push(byte).is_err() {
+                    line.clear();
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use embassy_rp::adc::{Adc, Async, Channel};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Ticker};
+use heapless::Vec;
+use portable_atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+
+use crate::protocol::{Message, MessageType, Source};
+use crate::{boot, config, power, self_test, supervisor, FORWARDING_QUEUE, PRIORITY_FORWARDING_CHANNEL};
+
+// [firmware version (major, minor, patch), uptime seconds (4 bytes), status flags, OBD TEC, OBD REC, comma TEC,
+// comma REC, forwarding queue drops (2 bytes), rate limited messages (2 bytes), reset reason (see boot::ResetReason),
+// task that stalled before the reset (see supervisor::Task, 0xFF if none), fault flags, RP2040 die temperature (0.1 °C,
+// 2 bytes signed, 0x8000 if the ADC failed)]
+pub const HEARTBEAT_FORWARDING_ID: u16 = 0x7B4;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+// An ECU answering within this long means the vehicle is awake
+const VEHICLE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const FLAG_VEHICLE_RESPONDING: u8 = 1 << 0;
+const FLAG_CAR_ON: u8 = 1 << 1;
+const FLAG_OBD_BUS_UP: u8 = 1 << 2;
+const FLAG_COMMA_BUS_UP: u8 = 1 << 3;
+const FLAG_OBD_ASLEEP: u8 = 1 << 4;
+const FLAG_COMMA_ASLEEP: u8 = 1 << 5;
+const FLAG_SELF_TEST_FAILED: u8 = 1 << 6;
+// The newest stored configuration was rejected and the device fell back to an older one, see config::save
+const FLAG_CONFIG_REVERTED: u8 = 1 << 7;
+
+// Fault flags, in their own byte since the status flags are full
+// The environment sensor stopped responding and couldn't be brought back, see environment.rs
+const FAULT_ENVIRONMENT_SENSOR: u8 = 1 << 0;
+// Same for the outside air one, on units that have it
+const FAULT_OUTSIDE_ENVIRONMENT_SENSOR: u8 = 1 << 1;
+
+pub static ENVIRONMENT_SENSOR_FAULT: AtomicBool = AtomicBool::new(false);
+pub static OUTSIDE_ENVIRONMENT_SENSOR_FAULT: AtomicBool = AtomicBool::new(false);
+// Latest (TEC << 8) | REC from each bus_health_task, indexed by bus
+pub static ERROR_COUNTERS: [AtomicU16; 2] = [AtomicU16::new(0), AtomicU16::new(0)];
+static LAST_VEHICLE_RESPONSE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+// Call whenever an ECU answers a query
+pub fn vehicle_responded() {
+    LAST_VEHICLE_RESPONSE.store(Instant::now().as_ticks(), Ordering::Relaxed);
+}
+
+fn vehicle_responding() -> bool {
+    match LAST_VEHICLE_RESPONSE.load(Ordering::Relaxed) {
+        u64::MAX => false,
+        ticks => Instant::from_ticks(ticks).elapsed() <= VEHICLE_RESPONSE_TIMEOUT,
+    }
+}
+
+// From the RP2040 datasheet: 0.706 V at 27 °C, falling 1.721 mV per degree
+fn die_temperature(raw: u16) -> f32 {
+    let volts = raw as f32 * 3.3 / 4095.0;
+    27.0 - (volts - 0.706) / 0.001721
+}
+
+// Lets the host tell "device alive but car asleep" from "device dead". Goes out ahead of bulk forwarding so a backed up
+// queue doesn't make the device look dead.
+#[embassy_executor::task]
+pub async fn heartbeat_task(
+    car_off_since: &'static Mutex<CriticalSectionRawMutex, Option<Instant>>,
+    adc: &'static Mutex<CriticalSectionRawMutex, Adc<'static, Async>>,
+    mut die_sensor: Channel<'static>,
+) {
+    let version = [
+        env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+    ];
+    let mut ticker = Ticker::every(HEARTBEAT_INTERVAL);
+    loop {
+        ticker.next().await;
+        let flag = |condition: bool, flag: u8| if condition { flag } else { 0 };
+        let flags = flag(vehicle_responding(), FLAG_VEHICLE_RESPONDING)
+            | flag(car_off_since.lock().await.is_none(), FLAG_CAR_ON)
+            | flag(boot::OBD_BUS_UP.load(Ordering::Relaxed), FLAG_OBD_BUS_UP)
+            | flag(boot::COMMA_BUS_UP.load(Ordering::Relaxed), FLAG_COMMA_BUS_UP)
+            | flag(power::OBD_POWER.is_asleep(), FLAG_OBD_ASLEEP)
+            | flag(power::COMMA_POWER.is_asleep(), FLAG_COMMA_ASLEEP)
+            | flag(self_test::failed(), FLAG_SELF_TEST_FAILED)
+            | flag(config::CONFIG_REVERTED.load(Ordering::Relaxed), FLAG_CONFIG_REVERTED);
+        let faults = flag(ENVIRONMENT_SENSOR_FAULT.load(Ordering::Relaxed), FAULT_ENVIRONMENT_SENSOR)
+            | flag(OUTSIDE_ENVIRONMENT_SENSOR_FAULT.load(Ordering::Relaxed), FAULT_OUTSIDE_ENVIRONMENT_SENSOR);
+
+        let mut forward_data: Vec<u8, 64> = Vec::new();
+        forward_data.extend_from_slice(&version).unwrap();
+        forward_data.extend_from_slice(&(Instant::now().as_secs() as u32).to_be_bytes()).unwrap();
+        forward_data.push(flags).unwrap();
+        for counters in ERROR_COUNTERS.iter() {
+            forward_data.extend_from_slice(&counters.load(Ordering::Relaxed).to_be_bytes()).unwrap();
+        }
+        forward_data.extend_from_slice(&(FORWARDING_QUEUE.dropped().min(u16::MAX as u32) as u16).to_be_bytes()).unwrap();
+        forward_data.extend_from_slice(&(FORWARDING_QUEUE.rate_limited().min(u16::MAX as u32) as u16).to_be_bytes()).unwrap();
+        forward_data.push(boot::reset_reason()).unwrap();
+        forward_data.push(supervisor::stalled_before_reset()).unwrap();
+        forward_data.push(faults).unwrap();
+        // Watches for the enclosure heat soaking in a parked car
+        let die_temperature = match adc.lock().await.read(&mut die_sensor).await {
+            Ok(raw) => (die_temperature(raw) * 10.0) as i16,
+            Err(_) => i16::MIN,
+        };
+        forward_data.extend_from_slice(&die_temperature.to_be_bytes()).unwrap();
+        // Skip a beat rather than pile up stale heartbeats if the comma link is stuck
+        let _ = PRIORITY_FORWARDING_CHANNEL.try_send(Message::new(HEARTBEAT_FORWARDING_ID, MessageType::Heartbeat, Source::Firmware, forward_data));
+    }
+}
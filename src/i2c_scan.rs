@@ -0,0 +1,117 @@
+use defmt::*;
+use embedded_hal_async::i2c::I2c;
+use heapless::Vec;
+
+use crate::sht4x;
+
+// Finds out what's on the sensor I2C bus at boot, so the sensor tasks are set up for what this unit was actually built
+// with. Every address is probed and logged, then the known parts are confirmed by their ID registers where they have
+// one, since a few share addresses. Sensors that weren't there at boot are looked for again later with rescan(), which
+// only touches the addresses they could be at.
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum Device {
+    Bme280,
+    Sht4x,
+    Lps22,
+    Lsm6ds3,
+    Icm42688,
+    Rtc,
+}
+
+// (address, device, ID register and the values it can read), parts without an ID register just have to acknowledge. The
+// ICM-42688 comes before the RTCs it shares 0x68 with.
+const KNOWN: [(u8, Device, Option<(u8, &[u8])>); 12] = [
+    (0x76, Device::Bme280, Some((0xD0, &[0x60]))),
+    (0x77, Device::Bme280, Some((0xD0, &[0x60]))),
+    // Checked with its serial number command, see identify()
+    (0x44, Device::Sht4x, None),
+    (0x5C, Device::Lps22, Some((0x0F, &[0xB1]))),
+    (0x5D, Device::Lps22, Some((0x0F, &[0xB1]))),
+    // LSM6DS3 and LSM6DS3TR-C
+    (0x6A, Device::Lsm6ds3, Some((0x0F, &[0x69, 0x6A]))),
+    (0x6B, Device::Lsm6ds3, Some((0x0F, &[0x69, 0x6A]))),
+    (0x68, Device::Icm42688, Some((0x75, &[0x47]))),
+    (0x69, Device::Icm42688, Some((0x75, &[0x47]))),
+    // DS3231, PCF8523
+    (0x68, Device::Rtc, None),
+    // PCF85063, PCF8563
+    (0x51, Device::Rtc, None),
+    // RV-3028
+    (0x52, Device::Rtc, None),
+];
+
+pub struct Inventory {
+    devices: Vec<(Device, u8), { KNOWN.len() }>,
+}
+impl Inventory {
+    // Address of the first one found
+    pub fn find(&self, device: Device) -> Option<u8> {
+        self.devices.iter().find(|&&(found, _)| found == device).map(|&(_, address)| address)
+    }
+    pub fn found(&self, device: Device, address: u8) -> bool {
+        self.devices.contains(&(device, address))
+    }
+}
+
+async fn identify<I2C: I2c>(i2c: &mut I2C, responding: &[u8], address: u8, device: Device, id: Option<(u8, &[u8])>) -> bool {
+    match (device, id) {
+        // Doesn't acknowledge a read without a command before it
+        (Device::Sht4x, _) => sht4x::Sht4x::new(&mut *i2c).serial().await.is_ok(),
+        (_, Some((register, ids))) => {
+            let mut value = [0u8];
+            i2c.write_read(address, &[register], &mut value).await.is_ok() && ids.contains(&value[0])
+        },
+        (_, None) => responding.contains(&address),
+    }
+}
+
+pub async fn scan<I2C: I2c>(i2c: &mut I2C) -> Inventory {
+    // Reserved addresses excluded
+    let mut responding: Vec<u8, 112> = Vec::new();
+    for address in 0x08..0x78 {
+        if acknowledges(i2c, address).await {
+            responding.push(address).unwrap();
+        }
+    }
+    info!("I2C devices at {=[u8]:x}", responding.as_slice());
+
+    let inventory = probe(i2c, &responding, |_| true, true).await;
+    if inventory.devices.is_empty() {
+        warn!("No known I2C devices found");
+    }
+    inventory
+}
+
+// Looks for just these devices, without logging them again. Nothing is sent to any other address, so the sensors that
+// are already running don't see any of it.
+pub async fn rescan<I2C: I2c>(i2c: &mut I2C, devices: &[Device]) -> Inventory {
+    let mut responding: Vec<u8, { KNOWN.len() }> = Vec::new();
+    for (address, device, _) in KNOWN {
+        if devices.contains(&device) && !responding.contains(&address) && acknowledges(i2c, address).await {
+            responding.push(address).unwrap();
+        }
+    }
+    probe(i2c, &responding, |device| devices.contains(&device), false).await
+}
+
+async fn acknowledges<I2C: I2c>(i2c: &mut I2C, address: u8) -> bool {
+    let mut byte = [0u8];
+    i2c.read(address, &mut byte).await.is_ok()
+}
+
+async fn probe<I2C: I2c>(i2c: &mut I2C, responding: &[u8], wanted: impl Fn(Device) -> bool, log: bool) -> Inventory {
+    let mut inventory = Inventory { devices: Vec::new() };
+    for (address, device, id) in KNOWN {
+        if !wanted(device) || inventory.devices.iter().any(|&(_, found)| found == address) {
+            continue;
+        }
+        if identify(i2c, responding, address, device, id).await {
+            if log {
+                info!("Found {} at {:x}", device, address);
+            }
+            inventory.devices.push((device, address)).unwrap();
+        }
+    }
+    inventory
+}
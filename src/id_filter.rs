@@ -0,0 +1,54 @@
+use defmt::*;
+use embedded_can::Id;
+use serde::{Deserialize, Serialize};
+
+use crate::log_level::debug;
+
+// Last line of defence for IDs this device must never put on the vehicle bus or hand to the host, like steering or
+// brake commands. Checked before gateway requests are transmitted and before sniffed or subscribed frames are
+// forwarded. A deny list blocks the IDs that match a rule, an allow list blocks everything else.
+
+pub const MAX_ID_RULES: usize = 8;
+
+// Matches IDs where (ID & mask) == (rule ID & mask). IDs are in the protocol's 4 byte form, bit 31 set for extended
+// IDs, and the mask always compares that bit.
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub struct IdRule {
+    pub id: u32,
+    pub mask: u32,
+}
+impl IdRule {
+    fn matches(&self, raw_id: u32) -> bool {
+        let mask = self.mask | EXTENDED_FLAG;
+        raw_id & mask == self.id & mask
+    }
+}
+
+const EXTENDED_FLAG: u32 = 0x8000_0000;
+
+fn raw_id(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => id.as_raw() as u32,
+        Id::Extended(id) => id.as_raw() | EXTENDED_FLAG,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub struct IdList {
+    pub allow: bool,
+    pub rules: [Option<IdRule>; MAX_ID_RULES],
+}
+impl IdList {
+    // Deny nothing
+    pub const DEFAULT: Self = Self { allow: false, rules: [None; MAX_ID_RULES] };
+
+    pub fn permits(&self, id: Id) -> bool {
+        let raw_id = raw_id(id);
+        let matched = self.rules.iter().flatten().any(|rule| rule.matches(raw_id));
+        if matched != self.allow {
+            debug!("{:x} blocked by the ID {} list", raw_id, if self.allow { "allow" } else { "deny" });
+            return false;
+        }
+        true
+    }
+}
@@ -0,0 +1,228 @@
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_async::i2c::I2c;
+use heapless::Vec;
+use micromath::F32Ext;
+
+use crate::errors::Module;
+use crate::protocol::{MessageType, MAX_MESSAGE_LENGTH};
+use crate::sensor::{sensor_task, ChannelCheck, Sensor, SENSOR_MOTION};
+use crate::smoothing::Smoother;
+use crate::{config, supervisor, SensorI2c};
+
+// Motion telemetry from an accelerometer/gyro on the sensor I2C bus, for spotting harsh events and rough roads. The IMU
+// is sampled every 10 ms by default and summarized every REPORT_INTERVAL as [samples (2 bytes),
+// RMS vibration (mg, 2 bytes), peak acceleration (mg, 2 bytes), peak rotation rate (0.1 °/s, 2 bytes),
+// faults (FAULT_ACCELEROMETER etc.)]. Vibration is how much the magnitude of the acceleration moves around its mean
+// over the interval, so gravity and mounting angle drop out of it.
+pub const MOTION_FORWARDING_ID: u16 = 0x7A1;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// An axis that's stuck in the last report, see sensor::ChannelCheck
+pub const FAULT_ACCELEROMETER: u8 = 1 << 0;
+pub const FAULT_GYRO: u8 = 1 << 1;
+// Noise alone moves every axis by a few LSBs from one sample to the next, even parked
+const STUCK_AFTER: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy)]
+pub enum Part {
+    // At 0x6A or 0x6B
+    Lsm6ds3,
+    // At 0x68 or 0x69
+    Icm42688,
+}
+
+// Both parts run at about 100 Hz, ±4 g and ±500 °/s
+mod lsm6ds3 {
+    pub const REG_CTRL1_XL: u8 = 0x10;
+    pub const REG_CTRL2_G: u8 = 0x11;
+    pub const REG_CTRL3_C: u8 = 0x12;
+    // Gyro X, Y, Z then accelerometer X, Y, Z, little-endian
+    pub const REG_OUTX_L_G: u8 = 0x22;
+
+    // 104 Hz, ±4 g
+    pub const CTRL1_XL: u8 = 0x48;
+    // 104 Hz, ±500 °/s
+    pub const CTRL2_G: u8 = 0x44;
+    // Block data update, address auto-increment
+    pub const CTRL3_C: u8 = 0x44;
+    pub const CTRL3_C_SW_RESET: u8 = 0x01;
+
+    pub const G_PER_LSB: f32 = 0.000_122;
+    pub const DPS_PER_LSB: f32 = 0.0175;
+}
+
+mod icm42688 {
+    pub const REG_DEVICE_CONFIG: u8 = 0x11;
+    // Accelerometer X, Y, Z then gyro X, Y, Z, big-endian
+    pub const REG_ACCEL_DATA_X1: u8 = 0x1F;
+    pub const REG_PWR_MGMT0: u8 = 0x4E;
+    pub const REG_GYRO_CONFIG0: u8 = 0x4F;
+    pub const REG_ACCEL_CONFIG0: u8 = 0x50;
+
+    pub const DEVICE_CONFIG_SOFT_RESET: u8 = 0x01;
+    // Accelerometer and gyro in low noise mode
+    pub const PWR_MGMT0: u8 = 0x0F;
+    // ±500 °/s, 100 Hz
+    pub const GYRO_CONFIG0: u8 = 0x48;
+    // ±4 g, 100 Hz
+    pub const ACCEL_CONFIG0: u8 = 0x48;
+
+    pub const G_PER_LSB: f32 = 1.0 / 8192.0;
+    pub const DPS_PER_LSB: f32 = 1.0 / 65.5;
+}
+
+pub struct Imu<I2C> {
+    i2c: I2C,
+    address: u8,
+    part: Part,
+    // Each axis of the accelerometer then the gyro, if smoothing is configured
+    smoothers: [Smoother; 6],
+    // Of the raw readings, indexed like smoothers
+    checks: [ChannelCheck; 6],
+    channel_faults: u8,
+    window: Window,
+    window_start: Instant,
+}
+impl<I2C: I2c> Imu<I2C> {
+    pub fn new(i2c: I2C, address: u8, part: Part) -> Self {
+        Self {
+            i2c,
+            address,
+            part,
+            smoothers: [const { Smoother::new() }; 6],
+            // Anything within full scale is possible, only stuck axes are caught
+            checks: [const { ChannelCheck::new(f32::MIN..=f32::MAX, STUCK_AFTER) }; 6],
+            channel_faults: 0,
+            window: Window::default(),
+            window_start: Instant::now(),
+        }
+    }
+
+    async fn write(&mut self, register: u8, value: u8) -> Result<(), I2C::Error> {
+        self.i2c.write(self.address, &[register, value]).await
+    }
+
+    pub async fn init(&mut self) -> Result<(), I2C::Error> {
+        match self.part {
+            Part::Lsm6ds3 => {
+                self.write(lsm6ds3::REG_CTRL3_C, lsm6ds3::CTRL3_C_SW_RESET).await?;
+                Timer::after_millis(1).await;
+                self.write(lsm6ds3::REG_CTRL3_C, lsm6ds3::CTRL3_C).await?;
+                self.write(lsm6ds3::REG_CTRL1_XL, lsm6ds3::CTRL1_XL).await?;
+                self.write(lsm6ds3::REG_CTRL2_G, lsm6ds3::CTRL2_G).await
+            },
+            Part::Icm42688 => {
+                self.write(icm42688::REG_DEVICE_CONFIG, icm42688::DEVICE_CONFIG_SOFT_RESET).await?;
+                Timer::after_millis(1).await;
+                self.write(icm42688::REG_GYRO_CONFIG0, icm42688::GYRO_CONFIG0).await?;
+                self.write(icm42688::REG_ACCEL_CONFIG0, icm42688::ACCEL_CONFIG0).await?;
+                self.write(icm42688::REG_PWR_MGMT0, icm42688::PWR_MGMT0).await?;
+                // The gyro takes 45 ms to start up
+                Timer::after_millis(50).await;
+                Ok(())
+            },
+        }
+    }
+
+    // (acceleration in g, rotation rate in °/s)
+    pub async fn read(&mut self) -> Result<([f32; 3], [f32; 3]), I2C::Error> {
+        let mut buf = [0u8; 12];
+        let (register, g_per_lsb, dps_per_lsb) = match self.part {
+            Part::Lsm6ds3 => (lsm6ds3::REG_OUTX_L_G, lsm6ds3::G_PER_LSB, lsm6ds3::DPS_PER_LSB),
+            Part::Icm42688 => (icm42688::REG_ACCEL_DATA_X1, icm42688::G_PER_LSB, icm42688::DPS_PER_LSB),
+        };
+        self.i2c.write_read(self.address, &[register], &mut buf).await?;
+        let word = |index: usize| match self.part {
+            Part::Lsm6ds3 => i16::from_le_bytes([buf[index * 2], buf[index * 2 + 1]]),
+            Part::Icm42688 => i16::from_be_bytes([buf[index * 2], buf[index * 2 + 1]]),
+        };
+        let (accel, gyro) = match self.part {
+            Part::Lsm6ds3 => ([3, 4, 5], [0, 1, 2]),
+            Part::Icm42688 => ([0, 1, 2], [3, 4, 5]),
+        };
+        Ok((accel.map(|index| word(index) as f32 * g_per_lsb), gyro.map(|index| word(index) as f32 * dps_per_lsb)))
+    }
+}
+
+// Running totals for one report
+#[derive(Default)]
+struct Window {
+    samples: u16,
+    // Of the acceleration magnitude, in g
+    sum: f32,
+    sum_squares: f32,
+    peak_accel: f32,
+    peak_rate: f32,
+}
+impl Window {
+    fn add(&mut self, accel: [f32; 3], gyro: [f32; 3]) {
+        let magnitude = |v: [f32; 3]| (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+        let accel = magnitude(accel);
+        self.samples = self.samples.saturating_add(1);
+        self.sum += accel;
+        self.sum_squares += accel * accel;
+        self.peak_accel = self.peak_accel.max(accel);
+        self.peak_rate = self.peak_rate.max(magnitude(gyro));
+    }
+
+    // Standard deviation of the magnitude, in g
+    fn vibration(&self) -> f32 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+        let mean = self.sum / self.samples as f32;
+        (self.sum_squares / self.samples as f32 - mean * mean).max(0.0).sqrt()
+    }
+}
+
+impl<I2C: I2c> Sensor for Imu<I2C> {
+    const NAME: &'static str = "IMU";
+    const MODULE: Module = Module::Motion;
+    const MESSAGE_TYPE: MessageType = MessageType::Motion;
+    const CONFIG_INDEX: u8 = SENSOR_MOTION;
+    type Sample = ([f32; 3], [f32; 3]);
+
+    fn task(&self) -> supervisor::Task {
+        supervisor::Task::Motion
+    }
+
+    async fn init(&mut self) -> bool {
+        Imu::init(self).await.is_ok()
+    }
+
+    async fn sample(&mut self) -> Option<Self::Sample> {
+        let (mut accel, mut gyro) = self.read().await.ok()?;
+        let smoothing = config::CONFIG.lock().await.sensor_smoothing[SENSOR_MOTION as usize];
+        self.channel_faults = 0;
+        for (axis, value) in accel.iter_mut().chain(gyro.iter_mut()).enumerate() {
+            if self.checks[axis].check(*value) {
+                self.channel_faults |= if axis < 3 { FAULT_ACCELEROMETER } else { FAULT_GYRO };
+            }
+            *value = self.smoothers[axis].apply(smoothing, *value);
+        }
+        Some((accel, gyro))
+    }
+
+    async fn encode(&mut self, (accel, gyro): Self::Sample, payload: &mut Vec<u8, MAX_MESSAGE_LENGTH>) -> Option<u16> {
+        self.window.add(accel, gyro);
+        if self.window_start.elapsed() < REPORT_INTERVAL {
+            return None;
+        }
+        let window = core::mem::take(&mut self.window);
+        self.window_start = Instant::now();
+        let to_u16 = |value: f32| value.clamp(0.0, u16::MAX as f32) as u16;
+        payload.extend_from_slice(&window.samples.to_be_bytes()).unwrap();
+        payload.extend_from_slice(&to_u16(window.vibration() * 1000.0).to_be_bytes()).unwrap();
+        payload.extend_from_slice(&to_u16(window.peak_accel * 1000.0).to_be_bytes()).unwrap();
+        payload.extend_from_slice(&to_u16(window.peak_rate * 10.0).to_be_bytes()).unwrap();
+        payload.push(self.channel_faults).unwrap();
+        Some(MOTION_FORWARDING_ID)
+    }
+
+    fn channel_faults(&self) -> u8 {
+        self.channel_faults
+    }
+}
+
+sensor_task!(motion_task, Imu<SensorI2c>);
@@ -0,0 +1,82 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+use crate::ecu_errors;
+use crate::polling::{ECU_COUNT, QUERY_COUNT};
+
+// A query still waiting on its response after this long when it's sent again counts as timed out. Much longer than a
+// retransmit takes, so those don't count.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Time from sending a periodic query to the first frame of its response, per ECU. Reset every time the stats task
+// reports them, so a slow ECU or a saturated bus shows up as the numbers creep up from one report to the next.
+#[derive(Clone, Copy, Default)]
+pub struct Latency {
+    pub min_ms: u16,
+    pub max_ms: u16,
+    total_ms: u32,
+    pub samples: u16,
+}
+impl Latency {
+    pub fn avg_ms(&self) -> u16 {
+        if self.samples == 0 {
+            return 0;
+        }
+        (self.total_ms / self.samples as u32) as u16
+    }
+
+    fn add(&mut self, ms: u16) {
+        if self.samples == 0 || ms < self.min_ms {
+            self.min_ms = ms;
+        }
+        self.max_ms = self.max_ms.max(ms);
+        self.total_ms = self.total_ms.saturating_add(ms as u32);
+        self.samples = self.samples.saturating_add(1);
+    }
+}
+
+struct State {
+    // When each query last went out and to which ECU, cleared once its response arrives
+    sent: [Option<(u8, Instant)>; QUERY_COUNT],
+    ecus: [Latency; ECU_COUNT as usize],
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    sent: [None; QUERY_COUNT],
+    ecus: [Latency { min_ms: 0, max_ms: 0, total_ms: 0, samples: 0 }; ECU_COUNT as usize],
+}));
+
+// A retransmit restarts the clock
+pub fn query_sent(index: usize, ecu: u8) {
+    let previous = STATE.lock(|state| state.borrow_mut().sent[index].replace((ecu, Instant::now())));
+    if let Some((ecu, sent_at)) = previous {
+        if sent_at.elapsed() > QUERY_TIMEOUT {
+            ecu_errors::query_timed_out(ecu);
+        }
+    }
+}
+
+pub fn response_received(index: usize, received_at: Instant) {
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        let Some((ecu, sent_at)) = state.sent[index].take() else {
+            return;
+        };
+        // Anything from before the query went out was answering an earlier one
+        if let Some(elapsed) = received_at.checked_duration_since(sent_at) {
+            state.ecus[ecu as usize].add(elapsed.as_millis().min(u16::MAX as u64) as u16);
+        }
+    });
+}
+
+pub fn get() -> [Latency; ECU_COUNT as usize] {
+    STATE.lock(|state| state.borrow().ecus)
+}
+
+// Returns the latencies since the last call and starts over
+pub fn take() -> [Latency; ECU_COUNT as usize] {
+    STATE.lock(|state| core::mem::take(&mut state.borrow_mut().ecus))
+}
@@ -0,0 +1,51 @@
+use portable_atomic::{AtomicU8, Ordering};
+
+// The firmware is built with every defmt level (DEFMT_LOG in .cargo/config.toml), and trace! and debug! are filtered
+// here at runtime instead, so per-frame logging can be turned on in the field with a command. Modules pick these up
+// with `use crate::log_level::{debug, trace};`, which takes precedence over defmt's glob import.
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, defmt::Format)]
+pub enum Level {
+    Trace = 0,
+    Debug = 1,
+    Info = 2,
+}
+impl Level {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::Trace),
+            1 => Some(Self::Debug),
+            2 => Some(Self::Info),
+            _ => None,
+        }
+    }
+}
+
+// Debug, like the build-time level used to be
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+
+pub fn set(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 >= LEVEL.load(Ordering::Relaxed)
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::log_level::enabled($crate::log_level::Level::Trace) {
+            defmt::trace!($($arg)*);
+        }
+    };
+}
+pub(crate) use trace;
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log_level::enabled($crate::log_level::Level::Debug) {
+            defmt::debug!($($arg)*);
+        }
+    };
+}
+pub(crate) use debug;
@@ -0,0 +1,41 @@
+use embassy_time::Timer;
+use embedded_hal_async::i2c::I2c;
+
+// ST LPS22HB/LPS22HH barometer, a better pressure channel than the BME280's that can sit on the same I2C bus. When one
+// is fitted its pressure is forwarded instead of the other sensor's. Both parts share the register layout used here
+// and run continuously at 1 Hz with block data update, so a read never mixes bytes from two samples.
+
+const REG_CTRL_REG1: u8 = 0x10;
+const REG_CTRL_REG2: u8 = 0x11;
+const REG_PRESS_OUT_XL: u8 = 0x28;
+
+// 1 Hz output data rate, block data update
+const CTRL_REG1_CONTINUOUS: u8 = 0x12;
+// Register address auto-increment (the default) and software reset
+const CTRL_REG2_RESET: u8 = 0x14;
+
+pub struct Lps22<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+impl<I2C: I2c> Lps22<I2C> {
+    // At 0x5C or 0x5D depending on how SA0 is strapped, see i2c_scan.rs
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self { i2c, address }
+    }
+
+    pub async fn init(&mut self) -> Result<(), I2C::Error> {
+        self.i2c.write(self.address, &[REG_CTRL_REG2, CTRL_REG2_RESET]).await?;
+        Timer::after_millis(1).await;
+        self.i2c.write(self.address, &[REG_CTRL_REG1, CTRL_REG1_CONTINUOUS]).await
+    }
+
+    // Pa
+    pub async fn pressure(&mut self) -> Result<f32, I2C::Error> {
+        let mut buf = [0u8; 3];
+        self.i2c.write_read(self.address, &[REG_PRESS_OUT_XL], &mut buf).await?;
+        // 24-bit two's complement in 1/4096 hPa
+        let raw = i32::from_le_bytes([0, buf[0], buf[1], buf[2]]) >> 8;
+        Ok(raw as f32 * 100.0 / 4096.0)
+    }
+}
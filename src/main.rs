@@ -1,47 +1,140 @@
 #![no_std]
 #![no_main]
 
-use bme280_rs::{AsyncBme280, Humidity, Temperature};
+mod ack;
+mod alerts;
+mod batch;
+mod battery;
+mod blackbox;
+mod board;
+mod boot;
+mod bus_errors;
+#[cfg(feature = "can-log")]
+mod can_log;
+mod clock;
+mod commands;
+mod config;
+mod config_service;
+mod console;
+mod crash;
+mod diag_server;
+mod dtc;
+mod e2e;
+mod ecu_errors;
+mod environment;
+mod errors;
+mod factory_reset;
+mod forwarding;
+mod gateway;
+mod gps;
+mod heartbeat;
+mod i2c_scan;
+mod id_filter;
+mod imu;
+mod latency;
+mod log_level;
+mod loopback;
+mod lps22;
+mod mcp;
+mod memory;
+mod mux;
+mod ntc;
+mod polling;
+mod power;
+mod protocol;
+mod self_test;
+mod sensor;
+mod session;
+mod sht4x;
+mod signals;
+mod smoothing;
+mod sniffer;
+mod stats;
+mod storage;
+mod strap;
+mod subscriptions;
+mod supervisor;
+mod tx_events;
+mod vehicle;
+mod weather;
+
+use core::cell::RefCell;
+
 use defmt::*;
+use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
+use embassy_embedded_hal::SetConfig;
 use embassy_executor::Spawner;
-use embassy_rp::gpio::{Input, Level, Output, Pull};
+use embassy_futures::select::{select, select4, Either, Either4};
+use embassy_rp::adc;
+use embassy_rp::flash::Flash;
+use embassy_rp::gpio::{Input, Level, Output, Pin, Pull};
 use embassy_rp::i2c;
-use embassy_rp::peripherals::{SPI0, I2C0};
+use embassy_rp::peripherals::{SPI0, I2C0, UART1, USB};
 use embassy_rp::spi::{self, Spi};
+use embassy_rp::uart;
+use embassy_rp::usb;
+use embassy_rp::watchdog::Watchdog;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
 use embassy_sync::channel::Channel;
-use embassy_time::{Delay, Timer, Duration, Ticker, Instant};
+use embassy_sync::signal::Signal;
+use embassy_time::{Timer, Duration, Ticker, Instant};
 use embedded_can::{ExtendedId, Id, StandardId};
 use heapless::Vec;
 use mcp25xxfd::frame::Frame;
 use mcp25xxfd::{config::{BitRate, Clock, Config, FIFOConfig, FilterConfig, MaskConfig}, registers, MCP25xxFD};
 use mcp25xxfd::registers::PayloadSize;
 use static_cell::StaticCell;
-use micromath::F32Ext;
 
-use {defmt_rtt as _, panic_probe as _};
+use log_level::{debug, trace};
+use vehicle::{Vehicle, VehicleProfile};
+
+#[cfg(feature = "rtt-log")]
+use defmt_rtt as _;
+#[cfg(not(any(feature = "rtt-log", feature = "can-log")))]
+compile_error!("Enable a log transport feature: rtt-log (default) or can-log");
+#[cfg(all(feature = "rtt-log", feature = "can-log"))]
+compile_error!("Only one log transport feature can be enabled (build with --no-default-features to switch)");
 
 type SPI0Type<BUS> = Spi<'static, BUS, spi::Async>;
 static SPI_BUS0: StaticCell<Mutex<CriticalSectionRawMutex, SPI0Type<SPI0>>> = StaticCell::new();
+type I2C0Type = i2c::I2c<'static, I2C0, i2c::Async>;
+// Shared by the environment sensor and the optional barometer
+static I2C_BUS0: StaticCell<Mutex<CriticalSectionRawMutex, I2C0Type>> = StaticCell::new();
+type SensorI2c = I2cDevice<'static, CriticalSectionRawMutex, I2C0Type>;
+// Conservative SPI clock for resets and configuration, before the controllers' oscillators are known to be running
+const SPI_INIT_FREQUENCY: u32 = 1_000_000;
+// Number of controllers that are done initializing (successfully or not), the shared SPI bus is only sped up after both
+static CONTROLLERS_SETTLED: portable_atomic::AtomicU8 = portable_atomic::AtomicU8::new(0);
 
-static FORWARDING_CHANNEL: Channel<CriticalSectionRawMutex, (StandardId, Vec<u8, 64>), 10> = Channel::new();
+static FORWARDING_QUEUE: forwarding::ForwardingQueue = forwarding::ForwardingQueue::new();
+// Time-critical frames that are sent through the comma controller's TXQ ahead of bulk forwarding
+static PRIORITY_FORWARDING_CHANNEL: Channel<CriticalSectionRawMutex, protocol::Message, 4> = Channel::new();
 
-static OBD_CONTROLLER: StaticCell<Mutex<CriticalSectionRawMutex, MCP25xxFD<SpiDevice<CriticalSectionRawMutex, SPI0Type<SPI0>, Output>>>> = StaticCell::new();
-static COMMA_CONTROLLER: StaticCell<Mutex<CriticalSectionRawMutex, MCP25xxFD<SpiDevice<CriticalSectionRawMutex, SPI0Type<SPI0>, Output>>>> = StaticCell::new();
+type CanController = MCP25xxFD<SpiDevice<'static, CriticalSectionRawMutex, SPI0Type<SPI0>, Output<'static>>>;
+static OBD_CONTROLLER: StaticCell<Mutex<CriticalSectionRawMutex, CanController>> = StaticCell::new();
+static COMMA_CONTROLLER: StaticCell<Mutex<CriticalSectionRawMutex, CanController>> = StaticCell::new();
+
+static FLASH: StaticCell<storage::FlashMutex> = StaticCell::new();
+
+// Shared by the supply voltage, thermistor and die temperature channels
+static ADC: StaticCell<Mutex<CriticalSectionRawMutex, adc::Adc<'static, adc::Async>>> = StaticCell::new();
 
 static CAR_OFF_SINCE: StaticCell<Mutex<CriticalSectionRawMutex, Option<Instant>>> = StaticCell::new();
 
 embassy_rp::bind_interrupts!(struct Irqs {
+    ADC_IRQ_FIFO => adc::InterruptHandler;
     I2C0_IRQ => i2c::InterruptHandler<I2C0>;
+    UART1_IRQ => uart::InterruptHandler<UART1>;
+    USBCTRL_IRQ => usb::InterruptHandler<USB>;
 });
 
-fn construct_uds_query(command: &[u8]) -> [u8; 8] {
+fn construct_query(service: u8, command: &[u8]) -> [u8; 8] {
     let mut query = [0u8; 8];
     if command.len() <= 6 {
-        query[0] = command.len() as u8 + 1; // Length of UDS command byte + ECU command
-        query[1] = 0x22; // UDS command = diagnostic read
+        query[0] = command.len() as u8 + 1; // Length of service byte + ECU command
+        query[1] = service;
         // Copy over the ECU subcommand
         for (i, byte) in command.iter().enumerate() {
             query[i + 2] = *byte;
@@ -49,6 +142,24 @@ fn construct_uds_query(command: &[u8]) -> [u8; 8] {
     }
     query
 }
+fn construct_uds_query(command: &[u8]) -> [u8; 8] {
+    construct_query(0x22, command) // UDS command = diagnostic read
+}
+fn construct_obd_query(mode: u8, pids: &[u8]) -> [u8; 8] {
+    construct_query(mode, pids)
+}
+#[derive(Clone, Copy, PartialEq, Format)]
+enum AddressingMode {
+    // 11-bit IDs, ECU responds on its request ID + 8
+    Standard,
+    // ISO 15765-4 29-bit normal fixed addressing: requests on 0x18DA<ECU>F1, responses on 0x18DAF1<ECU>
+    Extended,
+}
+// Used if neither functional address gets an answer during boot-time detection
+const DEFAULT_ADDRESSING: AddressingMode = AddressingMode::Standard;
+// Address of this device when using 29-bit addressing (external test equipment)
+const TESTER_ADDRESS: u32 = 0xF1;
+
 struct ECUAddresses {
     bms: Id,
     tpms: Id,
@@ -60,16 +171,23 @@ struct ECUAddresses {
     igpm: Id,
 }
 impl ECUAddresses {
-    fn new() -> (Self, Self) {
+    fn new(addressing: AddressingMode, ecus: &[config::EcuAddress; polling::ECU_COUNT as usize]) -> (Self, Self) {
+        let ecu = |index: u8| -> Id {
+            let ecu = ecus[index as usize];
+            match addressing {
+                AddressingMode::Standard => StandardId::new(ecu.request_id).unwrap().into(),
+                AddressingMode::Extended => ExtendedId::new(0x18DA_0000 | ((ecu.target as u32) << 8) | TESTER_ADDRESS).unwrap().into(),
+            }
+        };
         let tx = Self {
-            bms: StandardId::new(0x7E4).unwrap().into(),
-            tpms: StandardId::new(0x7A0).unwrap().into(),
-            hvac: StandardId::new(0x7B3).unwrap().into(),
-            adas: StandardId::new(0x730).unwrap().into(),
-            iccu: StandardId::new(0x7E5).unwrap().into(),
-            vcms: StandardId::new(0x744).unwrap().into(),
-            dash: StandardId::new(0x7C6).unwrap().into(),
-            igpm: StandardId::new(0x770).unwrap().into(),
+            bms: ecu(polling::ECU_BMS),
+            tpms: ecu(polling::ECU_TPMS),
+            hvac: ecu(polling::ECU_HVAC),
+            adas: ecu(polling::ECU_ADAS),
+            iccu: ecu(polling::ECU_ICCU),
+            vcms: ecu(polling::ECU_VCMS),
+            dash: ecu(polling::ECU_DASH),
+            igpm: ecu(polling::ECU_IGPM),
         };
         let rx = Self {
             bms: Self::rx_address(tx.bms),
@@ -83,59 +201,300 @@ impl ECUAddresses {
         };
         (tx, rx)
     }
-    fn address_offset<const O: i32>(ecu_addr: impl Into<Id>) -> Id {
-        let ecu_addr = ecu_addr.into();
-        match ecu_addr {
-            Id::Standard(addr) => StandardId::new(((addr.as_raw() as i32) + O) as u16).unwrap().into(),
-            Id::Extended(addr) => ExtendedId::new(((addr.as_raw() as i32) + O) as u32).unwrap().into(),
-        }
+    fn address_offset<const O: i32>(ecu_addr: StandardId) -> Id {
+        StandardId::new(((ecu_addr.as_raw() as i32) + O) as u16).unwrap().into()
+    }
+    fn swap_target_source(ecu_addr: ExtendedId) -> Id {
+        // 0x18DA<target><source> -> 0x18DA<source><target>
+        let raw = ecu_addr.as_raw();
+        let target = (raw >> 8) & 0xFF;
+        let source = raw & 0xFF;
+        ExtendedId::new((raw & 0x1FFF_0000) | (source << 8) | target).unwrap().into()
+    }
+    // Index used by host commands, see polling::ECU_COUNT
+    fn get(&self, index: u8) -> Option<Id> {
+        [self.bms, self.tpms, self.hvac, self.adas, self.iccu, self.vcms, self.dash, self.igpm].get(index as usize).copied()
+    }
+    fn index(&self, addr: Id) -> Option<u8> {
+        [self.bms, self.tpms, self.hvac, self.adas, self.iccu, self.vcms, self.dash, self.igpm].iter().position(|&ecu| ecu == addr).map(|index| index as u8)
     }
     fn rx_address(ecu_addr: impl Into<Id>) -> Id {
-        Self::address_offset::<8>(ecu_addr)
+        match ecu_addr.into() {
+            Id::Standard(addr) => Self::address_offset::<8>(addr),
+            Id::Extended(addr) => Self::swap_target_source(addr),
+        }
     }
     fn tx_address(ecu_addr: impl Into<Id>) -> Id {
-        Self::address_offset::<-8>(ecu_addr)
+        match ecu_addr.into() {
+            Id::Standard(addr) => Self::address_offset::<-8>(addr),
+            Id::Extended(addr) => Self::swap_target_source(addr),
+        }
+    }
+}
+
+fn controller_config() -> Config {
+    Config {
+        clock: Clock::Clock20MHz,
+        // Replaced with the configured bit rates by apply_config()
+        bit_rate: BitRate::default(),
+        ecc_enabled: true,
+        restrict_retx_attempts: true,
+        txq_enabled: true,
+        tx_event_fifo_enabled: true,
+        iso_crc_enabled: true,
     }
 }
 
+// Resets the controller into configuration mode with the given bit rates
+async fn apply_config(controller: &mut CanController, bit_rates: config::BitRates) -> Result<(), mcp25xxfd::Error> {
+    controller.reset_and_apply_config(&controller_config()).await?;
+    mcp::set_bit_rates(controller, bit_rates).await
+}
+
+const CONTROLLER_INIT_ATTEMPTS: u32 = 5;
+const SPI_RECOVERY_FREQUENCY: u32 = config::MIN_SPI_FREQUENCY;
+
+// Resets and configures a controller, escalating recovery measures after each failed attempt so marginal hardware
+// and cold-temperature startups still come up. Returns false if the controller never responded.
+async fn reset_controller_with_retries(
+    name: &str,
+    controller: &mut CanController,
+    spi_bus: &'static Mutex<CriticalSectionRawMutex, SPI0Type<SPI0>>,
+    stby: &mut Output<'static>,
+    bit_rates: config::BitRates,
+) -> bool {
+    for attempt in 1..=CONTROLLER_INIT_ATTEMPTS {
+        match apply_config(controller, bit_rates).await {
+            Ok(()) => {
+                if attempt > 1 {
+                    info!("{} controller came up on attempt {}", name, attempt);
+                }
+                return true;
+            },
+            Err(err) => warn!("{} controller init attempt {}/{} failed: {}", name, attempt, CONTROLLER_INIT_ATTEMPTS, err),
+        }
+        if attempt == CONTROLLER_INIT_ATTEMPTS {
+            break;
+        }
+        if attempt >= 2 {
+            info!("{}: pulsing transceiver STBY", name);
+            stby.set_high();
+            Timer::after_millis(10).await;
+            stby.set_low();
+        }
+        if attempt >= 3 {
+            info!("{}: re-initializing SPI at {} Hz", name, SPI_RECOVERY_FREQUENCY);
+            let mut spi_config = spi::Config::default();
+            spi_config.frequency = SPI_RECOVERY_FREQUENCY;
+            if spi_bus.lock().await.set_config(&spi_config).is_err() {
+                warn!("{}: unable to change SPI clock", name);
+            }
+        }
+        // 200 ms, 400 ms, 800 ms, ...
+        let settle_time = Duration::from_millis(100 << attempt);
+        info!("{}: waiting {} ms before retrying", name, settle_time.as_millis());
+        Timer::after(settle_time).await;
+    }
+    error!("{} controller didn't come up after {} attempts, treating its bus as absent", name, CONTROLLER_INIT_ATTEMPTS);
+    false
+}
+
+// Resets the controller and runs the self-test, reporting the outcome either way. On success the controller is left
+// freshly configured and ready for FIFO setup.
+async fn bring_up_controller(
+    name: &str,
+    bus: u8,
+    controller: &mut CanController,
+    spi_bus: &'static Mutex<CriticalSectionRawMutex, SPI0Type<SPI0>>,
+    stby: &mut Output<'static>,
+    bit_rates: config::BitRates,
+) -> bool {
+    if !reset_controller_with_retries(name, controller, spi_bus, stby, bit_rates).await {
+        self_test::report(name, bus, &self_test::SelfTestReport::NO_RESPONSE).await;
+        return false;
+    }
+    let report = self_test::run(controller).await;
+    self_test::report(name, bus, &report).await;
+    if report.result != self_test::SelfTestResult::Passed {
+        return false;
+    }
+    // Undo the self-test's loopback setup
+    apply_config(controller, bit_rates).await.is_ok()
+}
+
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
+    memory::paint_stack();
     let p = embassy_rp::init(Default::default());
+    boot::read_reset_reason();
+    supervisor::read_stall();
     info!("Hello World!");
+    info!("Built for the {} on the {} board", Vehicle::NAME, board::NAME);
+    let pins = board::take_pins!(p);
 
-    let miso = p.PIN_20;
-    let mosi = p.PIN_19;
-    let sclk = p.PIN_18;
+    let mut spi_config = spi::Config::default();
+    spi_config.frequency = SPI_INIT_FREQUENCY;
     let spi0 = Spi::new(
         p.SPI0,
-        sclk,
-        mosi,
-        miso,
+        pins.spi_sclk,
+        pins.spi_mosi,
+        pins.spi_miso,
         p.DMA_CH0,
         p.DMA_CH1,
-        spi::Config::default(),
+        spi_config,
     );
     let spi0 = SPI_BUS0.init(Mutex::new(spi0));
 
-    let obd_cs = Output::new(p.PIN_21, Level::High);
-    let obd_int = Input::new(p.PIN_14, Pull::Up);
-    let mut obd_stby = Output::new(p.PIN_24, Level::Low);
+    let obd_cs = Output::new(pins.obd_cs, Level::High);
+    // Both INT pins also wake the RP2040 from dormant, see power.rs
+    let int_pins = [pins.obd_int.pin(), pins.comma_int.pin()];
+    let obd_int = Input::new(pins.obd_int, Pull::Up);
+    let mut obd_stby = Output::new(pins.obd_stby, Level::Low);
     obd_stby.set_low();
 
-    let comma_cs = Output::new(p.PIN_22, Level::High);
-    let comma_int = Input::new(p.PIN_15, Pull::Up);
-    let mut comma_stby = Output::new(p.PIN_25, Level::Low);
+    let comma_cs = Output::new(pins.comma_cs, Level::High);
+    let comma_int = Input::new(pins.comma_int, Pull::Up);
+    let mut comma_stby = Output::new(pins.comma_stby, Level::Low);
     comma_stby.set_low();
 
-    let i2c = i2c::I2c::new_async(p.I2C0, p.PIN_1, p.PIN_0, Irqs, i2c::Config::default());
+    let adc = ADC.init(Mutex::new(adc::Adc::new(p.ADC, Irqs, adc::Config::default())));
+    #[cfg(not(feature = "pico"))]
+    let thermistor = Some(ntc::Thermistor::new(adc::Channel::new_pin(pins.ntc_sense, Pull::None)));
+    #[cfg(feature = "pico")]
+    let thermistor = None;
+    let i2c_bus = I2C_BUS0.init(Mutex::new(i2c::I2c::new_async(p.I2C0, pins.i2c_scl, pins.i2c_sda, Irqs, i2c::Config::default())));
+
+    let flash: &'static storage::FlashMutex = FLASH.init(embassy_sync::blocking_mutex::Mutex::new(RefCell::new(Flash::new_blocking(p.FLASH))));
 
     let car_off_since = CAR_OFF_SINCE.init(Mutex::new(None));
+    config::load(flash).await;
+    let strap = strap::read(pins.strap0, pins.strap1).await;
+    {
+        let mut config = config::CONFIG.lock().await;
+        strap::apply(&mut config, strap);
+        if let Some(conflict) = config.forwarding_ids.conflict() {
+            warn!("Forwarding ID {:x} conflicts with another ID, using the default map", conflict);
+            config.forwarding_ids = config::ForwardingIds::DEFAULT;
+        }
+    }
 
-    spawner.must_spawn(obd_task(spawner, spi0, obd_cs, obd_int, car_off_since));
-    spawner.must_spawn(bme_sender_task(i2c));
-    spawner.must_spawn(comma_task(spawner, spi0, comma_cs, comma_int, car_off_since));
+    spawner.must_spawn(boot::boot_confirm_task(flash, Watchdog::new(p.WATCHDOG)));
+    spawner.must_spawn(config::trial_task(flash));
+    spawner.must_spawn(crash::report_task(flash));
+    spawner.must_spawn(blackbox::dump_task(flash));
+    spawner.must_spawn(stats::stats_task());
+    #[cfg(feature = "can-log")]
+    spawner.must_spawn(can_log::drain_task());
+    spawner.must_spawn(console::console_task(usb::Driver::new(p.USB, Irqs)));
+    spawner.must_spawn(alerts::alert_task());
+
+    spawner.must_spawn(obd_task(spawner, spi0, obd_cs, obd_int, obd_stby, car_off_since, flash));
+    spawner.must_spawn(sensor_probe_task(spawner, i2c_bus, adc, thermistor));
+    spawner.must_spawn(comma_task(spawner, spi0, comma_cs, comma_int, comma_stby, car_off_since, flash));
+    spawner.must_spawn(spi_speed_task(spi0));
+    spawner.must_spawn(power::power_state_task(car_off_since, int_pins));
+    spawner.must_spawn(heartbeat::heartbeat_task(car_off_since, adc, adc::Channel::new_temp_sensor(p.ADC_TEMP_SENSOR)));
+    let supply_channel = adc::Channel::new_pin(pins.supply_sense, Pull::None);
+    spawner.must_spawn(battery::supply_task(adc, supply_channel, car_off_since));
+    // Without a GPS module fitted this never sees a valid sentence and does nothing
+    let mut gps_config = uart::Config::default();
+    gps_config.baudrate = gps::BAUD_RATE;
+    spawner.must_spawn(gps::gps_task(uart::UartRx::new(p.UART1, pins.gps_rx, Irqs, p.DMA_CH2, gps_config)));
+    // Status LED, blinks if either controller failed its self-test
+    spawner.must_spawn(self_test::status_led_task(Output::new(pins.status_led, Level::Low)));
+}
+
+// Switches the shared SPI bus to the configured speed once neither controller is being reset anymore
+#[embassy_executor::task]
+async fn spi_speed_task(spi_bus: &'static Mutex<CriticalSectionRawMutex, SPI0Type<SPI0>>) {
+    while CONTROLLERS_SETTLED.load(portable_atomic::Ordering::Relaxed) < 2 {
+        Timer::after_millis(100).await;
+    }
+    let frequency = config::CONFIG.lock().await.spi_frequency;
+    let mut spi_config = spi::Config::default();
+    spi_config.frequency = frequency;
+    if spi_bus.lock().await.set_config(&spi_config).is_err() {
+        warn!("Unable to switch SPI clock to {} Hz", frequency);
+        return;
+    }
+    info!("SPI clock switched to {} Hz", frequency);
+}
+
+// Sensors missing from the I2C bus at boot are looked for again after this, doubling each time, so one that's plugged in
+// later (or whose connector had shaken loose) comes online without a reboot. Most units are built without some of them,
+// so it gives up after SENSOR_PROBE_ATTEMPTS, about two hours. One that drops off once its task is running is brought
+// back by the task itself, see sensor.rs.
+const SENSOR_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+const SENSOR_PROBE_ATTEMPTS: u32 = 8;
+// Starts a task for each sensor on the I2C bus, and keeps probing for the missing ones for a while
+#[embassy_executor::task]
+async fn sensor_probe_task(
+    spawner: Spawner,
+    i2c_bus: &'static Mutex<CriticalSectionRawMutex, I2C0Type>,
+    adc: &'static Mutex<CriticalSectionRawMutex, adc::Adc<'static, adc::Async>>,
+    mut thermistor: Option<ntc::Thermistor>,
+) {
+    let mut inventory = i2c_scan::scan(&mut I2cDevice::new(i2c_bus)).await;
+    let (mut motion, mut cabin, mut outside) = (false, false, false);
+    for probe in 0..=SENSOR_PROBE_ATTEMPTS {
+        if probe > 0 {
+            Timer::after(SENSOR_PROBE_INTERVAL * (1 << (probe - 1))).await;
+            let mut missing: Vec<i2c_scan::Device, 5> = Vec::new();
+            if !motion {
+                missing.extend_from_slice(&[i2c_scan::Device::Lsm6ds3, i2c_scan::Device::Icm42688]).unwrap();
+            }
+            if !cabin {
+                missing.extend_from_slice(&[i2c_scan::Device::Sht4x, i2c_scan::Device::Lps22]).unwrap();
+            }
+            if !cabin || !outside {
+                missing.push(i2c_scan::Device::Bme280).unwrap();
+            }
+            inventory = i2c_scan::rescan(&mut I2cDevice::new(i2c_bus), &missing).await;
+        }
+        if !motion {
+            let imu = match (inventory.find(i2c_scan::Device::Lsm6ds3), inventory.find(i2c_scan::Device::Icm42688)) {
+                (Some(address), _) => Some(imu::Imu::new(I2cDevice::new(i2c_bus), address, imu::Part::Lsm6ds3)),
+                (None, Some(address)) => Some(imu::Imu::new(I2cDevice::new(i2c_bus), address, imu::Part::Icm42688)),
+                (None, None) => None,
+            };
+            if let Some(imu) = imu {
+                if probe > 0 {
+                    info!("IMU connected");
+                }
+                spawner.must_spawn(imu::motion_task(imu));
+                motion = true;
+            }
+        }
+        if !cabin {
+            if let Some(sensors) = environment::EnvironmentSensors::cabin(i2c_bus, &inventory, adc, &mut thermistor) {
+                if probe > 0 {
+                    info!("Environment sensor connected");
+                }
+                spawner.must_spawn(environment::environment_task(sensors));
+                cabin = true;
+            }
+            else if probe == 0 {
+                warn!("No environment sensor fitted, not forwarding environment readings until one is");
+            }
+        }
+        if !outside {
+            if let Some(sensors) = environment::EnvironmentSensors::outside(i2c_bus, &inventory) {
+                if probe > 0 {
+                    info!("Outside environment sensor connected");
+                }
+                spawner.must_spawn(environment::outside_environment_task(sensors));
+                outside = true;
+            }
+        }
+        if motion && cabin && outside {
+            return;
+        }
+    }
+    info!("Stopped looking for missing sensors");
 }
 
+// FIFO 0 is the TXQ, used for frames that shouldn't wait behind whatever is queued in TRANSMIT_FIFO
+const TXQ: u8 = 0;
 const TRANSMIT_FIFO: u8 = 1;
 const RX_BATTERY_FIFO: u8 = 2;
 const RX_TPMS_FIFO: u8 = 3;
@@ -145,6 +504,296 @@ const RX_ICCU_FIFO: u8 = 6;
 const RX_VCMS_FIFO: u8 = 7;
 const RX_DASH_FIFO: u8 = 8;
 const RX_IGPM_FIFO: u8 = 9;
+const OBD_RX_FIFOS: [u8; 9] = [
+    RX_BATTERY_FIFO,
+    RX_TPMS_FIFO,
+    RX_HVAC_FIFO,
+    RX_ADAS_FIFO,
+    RX_ICCU_FIFO,
+    RX_VCMS_FIFO,
+    RX_DASH_FIFO,
+    RX_IGPM_FIFO,
+    subscriptions::SUBSCRIPTION_FIFO,
+];
+const OBD_TXQ_DEPTH: u8 = 4;
+const OBD_TRANSMIT_DEPTH: u8 = 8;
+pub const OBD_TX_OBJECTS: usize = OBD_TXQ_DEPTH as usize + OBD_TRANSMIT_DEPTH as usize;
+const SUBSCRIPTION_FIFO_DEPTH: u8 = 16;
+// Depth of both the sniffer and the loopback query FIFO, only one of them is ever configured
+const OBD_MODE_FIFO_DEPTH: u8 = 8;
+
+// The MCP25xxFD hands out its 2 KB of message RAM in order: the TEF, the TXQ, then FIFO 1 up to the highest FIFO that's
+// configured. FIFOs below that one that are left alone still take up their reset size of one object with an 8 byte
+// payload. Objects are an 8 byte header, a 4 byte timestamp if it's enabled (only on the RX FIFOs here, TEF timestamps
+// are off) and the payload.
+const MESSAGE_RAM_SIZE: usize = 2048;
+const TEF_OBJECT_SIZE: usize = 8;
+const UNCONFIGURED_FIFO_SIZE: usize = 8 + 8;
+
+// With the loopback query FIFO right after the subscription FIFO and the sniffer FIFO after that, sniffer mode is the
+// worst case: the loopback query FIFO is left at its reset size below the sniffer FIFO
+const _: () = assert!(loopback::QUERY_FIFO == subscriptions::SUBSCRIPTION_FIFO + 1 && sniffer::SNIFFER_FIFO == loopback::QUERY_FIFO + 1);
+const fn obd_message_ram() -> usize {
+    let mut rx_objects = SUBSCRIPTION_FIFO_DEPTH as usize + OBD_MODE_FIFO_DEPTH as usize;
+    let mut ecu = 0;
+    while ecu < Vehicle::RESPONSE_FIFO_DEPTHS.len() {
+        rx_objects += Vehicle::RESPONSE_FIFO_DEPTHS[ecu] as usize;
+        ecu += 1;
+    }
+    tx_events::TX_EVENT_FIFO_DEPTH as usize * TEF_OBJECT_SIZE
+        + OBD_TX_OBJECTS * (8 + 8)
+        + rx_objects * (8 + 4 + 8)
+        + UNCONFIGURED_FIFO_SIZE
+}
+const _: () = assert!(obd_message_ram() <= MESSAGE_RAM_SIZE, "Vehicle profile's response FIFOs don't fit in message RAM");
+
+// OBD-II mode 03 (stored DTCs) is scanned once per ignition cycle and at least once a day
+const DTC_SCAN_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// Freeze frame 0 PIDs requested via mode 02 when an ECU reports DTCs (max 3 PID/frame pairs per request)
+// PID 0x02 is the DTC that caused the freeze frame to be stored
+// First byte of forwarded DTC frames: [kind, count, DTCs..., (freeze frame data for new DTCs)]
+const DTC_APPEARED: u8 = 0x01;
+const DTC_CLEARED: u8 = 0x02;
+const FREEZE_FRAME_REQUESTS: [[u8; 6]; 2] = [
+    [0x02, 0x00, 0x04, 0x00, 0x05, 0x00],
+    [0x0C, 0x00, 0x0D, 0x00, 0x42, 0x00],
+];
+
+
+const ADDRESSING_PROBE_ATTEMPTS: u32 = 5;
+
+// Send a mode 01 PID 00 request to both the 11-bit and 29-bit functional addresses and lock onto whichever
+// one gets answered so that the same firmware works on cars that only speak one of them
+async fn detect_addressing(obd_controller: &mut CanController, int: &mut Input<'static>, bit_rates: config::BitRates) -> Result<AddressingMode, mcp25xxfd::Error> {
+    apply_config(obd_controller, bit_rates).await?;
+    obd_controller.configure_fifo(
+        FIFOConfig::<TRANSMIT_FIFO>::tx_with_size(8, PayloadSize::Bytes8)
+    ).await?;
+    obd_controller.configure_fifo(
+        FIFOConfig::<RX_BATTERY_FIFO>::rx_with_size(8, PayloadSize::Bytes8)
+    ).await?;
+    // Any ECU's physical response address: 0x7E8-0x7EF or 0x18DAF100-0x18DAF1FF
+    let (standard_id, standard_mask) = mcp::range_filter(
+        StandardId::new(0x7E8).unwrap().into(),
+        StandardId::new(0x7EF).unwrap().into(),
+    ).unwrap();
+    let (extended_id, extended_mask) = mcp::range_filter(
+        ExtendedId::new(0x18DA_0000 | (TESTER_ADDRESS << 8)).unwrap().into(),
+        ExtendedId::new(0x18DA_00FF | (TESTER_ADDRESS << 8)).unwrap().into(),
+    ).unwrap();
+    mcp::set_filter(obd_controller, 0, RX_BATTERY_FIFO, standard_id, standard_mask).await?;
+    mcp::set_filter(obd_controller, 1, RX_BATTERY_FIFO, extended_id, extended_mask).await?;
+    obd_controller.set_mode(registers::OperationMode::Normal).await?;
+    Timer::after_millis(500).await;
+
+    let probes = [
+        Frame::new(StandardId::new(0x7DF).unwrap(), &construct_obd_query(0x01, &[0x00])).unwrap(),
+        Frame::new(ExtendedId::new(0x18DB_3300 | TESTER_ADDRESS).unwrap(), &construct_obd_query(0x01, &[0x00])).unwrap(),
+    ];
+    for attempt in 1..=ADDRESSING_PROBE_ATTEMPTS {
+        for probe in probes.iter() {
+            obd_controller.transmit::<TRANSMIT_FIFO>(probe).await?;
+        }
+        let probe_start = Instant::now();
+        while probe_start.elapsed().as_millis() < 1000 {
+            match obd_controller.receive(Some(RX_BATTERY_FIFO)).await {
+                Ok(Some((_, frame))) => {
+                    let addressing = match frame.id() {
+                        Id::Standard(_) => AddressingMode::Standard,
+                        Id::Extended(_) => AddressingMode::Extended,
+                    };
+                    info!("Detected {} diagnostic addressing (response from {:x})", addressing, frame.raw_id());
+                    return Ok(addressing);
+                },
+                _ => {
+                    let _ = embassy_time::with_timeout(Duration::from_millis(100), int.wait_for_low()).await;
+                },
+            }
+        }
+        debug!("No response to addressing probe {}/{}", attempt, ADDRESSING_PROBE_ATTEMPTS);
+    }
+    warn!("Unable to detect diagnostic addressing, falling back to {}", DEFAULT_ADDRESSING);
+    Ok(DEFAULT_ADDRESSING)
+}
+
+// [bus (0 = OBD, 1 = comma), bitmask of TX FIFOs that abandoned a frame (4 bytes)]
+const TX_ABANDONED_FORWARDING_ID: u16 = 0x7B3;
+
+// Checks for frames the controller gave up on after running out of attempts and reports them
+async fn service_abandoned_transmits(name: &str, bus: u8, controller: &mut CanController) {
+    match mcp::service_tx_attempts(controller).await {
+        Ok((0, _)) => {},
+        Ok((abandoned, lost_arbitration)) => {
+            warn!("{}: transmit abandoned after retries (FIFO mask {:b})", name, abandoned);
+            if lost_arbitration != 0 {
+                bus_errors::arbitration_lost(bus, lost_arbitration);
+            }
+            let mut forward_data: Vec<u8, 64> = Vec::new();
+            forward_data.push(bus).unwrap();
+            forward_data.extend_from_slice(&abandoned.to_be_bytes()).unwrap();
+            FORWARDING_QUEUE.send(protocol::Message::new(TX_ABANDONED_FORWARDING_ID, protocol::MessageType::TxAbandoned, protocol::Source::bus(bus), forward_data)).await;
+        },
+        Err(err) => error!("{}: unable to check TX attempts: {}", name, err),
+    }
+}
+
+// How long a multi-frame ISO-TP response gets to finish after its first frame
+const ISOTP_TRANSFER_TIMEOUT: Duration = Duration::from_millis(250);
+
+const BIT_RATE_PROBE_ROUNDS: u32 = 3;
+const BIT_RATE_PROBE_FRAMES: u32 = 3;
+const BIT_RATE_PROBE_TIME: Duration = Duration::from_millis(1000);
+
+// Listen at each common nominal bit rate until one receives several frames without any bus errors. Listen-only mode
+// keeps us from ACKing or sending error frames at the wrong rate, so probing can't disturb the bus.
+async fn detect_bit_rate(obd_controller: &mut CanController, int: &mut Input<'static>, data: config::DataBitRate) -> Result<Option<config::NominalBitRate>, mcp25xxfd::Error> {
+    let candidates = [config::NominalBitRate::Kbps500, config::NominalBitRate::Kbps250, config::NominalBitRate::Kbps125];
+    for round in 1..=BIT_RATE_PROBE_ROUNDS {
+        for nominal in candidates {
+            apply_config(obd_controller, config::BitRates { nominal, data }).await?;
+            obd_controller.configure_fifo(
+                FIFOConfig::<RX_BATTERY_FIFO>::rx_with_size(8, PayloadSize::Bytes8)
+            ).await?;
+            // Zero mask accepts every standard and extended ID
+            mcp::set_filter(obd_controller, 0, RX_BATTERY_FIFO, StandardId::ZERO.into(), 0).await?;
+            obd_controller.set_mode(registers::OperationMode::ListenOnly).await?;
+            // Start from clean diagnostic counters
+            mcp::read_error_counters(obd_controller).await?;
+
+            let mut received = 0;
+            let probe_start = Instant::now();
+            while probe_start.elapsed() < BIT_RATE_PROBE_TIME && received < BIT_RATE_PROBE_FRAMES {
+                match obd_controller.receive(Some(RX_BATTERY_FIFO)).await {
+                    Ok(Some(_)) => received += 1,
+                    _ => {
+                        let _ = embassy_time::with_timeout(Duration::from_millis(100), int.wait_for_low()).await;
+                    },
+                }
+            }
+            let counters = mcp::read_error_counters(obd_controller).await?;
+            if received >= BIT_RATE_PROBE_FRAMES && counters.nominal_rx_errors == 0 && counters.error_flags == 0 {
+                info!("Detected vehicle bus bit rate {}", nominal);
+                return Ok(Some(nominal));
+            }
+            debug!("No clean traffic at {} ({} frames, {} RX errors)", nominal, received, counters.nominal_rx_errors);
+        }
+        debug!("Bit rate probe round {}/{} found nothing", round, BIT_RATE_PROBE_ROUNDS);
+    }
+    Ok(None)
+}
+
+// Prefixes vehicle data with when it arrived if the host asked for timestamps. Anything past MAX_MESSAGE_LENGTH bytes is dropped.
+fn timestamped(received_at: Instant, data: &[u8]) -> Vec<u8, protocol::MAX_MESSAGE_LENGTH> {
+    let mut forward_data = Vec::new();
+    if session::current().has(session::CAP_TIMESTAMPS) {
+        forward_data.extend_from_slice(&(received_at.as_micros() as u32).to_be_bytes()).unwrap();
+    }
+    let remaining = forward_data.capacity() - forward_data.len();
+    forward_data.extend_from_slice(&data[..data.len().min(remaining)]).unwrap();
+    forward_data
+}
+
+// Sets up the OBD controller's FIFOs and filters after bring-up and leaves it in the bus mode's operation mode
+async fn configure_obd_controller(
+    obd_controller: &mut CanController,
+    bit_rates: config::BitRates,
+    rx_addrs: &ECUAddresses,
+    bus_mode: config::BusMode,
+) -> Result<(), mcp25xxfd::Error> {
+    apply_config(obd_controller, bit_rates).await?;
+
+    obd_controller.configure_fifo(
+        FIFOConfig::<TXQ>::tx_with_size(OBD_TXQ_DEPTH, PayloadSize::Bytes8)
+    ).await?;
+    obd_controller.configure_fifo(
+        FIFOConfig::<TRANSMIT_FIFO>::tx_with_size(OBD_TRANSMIT_DEPTH, PayloadSize::Bytes8)
+    ).await?;
+
+    obd_controller.configure_fifo(
+        FIFOConfig::<RX_BATTERY_FIFO>::rx_with_size(Vehicle::RESPONSE_FIFO_DEPTHS[polling::ECU_BMS as usize], PayloadSize::Bytes8)
+    ).await?;
+    obd_controller.configure_filter(
+        FilterConfig::<RX_BATTERY_FIFO, RX_BATTERY_FIFO>::from_id(rx_addrs.bms),
+        MaskConfig::<RX_BATTERY_FIFO>::match_exact(),
+    ).await?;
+
+    obd_controller.configure_fifo(
+        FIFOConfig::<RX_TPMS_FIFO>::rx_with_size(Vehicle::RESPONSE_FIFO_DEPTHS[polling::ECU_TPMS as usize], PayloadSize::Bytes8)
+    ).await?;
+    obd_controller.configure_filter(
+        FilterConfig::<RX_TPMS_FIFO, RX_TPMS_FIFO>::from_id(rx_addrs.tpms),
+        MaskConfig::<RX_TPMS_FIFO>::match_exact(),
+    ).await?;
+
+    obd_controller.configure_fifo(
+        FIFOConfig::<RX_HVAC_FIFO>::rx_with_size(Vehicle::RESPONSE_FIFO_DEPTHS[polling::ECU_HVAC as usize], PayloadSize::Bytes8)
+    ).await?;
+    obd_controller.configure_filter(
+        FilterConfig::<RX_HVAC_FIFO, RX_HVAC_FIFO>::from_id(rx_addrs.hvac),
+        MaskConfig::<RX_HVAC_FIFO>::match_exact(),
+    ).await?;
+
+    obd_controller.configure_fifo(
+        FIFOConfig::<RX_ADAS_FIFO>::rx_with_size(Vehicle::RESPONSE_FIFO_DEPTHS[polling::ECU_ADAS as usize], PayloadSize::Bytes8)
+    ).await?;
+    obd_controller.configure_filter(
+        FilterConfig::<RX_ADAS_FIFO, RX_ADAS_FIFO>::from_id(rx_addrs.adas),
+        MaskConfig::<RX_ADAS_FIFO>::match_exact(),
+    ).await?;
+
+    obd_controller.configure_fifo(
+        FIFOConfig::<RX_ICCU_FIFO>::rx_with_size(Vehicle::RESPONSE_FIFO_DEPTHS[polling::ECU_ICCU as usize], PayloadSize::Bytes8)
+    ).await?;
+    obd_controller.configure_filter(
+        FilterConfig::<RX_ICCU_FIFO, RX_ICCU_FIFO>::from_id(rx_addrs.iccu),
+        MaskConfig::<RX_ICCU_FIFO>::match_exact(),
+    ).await?;
+
+    obd_controller.configure_fifo(
+        FIFOConfig::<RX_VCMS_FIFO>::rx_with_size(Vehicle::RESPONSE_FIFO_DEPTHS[polling::ECU_VCMS as usize], PayloadSize::Bytes8)
+    ).await?;
+    obd_controller.configure_filter(
+        FilterConfig::<RX_VCMS_FIFO, RX_VCMS_FIFO>::from_id(rx_addrs.vcms),
+        MaskConfig::<RX_VCMS_FIFO>::match_exact(),
+    ).await?;
+
+    obd_controller.configure_fifo(
+        FIFOConfig::<RX_DASH_FIFO>::rx_with_size(Vehicle::RESPONSE_FIFO_DEPTHS[polling::ECU_DASH as usize], PayloadSize::Bytes8)
+    ).await?;
+    obd_controller.configure_filter(
+        FilterConfig::<RX_DASH_FIFO, RX_DASH_FIFO>::from_id(rx_addrs.dash),
+        MaskConfig::<RX_DASH_FIFO>::match_exact(),
+    ).await?;
+
+    obd_controller.configure_fifo(
+        FIFOConfig::<RX_IGPM_FIFO>::rx_with_size(Vehicle::RESPONSE_FIFO_DEPTHS[polling::ECU_IGPM as usize], PayloadSize::Bytes8)
+    ).await?;
+    obd_controller.configure_filter(
+        FilterConfig::<RX_IGPM_FIFO, RX_IGPM_FIFO>::from_id(rx_addrs.igpm),
+        MaskConfig::<RX_IGPM_FIFO>::match_exact(),
+    ).await?;
+
+    // Filters for this FIFO are only enabled while the host has an active raw frame subscription
+    obd_controller.configure_fifo(
+        FIFOConfig::<{ subscriptions::SUBSCRIPTION_FIFO }>::rx_with_size(SUBSCRIPTION_FIFO_DEPTH, PayloadSize::Bytes8)
+    ).await?;
+
+    mcp::enable_rx_overflow_interrupts(obd_controller, &OBD_RX_FIFOS).await?;
+    mcp::enable_ecc_interrupts(obd_controller).await?;
+    mcp::limit_tx_attempts(obd_controller, &[TXQ, TRANSMIT_FIFO]).await?;
+    mcp::enable_wake_interrupt(obd_controller).await?;
+    // Frames from these FIFOs have to be read with mcp::receive() from here on
+    mcp::enable_rx_timestamps(obd_controller, &OBD_RX_FIFOS).await?;
+    mcp::enable_time_base(obd_controller).await?;
+    mcp::configure_tx_event_fifo(obd_controller, tx_events::TX_EVENT_FIFO_DEPTH).await?;
+    match bus_mode {
+        config::BusMode::Loopback => loopback::configure(obd_controller).await?,
+        config::BusMode::Sniffer => sniffer::configure(obd_controller).await?,
+        _ => {},
+    }
+
+    obd_controller.set_mode(bus_mode.operation_mode()).await
+}
 
 #[embassy_executor::task]
 async fn obd_task(
@@ -152,114 +801,115 @@ async fn obd_task(
     spi_bus: &'static Mutex<CriticalSectionRawMutex, SPI0Type<SPI0>>,
     cs: Output<'static>,
     mut int: Input<'static>,
+    mut stby: Output<'static>,
     car_off_since: &'static Mutex<CriticalSectionRawMutex, Option<Instant>>,
+    flash: &'static storage::FlashMutex,
 ) {
 
-    let (tx_addrs, rx_addrs) = ECUAddresses::new();
-
     let obd_device = SpiDevice::new(spi_bus, cs);
     let obd_controller = OBD_CONTROLLER.init(Mutex::new(MCP25xxFD::new(obd_device)));
 
+    let (mut bit_rates, bus_mode, detect) = {
+        let config = config::CONFIG.lock().await;
+        (config.obd_bit_rates, config.obd_mode, config.obd_detect_bit_rate)
+    };
+    if !bring_up_controller("OBD", 0, &mut *obd_controller.lock().await, spi_bus, &mut stby, bit_rates).await {
+        // Nothing to query without the vehicle bus
+        CONTROLLERS_SETTLED.fetch_add(1, portable_atomic::Ordering::Relaxed);
+        return;
+    }
+
+    if detect && bus_mode != config::BusMode::Loopback {
+        let detected = detect_bit_rate(&mut *obd_controller.lock().await, &mut int, bit_rates.data).await;
+        match detected {
+            Ok(Some(nominal)) => {
+                bit_rates.nominal = nominal;
+                config::CONFIG.lock().await.obd_bit_rates = bit_rates;
+            },
+            Ok(None) => warn!("Unable to detect vehicle bus bit rate, staying at {}", bit_rates.nominal),
+            Err(err) => {
+                errors::report(protocol::Source::Obd, errors::Module::Setup, errors::ErrorCode::DetectionFailed, err.into(), 0).await;
+                warn!("Vehicle bus bit rate detection failed, staying at {}", bit_rates.nominal);
+            },
+        }
+    }
+
+    let addressing = if !bus_mode.can_transmit() || bus_mode == config::BusMode::Loopback {
+        // Detection needs to transmit probes to real ECUs
+        info!("OBD controller in {} mode, using {} addressing", bus_mode, DEFAULT_ADDRESSING);
+        DEFAULT_ADDRESSING
+    }
+    else {
+        let detected = detect_addressing(&mut *obd_controller.lock().await, &mut int, bit_rates).await;
+        match detected {
+            Ok(addressing) => addressing,
+            Err(err) => {
+                errors::report(protocol::Source::Obd, errors::Module::Setup, errors::ErrorCode::DetectionFailed, err.into(), 0).await;
+                warn!("Diagnostic addressing detection failed, falling back to {}", DEFAULT_ADDRESSING);
+                DEFAULT_ADDRESSING
+            },
+        }
+    };
+    let (ecus, query_table) = {
+        let config = config::CONFIG.lock().await;
+        (config.ecus, config.queries)
+    };
+    let (tx_addrs, rx_addrs) = ECUAddresses::new(addressing, &ecus);
+
     {
         let mut obd_controller = obd_controller.lock().await;
-        obd_controller.reset_and_apply_config(&Config {
-            clock: Clock::Clock20MHz,
-            bit_rate: BitRate::default(),
-            ecc_enabled: true,
-            restrict_retx_attempts: false,
-            txq_enabled: false,
-            tx_event_fifo_enabled: false,
-            iso_crc_enabled: true,
-        }).await.unwrap();
-
-        obd_controller.configure_fifo(
-            FIFOConfig::<TRANSMIT_FIFO>::tx_with_size(8, PayloadSize::Bytes8)
-        ).await.unwrap();
-
-        obd_controller.configure_fifo(
-            FIFOConfig::<RX_BATTERY_FIFO>::rx_with_size(8, PayloadSize::Bytes8)
-        ).await.unwrap();
-        obd_controller.configure_filter(
-            FilterConfig::<RX_BATTERY_FIFO, RX_BATTERY_FIFO>::from_id(rx_addrs.bms),
-            MaskConfig::<RX_BATTERY_FIFO>::match_exact(),
-        ).await.unwrap();
-
-        obd_controller.configure_fifo(
-            FIFOConfig::<RX_TPMS_FIFO>::rx_with_size(8, PayloadSize::Bytes8)
-        ).await.unwrap();
-        obd_controller.configure_filter(
-            FilterConfig::<RX_TPMS_FIFO, RX_TPMS_FIFO>::from_id(rx_addrs.tpms),
-            MaskConfig::<RX_TPMS_FIFO>::match_exact(),
-        ).await.unwrap();
-
-        obd_controller.configure_fifo(
-            FIFOConfig::<RX_HVAC_FIFO>::rx_with_size(8, PayloadSize::Bytes8)
-        ).await.unwrap();
-        obd_controller.configure_filter(
-            FilterConfig::<RX_HVAC_FIFO, RX_HVAC_FIFO>::from_id(rx_addrs.hvac),
-            MaskConfig::<RX_HVAC_FIFO>::match_exact(),
-        ).await.unwrap();
-
-        obd_controller.configure_fifo(
-            FIFOConfig::<RX_ADAS_FIFO>::rx_with_size(8, PayloadSize::Bytes8)
-        ).await.unwrap();
-        obd_controller.configure_filter(
-            FilterConfig::<RX_ADAS_FIFO, RX_ADAS_FIFO>::from_id(rx_addrs.adas),
-            MaskConfig::<RX_ADAS_FIFO>::match_exact(),
-        ).await.unwrap();
-
-        obd_controller.configure_fifo(
-            FIFOConfig::<RX_ICCU_FIFO>::rx_with_size(8, PayloadSize::Bytes8)
-        ).await.unwrap();
-        obd_controller.configure_filter(
-            FilterConfig::<RX_ICCU_FIFO, RX_ICCU_FIFO>::from_id(rx_addrs.iccu),
-            MaskConfig::<RX_ICCU_FIFO>::match_exact(),
-        ).await.unwrap();
-
-        obd_controller.configure_fifo(
-            FIFOConfig::<RX_VCMS_FIFO>::rx_with_size(8, PayloadSize::Bytes8)
-        ).await.unwrap();
-        obd_controller.configure_filter(
-            FilterConfig::<RX_VCMS_FIFO, RX_VCMS_FIFO>::from_id(rx_addrs.vcms),
-            MaskConfig::<RX_VCMS_FIFO>::match_exact(),
-        ).await.unwrap();
-
-        obd_controller.configure_fifo(
-            FIFOConfig::<RX_DASH_FIFO>::rx_with_size(8, PayloadSize::Bytes8)
-        ).await.unwrap();
-        obd_controller.configure_filter(
-            FilterConfig::<RX_DASH_FIFO, RX_DASH_FIFO>::from_id(rx_addrs.dash),
-            MaskConfig::<RX_DASH_FIFO>::match_exact(),
-        ).await.unwrap();
-
-        obd_controller.configure_fifo(
-            FIFOConfig::<RX_IGPM_FIFO>::rx_with_size(8, PayloadSize::Bytes8)
-        ).await.unwrap();
-        obd_controller.configure_filter(
-            FilterConfig::<RX_IGPM_FIFO, RX_IGPM_FIFO>::from_id(rx_addrs.igpm),
-            MaskConfig::<RX_IGPM_FIFO>::match_exact(),
-        ).await.unwrap();
-
-        obd_controller.set_mode(registers::OperationMode::Normal).await.unwrap();
+        if let Err(err) = configure_obd_controller(&mut obd_controller, bit_rates, &rx_addrs, bus_mode).await {
+            errors::report(protocol::Source::Obd, errors::Module::Setup, errors::ErrorCode::SetupFailed, err.into(), 0).await;
+            CONTROLLERS_SETTLED.fetch_add(1, portable_atomic::Ordering::Relaxed);
+            return;
+        }
         Timer::after_millis(500).await;
     }
-    spawner.must_spawn(obd_sender_task(obd_controller, tx_addrs, car_off_since));
+    boot::OBD_BUS_UP.store(true, portable_atomic::Ordering::Relaxed);
+    CONTROLLERS_SETTLED.fetch_add(1, portable_atomic::Ordering::Relaxed);
+    if bus_mode.can_transmit() {
+        spawner.must_spawn(obd_sender_task(obd_controller, tx_addrs, car_off_since));
+    }
+    spawner.must_spawn(obd_interrupt_task(obd_controller, int));
+    if bus_mode == config::BusMode::Loopback {
+        spawner.must_spawn(loopback::ecu_task(obd_controller));
+    }
+    spawner.must_spawn(subscriptions::subscription_task(obd_controller));
+    spawner.must_spawn(subscriptions::capture_task(obd_controller));
+    spawner.must_spawn(bus_health_task(obd_controller, 0x7B0, protocol::Source::Obd, &OBD_RX_FIFOS, &mcp::OBD_RX_OVERFLOWS, &tx_events::OBD_TX, &power::OBD_POWER));
+    spawner.must_spawn(bit_rate_task("OBD", protocol::Source::Obd, obd_controller, &config::OBD_BIT_RATE_CHANGES, bus_mode));
+    spawner.must_spawn(power::power_task("OBD", protocol::Source::Obd, obd_controller, stby, &power::OBD_POWER, bus_mode));
 
     #[derive(Format)]
     struct ISOTPTransfer {
         rx_addr: Id,
         raw_data: Vec<u8, 80>,
         length: u16,
-        rx_fifo: u8,
+        // Arrival of the first frame
+        received_at: Instant,
     }
     impl ISOTPTransfer {
-        fn new(rx_addr: Id, data: &[u8], length: u16, rx_fifo: u8) -> Self {
-            Self {
+        fn new(rx_addr: Id, data: &[u8], length: u16, received_at: Instant) -> Result<Self, errors::Error> {
+            Ok(Self {
                 rx_addr,
-                raw_data: Vec::from_slice(data).unwrap(),
+                raw_data: Vec::from_slice(data).map_err(|_| errors::Error::IsoTp(errors::IsoTpError::TooLong))?,
                 length,
-                rx_fifo,
-            }
+                received_at,
+            })
+        }
+        // Returns whether the transfer is complete
+        fn append(&mut self, data: &[u8]) -> Result<bool, errors::Error> {
+            // Don't copy more bytes than the transfer size
+            let remaining_bytes = (self.length as usize).saturating_sub(self.raw_data.len());
+            self.raw_data.extend_from_slice(&data[..data.len().min(remaining_bytes)]).map_err(|_| errors::Error::IsoTp(errors::IsoTpError::TooLong))?;
+            Ok(self.raw_data.len() as u16 >= self.length)
+        }
+        // Without the padding after a single frame's data
+        fn payload(&self) -> &[u8] {
+            &self.raw_data[..self.raw_data.len().min(self.length as usize)]
+        }
+        fn service(&self) -> u8 {
+            self.raw_data[0]
         }
         fn pid(&self) -> &[u8] {
             // First byte is UDS response type
@@ -277,186 +927,573 @@ async fn obd_task(
         }
     }
 
-    // Receive loop
+    // DTCs reported by an ECU that are waiting on their mode 02 freeze frame data before being forwarded
+    struct FreezeFrameRequest {
+        rx_addr: Id,
+        forwarding_address: u16,
+        forward_data: Vec<u8, 64>,
+        responses_remaining: usize,
+        requested_at: Instant,
+    }
+    impl FreezeFrameRequest {
+        fn append(&mut self, data: &[u8]) {
+            // Freeze frame data that doesn't fit in a single forwarded frame is dropped
+            let remaining = self.forward_data.capacity() - self.forward_data.len();
+            self.forward_data.extend_from_slice(&data[..data.len().min(remaining)]).unwrap();
+        }
+        async fn forward(self) {
+            FORWARDING_QUEUE.send(protocol::Message::new(self.forwarding_address, protocol::MessageType::Dtc, protocol::Source::Obd, self.forward_data)).await;
+        }
+    }
+    let mut freeze_frame_request: Option<FreezeFrameRequest> = None;
+    let mut dtc_store = dtc::DtcStore::load(flash);
+
+    // In-progress multi-frame transfers, indexed by the RX FIFO they're coming in on
+    let mut transfers: [Option<ISOTPTransfer>; subscriptions::SUBSCRIPTION_FIFO as usize] = core::array::from_fn(|_| None);
+
+    // Receive loop, frames are pulled off the controller by obd_interrupt_task
     loop {
-        // Wait for interrupt pin to go low (aka active) before calling receive so we don't spinlock
-        int.wait_for_low().await;
-        // Lock the mutex for this receive cycle (sender thread must wait until we're done receiving)
-        let mut obd_controller = obd_controller.lock().await;
-        let mut transfer: Option<ISOTPTransfer> = None;
-        let transfer_start = Instant::now();
+        supervisor::pet(supervisor::Task::ObdReceive);
+        // Wake up regularly even without traffic so that transfers that stalled partway through get cleaned up
+        let received = embassy_time::with_timeout(ISOTP_TRANSFER_TIMEOUT, OBD_RX_CHANNEL.receive()).await.ok();
+        let mut completed: Option<ISOTPTransfer> = None;
+        if let Some((fifo, frame, received_at)) = received {
+            let raw_frame = fifo == subscriptions::SUBSCRIPTION_FIFO || fifo == sniffer::SNIFFER_FIFO;
+            if raw_frame && !config::CONFIG.lock().await.id_list.permits(frame.id()) {
+                continue;
+            }
+            if fifo == subscriptions::SUBSCRIPTION_FIFO {
+                // Raw frame the host subscribed to, not part of an ISO-TP transfer
+                FORWARDING_QUEUE.send(protocol::Message::new(
+                    subscriptions::STREAM_FORWARDING_ID,
+                    protocol::MessageType::RawFrame,
+                    protocol::Source::Obd,
+                    timestamped(received_at, &subscriptions::encode_stream_frame(frame.id(), frame.data())),
+                ).at(received_at)).await;
+            }
+            else if fifo == sniffer::SNIFFER_FIFO {
+                FORWARDING_QUEUE.send(protocol::Message::new(
+                    sniffer::SNIFFER_FORWARDING_ID,
+                    protocol::MessageType::RawFrame,
+                    protocol::Source::Obd,
+                    sniffer::encode(received_at, &frame),
+                ).at(received_at)).await;
+            }
+            else if fifo == loopback::QUERY_FIFO {
+                // Our own query or flow control frame, for the simulated ECUs
+                let raw_id = frame.raw_id();
+                if loopback::QUERIES.try_send(frame).is_err() {
+                    warn!("Loopback ECU is behind, dropping query");
+                    errors::report(protocol::Source::Obd, errors::Module::ObdReceive, errors::ErrorCode::Dropped, errors::Error::ChannelFull, raw_id).await;
+                }
+            }
+            else if let Some(transfer) = transfers.get_mut(fifo as usize) {
+                trace!("Received message from FIFO{}: {:x} ({} bytes): {:x}", fifo, frame.raw_id(), frame.data().len(), frame.data());
 
-        loop {
-            let rx_fifo = transfer.as_ref().map(|t| t.rx_fifo); // Hold the RX FIFO number if there is an active transfer
-            match obd_controller.receive(rx_fifo).await {
-                Ok(Some((fifo, frame))) => {
-                    trace!("Received message from FIFO{}: {:x} ({} bytes): {:x}", fifo, frame.raw_id(), frame.data().len(), frame.data());
-
-                    match frame.data()[0] >> 4 {
-                        0 => {
-                            // Single ISO-TP frame
-                            trace!("Single frame of data");
-                            // ISO-TP transmission complete
-                            transfer = Some(ISOTPTransfer::new(frame.id(),&frame.data()[1..], 8 - 3, fifo));
-                            break;
-                        },
-                        1 => {
-                            // First ISO-TP frame
-                            let length = frame.data()[1] as u16 + ((frame.data()[0] as u16 & 0b1111) << 8);
-                            trace!("First frame of data with total length {}", length);
-                            if length >= 80 {
-                                warn!("Unable to handle ISO-TP transmission with length {} (ECU: {:x}, PID: {:x})", length, frame.raw_id(), &frame.data());
-                                transfer = None;
-                                break;
+                let isotp_error = |err: errors::IsoTpError| errors::report(protocol::Source::Obd, errors::Module::ObdReceive, errors::ErrorCode::BadTransfer, errors::Error::IsoTp(err), frame.raw_id());
+                let Some(&pci) = frame.data().first() else {
+                    isotp_error(errors::IsoTpError::Truncated).await;
+                    continue;
+                };
+                match pci >> 4 {
+                    0 => {
+                        // Single ISO-TP frame
+                        trace!("Single frame of data");
+                        // ISO-TP transmission complete
+                        *transfer = None;
+                        match ISOTPTransfer::new(frame.id(), &frame.data()[1..], (pci & 0b1111) as u16, received_at) {
+                            Ok(single) => completed = Some(single),
+                            Err(err) => errors::report(protocol::Source::Obd, errors::Module::ObdReceive, errors::ErrorCode::BadTransfer, err, frame.raw_id()).await,
+                        }
+                    },
+                    1 => {
+                        // First ISO-TP frame
+                        let Some(&length_low) = frame.data().get(1) else {
+                            isotp_error(errors::IsoTpError::Truncated).await;
+                            continue;
+                        };
+                        let length = length_low as u16 + ((pci as u16 & 0b1111) << 8);
+                        trace!("First frame of data with total length {}", length);
+                        if length >= 80 {
+                            warn!("Unable to handle ISO-TP transmission with length {} (ECU: {:x}, PID: {:x})", length, frame.raw_id(), &frame.data());
+                            stats::isotp_aborted();
+                            if let Some(ecu) = rx_addrs.index(frame.id()) {
+                                ecu_errors::transfer_aborted(ecu);
                             }
-                            transfer = Some(ISOTPTransfer::new(frame.id(), &frame.data()[2..], length, fifo));
-
-                            // Send flow control message to receive the rest of the data
-                            let flow_control_frame = Frame::new(ECUAddresses::tx_address(frame.id()), &[0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap();
-                            obd_controller.transmit::<TRANSMIT_FIFO>(&flow_control_frame).await.unwrap();
-                        },
-                        2 => {
-                            // Consecutive ISO-TP frame
-                            let frame_number = frame.data()[0] & 0b1111;
-                            trace!("Consecutive frame #{}", frame_number);
-
-                            match transfer {
-                                Some(ref mut transfer) => {
-                                    let remaining_bytes: usize = transfer.length as usize - transfer.raw_data.len();
-                                    if remaining_bytes > 7 {
-                                        transfer.raw_data.extend_from_slice(&frame.data()[1..]).unwrap();
-                                    }
-                                    else {
-                                        // Don't copy more bytes than the transfer size
-                                        transfer.raw_data.extend_from_slice(&frame.data()[1..1 + remaining_bytes]).unwrap();
-                                    }
-
-                                    if transfer.raw_data.len() as u16 >= transfer.length {
-                                        // ISO-TP transmission complete
-                                        break;
-                                    }
+                            *transfer = None;
+                            isotp_error(errors::IsoTpError::TooLong).await;
+                        }
+                        else {
+                            *transfer = match ISOTPTransfer::new(frame.id(), &frame.data()[2..], length, received_at) {
+                                Ok(first) => Some(first),
+                                Err(err) => {
+                                    errors::report(protocol::Source::Obd, errors::Module::ObdReceive, errors::ErrorCode::BadTransfer, err, frame.raw_id()).await;
+                                    continue;
                                 },
-                                None => warn!("Received consecutive frame without an active transfer!"),
+                            };
+
+                            // Without transmits, rely on whichever tester made the request to send flow control
+                            if bus_mode.can_transmit() {
+                                // Send flow control message to receive the rest of the data
+                                let flow_control_frame = Frame::new(ECUAddresses::tx_address(frame.id()), &[0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap();
+                                // The ECU is waiting on this before it sends the rest, don't queue it behind pending queries
+                                match obd_controller.lock().await.transmit::<TXQ>(&flow_control_frame).await {
+                                    Ok(()) => {
+                                        tx_events::OBD_TX.record(flow_control_frame.id());
+                                        stats::OBD.transmitted(TXQ);
+                                        blackbox::transmitted(&flow_control_frame);
+                                    },
+                                    Err(err) => error!("Unable to send flow control to {:x}: {}", frame.raw_id(), err),
+                                }
                             }
-                        },
-                        _ => {},
-                    }
-                },
-                Ok(None) => {
-                    // No message in the specified RX FIFO, wait for another RX interrupt before continuing (but not more than 250 ms)
-                    let _ = embassy_time::with_timeout(Duration::from_millis(250), int.wait_for_low()).await;
-                },
-                Err(mcp25xxfd::Error::ControllerError(description)) => {
-                    error!("{} Transfer: {}", description, transfer);
-                    FORWARDING_CHANNEL.send((StandardId::new(0x700).unwrap(), Vec::from_slice(description.as_bytes()).unwrap())).await;
-                    transfer = None;
-                    break;
-                },
-                Err(err) => {
-                    dbg!(err);
-                    transfer = None;
-                    break;
-                },
+                        }
+                    },
+                    2 => {
+                        // Consecutive ISO-TP frame
+                        let frame_number = pci & 0b1111;
+                        trace!("Consecutive frame #{}", frame_number);
+
+                        match transfer.as_mut().map(|active| active.append(&frame.data()[1..])) {
+                            // ISO-TP transmission complete
+                            Some(Ok(true)) => completed = transfer.take(),
+                            Some(Ok(false)) => {},
+                            Some(Err(err)) => {
+                                *transfer = None;
+                                stats::isotp_aborted();
+                                if let Some(ecu) = rx_addrs.index(frame.id()) {
+                                    ecu_errors::transfer_aborted(ecu);
+                                }
+                                errors::report(protocol::Source::Obd, errors::Module::ObdReceive, errors::ErrorCode::BadTransfer, err, frame.raw_id()).await;
+                            },
+                            None => isotp_error(errors::IsoTpError::Unexpected).await,
+                        }
+                    },
+                    _ => {},
+                }
+            }
+            else {
+                warn!("Frame from unexpected FIFO{}", fifo);
             }
-            // If we've been receiving for more than 250 milliseconds, abort so that we don't hold the mutex lock forever
-            if transfer_start.elapsed().as_millis() > 250 {
-                if let Some(transfer) = transfer {
-                    warn!("Transfer from {:x} timed out: {:?}", transfer.raw_rx_addr(), transfer);
+        }
+
+        // Drop transfers whose remaining frames never showed up
+        for transfer in transfers.iter_mut() {
+            if transfer.as_ref().is_some_and(|transfer| transfer.received_at.elapsed() > ISOTP_TRANSFER_TIMEOUT) {
+                let transfer = transfer.take().unwrap();
+                warn!("Transfer from {:x} timed out: {:?}", transfer.raw_rx_addr(), transfer);
+                stats::isotp_aborted();
+                if let Some(ecu) = rx_addrs.index(transfer.rx_addr) {
+                    ecu_errors::transfer_aborted(ecu);
                 }
-                else {
-                    warn!("Unknown transfer timed out");
+            }
+        }
+
+        // Don't wait forever on an ECU that never answers the freeze frame request
+        if freeze_frame_request.as_ref().is_some_and(|request| request.requested_at.elapsed().as_millis() > 2000) {
+            warn!("Freeze frame request timed out, forwarding DTCs without it");
+            freeze_frame_request.take().unwrap().forward().await;
+        }
+
+        if let Some(transfer) = completed {
+            // Every response has at least a service and two bytes after it, the DID for a UDS read
+            if transfer.payload().len() < 3 {
+                errors::report(protocol::Source::Obd, errors::Module::ObdReceive, errors::ErrorCode::BadTransfer, errors::Error::IsoTp(errors::IsoTpError::Truncated), transfer.raw_rx_addr()).await;
+                continue;
+            }
+            heartbeat::vehicle_responded();
+            stats::isotp_completed();
+            if transfer.service() == 0x7F {
+                if let Some(ecu) = rx_addrs.index(transfer.rx_addr) {
+                    ecu_errors::negative_response(ecu);
                 }
-                transfer = None;
-                break;
             }
-        };
+            match transfer.service() {
+                0x43 => {
+                    // Mode 03 response: DTC count followed by two bytes per DTC
+                    let payload = transfer.payload();
+                    let dtc_count = payload[1] as usize;
+                    let dtcs: dtc::DtcList = payload[2..(2 + dtc_count * 2).min(payload.len())]
+                        .chunks_exact(2)
+                        .map(|dtc| u16::from_be_bytes([dtc[0], dtc[1]]))
+                        .take(dtc::MAX_DTCS)
+                        .collect();
+                    debug!("ECU {:x} reported {} DTCs: {:x}", transfer.raw_rx_addr(), dtc_count, dtcs);
 
-        if let Some(transfer) = transfer.take() {
-            let forwarding_address = match transfer.rx_addr {
-                addr if addr == rx_addrs.bms && transfer.pid() == [0x01, 0x01] => {
-                    let mut car_off_since = car_off_since.lock().await;
-                    // Poll more frequently when the HV battery is connected (current > 0 amps)
-                    if transfer.data()[10..12] == [0x00, 0x00] {
-                        // Battery current is 0.0 amps -- car is off
-                        if car_off_since.is_none() {
-                            *car_off_since = Some(Instant::now());
+                    // Only report codes that changed since the last scan
+                    let (appeared, cleared) = dtc_store.update(transfer.raw_rx_addr(), &dtcs);
+                    if appeared.is_empty() && cleared.is_empty() {
+                        continue;
+                    }
+                    dtc_store.save(flash);
+
+                    let new_request = |kind: u8, dtcs: &[u16]| {
+                        let mut request = FreezeFrameRequest {
+                            rx_addr: transfer.rx_addr,
+                            forwarding_address: match transfer.rx_addr {
+                                addr if addr == rx_addrs.bms => protocol::BMS_DTC_FORWARDING_ID,
+                                addr if addr == rx_addrs.iccu => protocol::ICCU_DTC_FORWARDING_ID,
+                                _ => protocol::DTC_FORWARDING_ID,
+                            },
+                            forward_data: Vec::new(),
+                            responses_remaining: 0,
+                            requested_at: Instant::now(),
+                        };
+                        request.append(&[kind, dtcs.len() as u8]);
+                        for dtc in dtcs {
+                            request.append(&dtc.to_be_bytes());
+                        }
+                        request
+                    };
+                    if !cleared.is_empty() {
+                        info!("ECU {:x} cleared DTCs: {:x}", transfer.raw_rx_addr(), cleared);
+                        new_request(DTC_CLEARED, &cleared).forward().await;
+                    }
+                    if appeared.is_empty() {
+                        continue;
+                    }
+                    info!("ECU {:x} set new DTCs: {:x}", transfer.raw_rx_addr(), appeared);
+                    let mut request = new_request(DTC_APPEARED, &appeared);
+                    if let Some(previous) = freeze_frame_request.take() {
+                        previous.forward().await;
+                    }
+                    if !bus_mode.can_transmit() {
+                        // Can't ask for the freeze frame without transmitting
+                        request.forward().await;
+                        continue;
+                    }
+                    // Pull the freeze frame stored alongside the DTC so the fault context isn't lost
+                    for pids in FREEZE_FRAME_REQUESTS.iter() {
+                        let freeze_frame_query = Frame::new(ECUAddresses::tx_address(transfer.rx_addr), &construct_obd_query(0x02, pids)).unwrap();
+                        if let Err(err) = obd_controller.lock().await.transmit::<TRANSMIT_FIFO>(&freeze_frame_query).await {
+                            error!("Unable to request freeze frame from {:x}: {}", transfer.raw_rx_addr(), err);
+                            continue;
                         }
+                        tx_events::OBD_TX.record(freeze_frame_query.id());
+                        stats::OBD.transmitted(TRANSMIT_FIFO);
+                        blackbox::transmitted(&freeze_frame_query);
+                        request.responses_remaining += 1;
+                    }
+                    freeze_frame_request = Some(request);
+                    continue;
+                },
+                0x42 | 0x7F if (transfer.service() == 0x42 || transfer.raw_data[1] == 0x02) && freeze_frame_request.as_ref().is_some_and(|request| request.rx_addr == transfer.rx_addr) => {
+                    let mut request = freeze_frame_request.take().unwrap();
+                    if transfer.service() == 0x42 {
+                        // Mode 02 response: (PID, frame number, data...) for each requested PID
+                        request.append(&transfer.payload()[1..]);
+                        request.responses_remaining -= 1;
                     }
                     else {
-                        // Car is on
-                        *car_off_since = None;
+                        // Negative response, ECU doesn't support freeze frames
+                        warn!("ECU {:x} rejected freeze frame request: {:x}", transfer.raw_rx_addr(), &transfer.raw_data[1..]);
+                        request.responses_remaining = 0;
+                    }
+                    if request.responses_remaining == 0 {
+                        request.forward().await;
                     }
-                    0x701
+                    else {
+                        freeze_frame_request = Some(request);
+                    }
+                    continue;
                 },
-                addr if addr == rx_addrs.bms && transfer.pid() == [0x01, 0x05] => 0x705,
-                addr if addr == rx_addrs.bms && transfer.pid() == [0x01, 0x06] => 0x706,
-                addr if addr == rx_addrs.bms && transfer.pid() == [0x01, 0x11] => 0x70B,
-                addr if addr == rx_addrs.tpms && transfer.pid() == [0xC0, 0x0B] => 0x710,
-                addr if addr == rx_addrs.hvac && transfer.pid() == [0x01, 0x00] => 0x720,
-                addr if addr == rx_addrs.adas && transfer.pid() == [0xF0, 0x10] => 0x730,
-                addr if addr == rx_addrs.iccu && transfer.pid() == [0xE0, 0x01] => 0x741,
-                addr if addr == rx_addrs.iccu && transfer.pid() == [0xE0, 0x02] => 0x742,
-                addr if addr == rx_addrs.iccu && transfer.pid() == [0xE0, 0x03] => 0x743,
-                addr if addr == rx_addrs.iccu && transfer.pid() == [0xE0, 0x11] => 0x74B,
-                addr if addr == rx_addrs.vcms && transfer.pid() == [0xE0, 0x01] => 0x751,
-                addr if addr == rx_addrs.vcms && transfer.pid() == [0xE0, 0x02] => 0x752,
-                addr if addr == rx_addrs.vcms && transfer.pid() == [0xE0, 0x03] => 0x753,
-                addr if addr == rx_addrs.vcms && transfer.pid() == [0xE0, 0x04] => 0x754,
-                addr if addr == rx_addrs.dash && transfer.pid() == [0xB0, 0x02] => 0x760,
-                addr if addr == rx_addrs.igpm && transfer.pid() == [0xBC, 0x03] => 0x773,
-                addr if addr == rx_addrs.igpm && transfer.pid() == [0xBC, 0x04] => 0x774,
-                _ => {
+                _ => {},
+            }
+
+            let bms_status = (transfer.rx_addr == rx_addrs.bms && transfer.pid() == Vehicle::BMS_STATUS_DID)
+                .then(|| Vehicle::decode_bms_status(transfer.data()))
+                .flatten();
+            if let Some(bms_status) = bms_status {
+                let mut car_off_since = car_off_since.lock().await;
+                // Poll more frequently when the HV battery is connected (current > 0 amps)
+                if !bms_status.hv_connected {
+                    // Battery current is 0.0 amps -- car is off
+                    if car_off_since.is_none() {
+                        *car_off_since = Some(Instant::now());
+                    }
+                }
+                else {
+                    // Car is on
+                    *car_off_since = None;
+                }
+                // Feed the alert rules without stalling the receive loop if the alert task is behind
+                if let Some(cell_voltage_delta) = bms_status.cell_voltage_delta {
+                    let _ = alerts::SIGNAL_CHANNEL.try_send((alerts::Signal::CellVoltageDelta, cell_voltage_delta));
+                }
+                if let Some(aux_battery_voltage) = bms_status.aux_battery_voltage {
+                    let _ = alerts::SIGNAL_CHANNEL.try_send((alerts::Signal::AuxBatteryVoltage, aux_battery_voltage));
+                }
+            }
+            let query = query_table.iter().position(|&(ecu, did)| rx_addrs.get(ecu) == Some(transfer.rx_addr) && transfer.pid() == did);
+            let forwarding_address = match query {
+                Some(index) => {
+                    polling::response_received(index, transfer.data());
+                    latency::response_received(index, transfer.received_at);
+                    if let Some(decoded) = signals::encode(index, transfer.data()) {
+                        FORWARDING_QUEUE.send(protocol::Message::new(
+                            signals::SIGNALS_FORWARDING_ID,
+                            protocol::MessageType::Signals,
+                            protocol::Source::Obd,
+                            decoded,
+                        ).at(transfer.received_at)).await;
+                    }
+                    config::CONFIG.lock().await.forwarding_ids.queries[index]
+                },
+                None => {
+                    if let Some(ecu) = polling::take_one_shot(transfer.rx_addr, &transfer.raw_data) {
+                        let mut forward_data: Vec<u8, protocol::MAX_MESSAGE_LENGTH> = Vec::new();
+                        forward_data.push(ecu).unwrap();
+                        let length = transfer.raw_data.len().min(forward_data.capacity() - 1);
+                        forward_data.extend_from_slice(&transfer.raw_data[..length]).unwrap();
+                        FORWARDING_QUEUE.send(protocol::Message::new(
+                            polling::ONE_SHOT_FORWARDING_ID,
+                            protocol::MessageType::DidResponse,
+                            protocol::Source::Obd,
+                            forward_data,
+                        )).await;
+                        continue;
+                    }
+                    if let Some(ecu) = gateway::take(transfer.rx_addr) {
+                        let mut forward_data: Vec<u8, protocol::MAX_MESSAGE_LENGTH> = Vec::new();
+                        forward_data.push(ecu).unwrap();
+                        let length = transfer.raw_data.len().min(forward_data.capacity() - 1);
+                        forward_data.extend_from_slice(&transfer.raw_data[..length]).unwrap();
+                        FORWARDING_QUEUE.send(protocol::Message::new(
+                            gateway::GATEWAY_RESPONSE_ID,
+                            protocol::MessageType::GatewayResponse,
+                            protocol::Source::Obd,
+                            forward_data,
+                        ).at(transfer.received_at)).await;
+                        continue;
+                    }
+                    if transfer.service() == 0x7F {
+                        // Negative response to a request nobody's waiting on anymore
+                        let (service, code) = (transfer.raw_data[1], transfer.raw_data[2]);
+                        let context = (transfer.raw_rx_addr() & 0xFFFF) << 16 | (service as u32) << 8 | code as u32;
+                        errors::report(protocol::Source::Obd, errors::Module::ObdReceive, errors::ErrorCode::NegativeResponse, errors::Error::Uds { service, code }, context).await;
+                        continue;
+                    }
                     warn!("Unhandled ISO-TP response from address {:x} to PID {:x}: {:x}", transfer.raw_rx_addr(), transfer.pid(), transfer.data());
                     continue;
                 },
             };
-            let forwarding_address = StandardId::new(forwarding_address).unwrap();
-            FORWARDING_CHANNEL.send((forwarding_address, Vec::from_slice(
-                &transfer
-                    .data()
-                    .chunks(64)
-                    .next()
-                    .unwrap()
-            ).unwrap())).await;
+            FORWARDING_QUEUE.send(protocol::Message::new(
+                forwarding_address,
+                protocol::MessageType::EcuData,
+                protocol::Source::Obd,
+                timestamped(transfer.received_at, transfer.data()),
+            ).at(transfer.received_at)).await;
+        }
+    }
+}
+
+// Frames drained from the OBD controller's RX FIFOs: (FIFO, frame, arrival time)
+const OBD_RX_CHANNEL_DEPTH: usize = 32;
+static OBD_RX_CHANNEL: Channel<CriticalSectionRawMutex, (u8, Frame, Instant), OBD_RX_CHANNEL_DEPTH> = Channel::new();
+
+// Services the OBD controller's interrupts and moves received frames into OBD_RX_CHANNEL, so that the SPI side keeps
+// up with the bus no matter how long decoding takes
+#[embassy_executor::task]
+async fn obd_interrupt_task(
+    obd_controller: &'static Mutex<CriticalSectionRawMutex, CanController>,
+    mut int: Input<'static>,
+) {
+    loop {
+        supervisor::pet(supervisor::Task::ObdInterrupt);
+        // Wait for interrupt pin to go low (aka active) before calling receive so we don't spinlock, waking up regularly
+        // on a quiet bus to check in with the supervisor
+        if embassy_time::with_timeout(supervisor::PET_INTERVAL, int.wait_for_low()).await.is_err() {
+            continue;
+        }
+        let mut received: Vec<(u8, Frame, Instant), 32> = Vec::new();
+        {
+            let mut obd_controller = obd_controller.lock().await;
+            match mcp::service_rx_overflows(&mut obd_controller, &mcp::OBD_RX_OVERFLOWS).await {
+                Ok(0) => {},
+                Ok(overflowed) => warn!("RX FIFO overflow (FIFO mask {:b})", overflowed),
+                Err(err) => error!("Unable to check RX overflows: {}", err),
+            }
+            match mcp::service_ecc_errors(&mut obd_controller).await {
+                Ok(None) => {},
+                Ok(Some(ecc_error)) => warn!("OBD controller RAM error: {}", ecc_error),
+                Err(err) => error!("Unable to check ECC status: {}", err),
+            }
+            match mcp::service_wake(&mut obd_controller).await {
+                Ok(true) => power::OBD_POWER.woke(),
+                Ok(false) => {},
+                Err(err) => error!("Unable to check wake-up interrupt: {}", err),
+            }
+            service_abandoned_transmits("OBD", 0, &mut obd_controller).await;
+
+            // Read the RX interrupt flags once and drain exactly the FIFOs that have something in them. Anything left
+            // over once the batch is full keeps INT asserted and gets picked up next time around.
+            let pending = match mcp::pending_rx_fifos(&mut obd_controller).await {
+                Ok(pending) => pending,
+                Err(err) => {
+                    error!("Unable to read RX interrupt flags: {}", err);
+                    0
+                },
+            };
+            for fifo in (1..32u8).filter(|fifo| pending & (1 << fifo) != 0) {
+                let received_before = received.len();
+                while !received.is_full() {
+                    match mcp::receive(&mut obd_controller, Some(fifo)).await {
+                        Ok(Some(frame)) => {
+                            stats::OBD.received(fifo);
+                            blackbox::received(&frame.1);
+                            received.push(frame).ok().unwrap()
+                        },
+                        Ok(None) => break,
+                        Err(err) => {
+                            error!("FIFO{}: {}", fifo, err);
+                            let report = errors::ErrorReport::new(errors::Module::ObdReceive, errors::ErrorCode::ReceiveFailed, &err.into(), fifo as u32);
+                            FORWARDING_QUEUE.send(report.message(protocol::Source::Obd)).await;
+                            break;
+                        },
+                    }
+                }
+                stats::OBD.drained(fifo, received.len() - received_before);
+            }
+        }
+        if !received.is_empty() {
+            power::vehicle_activity();
+        }
+        // Only block on a full channel once the controller is released, processing may need it for flow control
+        for frame in received {
+            OBD_RX_CHANNEL.send(frame).await;
+            memory::OBD_RX_PEAK.record(OBD_RX_CHANNEL.len());
         }
     }
 }
 
 #[embassy_executor::task]
 async fn obd_sender_task(
-    obd_controller: &'static Mutex<CriticalSectionRawMutex, MCP25xxFD<SpiDevice<'_, CriticalSectionRawMutex, SPI0Type<SPI0>, Output<'_>>>>,
+    obd_controller: &'static Mutex<CriticalSectionRawMutex, CanController>,
     tx_addrs: ECUAddresses,
     car_off_since: &'static Mutex<CriticalSectionRawMutex, Option<Instant>>,
 ) {
-    let queries = [
-        Frame::new(tx_addrs.bms, &construct_uds_query(&[0x01, 0x01])).unwrap(),
-        Frame::new(tx_addrs.bms, &construct_uds_query(&[0x01, 0x05])).unwrap(),
-        // Frame::new(tx_addrs.bms, &construct_uds_query(&[0x01, 0x06])).unwrap(),
-        Frame::new(tx_addrs.bms, &construct_uds_query(&[0x01, 0x11])).unwrap(),
-        Frame::new(tx_addrs.tpms, &construct_uds_query(&[0xC0, 0x0B])).unwrap(),
-        Frame::new(tx_addrs.hvac, &construct_uds_query(&[0x01, 0x00])).unwrap(),
-        // Frame::new(tx_addrs.adas, &construct_uds_query(&[0xF0, 0x10])).unwrap(),
-        Frame::new(tx_addrs.iccu, &construct_uds_query(&[0xE0, 0x01])).unwrap(),
-        Frame::new(tx_addrs.iccu, &construct_uds_query(&[0xE0, 0x02])).unwrap(),
-        Frame::new(tx_addrs.iccu, &construct_uds_query(&[0xE0, 0x03])).unwrap(),
-        Frame::new(tx_addrs.iccu, &construct_uds_query(&[0xE0, 0x11])).unwrap(),
-        Frame::new(tx_addrs.vcms, &construct_uds_query(&[0xE0, 0x01])).unwrap(),
-        Frame::new(tx_addrs.vcms, &construct_uds_query(&[0xE0, 0x02])).unwrap(),
-        Frame::new(tx_addrs.vcms, &construct_uds_query(&[0xE0, 0x03])).unwrap(),
-        Frame::new(tx_addrs.vcms, &construct_uds_query(&[0xE0, 0x04])).unwrap(),
-        Frame::new(tx_addrs.dash, &construct_uds_query(&[0xB0, 0x02])).unwrap(),
-        Frame::new(tx_addrs.igpm, &construct_uds_query(&[0xBC, 0x03])).unwrap(),
-        Frame::new(tx_addrs.igpm, &construct_uds_query(&[0xBC, 0x04])).unwrap(),
+    // The host starts, stops and reschedules these by index
+    let query_table = config::CONFIG.lock().await.queries;
+    // None for anything a frame can't be built for, those just never get sent
+    let queries: [Option<Frame>; polling::QUERY_COUNT] = core::array::from_fn(|index| {
+        let (ecu, did) = query_table[index];
+        tx_addrs.get(ecu).and_then(|id| Frame::new(id, &construct_uds_query(&did)))
+    });
+    // Only ECUs in the OBD-II emissions address range answer mode 03
+    let dtc_queries = [
+        Frame::new(tx_addrs.bms, &construct_obd_query(0x03, &[])),
+        Frame::new(tx_addrs.iccu, &construct_obd_query(0x03, &[])),
     ];
 
-    let mut ticker = Ticker::every(Duration::from_secs(1));
+    // Waits until the given time while applying host requests, so one-shot reads don't wait for the next cycle
+    async fn wait_until(
+        until: Instant,
+        obd_controller: &Mutex<CriticalSectionRawMutex, CanController>,
+        tx_addrs: &ECUAddresses,
+        schedule: &mut polling::Schedule,
+    ) {
+        loop {
+            // Called at least once a second, even while the car is off
+            supervisor::pet(supervisor::Task::ObdSender);
+            let request = match select(Timer::at(until), polling::QUERY_REQUESTS.receive()).await {
+                Either::First(_) => return,
+                Either::Second(request) => request,
+            };
+            debug!("Applying query request: {}", request);
+            let Some(request) = schedule.apply(request) else {
+                continue;
+            };
+            if power::OBD_POWER.is_asleep() {
+                warn!("OBD controller asleep, dropping {}", request);
+                continue;
+            }
+            let frame = match &request {
+                polling::QueryRequest::ReadDid { ecu, did } => tx_addrs.get(*ecu).and_then(|id| Frame::new(id, &construct_uds_query(did))),
+                polling::QueryRequest::Gateway { ecu, request } => tx_addrs.get(*ecu)
+                    .zip(request.split_first())
+                    .and_then(|(id, (&service, data))| Frame::new(id, &construct_query(service, data))),
+                polling::QueryRequest::Raw { id, data } => Frame::new(*id, data),
+                _ => continue,
+            };
+            let Some(frame) = frame else {
+                warn!("Unable to build a frame for {}, dropping it", request);
+                continue;
+            };
+            let filtered = matches!(request, polling::QueryRequest::Gateway { .. } | polling::QueryRequest::Raw { .. });
+            if filtered && !config::CONFIG.lock().await.id_list.permits(frame.id()) {
+                warn!("Request to {:x} blocked by the ID list", frame.raw_id());
+                continue;
+            }
+            match obd_controller.lock().await.transmit::<TRANSMIT_FIFO>(&frame).await {
+                Ok(()) => {
+                    tx_events::OBD_TX.record(frame.id());
+                    stats::OBD.transmitted(TRANSMIT_FIFO);
+                    blackbox::transmitted(&frame);
+                    let rx_addr = ECUAddresses::rx_address(frame.id());
+                    match request {
+                        polling::QueryRequest::ReadDid { ecu, did } => polling::one_shot_sent(rx_addr, ecu, did),
+                        polling::QueryRequest::Gateway { ecu, .. } => gateway::sent(rx_addr, ecu),
+                        _ => {},
+                    }
+                },
+                Err(err) => error!("Unable to send {} to {:x}: {}", request, frame.raw_id(), err),
+            }
+        }
+    }
+
+    let mut schedule = polling::Schedule::new();
+    let mut last_dtc_scan: Option<Instant> = None;
+    let mut car_was_on = false;
     loop {
-        for frame in queries.iter() {
-            obd_controller
-                .lock().await
-                .transmit::<TRANSMIT_FIFO>(frame).await
-                .unwrap();
-            Timer::after_millis(30).await;
+        supervisor::pet(supervisor::Task::ObdSender);
+        let cycle_start = Instant::now();
+        if power::OBD_POWER.is_asleep() {
+            // Nothing can be sent until bus activity wakes the controller back up
+            wait_until(cycle_start + Duration::from_secs(1), obd_controller, &tx_addrs, &mut schedule).await;
+            continue;
+        }
+        let car_on = car_off_since.lock().await.is_none();
+        let dtc_scan = (car_on && !car_was_on) || last_dtc_scan.is_none_or(|scan| scan.elapsed() >= DTC_SCAN_INTERVAL);
+        car_was_on = car_on;
+        if dtc_scan {
+            last_dtc_scan = Some(Instant::now());
+        }
+        let config = config::CONFIG.lock().await;
+        let due: [bool; polling::QUERY_COUNT] = core::array::from_fn(|index| {
+            config.ecu_polled(query_table[index].0) && schedule.due(index)
+        });
+        drop(config);
+        let due_queries = queries.iter().enumerate().filter(|(index, _)| due[*index]).filter_map(|(index, frame)| Some((Some(index), frame.as_ref()?)));
+        for (index, frame) in due_queries.chain(dtc_queries.iter().flatten().filter(|_| dtc_scan).map(|frame| (None, frame))) {
+            if let Some(index) = index {
+                schedule.sent(index);
+            }
+            // Send each query at most twice if it never shows up in the TX event FIFO
+            for attempt in 0..2 {
+                if let Err(err) = obd_controller.lock().await.transmit::<TRANSMIT_FIFO>(frame).await {
+                    error!("Unable to send query to {:x}: {}", frame.raw_id(), err);
+                    let report = errors::ErrorReport::new(errors::Module::ObdSender, errors::ErrorCode::TransmitFailed, &err.into(), frame.raw_id());
+                    FORWARDING_QUEUE.send(report.message(protocol::Source::Obd)).await;
+                    break;
+                }
+                tx_events::OBD_TX.record(frame.id());
+                stats::OBD.transmitted(TRANSMIT_FIFO);
+                blackbox::transmitted(frame);
+                if let Some(index) = index {
+                    latency::query_sent(index, query_table[index].0);
+                }
+                Timer::after_millis(30).await;
+
+                if let Err(err) = tx_events::OBD_TX.service(&mut *obd_controller.lock().await).await {
+                    error!("Unable to read TX events: {}", err);
+                    break;
+                }
+                if !tx_events::OBD_TX.is_pending(frame.id()) {
+                    break;
+                }
+                if attempt == 0 {
+                    debug!("Query to {:x} wasn't confirmed, retransmitting", frame.raw_id());
+                }
+            }
         }
         // Wait 5 minutes between polls if car is off to allow ECUs to deep sleep and save battery
         // Check once per second while waiting to see if car is on again
@@ -471,128 +1508,408 @@ async fn obd_sender_task(
                 // Car is on, exit 5-minute timer loop
                 break;
             }
-            Timer::after_millis(1000).await;
+            wait_until(Instant::now() + Duration::from_secs(1), obd_controller, &tx_addrs, &mut schedule).await;
         }
-        ticker.next().await;
+        wait_until(cycle_start + Duration::from_secs(1), obd_controller, &tx_addrs, &mut schedule).await;
     }
 }
 
-#[embassy_executor::task]
-async fn bme_sender_task(i2c: i2c::I2c<'static, I2C0, i2c::Async>) {
-    let mut bme280 = AsyncBme280::new(i2c, Delay);
-    bme280.init().await.unwrap();
-    bme280.set_sampling_configuration(
-        bme280_rs::Configuration::default()
-            .with_sensor_mode(bme280_rs::SensorMode::Normal)
-            .with_standby_time(bme280_rs::StandbyTime::Millis1000)
-            .with_pressure_oversampling(bme280_rs::Oversampling::Oversample8)
-            .with_temperature_oversampling(bme280_rs::Oversampling::Oversample8)
-            .with_humidity_oversampling(bme280_rs::Oversampling::Oversample8)
-            .with_filter(bme280_rs::Filter::Filter4)
-    ).await.unwrap();
-
-    fn compensate_temperature(sensor_temp: Temperature) -> Temperature {
-        sensor_temp - 6.0
-    }
-    fn compensate_humidity(sensor_temp: Temperature, sensor_humidity: Humidity) -> Humidity {
-        // From https://www.renesas.com/ja/document/apn/compensating-temperature-and-relative-humidity-pcb
-        const A: f32 = 6.1162;
-        const M: f32 = 7.5892;
-        const TN: f32 = 240.71;
-
-        let corrected_temp = compensate_temperature(sensor_temp);
-        let sensor_saturation_vapor_pressure = A * f32::powf(10.0,(M * sensor_temp) / (sensor_temp + TN));
-        let corrected_saturation_vapor_pressure = A * f32::powf(10.0,(M * corrected_temp) / (corrected_temp + TN));
-        let vapor_pressure = (sensor_humidity * sensor_saturation_vapor_pressure) / 100.0;
-        (vapor_pressure / corrected_saturation_vapor_pressure) * 100.0
-    }
-
-    let mut ticker = Ticker::every(Duration::from_secs(30));
+// Applies bit rate changes requested at runtime. Bit timing can only be written in configuration mode, which keeps the
+// FIFO and filter setup but drops anything still queued in the FIFOs.
+#[embassy_executor::task(pool_size = 2)]
+async fn bit_rate_task(
+    name: &'static str,
+    source: protocol::Source,
+    controller: &'static Mutex<CriticalSectionRawMutex, CanController>,
+    changes: &'static Signal<CriticalSectionRawMutex, config::BitRates>,
+    mode: config::BusMode,
+) {
     loop {
-        let mut forward_data: Vec<u8, 64> = Vec::new();
-
-        let sample = bme280.read_sample().await.unwrap();
-        let pressure = sample.pressure.unwrap_or(0.0).to_be_bytes();
-        let temperature = compensate_temperature(sample.temperature.unwrap_or(0.0)).to_be_bytes();
-        let humidity = compensate_humidity(sample.temperature.unwrap_or(0.0), sample.humidity.unwrap_or(0.0)).to_be_bytes();
+        let bit_rates = changes.wait().await;
+        info!("{}: switching to {}", name, bit_rates);
+        let mut controller = controller.lock().await;
+        if let Err(err) = controller.set_mode(registers::OperationMode::Configuration).await {
+            error!("{}: unable to enter configuration mode: {}", name, err);
+            continue;
+        }
+        if let Err(err) = mcp::set_bit_rates(&mut controller, bit_rates).await {
+            error!("{}: unable to set bit rates: {}", name, err);
+        }
+        if let Err(err) = controller.set_mode(mode.operation_mode()).await {
+            // Stuck in configuration mode, report it and keep going so another bit rate change can retry
+            drop(controller);
+            errors::report(source, errors::Module::BitRate, errors::ErrorCode::ModeChangeFailed, err.into(), source as u32).await;
+        }
+    }
+}
 
-        forward_data.extend_from_slice(&pressure).unwrap();
-        forward_data.extend_from_slice(&temperature).unwrap();
-        forward_data.extend_from_slice(&humidity).unwrap();
-        FORWARDING_CHANNEL.send((StandardId::new(0x7A0).unwrap(), forward_data)).await;
+const BUS_HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+// Bus load is estimated from the controller's count of error-free frames, assuming each is a standard 8-byte frame with
+// typical bit stuffing. FD frames with bit-rate switching spend less time on the bus than that, so on the comma bus
+// it errs high.
+const BITS_PER_FRAME: u64 = 125;
 
+#[embassy_executor::task(pool_size = 2)]
+async fn bus_health_task(
+    controller: &'static Mutex<CriticalSectionRawMutex, CanController>,
+    forwarding_address: u16,
+    source: protocol::Source,
+    rx_fifos: &'static [u8],
+    rx_overflows: &'static mcp::RxOverflowCounters,
+    tx_tracker: &'static tx_events::TxTracker,
+    power: &'static power::BusPower,
+) {
+    let mut ticker = Ticker::every(BUS_HEALTH_INTERVAL);
+    let mut bus_off = false;
+    let mut last_read = Instant::now();
+    loop {
         ticker.next().await;
+        if power.is_asleep() {
+            continue;
+        }
+        if let Err(err) = tx_tracker.service(&mut *controller.lock().await).await {
+            error!("Unable to read TX events for {:x}: {}", forwarding_address, err);
+        }
+        let counters = match mcp::read_error_counters(&mut *controller.lock().await).await {
+            Ok(counters) => counters,
+            Err(err) => {
+                error!("Unable to read error counters for {:x}: {}", forwarding_address, err);
+                continue;
+            },
+        };
+        let elapsed = core::mem::replace(&mut last_read, Instant::now()).elapsed().as_millis().max(1);
+        let config = config::CONFIG.lock().await;
+        let bit_rates = if source == protocol::Source::Obd { config.obd_bit_rates } else { config.comma_bit_rates };
+        drop(config);
+        // 0.1 % steps
+        let bus_load = (counters.error_free_messages as u64 * BITS_PER_FRAME * 1_000_000 / (bit_rates.nominal.bits_per_second() as u64 * elapsed)).min(1000) as u16;
+        debug!("Bus load on {:x}: {}.{} %", forwarding_address, bus_load / 10, bus_load % 10);
+        bus_errors::check(source as u8, &counters);
+        heartbeat::ERROR_COUNTERS[source as usize].store(((counters.tec as u16) << 8) | counters.rec as u16, portable_atomic::Ordering::Relaxed);
+        if counters.tec > 0 || counters.rec > 0 || counters.error_flags != 0 {
+            warn!("Bus errors reported by {:x}: {}", forwarding_address, counters);
+        }
+        // TXBO, only dump on the way into bus-off so the frames that caused it aren't overwritten while it recovers
+        let was_bus_off = core::mem::replace(&mut bus_off, counters.state_flags & 0x20 != 0);
+        if bus_off && !was_bus_off {
+            blackbox::request_dump(if source == protocol::Source::Obd { blackbox::Reason::ObdBusOff } else { blackbox::Reason::CommaBusOff });
+        }
+
+        let mut forward_data: Vec<u8, 64> = Vec::new();
+        forward_data.extend_from_slice(&[
+            counters.tec,
+            counters.rec,
+            counters.state_flags,
+            counters.nominal_tx_errors,
+            counters.nominal_rx_errors,
+            counters.data_tx_errors,
+            counters.data_rx_errors,
+        ]).unwrap();
+        forward_data.extend_from_slice(&counters.error_flags.to_be_bytes()).unwrap();
+        forward_data.extend_from_slice(&counters.error_free_messages.to_be_bytes()).unwrap();
+        // Rolling counts of transmissions confirmed/lost according to the TX event FIFO
+        let (tx_confirmed, tx_unconfirmed) = tx_tracker.counts();
+        forward_data.extend_from_slice(&(tx_confirmed as u16).to_be_bytes()).unwrap();
+        forward_data.extend_from_slice(&(tx_unconfirmed as u16).to_be_bytes()).unwrap();
+        // Messages the forwarding queue had to drop since boot (2 bytes)
+        forward_data.extend_from_slice(&(FORWARDING_QUEUE.dropped().min(u16::MAX as u32) as u16).to_be_bytes()).unwrap();
+        // Estimated bus load since the last report (0.1 %, 2 bytes)
+        forward_data.extend_from_slice(&bus_load.to_be_bytes()).unwrap();
+        // Followed by [FIFO, overflow count (2 bytes)] for every RX FIFO that has ever overflowed
+        for &fifo in rx_fifos.iter().filter(|&&fifo| rx_overflows.get(fifo) > 0) {
+            forward_data.push(fifo).unwrap();
+            forward_data.extend_from_slice(&(rx_overflows.get(fifo).min(u16::MAX as u32) as u16).to_be_bytes()).unwrap();
+        }
+        FORWARDING_QUEUE.send(protocol::Message::new(forwarding_address, protocol::MessageType::BusHealth, source, forward_data)).await;
     }
 }
 
 const IGNITION_FIFO: u8 = 2;
+const COMMAND_FIFO: u8 = 3;
+// Config service and diagnostic server requests share the command FIFO, see comma_interrupt_task
+const CONFIG_FILTER: u8 = 4;
+const DIAG_FILTER: u8 = 5;
+const COMMA_RX_FIFOS: [u8; 2] = [IGNITION_FIFO, COMMAND_FIFO];
+const COMMA_TXQ_DEPTH: u8 = 2;
+const COMMA_TRANSMIT_DEPTH: u8 = 8;
+pub const COMMA_TX_OBJECTS: usize = COMMA_TXQ_DEPTH as usize + COMMA_TRANSMIT_DEPTH as usize;
+const IGNITION_FIFO_DEPTH: u8 = 32;
+const COMMAND_FIFO_DEPTH: u8 = 8;
+
+// Laid out like the OBD controller's (see obd_message_ram), with 64 byte payloads on everything but the ignition FIFO
+// and no RX timestamps
+const fn comma_message_ram() -> usize {
+    tx_events::TX_EVENT_FIFO_DEPTH as usize * TEF_OBJECT_SIZE
+        + COMMA_TX_OBJECTS * (8 + 64)
+        + IGNITION_FIFO_DEPTH as usize * (8 + 8)
+        + COMMAND_FIFO_DEPTH as usize * (8 + 64)
+}
+const _: () = assert!(comma_message_ram() <= MESSAGE_RAM_SIZE, "Comma controller's FIFOs don't fit in message RAM");
+
+// Sets up the comma controller's FIFOs and filters after bring-up and puts it on the bus
+async fn configure_comma_controller(comma_controller: &mut CanController) -> Result<(), mcp25xxfd::Error> {
+    comma_controller.configure_fifo(
+        FIFOConfig::<TXQ>::tx_with_size(COMMA_TXQ_DEPTH, PayloadSize::Bytes64)
+    ).await?;
+    comma_controller.configure_fifo(
+        FIFOConfig::<TRANSMIT_FIFO>::tx_with_size(COMMA_TRANSMIT_DEPTH, PayloadSize::Bytes64)
+    ).await?;
+
+    comma_controller.configure_fifo(
+        FIFOConfig::<IGNITION_FIFO>::rx_with_size(IGNITION_FIFO_DEPTH, PayloadSize::Bytes8)
+    ).await?;
+    comma_controller.configure_filter(
+        FilterConfig::<IGNITION_FIFO, IGNITION_FIFO>::from_id(StandardId::new(0x201).unwrap()),
+        MaskConfig::<IGNITION_FIFO>::match_exact(),
+    ).await?;
+
+    comma_controller.configure_fifo(
+        FIFOConfig::<COMMAND_FIFO>::rx_with_size(COMMAND_FIFO_DEPTH, PayloadSize::Bytes64)
+    ).await?;
+    comma_controller.configure_filter(
+        FilterConfig::<COMMAND_FIFO, COMMAND_FIFO>::from_id(StandardId::new(commands::COMMAND_ID).unwrap()),
+        MaskConfig::<COMMAND_FIFO>::match_exact(),
+    ).await?;
+    comma_controller.configure_filter(
+        FilterConfig::<CONFIG_FILTER, COMMAND_FIFO>::from_id(StandardId::new(config_service::CONFIG_REQUEST_ID).unwrap()),
+        MaskConfig::<CONFIG_FILTER>::match_exact(),
+    ).await?;
+    comma_controller.configure_filter(
+        FilterConfig::<DIAG_FILTER, COMMAND_FIFO>::from_id(StandardId::new(diag_server::DIAG_REQUEST_ID).unwrap()),
+        MaskConfig::<DIAG_FILTER>::match_exact(),
+    ).await?;
+
+    // Ignition frames arrive continuously and are only sampled, so only commands get to assert INT
+    mcp::disable_rx_interrupt(comma_controller, IGNITION_FIFO).await?;
+    mcp::enable_rx_overflow_interrupts(comma_controller, &[COMMAND_FIFO]).await?;
+    mcp::enable_ecc_interrupts(comma_controller).await?;
+    mcp::limit_tx_attempts(comma_controller, &[TXQ, TRANSMIT_FIFO]).await?;
+    mcp::enable_wake_interrupt(comma_controller).await?;
+    mcp::configure_tx_event_fifo(comma_controller, tx_events::TX_EVENT_FIFO_DEPTH).await?;
+
+    comma_controller.set_mode(registers::OperationMode::Normal).await
+}
+
 #[embassy_executor::task]
 async fn comma_task(
     spawner: Spawner,
     spi_bus: &'static Mutex<CriticalSectionRawMutex, SPI0Type<SPI0>>,
     cs: Output<'static>,
     int: Input<'static>,
+    mut stby: Output<'static>,
     car_off_since: &'static Mutex<CriticalSectionRawMutex, Option<Instant>>,
+    flash: &'static storage::FlashMutex,
 ) {
     let comma_device = SpiDevice::new(spi_bus, cs);
     let comma_controller = COMMA_CONTROLLER.init(Mutex::new(MCP25xxFD::new(comma_device)));
+
+    let bit_rates = config::CONFIG.lock().await.comma_bit_rates;
+    if !bring_up_controller("Comma", 1, &mut *comma_controller.lock().await, spi_bus, &mut stby, bit_rates).await {
+        // Producers never block on the forwarding queue, it just fills up and drops messages from here on
+        CONTROLLERS_SETTLED.fetch_add(1, portable_atomic::Ordering::Relaxed);
+        return;
+    }
     {
         let mut comma_controller = comma_controller.lock().await;
-        comma_controller.reset_and_apply_config(&Config {
-            clock: Clock::Clock20MHz,
-            bit_rate: BitRate::default(),
-            ecc_enabled: true,
-            restrict_retx_attempts: false,
-            txq_enabled: false,
-            tx_event_fifo_enabled: false,
-            iso_crc_enabled: true,
-        }).await.unwrap();
-
-        comma_controller.configure_fifo(
-            FIFOConfig::<TRANSMIT_FIFO>::tx_with_size(8, PayloadSize::Bytes64)
-        ).await.unwrap();
-
-        comma_controller.configure_fifo(
-            FIFOConfig::<IGNITION_FIFO>::rx_with_size(32, PayloadSize::Bytes8)
-        ).await.unwrap();
-        comma_controller.configure_filter(
-            FilterConfig::<IGNITION_FIFO, IGNITION_FIFO>::from_id(StandardId::new(0x201).unwrap()),
-            MaskConfig::<IGNITION_FIFO>::match_exact(),
-        ).await.unwrap();
-
-        comma_controller.set_mode(registers::OperationMode::Normal).await.unwrap();
+        if let Err(err) = configure_comma_controller(&mut comma_controller).await {
+            errors::report(protocol::Source::Comma, errors::Module::Setup, errors::ErrorCode::SetupFailed, err.into(), 1).await;
+            CONTROLLERS_SETTLED.fetch_add(1, portable_atomic::Ordering::Relaxed);
+            return;
+        }
         Timer::after_millis(500).await;
     }
-    spawner.must_spawn(comma_car_on_task(comma_controller, int, car_off_since));
+    boot::COMMA_BUS_UP.store(true, portable_atomic::Ordering::Relaxed);
+    CONTROLLERS_SETTLED.fetch_add(1, portable_atomic::Ordering::Relaxed);
+    spawner.must_spawn(comma_interrupt_task(comma_controller, int, car_off_since));
+    spawner.must_spawn(commands::command_task(flash));
+    spawner.must_spawn(config_service::config_service_task(flash));
+    spawner.must_spawn(diag_server::diag_server_task(comma_controller));
+    spawner.must_spawn(bus_health_task(comma_controller, 0x7B1, protocol::Source::Comma, &COMMA_RX_FIFOS, &mcp::COMMA_RX_OVERFLOWS, &tx_events::COMMA_TX, &power::COMMA_POWER));
+    spawner.must_spawn(bit_rate_task("Comma", protocol::Source::Comma, comma_controller, &config::COMMA_BIT_RATE_CHANGES, config::BusMode::Normal));
+    spawner.must_spawn(power::power_task("Comma", protocol::Source::Comma, comma_controller, stby, &power::COMMA_POWER, config::BusMode::Normal));
 
-    loop {
-        let (forward_addr, forward_data) = FORWARDING_CHANNEL.receive().await;
-        let forward_frame = Frame::new(forward_addr, forward_data.as_slice()).unwrap();
-
-        debug!("Forwarding {} bytes to address {:x}", forward_data.len(), forward_addr.as_raw());
-
-        match comma_controller.lock().await.transmit::<TRANSMIT_FIFO>(&forward_frame).await {
-            Ok(()) => {},
+    async fn forward(comma_controller: &mut CanController, session: session::Session, priority: bool, forward_addr: StandardId, forward_data: &[u8]) {
+        // With logs going out over CAN every forwarded message would log another one
+        if !cfg!(feature = "can-log") {
+            debug!("Forwarding {} bytes to address {:x}", forward_data.len(), forward_addr.as_raw());
+        }
+        let fifo = if priority { TXQ } else { TRANSMIT_FIFO };
+        let result = if session.has(session::CAP_FD) {
+            // FD with bit-rate switching, so the payload goes out at the data phase rate
+            mcp::transmit_fd(comma_controller, fifo, forward_addr.into(), forward_data, true).await
+        }
+        else {
+            match Frame::new(forward_addr, forward_data) {
+                Some(forward_frame) if priority => comma_controller.transmit::<TXQ>(&forward_frame).await,
+                Some(forward_frame) => comma_controller.transmit::<TRANSMIT_FIFO>(&forward_frame).await,
+                // Longer than a classic frame without an FD session, the forwarder should never hand us one of these
+                None => Err(mcp25xxfd::Error::ControllerError("Payload too long for a classic frame")),
+            }
+        };
+        match result {
+            Ok(()) => {
+                tx_events::COMMA_TX.record(forward_addr.into());
+                stats::COMMA.transmitted(fifo);
+            },
             Err(err) => {
                 error!("Forwarding error: {}", err);
             }
         }
     }
+
+    let mut e2e_protector = e2e::E2EProtector::new();
+    let mut sequences = protocol::Sequences::new();
+    let mut batcher = batch::Batcher::new();
+    loop {
+        // The heartbeat wakes this up every second even with nothing else to forward
+        supervisor::pet(supervisor::Task::Forwarder);
+        // select4() polls the priority channel first, so it always wins when both have something queued. Retransmits
+        // keep the sequence number they were first sent with.
+        let (priority, message, retransmit) = match select4(
+            PRIORITY_FORWARDING_CHANNEL.receive(),
+            FORWARDING_QUEUE.receive(),
+            batcher.wait(),
+            ack::next_retransmit(),
+        ).await {
+            Either4::First(message) => (true, message, None),
+            Either4::Second(message) => {
+                let session = session::current();
+                // Diagnostics don't wait for a batch to fill up
+                if !session.has(session::CAP_BATCHING) || forwarding::class(message.message_type) == forwarding::Class::Diagnostics {
+                    (false, message, None)
+                }
+                else {
+                    // A multiplexed batch still has to fit in one frame
+                    let mux_header = if session.has(session::CAP_MUX) { mux::MUX_HEADER_LENGTH } else { 0 };
+                    match batcher.add(message, session.max_forward_payload().saturating_sub(mux_header)) {
+                        Some(message) => (false, message, None),
+                        None => continue,
+                    }
+                }
+            },
+            Either4::Third(()) => (false, batcher.take().unwrap(), None),
+            Either4::Fourth((message, sequence)) => (true, message, Some(sequence)),
+        };
+        if power::COMMA_POWER.is_asleep() {
+            debug!("Comma link asleep, dropping frame for {:x}", message.id.as_raw());
+            continue;
+        }
+        // Only use what was negotiated with the host
+        let session = session::current();
+        // Retransmits were already multiplexed the first time around
+        let message = if session.has(session::CAP_MUX) && retransmit.is_none() { mux::wrap(message) } else { message };
+        let forward_addr = message.id;
+        // The controller stays locked for every segment of a message, so nothing else gets sent in between
+        let mut comma_controller = comma_controller.lock().await;
+        if session.framed() {
+            let sequence = retransmit.unwrap_or_else(|| sequences.next(message.source));
+            // Only repetitive, high-rate data is worth the trouble
+            let compress = session.has(session::CAP_COMPRESSION)
+                && matches!(message.message_type, protocol::MessageType::EcuData | protocol::MessageType::RawFrame);
+            let segments = protocol::encode(&message, sequence, clock::wall_clock(message.timestamp), compress, session.max_payload as usize);
+            for segment in segments {
+                forward(&mut comma_controller, session, priority, forward_addr, &segment).await;
+            }
+            if retransmit.is_none() && session.has(session::CAP_ACK) && ack::needs_ack(message.message_type) {
+                ack::sent(&message, sequence);
+            }
+        }
+        else {
+            // Hosts without the framing can't reassemble segments
+            let mut forward_data = message.payload.as_slice();
+            let max_payload = session.max_forward_payload();
+            if forward_data.len() > max_payload {
+                warn!("Truncating {} byte payload for {:x} to the session's {} byte limit", forward_data.len(), forward_addr.as_raw(), max_payload);
+                forward_data = &forward_data[..max_payload];
+            }
+            if session.has(session::CAP_E2E) {
+                let protected = e2e_protector.protect(forward_addr.as_raw(), forward_data);
+                forward(&mut comma_controller, session, priority, forward_addr, &protected).await;
+            }
+            else {
+                forward(&mut comma_controller, session, priority, forward_addr, forward_data).await;
+            }
+        }
+        if let Err(err) = tx_events::COMMA_TX.service(&mut comma_controller).await {
+            error!("Unable to read TX events: {}", err);
+        }
+    }
 }
 
 #[embassy_executor::task]
-async fn comma_car_on_task(
-    comma_controller: &'static Mutex<CriticalSectionRawMutex, MCP25xxFD<SpiDevice<'_, CriticalSectionRawMutex, SPI0Type<SPI0>, Output<'_>>>>,
+async fn comma_interrupt_task(
+    comma_controller: &'static Mutex<CriticalSectionRawMutex, CanController>,
     mut int: Input<'static>,
     car_off_since: &'static Mutex<CriticalSectionRawMutex, Option<Instant>>,
 ) {
+    // Ignition frames come in continuously, checking once a second is plenty
+    let mut ignition_ticker = Ticker::every(Duration::from_secs(1));
     loop {
-        // Wait for interrupt pin to go low (aka active) before calling receive so we don't spinlock
-        int.wait_for_low().await;
-        if let Ok(Some(_)) = comma_controller.lock().await.receive(Some(IGNITION_FIFO)).await {
-            debug!("Car ignition detected via CAN 0");
-            *car_off_since.lock().await = None;
+        supervisor::pet(supervisor::Task::CommaInterrupt);
+        // INT only asserts for commands (and errors), the ignition FIFO is checked on its own schedule
+        let check_ignition = match select(int.wait_for_low(), ignition_ticker.next()).await {
+            Either::First(_) => false,
+            Either::Second(_) => true,
+        };
+        if check_ignition && power::COMMA_POWER.is_asleep() {
+            continue;
+        }
+        let mut comma_controller = comma_controller.lock().await;
+        if let Err(err) = mcp::service_rx_overflows(&mut comma_controller, &mcp::COMMA_RX_OVERFLOWS).await {
+            error!("Unable to check RX overflows: {}", err);
+        }
+        match mcp::service_ecc_errors(&mut comma_controller).await {
+            Ok(None) => {},
+            Ok(Some(ecc_error)) => warn!("Comma controller RAM error: {}", ecc_error),
+            Err(err) => error!("Unable to check ECC status: {}", err),
+        }
+        match mcp::service_wake(&mut comma_controller).await {
+            Ok(true) => power::COMMA_POWER.woke(),
+            Ok(false) => {},
+            Err(err) => error!("Unable to check wake-up interrupt: {}", err),
+        }
+        // Only the wake-up interrupt is looked at while the controller sleeps, the FIFOs wait until it's back
+        if power::COMMA_POWER.is_asleep() {
+            continue;
+        }
+        service_abandoned_transmits("Comma", 1, &mut comma_controller).await;
+        let mut received_commands: Vec<(u32, Vec<u8, 64>), 8> = Vec::new();
+        while !received_commands.is_full() {
+            match comma_controller.receive(Some(COMMAND_FIFO)).await {
+                Ok(Some((_, frame))) => {
+                    stats::COMMA.received(COMMAND_FIFO);
+                    received_commands.push((frame.raw_id(), Vec::from_slice(frame.data()).unwrap())).unwrap();
+                },
+                _ => break,
+            }
+        }
+        stats::COMMA.drained(COMMAND_FIFO, received_commands.len());
+        if check_ignition {
+            // Empty the FIFO so the next check only sees frames from the last second
+            let mut ignition_frames = 0;
+            while let Ok(Some(_)) = comma_controller.receive(Some(IGNITION_FIFO)).await {
+                stats::COMMA.received(IGNITION_FIFO);
+                ignition_frames += 1;
+            }
+            stats::COMMA.drained(IGNITION_FIFO, ignition_frames);
+            if ignition_frames > 0 {
+                debug!("Car ignition detected via CAN 0");
+                *car_off_since.lock().await = None;
+            }
+        }
+        drop(comma_controller);
+        // Handled by command_task, config_service_task and diag_server_task since they all need the controller to reply
+        for (id, command) in received_commands {
+            if id == config_service::CONFIG_REQUEST_ID as u32 {
+                config_service::CONFIG_REQUESTS.send(command).await;
+            }
+            else if id == diag_server::DIAG_REQUEST_ID as u32 {
+                diag_server::REQUESTS.send(command).await;
+            }
+            else {
+                commands::COMMAND_CHANNEL.send(command).await;
+                memory::COMMAND_PEAK.record(commands::COMMAND_CHANNEL.len());
+            }
         }
-        Timer::after_millis(1000).await;
     }
 }
\ No newline at end of file
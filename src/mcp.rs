@@ -0,0 +1,492 @@
+// Register-level helpers for MCP25xxFD features the driver doesn't wrap (yet), built on raw SFR access
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use embedded_can::{ExtendedId, Id, StandardId};
+use mcp25xxfd::frame::Frame;
+use mcp25xxfd::Error;
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::config::{BitRates, DataBitRate, NominalBitRate};
+use crate::CanController;
+
+// SFR addresses (DS20005678)
+pub const C1CON: u16 = 0x000;
+pub const C1NBTCFG: u16 = 0x004;
+pub const C1DBTCFG: u16 = 0x008;
+pub const C1TDC: u16 = 0x00C;
+pub const C1TBC: u16 = 0x010;
+pub const C1TSCON: u16 = 0x014;
+pub const C1INT: u16 = 0x01C;
+pub const C1RXIF: u16 = 0x020;
+pub const C1RXOVIF: u16 = 0x028;
+pub const C1TXATIF: u16 = 0x02C;
+pub const C1TREC: u16 = 0x034;
+pub const C1BDIAG0: u16 = 0x038;
+pub const C1BDIAG1: u16 = 0x03C;
+pub const C1TEFCON: u16 = 0x040;
+pub const C1TEFSTA: u16 = 0x044;
+pub const C1TEFUA: u16 = 0x048;
+pub const C1FIFOCON: u16 = 0x05C;
+pub const C1FIFOSTA: u16 = 0x060;
+pub const C1FIFOUA: u16 = 0x064;
+pub const C1FLTCON: u16 = 0x1D0;
+pub const C1FLTOBJ: u16 = 0x1F0;
+pub const C1MASK: u16 = 0x1F4;
+pub const OSC: u16 = 0xE00;
+pub const ECCCON: u16 = 0xE0C;
+pub const ECCSTAT: u16 = 0xE10;
+pub const DEVID: u16 = 0xE14;
+
+// Start of message RAM, user addresses are relative to this
+const RAM_START: u16 = 0x400;
+
+const C1INT_RXOVIE: u32 = 1 << 27;
+const C1INT_ECCIE: u32 = 1 << 24;
+const C1INT_TXATIE: u32 = 1 << 26;
+const C1INT_WAKIE: u32 = 1 << 30;
+const C1INT_WAKIF: u32 = 1 << 14;
+const CON_WAKFIL: u32 = 1 << 8;
+const ECC_SECIE: u32 = 1 << 1;
+const ECC_DEDIE: u32 = 1 << 2;
+const ECC_SECIF: u32 = 1 << 1;
+const ECC_DEDIF: u32 = 1 << 2;
+const TEFCON_TEFTSEN: u32 = 1 << 5;
+const FIFOCON_TXEN: u32 = 1 << 7;
+const FIFOCON_FRESET: u32 = 1 << 10;
+const TEFCON_UINC: u32 = 1 << 8;
+const TEFSTA_TEFNEIF: u32 = 1 << 0;
+const OBJ_IDE: u32 = 1 << 4;
+const FIFOCON_RXOVIE: u32 = 1 << 3;
+const FIFOCON_RXTSEN: u32 = 1 << 5;
+const FIFOCON_TFNRFNIE: u32 = 1 << 0;
+const FIFOCON_TXATIE: u32 = 1 << 4;
+const FIFOCON_TXAT_MASK: u32 = 0b11 << 21;
+const FIFOCON_TXAT_THREE: u32 = 0b01 << 21;
+const FIFOSTA_TXLARB: u32 = 1 << 6;
+const FIFOSTA_TXATIF: u32 = 1 << 4;
+const FIFOSTA_RXOVIF: u32 = 1 << 3;
+const FIFOSTA_TFNRFNIF: u32 = 1 << 0;
+const FIFOCON_UINC: u32 = 1 << 8;
+const FIFOCON_TXREQ: u32 = 1 << 9;
+const OBJ_BRS: u32 = 1 << 6;
+const OBJ_FDF: u32 = 1 << 7;
+const FLTEN: u32 = 1 << 7;
+const EXIDE: u32 = 1 << 30;
+const MIDE: u32 = 1 << 30;
+const TDCMOD_AUTO: u32 = 0b10 << 16;
+const TSCON_TBCEN: u32 = 1 << 16;
+pub const OSC_OSCRDY: u32 = 1 << 10;
+// 20 MHz SYSCLK / (19 + 1) gives 1 us time base ticks
+const TBC_PRESCALER: u32 = 19;
+
+// FIFO registers are spaced 12 bytes apart starting at FIFO 1, "FIFO 0" lands on the matching TXQ register
+pub fn fifo_control_address(fifo: u8) -> u16 {
+    C1FIFOCON - 12 + fifo as u16 * 12
+}
+pub fn fifo_status_address(fifo: u8) -> u16 {
+    C1FIFOSTA - 12 + fifo as u16 * 12
+}
+pub fn fifo_user_address(fifo: u8) -> u16 {
+    C1FIFOUA - 12 + fifo as u16 * 12
+}
+
+// FLTOBJ/MASK layout: SID[10:0] in bits 0-10, EID[17:0] in bits 11-28
+pub fn encode_id(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => id.as_raw() as u32,
+        Id::Extended(id) => {
+            let raw = id.as_raw();
+            ((raw >> 18) & 0x7FF) | ((raw & 0x3FFFF) << 11) | EXIDE
+        },
+    }
+}
+pub fn decode_id(object_id: u32, extended: bool) -> Id {
+    let sid = object_id & 0x7FF;
+    if extended {
+        let eid = (object_id >> 11) & 0x3FFFF;
+        ExtendedId::new((sid << 18) | eid).unwrap().into()
+    }
+    else {
+        StandardId::new(sid as u16).unwrap().into()
+    }
+}
+// Matches every ID bit and the IDE bit
+pub const MASK_EXACT: u32 = 0x7FF | (0x3FFFF << 11) | MIDE;
+
+// Mask that only compares the set bits of the given ID (standard or extended, to match the filter's ID)
+pub fn partial_mask(bits: Id) -> u32 {
+    (encode_id(bits) & !EXIDE) | MIDE
+}
+
+// Filter ID and mask accepting every ID from first to last inclusive, e.g. 0x7E8-0x7EF. A single filter can only cover
+// a block whose size is a power of two and that starts on a multiple of its size, None otherwise.
+pub fn range_filter(first: Id, last: Id) -> Option<(Id, u32)> {
+    let (first_raw, last_raw, all_bits) = match (first, last) {
+        (Id::Standard(first), Id::Standard(last)) => (first.as_raw() as u32, last.as_raw() as u32, 0x7FF),
+        (Id::Extended(first), Id::Extended(last)) => (first.as_raw(), last.as_raw(), 0x1FFF_FFFF),
+        _ => return None,
+    };
+    let size = last_raw.checked_sub(first_raw)? + 1;
+    if !size.is_power_of_two() || first_raw % size != 0 {
+        return None;
+    }
+    let mask_bits = all_bits & !(size - 1);
+    let mask_id = match first {
+        Id::Standard(_) => StandardId::new(mask_bits as u16)?.into(),
+        Id::Extended(_) => ExtendedId::new(mask_bits)?.into(),
+    };
+    Some((first, partial_mask(mask_id)))
+}
+
+async fn modify_register(controller: &mut CanController, address: u16, modify: impl FnOnce(u32) -> u32) -> Result<(), Error> {
+    let value = controller.read_register(address).await?;
+    controller.write_register(address, modify(value)).await
+}
+
+fn filter_control_address(filter: u8) -> (u16, u32) {
+    // Each FLTCON register holds the control bytes for 4 filters
+    (C1FLTCON + (filter as u16 / 4) * 4, (filter as u32 % 4) * 8)
+}
+
+pub async fn disable_filter(controller: &mut CanController, filter: u8) -> Result<(), Error> {
+    let (address, shift) = filter_control_address(filter);
+    modify_register(controller, address, |value| value & !(FLTEN << shift)).await
+}
+
+// Filters can be rewritten while the controller stays in normal mode as long as they're disabled first
+pub async fn set_filter(controller: &mut CanController, filter: u8, fifo: u8, id: Id, mask: u32) -> Result<(), Error> {
+    disable_filter(controller, filter).await?;
+    controller.write_register(C1FLTOBJ + filter as u16 * 8, encode_id(id)).await?;
+    controller.write_register(C1MASK + filter as u16 * 8, mask).await?;
+    let (address, shift) = filter_control_address(filter);
+    modify_register(controller, address, |value| {
+        (value & !(0xFF << shift)) | ((FLTEN | (fifo as u32 & 0x1F)) << shift)
+    }).await
+}
+
+#[derive(Format)]
+pub struct ErrorCounters {
+    pub tec: u8,
+    pub rec: u8,
+    // C1TREC bits 16-21: EWARN, RXWARN, TXWARN, RXBP, TXBP, TXBO
+    pub state_flags: u8,
+    pub nominal_tx_errors: u8,
+    pub nominal_rx_errors: u8,
+    pub data_tx_errors: u8,
+    pub data_rx_errors: u8,
+    // C1BDIAG1 bits 16-31: which kinds of errors occurred
+    pub error_flags: u16,
+    pub error_free_messages: u16,
+}
+
+// Reads TEC/REC and the bus diagnostic registers, then clears the diagnostic registers so the next read only
+// covers what happened since this one
+pub async fn read_error_counters(controller: &mut CanController) -> Result<ErrorCounters, Error> {
+    let trec = controller.read_register(C1TREC).await?;
+    let bdiag0 = controller.read_register(C1BDIAG0).await?;
+    let bdiag1 = controller.read_register(C1BDIAG1).await?;
+    controller.write_register(C1BDIAG0, 0).await?;
+    controller.write_register(C1BDIAG1, 0).await?;
+
+    Ok(ErrorCounters {
+        tec: (trec >> 8) as u8,
+        rec: trec as u8,
+        state_flags: ((trec >> 16) & 0x3F) as u8,
+        nominal_tx_errors: (bdiag0 >> 8) as u8,
+        nominal_rx_errors: bdiag0 as u8,
+        data_tx_errors: (bdiag0 >> 24) as u8,
+        data_rx_errors: (bdiag0 >> 16) as u8,
+        error_flags: (bdiag1 >> 16) as u16,
+        error_free_messages: bdiag1 as u16,
+    })
+}
+
+pub struct RxOverflowCounters([AtomicU32; 32]);
+impl RxOverflowCounters {
+    pub const fn new() -> Self {
+        Self([const { AtomicU32::new(0) }; 32])
+    }
+    pub fn get(&self, fifo: u8) -> u32 {
+        self.0[fifo as usize].load(Ordering::Relaxed)
+    }
+}
+pub static OBD_RX_OVERFLOWS: RxOverflowCounters = RxOverflowCounters::new();
+pub static COMMA_RX_OVERFLOWS: RxOverflowCounters = RxOverflowCounters::new();
+
+// Must be called after the FIFOs are configured
+pub async fn enable_rx_overflow_interrupts(controller: &mut CanController, fifos: &[u8]) -> Result<(), Error> {
+    for &fifo in fifos {
+        modify_register(controller, fifo_control_address(fifo), |value| value | FIFOCON_RXOVIE).await?;
+    }
+    modify_register(controller, C1INT, |value| value | C1INT_RXOVIE).await
+}
+
+// Stops a FIFO that's only ever sampled from asserting INT whenever it has something in it
+pub async fn disable_rx_interrupt(controller: &mut CanController, fifo: u8) -> Result<(), Error> {
+    modify_register(controller, fifo_control_address(fifo), |value| value & !FIFOCON_TFNRFNIE).await
+}
+
+// Gives up on a frame after three attempts instead of retrying forever (no ACK, bus off, lost arbitration), and
+// interrupts when that happens. Only takes effect with restrict_retx_attempts set in the controller config.
+pub async fn limit_tx_attempts(controller: &mut CanController, fifos: &[u8]) -> Result<(), Error> {
+    for &fifo in fifos {
+        modify_register(controller, fifo_control_address(fifo), |value| {
+            (value & !FIFOCON_TXAT_MASK) | FIFOCON_TXAT_THREE | FIFOCON_TXATIE
+        }).await?;
+    }
+    modify_register(controller, C1INT, |value| value | C1INT_TXATIE).await
+}
+
+// Clears any pending TX attempt interrupts. Returns the bitmasks of FIFOs (bit 0 is the TXQ) that abandoned a frame and
+// of those that lost arbitration on the last attempt.
+pub async fn service_tx_attempts(controller: &mut CanController) -> Result<(u32, u32), Error> {
+    let abandoned = controller.read_register(C1TXATIF).await?;
+    let mut lost_arbitration = 0;
+    for fifo in (0..32u8).filter(|fifo| abandoned & (1 << fifo) != 0) {
+        if controller.read_register(fifo_status_address(fifo)).await? & FIFOSTA_TXLARB != 0 {
+            lost_arbitration |= 1 << fifo;
+        }
+        modify_register(controller, fifo_status_address(fifo), |value| value & !FIFOSTA_TXATIF).await?;
+    }
+    Ok((abandoned, lost_arbitration))
+}
+
+// Counts and clears any pending RX overflows so the interrupt deasserts. Returns the bitmask of overflowed FIFOs.
+pub async fn service_rx_overflows(controller: &mut CanController, counters: &RxOverflowCounters) -> Result<u32, Error> {
+    let overflowed = controller.read_register(C1RXOVIF).await?;
+    for fifo in (1..32u8).filter(|fifo| overflowed & (1 << fifo) != 0) {
+        counters.0[fifo as usize].fetch_add(1, Ordering::Relaxed);
+        modify_register(controller, fifo_status_address(fifo), |value| value & !FIFOSTA_RXOVIF).await?;
+    }
+    Ok(overflowed)
+}
+
+// Interrupt on both corrected single-bit and uncorrectable double-bit RAM errors
+pub async fn enable_ecc_interrupts(controller: &mut CanController) -> Result<(), Error> {
+    modify_register(controller, ECCCON, |value| value | ECC_SECIE | ECC_DEDIE).await?;
+    modify_register(controller, C1INT, |value| value | C1INT_ECCIE).await
+}
+
+// Assert INT when bus activity wakes the controller from sleep, must be called in configuration mode. The wake-up
+// filter keeps short glitches from waking it.
+pub async fn enable_wake_interrupt(controller: &mut CanController) -> Result<(), Error> {
+    modify_register(controller, C1CON, |value| value | CON_WAKFIL).await?;
+    modify_register(controller, C1INT, |value| value | C1INT_WAKIE).await
+}
+
+// Clears a pending wake-up interrupt, returning whether there was one
+pub async fn service_wake(controller: &mut CanController) -> Result<bool, Error> {
+    let interrupts = controller.read_register(C1INT).await?;
+    if interrupts & C1INT_WAKIF == 0 {
+        return Ok(false);
+    }
+    // Only the flag bits are cleared by writing zero, everything else is written back unchanged
+    controller.write_register(C1INT, interrupts & !C1INT_WAKIF).await?;
+    Ok(true)
+}
+
+#[derive(Format)]
+pub struct EccError {
+    pub double_bit: bool,
+    pub address: u16,
+    // FIFO whose RAM the error was in, 0 for the TXQ, None for the TEF or unallocated RAM
+    pub fifo: Option<u8>,
+}
+
+// Walks the message RAM layout (TEF, TXQ, then FIFOs 1-31 back to back) to find which FIFO owns an address
+async fn fifo_at_address(controller: &mut CanController, address: u16) -> Result<Option<u8>, Error> {
+    // PLSIZE counts up through the same lengths as the FD DLCs starting at 8
+    let payload_size = |control: u32| dlc_length(((control >> 29) & 0x7) + 8);
+    let depth = |control: u32| ((control >> 24) & 0x1F) as u16 + 1;
+
+    let tef_control = controller.read_register(C1TEFCON).await?;
+    let mut end = RAM_START + depth(tef_control) * if tef_control & TEFCON_TEFTSEN != 0 { 12 } else { 8 };
+    if address < end {
+        return Ok(None);
+    }
+    for fifo in 0..32u8 {
+        let control = controller.read_register(fifo_control_address(fifo)).await?;
+        let header = if fifo != 0 && control & FIFOCON_TXEN == 0 && control & FIFOCON_RXTSEN != 0 { 12 } else { 8 };
+        end += depth(control) * (header + payload_size(control) as u16);
+        if address < end {
+            return Ok(Some(fifo));
+        }
+    }
+    Ok(None)
+}
+
+// Logs and clears any pending ECC error. Single-bit errors were already corrected, but a double-bit error means a
+// corrupted message object, so the FIFO holding it is reset rather than handing out garbage. A bad TEF entry only
+// costs one TX confirmation, which the tracker times out on its own.
+pub async fn service_ecc_errors(controller: &mut CanController) -> Result<Option<EccError>, Error> {
+    let status = controller.read_register(ECCSTAT).await?;
+    if status & (ECC_SECIF | ECC_DEDIF) == 0 {
+        return Ok(None);
+    }
+    controller.write_register(ECCSTAT, status & !(ECC_SECIF | ECC_DEDIF)).await?;
+    let address = ((status >> 16) & 0xFFF) as u16;
+    let double_bit = status & ECC_DEDIF != 0;
+    let fifo = fifo_at_address(controller, address).await?;
+    if let (true, Some(fifo)) = (double_bit, fifo) {
+        modify_register(controller, fifo_control_address(fifo), |value| value | FIFOCON_FRESET).await?;
+    }
+    Ok(Some(EccError { double_bit, address, fifo }))
+}
+
+// Overrides the bit timing the driver applied, must be called in configuration mode. All values assume the 20 MHz
+// clock, nominal timings are 40 TQ per bit at 80% sample point and data timings use BRP 0 so TDC can measure in SYSCLKs.
+pub async fn set_bit_rates(controller: &mut CanController, bit_rates: BitRates) -> Result<(), Error> {
+    // (BRP, TSEG1, TSEG2, SJW), all minus one
+    let (brp, tseg1, tseg2, sjw) = match bit_rates.nominal {
+        NominalBitRate::Kbps500 => (0, 30, 7, 7),
+        NominalBitRate::Kbps250 => (1, 30, 7, 7),
+        NominalBitRate::Kbps125 => (3, 30, 7, 7),
+    };
+    controller.write_register(C1NBTCFG, (brp << 24) | (tseg1 << 16) | (tseg2 << 8) | sjw).await?;
+
+    let (brp, tseg1, tseg2, sjw) = match bit_rates.data {
+        // 10 TQ, 80% sample point
+        DataBitRate::Mbps2 => (0, 6, 1, 1),
+        // 4 TQ, 75% sample point
+        DataBitRate::Mbps5 => (0, 1, 0, 0),
+    };
+    controller.write_register(C1DBTCFG, (brp << 24) | (tseg1 << 16) | (tseg2 << 8) | sjw).await?;
+    // Secondary sample point at the data phase sample point, transceiver delay measured automatically
+    controller.write_register(C1TDC, TDCMOD_AUTO | ((tseg1 + 1) << 8)).await
+}
+
+// Rounds a payload length up to the nearest length a CAN FD DLC can encode, returning (DLC, padded length)
+fn fd_dlc(length: usize) -> (u32, usize) {
+    match length {
+        0..=8 => (length as u32, length),
+        9..=12 => (9, 12),
+        13..=16 => (10, 16),
+        17..=20 => (11, 20),
+        21..=24 => (12, 24),
+        25..=32 => (13, 32),
+        33..=48 => (14, 48),
+        _ => (15, 64),
+    }
+}
+
+fn dlc_length(dlc: u32) -> usize {
+    match dlc {
+        0..=8 => dlc as usize,
+        9 => 12,
+        10 => 16,
+        11 => 20,
+        12 => 24,
+        13 => 32,
+        14 => 48,
+        _ => 64,
+    }
+}
+
+// The driver only builds classic frames, so FD frames are written into the TX FIFO's message RAM directly. Payloads
+// are zero-padded up to the next valid FD length. The FIFO has to be configured for at least that payload size.
+pub async fn transmit_fd(controller: &mut CanController, fifo: u8, id: Id, data: &[u8], bit_rate_switch: bool) -> Result<(), Error> {
+    if data.len() > 64 {
+        return Err(Error::ControllerError("FD payload longer than 64 bytes"));
+    }
+    if controller.read_register(fifo_status_address(fifo)).await? & FIFOSTA_TFNRFNIF == 0 {
+        return Err(Error::ControllerError("TX FIFO full"));
+    }
+    let (dlc, length) = fd_dlc(data.len());
+    let mut flags = dlc | OBJ_FDF;
+    if bit_rate_switch {
+        flags |= OBJ_BRS;
+    }
+    if let Id::Extended(_) = id {
+        flags |= OBJ_IDE;
+    }
+
+    let object_address = RAM_START + controller.read_register(fifo_user_address(fifo)).await? as u16;
+    // T0 uses the same ID layout as the filter objects, minus the filter-only EXIDE bit
+    controller.write_register(object_address, encode_id(id) & !EXIDE).await?;
+    controller.write_register(object_address + 4, flags).await?;
+    // Classic lengths 1-3 and 5-7 end in a partial word
+    for word in 0..length.div_ceil(4) {
+        let mut bytes = [0u8; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = data.get(word * 4 + i).copied().unwrap_or(0);
+        }
+        controller.write_register(object_address + 8 + word as u16 * 4, u32::from_le_bytes(bytes)).await?;
+    }
+    modify_register(controller, fifo_control_address(fifo), |value| value | FIFOCON_UINC | FIFOCON_TXREQ).await
+}
+
+// Starts the time base counter that RX timestamps are taken from
+pub async fn enable_time_base(controller: &mut CanController) -> Result<(), Error> {
+    controller.write_register(C1TSCON, TSCON_TBCEN | TBC_PRESCALER).await
+}
+
+// Stores a timestamp with every message received into these FIFOs, must be called in configuration mode. The driver
+// doesn't expect the extra word in the RX object, so these FIFOs have to be read with receive() below.
+pub async fn enable_rx_timestamps(controller: &mut CanController, fifos: &[u8]) -> Result<(), Error> {
+    for &fifo in fifos {
+        modify_register(controller, fifo_control_address(fifo), |value| value | FIFOCON_RXTSEN).await?;
+    }
+    Ok(())
+}
+
+// Bitmask of FIFOs with a pending RX interrupt (i.e. not empty)
+pub async fn pending_rx_fifos(controller: &mut CanController) -> Result<u32, Error> {
+    controller.read_register(C1RXIF).await
+}
+
+// Timestamped stand-in for the driver's receive(). Reads from the given FIFO, or the lowest-numbered one with something
+// in it, and converts the time base timestamp to the local monotonic clock.
+pub async fn receive(controller: &mut CanController, fifo: Option<u8>) -> Result<Option<(u8, Frame, Instant)>, Error> {
+    let fifo = match fifo {
+        Some(fifo) => {
+            if controller.read_register(fifo_status_address(fifo)).await? & FIFOSTA_TFNRFNIF == 0 {
+                return Ok(None);
+            }
+            fifo
+        },
+        None => {
+            let pending = controller.read_register(C1RXIF).await?;
+            if pending == 0 {
+                return Ok(None);
+            }
+            pending.trailing_zeros() as u8
+        },
+    };
+    let object_address = RAM_START + controller.read_register(fifo_user_address(fifo)).await? as u16;
+    let object_id = controller.read_register(object_address).await?;
+    let object_flags = controller.read_register(object_address + 4).await?;
+    let timestamp = controller.read_register(object_address + 8).await?;
+    let length = dlc_length(object_flags & 0xF);
+    // Classic frames can have a DLC up to 15 but never carry more than 8 bytes
+    let length = if object_flags & OBJ_FDF != 0 { length } else { length.min(8) };
+    let mut data = [0u8; 64];
+    for word in 0..length.div_ceil(4) {
+        let value = controller.read_register(object_address + 12 + word as u16 * 4).await?;
+        data[word * 4..word * 4 + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    modify_register(controller, fifo_control_address(fifo), |value| value | FIFOCON_UINC).await?;
+
+    // The counter wraps every ~71 minutes, far longer than anything sits in a FIFO
+    let age = controller.read_register(C1TBC).await?.wrapping_sub(timestamp);
+    let received_at = Instant::now() - Duration::from_micros(age as u64);
+    let frame = Frame::new(decode_id(object_id, object_flags & OBJ_IDE != 0), &data[..length])
+        .ok_or(Error::ControllerError("Received frame can't be represented"))?;
+    Ok(Some((fifo, frame, received_at)))
+}
+
+// Sets the TEF depth, must be called in configuration mode before the controller allocates its message RAM
+pub async fn configure_tx_event_fifo(controller: &mut CanController, depth: u8) -> Result<(), Error> {
+    modify_register(controller, C1TEFCON, |value| (value & !(0x1F << 24)) | (((depth as u32 - 1) & 0x1F) << 24)).await
+}
+
+// Pops the oldest TX event, returning the ID of the frame that made it onto the bus
+pub async fn read_tx_event(controller: &mut CanController) -> Result<Option<Id>, Error> {
+    if controller.read_register(C1TEFSTA).await? & TEFSTA_TEFNEIF == 0 {
+        return Ok(None);
+    }
+    let object_address = RAM_START + controller.read_register(C1TEFUA).await? as u16;
+    let object_id = controller.read_register(object_address).await?;
+    let object_flags = controller.read_register(object_address + 4).await?;
+    modify_register(controller, C1TEFCON, |value| value | TEFCON_UINC).await?;
+    Ok(Some(decode_id(object_id, object_flags & OBJ_IDE != 0)))
+}
@@ -0,0 +1,69 @@
+use portable_atomic::{AtomicU8, Ordering};
+
+// Embassy tasks don't get stacks of their own: they're polled one after another on the main stack, which interrupt
+// handlers share too, and their state lives in the executor's task arena, which is sized at build time (a task that
+// doesn't fit fails to spawn at boot). So the margins worth watching at runtime are the one stack and the fixed-size
+// queues between tasks. The stack is painted at boot and the untouched part measured later.
+
+const PAINT: u32 = 0xDEAD_BEEF;
+// Left alone below the stack pointer while painting, for whatever paint_stack() itself and interrupts push
+const PAINT_MARGIN: usize = 512;
+const RAM_ORIGIN: usize = 0x2000_0000;
+
+extern "C" {
+    // End of the statics, from cortex-m-rt's link.x
+    static __sheap: u32;
+}
+
+// Lowest address the stack can grow down to. flip-link (see .cargo/config.toml) puts the stack below the statics, at
+// the start of RAM, without it the stack grows down towards the end of the statics.
+fn stack_limit() -> usize {
+    let statics_end = unsafe { &__sheap as *const u32 as usize };
+    if statics_end < cortex_m::register::msp::read() as usize { statics_end } else { RAM_ORIGIN }
+}
+
+// Call once, first thing at boot
+pub fn paint_stack() {
+    let stack_pointer = cortex_m::register::msp::read() as usize;
+    let mut address = stack_limit();
+    while address + PAINT_MARGIN < stack_pointer {
+        unsafe { core::ptr::write_volatile(address as *mut u32, PAINT) };
+        address += 4;
+    }
+}
+
+// Bytes of stack that have never been used since boot
+pub fn stack_margin() -> u32 {
+    let stack_pointer = cortex_m::register::msp::read() as usize;
+    let mut address = stack_limit();
+    while address < stack_pointer && unsafe { core::ptr::read_volatile(address as *const u32) } == PAINT {
+        address += 4;
+    }
+    (address - stack_limit()) as u32
+}
+
+// Deepest a queue between tasks has been since boot, next to its capacity
+pub struct QueuePeak {
+    peak: AtomicU8,
+    pub capacity: u8,
+}
+impl QueuePeak {
+    pub const fn new(capacity: usize) -> Self {
+        Self { peak: AtomicU8::new(0), capacity: capacity as u8 }
+    }
+
+    pub fn record(&self, depth: usize) {
+        self.peak.fetch_max(depth.min(u8::MAX as usize) as u8, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u8 {
+        self.peak.load(Ordering::Relaxed)
+    }
+}
+
+pub static FORWARDING_QUEUE_PEAK: QueuePeak = QueuePeak::new(crate::forwarding::QUEUE_DEPTH);
+pub static OBD_RX_PEAK: QueuePeak = QueuePeak::new(crate::OBD_RX_CHANNEL_DEPTH);
+pub static COMMAND_PEAK: QueuePeak = QueuePeak::new(crate::commands::COMMAND_CHANNEL_DEPTH);
+// Transmissions queued in a controller's TXQ and TX FIFO that haven't shown up in its TX event FIFO yet, see
+// tx_events.rs
+pub static OBD_TX_PEAK: QueuePeak = QueuePeak::new(crate::OBD_TX_OBJECTS);
+pub static COMMA_TX_PEAK: QueuePeak = QueuePeak::new(crate::COMMA_TX_OBJECTS);
@@ -0,0 +1,47 @@
+use heapless::Vec;
+
+use crate::protocol::{Message, MessageType, MAX_MESSAGE_LENGTH};
+
+// With CAP_MUX negotiated everything forwarded to the comma device shares one CAN ID, and each payload starts with
+// [stream ID, forwarding ID (2 bytes)]. The stream ID lets the host hand whole categories to their consumers without a
+// table of CAN IDs, the forwarding ID still tells the messages within a stream apart.
+pub const MUX_FORWARDING_ID: u16 = 0x6F2;
+pub const MUX_HEADER_LENGTH: usize = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stream {
+    // Command responses
+    Control = 0x00,
+    // Error reports, alerts, self tests and abandoned transmits
+    Log = 0x01,
+    // Bus health and heartbeats
+    Stats = 0x02,
+    // ECU data, DTCs, one-shot reads and gateway responses
+    Uds = 0x03,
+    // Environment, motion, GPS and supply voltage
+    Environment = 0x04,
+    RawFrames = 0x05,
+    Batch = 0x06,
+}
+
+pub fn stream(message_type: MessageType) -> Stream {
+    match message_type {
+        MessageType::CommandResponse => Stream::Control,
+        MessageType::ControllerError | MessageType::Alert | MessageType::SelfTest | MessageType::TxAbandoned | MessageType::CrashReport | MessageType::Log | MessageType::Blackbox | MessageType::BusErrors => Stream::Log,
+        MessageType::BusHealth | MessageType::Heartbeat | MessageType::Statistics => Stream::Stats,
+        MessageType::EcuData | MessageType::Dtc | MessageType::DidResponse | MessageType::GatewayResponse | MessageType::Signals => Stream::Uds,
+        MessageType::Environment | MessageType::Motion | MessageType::Position | MessageType::Supply => Stream::Environment,
+        MessageType::RawFrame => Stream::RawFrames,
+        MessageType::Batch => Stream::Batch,
+    }
+}
+
+// Moves the message onto the shared ID. Payloads already at MAX_MESSAGE_LENGTH lose their last few bytes.
+pub fn wrap(message: Message) -> Message {
+    let mut payload: Vec<u8, MAX_MESSAGE_LENGTH> = Vec::new();
+    payload.push(stream(message.message_type) as u8).unwrap();
+    payload.extend_from_slice(&message.id.as_raw().to_be_bytes()).unwrap();
+    let length = message.payload.len().min(MAX_MESSAGE_LENGTH - MUX_HEADER_LENGTH);
+    payload.extend_from_slice(&message.payload[..length]).unwrap();
+    Message::new(MUX_FORWARDING_ID, message.message_type, message.source, payload).at(message.timestamp)
+}
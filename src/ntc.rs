@@ -0,0 +1,38 @@
+use embassy_rp::adc::{Adc, Async, Channel};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use micromath::F32Ext;
+
+// An external 10 kΩ NTC thermistor (on the charge cable, the enclosure, wherever it's strapped to) from ADC3 to ground,
+// with a 10 kΩ pull-up to 3.3 V. Converted with the Steinhart-Hart equation and forwarded with the environment readings.
+// Not fitted reads as an open or shorted thermistor, and is left out.
+
+const PULL_UP_OHMS: f32 = 10_000.0;
+const ADC_FULL_SCALE: f32 = 4095.0;
+// Steinhart-Hart coefficients for a common 10 kΩ B3950 part
+const A: f32 = 1.009_249_5e-3;
+const B: f32 = 2.378_405_4e-4;
+const C: f32 = 2.019_202_7e-7;
+// Raw readings this close to either rail mean there's no thermistor, or it's shorted
+const RAIL_MARGIN: u16 = 40;
+const KELVIN: f32 = 273.15;
+
+pub struct Thermistor {
+    channel: Channel<'static>,
+}
+impl Thermistor {
+    pub fn new(channel: Channel<'static>) -> Self {
+        Self { channel }
+    }
+
+    // °C, None if it isn't connected or the ADC failed
+    pub async fn read(&mut self, adc: &Mutex<CriticalSectionRawMutex, Adc<'static, Async>>) -> Option<f32> {
+        let raw = adc.lock().await.read(&mut self.channel).await.ok()?;
+        if raw < RAIL_MARGIN || raw > ADC_FULL_SCALE as u16 - RAIL_MARGIN {
+            return None;
+        }
+        let ohms = PULL_UP_OHMS * raw as f32 / (ADC_FULL_SCALE - raw as f32);
+        let ln = ohms.ln();
+        Some(1.0 / (A + B * ln + C * ln * ln * ln) - KELVIN)
+    }
+}
@@ -0,0 +1,167 @@
+use core::cell::RefCell;
+
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant};
+use embedded_can::Id;
+use heapless::Vec;
+
+use crate::gateway;
+use crate::protocol::crc16;
+use crate::vehicle::{Vehicle, VehicleProfile};
+
+// ECUs in ECUAddresses order, host commands refer to them by these indexes
+pub const ECU_BMS: u8 = 0;
+pub const ECU_TPMS: u8 = 1;
+pub const ECU_HVAC: u8 = 2;
+pub const ECU_ADAS: u8 = 3;
+pub const ECU_ICCU: u8 = 4;
+pub const ECU_VCMS: u8 = 5;
+pub const ECU_DASH: u8 = 6;
+pub const ECU_IGPM: u8 = 7;
+pub const ECU_COUNT: u8 = 8;
+
+// Default periodic queries sent by obd_sender_task, from the vehicle profile. The host refers to them by their index in
+// this list. The ECUs and DIDs actually polled come from config::DeviceConfig::queries, and the forwarding IDs can be
+// remapped through config::ForwardingIds.
+pub const QUERIES: &[QueryDefinition] = Vehicle::QUERIES;
+pub const QUERY_COUNT: usize = QUERIES.len();
+// [ECU index, UDS response (service, DID, data...)]
+pub const ONE_SHOT_FORWARDING_ID: u16 = 0x7C0;
+// One-shot reads the ECU never answered are forgotten after this long
+const ONE_SHOT_TIMEOUT: Duration = Duration::from_secs(2);
+// Adaptive queries polled every cycle back off from this
+const ADAPTIVE_BASE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Generated by build.rs from queries/<profile>.csv
+pub struct QueryDefinition {
+    pub ecu: u8,
+    pub did: [u8; 2],
+    // Default seconds between sends, 0 polls it every cycle
+    pub interval: u16,
+    pub forwarding_id: u16,
+}
+
+// Host requests that change what the OBD sender polls, applied by obd_sender_task
+pub static QUERY_REQUESTS: Channel<CriticalSectionRawMutex, QueryRequest, 4> = Channel::new();
+
+#[derive(Format)]
+pub enum QueryRequest {
+    SetEnabled { index: u8, enabled: bool },
+    // Send the query at most once every this many seconds, 0 polls it every cycle
+    SetInterval { index: u8, interval: u16 },
+    // Back the query off while its response doesn't change, doubling the interval for every unchanged response up to
+    // this many seconds, and go straight back to the set interval once it does. 0 turns this off.
+    SetAdaptive { index: u8, max_interval: u16 },
+    ReadDid { ecu: u8, did: [u8; 2] },
+    // Diagnostic request from the comma device, see gateway.rs
+    Gateway { ecu: u8, request: Vec<u8, gateway::MAX_REQUEST_LENGTH> },
+    // Raw frame typed at the console, see console.rs
+    Raw { id: Id, data: Vec<u8, 8> },
+}
+
+#[derive(Clone, Copy)]
+struct QuerySlot {
+    enabled: bool,
+    interval: Duration,
+    max_interval: Option<Duration>,
+    last_sent: Option<Instant>,
+}
+
+pub struct Schedule {
+    slots: [QuerySlot; QUERY_COUNT],
+}
+impl Schedule {
+    pub const fn new() -> Self {
+        let mut slots = [QuerySlot { enabled: true, interval: Duration::from_secs(0), max_interval: None, last_sent: None }; QUERY_COUNT];
+        let mut index = 0;
+        while index < QUERY_COUNT {
+            slots[index].interval = Duration::from_secs(QUERIES[index].interval as u64);
+            index += 1;
+        }
+        Self { slots }
+    }
+    // One-shot reads and gateway requests aren't part of the schedule, those are handed back to the sender
+    pub fn apply(&mut self, request: QueryRequest) -> Option<QueryRequest> {
+        match request {
+            QueryRequest::SetEnabled { index, enabled } => self.slots[index as usize].enabled = enabled,
+            QueryRequest::SetInterval { index, interval } => {
+                self.slots[index as usize].interval = Duration::from_secs(interval as u64);
+            },
+            QueryRequest::SetAdaptive { index, max_interval } => {
+                self.slots[index as usize].max_interval = match max_interval {
+                    0 => None,
+                    seconds => Some(Duration::from_secs(seconds as u64)),
+                };
+            },
+            QueryRequest::ReadDid { .. } | QueryRequest::Gateway { .. } | QueryRequest::Raw { .. } => return Some(request),
+        }
+        None
+    }
+    fn interval(&self, index: usize) -> Duration {
+        let slot = &self.slots[index];
+        let Some(max_interval) = slot.max_interval else {
+            return slot.interval;
+        };
+        let unchanged = STABILITY.lock(|stability| stability.borrow()[index].1);
+        if unchanged == 0 {
+            return slot.interval;
+        }
+        let backed_off = slot.interval.max(ADAPTIVE_BASE_INTERVAL).as_ticks().saturating_mul(1 << unchanged.min(16));
+        Duration::from_ticks(backed_off).min(max_interval.max(slot.interval))
+    }
+    pub fn due(&self, index: usize) -> bool {
+        let slot = &self.slots[index];
+        slot.enabled && slot.last_sent.is_none_or(|sent| sent.elapsed() >= self.interval(index))
+    }
+    pub fn sent(&mut self, index: usize) {
+        self.slots[index].last_sent = Some(Instant::now());
+    }
+}
+
+// For each query: (CRC of the last response, responses in a row that came back the same)
+static STABILITY: Mutex<CriticalSectionRawMutex, RefCell<[(u16, u8); QUERY_COUNT]>> = Mutex::new(RefCell::new([(0, 0); QUERY_COUNT]));
+
+// Call with each periodic query's response data, adaptive queries slow down while it stays the same
+pub fn response_received(index: usize, data: &[u8]) {
+    let crc = crc16(data.iter().copied());
+    STABILITY.lock(|stability| {
+        let (last_crc, unchanged) = &mut stability.borrow_mut()[index];
+        *unchanged = if *last_crc == crc { unchanged.saturating_add(1) } else { 0 };
+        *last_crc = crc;
+    });
+}
+
+// One-shot reads waiting on a response: (ECU response address, ECU index, DID, sent at)
+static ONE_SHOTS: Mutex<CriticalSectionRawMutex, RefCell<Vec<(Id, u8, [u8; 2], Instant), 4>>> = Mutex::new(RefCell::new(Vec::new()));
+
+// Call once the read has been queued for transmission
+pub fn one_shot_sent(rx_addr: Id, ecu: u8, did: [u8; 2]) {
+    ONE_SHOTS.lock(|one_shots| {
+        let mut one_shots = one_shots.borrow_mut();
+        one_shots.retain(|(_, _, _, sent_at)| sent_at.elapsed() <= ONE_SHOT_TIMEOUT);
+        if one_shots.is_full() {
+            one_shots.remove(0);
+        }
+        one_shots.push((rx_addr, ecu, did, Instant::now())).ok();
+    });
+}
+
+// Checks a UDS response against the pending one-shot reads, returning the ECU index if it answers one of them
+pub fn take_one_shot(rx_addr: Id, response: &[u8]) -> Option<u8> {
+    ONE_SHOTS.lock(|one_shots| {
+        let mut one_shots = one_shots.borrow_mut();
+        let position = one_shots.iter().position(|(pending_addr, _, did, _)| {
+            *pending_addr == rx_addr && match response {
+                // Positive response to read data by identifier
+                [0x62, did_high, did_low, ..] => [*did_high, *did_low] == *did,
+                // Negative response to read data by identifier
+                [0x7F, 0x22, ..] => true,
+                _ => false,
+            }
+        })?;
+        Some(one_shots.remove(position).1)
+    })
+}
@@ -0,0 +1,172 @@
+use defmt::*;
+use embassy_rp::clocks;
+use embassy_rp::gpio::Output;
+use embassy_rp::pac;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use mcp25xxfd::registers;
+use portable_atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::config::BusMode;
+use crate::errors::{self, ErrorCode, Module};
+use crate::protocol::Source;
+use crate::CanController;
+
+// Device-wide power state. The node goes idle once the vehicle bus has been silent for SLEEP_AFTER_SILENCE, or the car
+// has been parked for SLEEP_AFTER_PARKED: both controllers and transceivers are put to sleep (which stops the queries)
+// and the sensors drop to IDLE_SAMPLE_INTERVAL. After DORMANT_AFTER_IDLE more the RP2040 itself goes dormant with every
+// clock stopped. Activity on either bus (the vehicle waking up, ignition frames or a command from the comma device)
+// wakes a controller, which asserts its INT pin, which wakes the RP2040, and everything goes back to full operation.
+const SLEEP_AFTER_SILENCE: Duration = Duration::from_secs(10 * 60);
+const SLEEP_AFTER_PARKED: Duration = Duration::from_secs(15 * 60);
+const DORMANT_AFTER_IDLE: Duration = Duration::from_secs(30 * 60);
+pub const IDLE_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+const STATE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// A controller that can't be switched back to its operation mode stays marked asleep and is retried this often
+const WAKE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+static IDLE: AtomicBool = AtomicBool::new(false);
+// Ticks of the last frame received from the vehicle, 0 (boot) until the first
+static LAST_VEHICLE_ACTIVITY: AtomicU64 = AtomicU64::new(0);
+
+pub fn is_idle() -> bool {
+    IDLE.load(Ordering::Relaxed)
+}
+
+// Call whenever frames come in from the vehicle bus
+pub fn vehicle_activity() {
+    LAST_VEHICLE_ACTIVITY.store(Instant::now().as_ticks(), Ordering::Relaxed);
+}
+
+fn vehicle_silent_for() -> Duration {
+    Instant::now() - Instant::from_ticks(LAST_VEHICLE_ACTIVITY.load(Ordering::Relaxed))
+}
+
+// Back to full operation, both controllers included. Counts as vehicle activity, so the node stays up for at least
+// SLEEP_AFTER_SILENCE to find out whether the car actually turned on.
+fn wake() {
+    vehicle_activity();
+    if IDLE.swap(false, Ordering::Relaxed) {
+        info!("Woke up, back to full operation");
+    }
+    OBD_POWER.wake.signal(());
+    COMMA_POWER.wake.signal(());
+}
+
+pub struct BusPower {
+    asleep: AtomicBool,
+    wake: Signal<CriticalSectionRawMutex, ()>,
+}
+impl BusPower {
+    const fn new() -> Self {
+        Self {
+            asleep: AtomicBool::new(false),
+            wake: Signal::new(),
+        }
+    }
+    // Anything that talks to the controller should hold off while this is set
+    pub fn is_asleep(&self) -> bool {
+        self.asleep.load(Ordering::Relaxed)
+    }
+    // Called from the receive loop once it sees the wake-up interrupt, wakes the rest of the node with it
+    pub fn woke(&self) {
+        wake();
+    }
+}
+pub static OBD_POWER: BusPower = BusPower::new();
+pub static COMMA_POWER: BusPower = BusPower::new();
+
+#[embassy_executor::task(pool_size = 2)]
+pub async fn power_task(
+    name: &'static str,
+    source: Source,
+    controller: &'static Mutex<CriticalSectionRawMutex, CanController>,
+    mut stby: Output<'static>,
+    power: &'static BusPower,
+    mode: BusMode,
+) {
+    loop {
+        Timer::after(STATE_CHECK_INTERVAL).await;
+        if !is_idle() {
+            continue;
+        }
+
+        {
+            let mut controller = controller.lock().await;
+            if let Err(err) = controller.set_mode(registers::OperationMode::Sleep).await {
+                error!("{}: unable to enter sleep mode: {}", name, err);
+                // Otherwise the node stays idle with this bus still running, back to full operation instead and
+                // try again once it's been silent long enough
+                wake();
+                continue;
+            }
+            power.asleep.store(true, Ordering::Relaxed);
+            power.wake.reset();
+            stby.set_high();
+        }
+        info!("{}: controller and transceiver asleep until bus activity", name);
+
+        power.wake.wait().await;
+        stby.set_low();
+        for attempt in 0u32.. {
+            // The controller wakes up into configuration mode
+            let result = controller.lock().await.set_mode(mode.operation_mode()).await;
+            match result {
+                Ok(()) => break,
+                // Only the first failure is forwarded so a dead controller doesn't flood the comma link
+                Err(err) if attempt == 0 => {
+                    errors::report(source, Module::Power, ErrorCode::ModeChangeFailed, err.into(), source as u32).await;
+                },
+                Err(err) => error!("{}: unable to leave sleep mode: {}", name, err),
+            }
+            Timer::after(WAKE_RETRY_INTERVAL).await;
+        }
+        power.asleep.store(false, Ordering::Relaxed);
+        info!("{}: awake", name);
+    }
+}
+
+// Stops every clock until one of the pins goes low. Time stands still meanwhile, as far as embassy-time and the
+// watchdog are concerned.
+fn dormant(wake_pins: [u8; 2]) {
+    let wake_on_low = |enabled: bool| {
+        for pin in wake_pins.map(usize::from) {
+            pac::IO_BANK0.dormant_wake_inte(pin / 8).modify(|w| w.set_level_low(pin % 8, enabled));
+        }
+    };
+    wake_on_low(true);
+    clocks::dormant_sleep();
+    wake_on_low(false);
+}
+
+// Moves the node between active, idle and dormant, see the top of the file. Wakes up from dormant on the INT pins.
+#[embassy_executor::task]
+pub async fn power_state_task(car_off_since: &'static Mutex<CriticalSectionRawMutex, Option<Instant>>, int_pins: [u8; 2]) {
+    let mut idle_since = Instant::now();
+    loop {
+        Timer::after(STATE_CHECK_INTERVAL).await;
+        let car_off_for = car_off_since.lock().await.map(|off_time| off_time.elapsed());
+        if !is_idle() {
+            let silent_for = vehicle_silent_for();
+            if silent_for < SLEEP_AFTER_SILENCE && car_off_for.is_none_or(|off_for| off_for < SLEEP_AFTER_PARKED) {
+                continue;
+            }
+            info!("Going idle, vehicle bus silent for {} s", silent_for.as_secs());
+            IDLE.store(true, Ordering::Relaxed);
+            idle_since = Instant::now();
+            continue;
+        }
+        if idle_since.elapsed() < DORMANT_AFTER_IDLE || !OBD_POWER.is_asleep() || !COMMA_POWER.is_asleep() {
+            continue;
+        }
+        info!("Idle for {} s, going dormant until bus activity", idle_since.elapsed().as_secs());
+        // Let the log out before the clocks stop
+        Timer::after_millis(100).await;
+        dormant(int_pins);
+        info!("Woke from dormant");
+        // Anything that didn't turn out to be bus activity waits out another DORMANT_AFTER_IDLE before the next try
+        idle_since = Instant::now();
+    }
+}
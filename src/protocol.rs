@@ -0,0 +1,513 @@
+use embassy_time::Instant;
+use embedded_can::StandardId;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+// Framing for everything forwarded to the comma device. Each frame carries a header in front of the payload:
+// [format version, message type, source, sequence, segment, flags, payload length, timestamp (4 bytes), wall clock
+// time (8 bytes, only with FLAG_WALL_CLOCK), CRC-8 over the other header bytes and payload]
+// and the last frame of a message carries a CRC-16 over the logical payload behind it. The timestamp is when the
+// message's data was received or produced, in microseconds since boot (rolling over every 71 minutes). Once the host
+// has synced the wall clock, the first frame of each message also carries that same moment in microseconds since the
+// Unix epoch. The CRC-16 is what catches mis-reassembly when a payload spans several frames, the CRC-8 only covers a
+// single frame. Frames keep going out on the same CAN IDs as before, the header just makes them self-describing.
+// Nothing in here depends on the rest of the firmware so a host-side decoder can include this file as-is.
+//
+// Payloads that don't fit in one frame are split into segments. The segment byte holds the segment's index, with
+// SEGMENT_MORE set on every segment but the last, so a message that fits in one frame has a segment byte of 0. The
+// segments of a message go out back to back on the same CAN ID with the same sequence number, and nothing else is
+// sent on the comma bus in between. A receiver appends segments in index order and checks the CRC-16 once the last one
+// is in; any segment that doesn't continue the message being reassembled discards it. Reassembler does exactly that.
+
+pub const FORMAT_VERSION: u8 = 4;
+// Without the wall clock time
+pub const HEADER_LENGTH: usize = 12;
+pub const WALL_CLOCK_LENGTH: usize = 8;
+pub const PAYLOAD_CRC_LENGTH: usize = 2;
+// Worst case, for a frame that carries the wall clock time
+pub const FRAMING_OVERHEAD: usize = HEADER_LENGTH + WALL_CLOCK_LENGTH + PAYLOAD_CRC_LENGTH;
+// Most payload a message can carry across all of its segments
+pub const MAX_MESSAGE_LENGTH: usize = 128;
+pub const SEGMENT_MORE: u8 = 0x80;
+pub const FLAG_WALL_CLOCK: u8 = 1 << 0;
+// The payload (all segments of it, reassembled) has to go through decompress(). The CRC-16 covers it as sent.
+pub const FLAG_COMPRESSED: u8 = 1 << 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum MessageType {
+    // Decoded ECU data (0x701-0x774)
+    EcuData = 0x01,
+    // DTCs that appeared or cleared, with freeze frame data (0x780-0x785)
+    Dtc = 0x02,
+    // Cabin temperature, pressure and humidity (0x7A0), a postcard-encoded Environment
+    Environment = 0x03,
+    Alert = 0x04,
+    // Error counters and FIFO statistics (0x7B0-0x7B1)
+    BusHealth = 0x05,
+    SelfTest = 0x06,
+    TxAbandoned = 0x07,
+    // Structured error reports (0x700), see errors.rs
+    ControllerError = 0x08,
+    // Subscribed, captured or sniffed raw vehicle frames (0x7F0-0x7F1)
+    RawFrame = 0x09,
+    CommandResponse = 0x0A,
+    // One-shot DID reads requested by the host (0x7C0)
+    DidResponse = 0x0B,
+    // Several small messages packed into one frame (0x7D0), see batch.rs
+    Batch = 0x0C,
+    // 1 Hz device status (0x7B4)
+    Heartbeat = 0x0D,
+    // Vehicle responses to diagnostic requests from the comma device (0x7C1)
+    GatewayResponse = 0x0E,
+    // Signals decoded from the periodic query responses (0x7D1), see signals.rs
+    Signals = 0x0F,
+    // Panic or hard fault from the previous run (0x7B5), see crash.rs
+    CrashReport = 0x10,
+    // Frame, ISO-TP and queue counters (0x7B6), see stats.rs
+    Statistics = 0x11,
+    // Encoded defmt log stream (0x7B7), see can_log.rs
+    Log = 0x12,
+    // Vehicle bus frames leading up to the last crash or bus-off (0x7B8), see blackbox.rs
+    Blackbox = 0x13,
+    // Timestamped error events from both buses (0x7B9), see bus_errors.rs
+    BusErrors = 0x14,
+    // Vibration and peak acceleration from the IMU (0x7A1), see imu.rs
+    Motion = 0x15,
+    // GPS fix, speed and course (0x7A2), a postcard-encoded Position, see gps.rs
+    Position = 0x16,
+    // Filtered 12 V supply voltage (0x7A3), see battery.rs
+    Supply = 0x17,
+}
+impl MessageType {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x01 => Some(Self::EcuData),
+            0x02 => Some(Self::Dtc),
+            0x03 => Some(Self::Environment),
+            0x04 => Some(Self::Alert),
+            0x05 => Some(Self::BusHealth),
+            0x06 => Some(Self::SelfTest),
+            0x07 => Some(Self::TxAbandoned),
+            0x08 => Some(Self::ControllerError),
+            0x09 => Some(Self::RawFrame),
+            0x0A => Some(Self::CommandResponse),
+            0x0B => Some(Self::DidResponse),
+            0x0C => Some(Self::Batch),
+            0x0D => Some(Self::Heartbeat),
+            0x0E => Some(Self::GatewayResponse),
+            0x0F => Some(Self::Signals),
+            0x10 => Some(Self::CrashReport),
+            0x11 => Some(Self::Statistics),
+            0x12 => Some(Self::Log),
+            0x13 => Some(Self::Blackbox),
+            0x14 => Some(Self::BusErrors),
+            0x15 => Some(Self::Motion),
+            0x16 => Some(Self::Position),
+            0x17 => Some(Self::Supply),
+            _ => None,
+        }
+    }
+}
+
+// DTC reports from the BMS and ICCU go out on IDs of their own, the other ECUs' on DTC_FORWARDING_ID
+pub const DTC_FORWARDING_ID: u16 = 0x780;
+pub const BMS_DTC_FORWARDING_ID: u16 = 0x784;
+pub const ICCU_DTC_FORWARDING_ID: u16 = 0x785;
+
+// Message payloads that are postcard-encoded structs rather than hand-packed bytes, so a host-side decoder can derive
+// its side from these same definitions
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Environment {
+    // Pa, 0 without a sensor that has a pressure channel (an SHT4x on its own)
+    pub pressure: f32,
+    // °C
+    pub temperature: f32,
+    // %RH
+    pub humidity: f32,
+    // Derived from the readings above, see weather.rs. °C, then m, the altitudes only with a pressure reading.
+    pub dew_point: f32,
+    pub pressure_altitude: Option<f32>,
+    pub density_altitude: Option<f32>,
+    // Which sensor each reading came from, humidity always comes from the temperature sensor
+    pub pressure_sensor: SensorKind,
+    pub temperature_sensor: SensorKind,
+    // °C from the external thermistor, if one is connected, see ntc.rs
+    pub external_temperature: Option<f32>,
+    // Environment::FAULT_PRESSURE etc. for each reading that's stuck or out of range, see sensor::ChannelCheck
+    pub faults: u8,
+    // Which sensor set the readings are from, units with two BME280s forward both on the same ID
+    pub location: Location,
+}
+impl Environment {
+    pub const FAULT_PRESSURE: u8 = 1 << 0;
+    pub const FAULT_TEMPERATURE: u8 = 1 << 1;
+    pub const FAULT_HUMIDITY: u8 = 1 << 2;
+    pub const FAULT_EXTERNAL_TEMPERATURE: u8 = 1 << 3;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Position {
+    // 1e-7 degrees, north and east positive
+    pub latitude: i32,
+    pub longitude: i32,
+    // m above mean sea level
+    pub altitude: f32,
+    // m/s over ground
+    pub speed: f32,
+    // Degrees from true north
+    pub course: f32,
+    // 0 = no fix, 1 = GPS, 2 = differential GPS. Position and altitude are the last good ones without a fix.
+    pub fix_quality: u8,
+    pub satellites: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Location {
+    Cabin = 0,
+    Outside = 1,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum SensorKind {
+    None = 0,
+    Bme280 = 1,
+    Sht4x = 2,
+    Lps22 = 3,
+}
+
+// Where the message originated
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum Source {
+    Obd = 0,
+    Comma = 1,
+    Sensors = 2,
+    Firmware = 3,
+}
+impl Source {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::Obd),
+            1 => Some(Self::Comma),
+            2 => Some(Self::Sensors),
+            3 => Some(Self::Firmware),
+            _ => None,
+        }
+    }
+    // Bus numbers used in diagnostics payloads (0 = OBD, 1 = comma)
+    pub fn bus(bus: u8) -> Self {
+        if bus == 0 { Self::Obd } else { Self::Comma }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub struct Header {
+    pub version: u8,
+    pub message_type: MessageType,
+    pub source: Source,
+    // Rolls over separately for each source, so the host can count what it missed from each and tell a stale repeat
+    // from fresh data
+    pub sequence: u8,
+    // Index of this segment within the message, 0 for messages that fit in one frame
+    pub segment: u8,
+    // More segments of the same message follow
+    pub more: bool,
+    pub length: u8,
+    // Microseconds since boot
+    pub timestamp: u32,
+    // Microseconds since the Unix epoch
+    pub wall_clock: Option<u64>,
+    pub compressed: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum DecodeError {
+    TooShort,
+    UnsupportedVersion(u8),
+    UnknownMessageType(u8),
+    UnknownSource(u8),
+    // Length byte doesn't fit the frame, apart from FD padding
+    BadLength,
+    BadCrc,
+    BadPayloadCrc,
+    // Segment doesn't continue the message being reassembled
+    UnexpectedSegment,
+    // Compressed payload is truncated or expands past MAX_MESSAGE_LENGTH
+    BadCompression,
+}
+
+// CRC-8 SAE J1850 (polynomial 0x1D, initial value 0xFF, final XOR 0xFF)
+pub fn crc8(data: impl IntoIterator<Item = u8>) -> u8 {
+    let mut crc: u8 = 0xFF;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x1D } else { crc << 1 };
+        }
+    }
+    crc ^ 0xFF
+}
+
+// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF, no final XOR)
+pub fn crc16(data: impl IntoIterator<Item = u8>) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// Next sequence number for each source
+pub struct Sequences([u8; 4]);
+impl Sequences {
+    pub const fn new() -> Self {
+        Self([0; 4])
+    }
+    pub fn next(&mut self, source: Source) -> u8 {
+        let counter = &mut self.0[source as usize];
+        let sequence = *counter;
+        *counter = counter.wrapping_add(1);
+        sequence
+    }
+}
+
+// Number of messages from a source lost between two consecutive sequence numbers received from it
+#[allow(dead_code)]
+pub fn missed(previous: u8, current: u8) -> u8 {
+    current.wrapping_sub(previous).wrapping_sub(1)
+}
+
+// Delta coding followed by run-length coding of zeros: each byte is replaced by its difference from the one before it,
+// then every run of zeros becomes [0x00, run length]. Slowly varying data like cell voltage blocks and repeated or
+// padded raw frames turn into long zero runs. Returns None if that doesn't make the payload any shorter.
+pub fn compress(payload: &[u8]) -> Option<Vec<u8, MAX_MESSAGE_LENGTH>> {
+    let mut compressed: Vec<u8, MAX_MESSAGE_LENGTH> = Vec::new();
+    let mut previous = 0u8;
+    let mut zeros = 0u8;
+    for &byte in payload {
+        let delta = byte.wrapping_sub(previous);
+        previous = byte;
+        if delta == 0 && zeros < u8::MAX {
+            zeros += 1;
+            continue;
+        }
+        if zeros > 0 {
+            compressed.extend_from_slice(&[0x00, zeros]).ok()?;
+            zeros = 0;
+        }
+        if delta == 0 {
+            // Run hit its maximum length, this byte starts the next one
+            zeros = 1;
+        }
+        else {
+            compressed.push(delta).ok()?;
+        }
+    }
+    if zeros > 0 {
+        compressed.extend_from_slice(&[0x00, zeros]).ok()?;
+    }
+    (compressed.len() < payload.len()).then_some(compressed)
+}
+
+// Reverses compress() on a reassembled payload
+#[allow(dead_code)]
+pub fn decompress(compressed: &[u8]) -> Result<Vec<u8, MAX_MESSAGE_LENGTH>, DecodeError> {
+    let mut payload: Vec<u8, MAX_MESSAGE_LENGTH> = Vec::new();
+    let mut previous = 0u8;
+    let mut bytes = compressed.iter();
+    while let Some(&delta) = bytes.next() {
+        let run = if delta == 0 { *bytes.next().ok_or(DecodeError::BadCompression)? } else { 1 };
+        for _ in 0..run {
+            previous = previous.wrapping_add(delta);
+            payload.push(previous).map_err(|_| DecodeError::BadCompression)?;
+        }
+    }
+    Ok(payload)
+}
+
+// Splits the message's payload into as many frames of up to frame_length bytes as it takes, compressing it first if
+// asked to and if that helps. Only sessions with room for FRAMING_OVERHEAD are framed, see Session::framed(), so
+// frame_length is only raised to it to keep a bad length from underflowing.
+pub fn encode(message: &Message, sequence: u8, wall_clock: Option<u64>, compress: bool, frame_length: usize) -> impl Iterator<Item = Vec<u8, 64>> {
+    let (message_type, source) = (message.message_type, message.source);
+    let timestamp = message.timestamp.as_micros() as u32;
+    let (payload, compressed) = match self::compress(&message.payload).filter(|_| compress) {
+        Some(compressed) => (compressed, true),
+        None => (message.payload.clone(), false),
+    };
+    let payload_crc = crc16(payload.iter().copied()).to_be_bytes();
+    let frame_length = frame_length.clamp(FRAMING_OVERHEAD, 64);
+    let mut offset = 0;
+    let mut segment: u8 = 0;
+    let mut done = false;
+    core::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let wall_clock = wall_clock.filter(|_| segment == 0);
+        let capacity = frame_length - HEADER_LENGTH - if wall_clock.is_some() { WALL_CLOCK_LENGTH } else { 0 };
+        let remaining = payload.len() - offset;
+        // The CRC-16 has to fit behind the last segment's payload
+        let last = remaining + PAYLOAD_CRC_LENGTH <= capacity;
+        let chunk = &payload[offset..offset + remaining.min(capacity)];
+        let segment_byte = if last { segment } else { segment | SEGMENT_MORE };
+        let mut flags = if wall_clock.is_some() { FLAG_WALL_CLOCK } else { 0 };
+        if compressed {
+            flags |= FLAG_COMPRESSED;
+        }
+
+        let mut frame: Vec<u8, 64> = Vec::new();
+        frame.extend_from_slice(&[FORMAT_VERSION, message_type as u8, source as u8, sequence, segment_byte, flags, chunk.len() as u8]).unwrap();
+        frame.extend_from_slice(&timestamp.to_be_bytes()).unwrap();
+        if let Some(wall_clock) = wall_clock {
+            frame.extend_from_slice(&wall_clock.to_be_bytes()).unwrap();
+        }
+        let crc = crc8(frame.iter().chain(chunk.iter()).copied());
+        frame.push(crc).unwrap();
+        frame.extend_from_slice(chunk).unwrap();
+        if last {
+            frame.extend_from_slice(&payload_crc).unwrap();
+        }
+        offset += chunk.len();
+        segment += 1;
+        done = last;
+        Some(frame)
+    })
+}
+
+// Decodes a single frame, returning its header and its part of the payload. The CRC-16 is checked here for messages
+// that fit in one frame, segmented ones have to go through Reassembler.
+// Only needed on the host side, kept next to encode() so the two can't drift apart
+#[allow(dead_code)]
+pub fn decode(frame: &[u8]) -> Result<(Header, &[u8]), DecodeError> {
+    let (header, payload, payload_crc) = decode_segment(frame)?;
+    if header.segment == 0 && !header.more && payload_crc != Some(crc16(payload.iter().copied())) {
+        return Err(DecodeError::BadPayloadCrc);
+    }
+    Ok((header, payload))
+}
+
+// Also returns the CRC-16 carried by the last segment
+fn decode_segment(frame: &[u8]) -> Result<(Header, &[u8], Option<u16>), DecodeError> {
+    if frame.len() < HEADER_LENGTH {
+        return Err(DecodeError::TooShort);
+    }
+    if frame[0] != FORMAT_VERSION {
+        return Err(DecodeError::UnsupportedVersion(frame[0]));
+    }
+    let more = frame[4] & SEGMENT_MORE != 0;
+    let flags = frame[5];
+    let length = frame[6] as usize;
+    let (wall_clock, header_length) = if flags & FLAG_WALL_CLOCK != 0 {
+        let wall_clock = frame.get(HEADER_LENGTH - 1..HEADER_LENGTH - 1 + WALL_CLOCK_LENGTH).ok_or(DecodeError::TooShort)?;
+        (Some(u64::from_be_bytes(wall_clock.try_into().unwrap())), HEADER_LENGTH + WALL_CLOCK_LENGTH)
+    }
+    else {
+        (None, HEADER_LENGTH)
+    };
+    let payload = frame.get(header_length..header_length + length).ok_or(DecodeError::BadLength)?;
+    if crc8(frame[..header_length - 1].iter().chain(payload.iter()).copied()) != frame[header_length - 1] {
+        return Err(DecodeError::BadCrc);
+    }
+    let payload_crc = if more {
+        None
+    }
+    else {
+        let payload_crc = frame.get(header_length + length..header_length + length + PAYLOAD_CRC_LENGTH).ok_or(DecodeError::BadLength)?;
+        Some(u16::from_be_bytes([payload_crc[0], payload_crc[1]]))
+    };
+    let header = Header {
+        version: frame[0],
+        message_type: MessageType::from_code(frame[1]).ok_or(DecodeError::UnknownMessageType(frame[1]))?,
+        source: Source::from_code(frame[2]).ok_or(DecodeError::UnknownSource(frame[2]))?,
+        sequence: frame[3],
+        segment: frame[4] & !SEGMENT_MORE,
+        more,
+        length: frame[6],
+        timestamp: u32::from_be_bytes([frame[7], frame[8], frame[9], frame[10]]),
+        wall_clock,
+        compressed: flags & FLAG_COMPRESSED != 0,
+    };
+    Ok((header, payload, payload_crc))
+}
+
+// Host side reassembly of the frames received on one CAN ID
+#[allow(dead_code)]
+pub struct Reassembler {
+    // Header of the first segment of the message being reassembled
+    first: Option<Header>,
+    next_segment: u8,
+    payload: Vec<u8, MAX_MESSAGE_LENGTH>,
+}
+#[allow(dead_code)]
+impl Reassembler {
+    pub const fn new() -> Self {
+        Self { first: None, next_segment: 0, payload: Vec::new() }
+    }
+
+    // Returns the first segment's header and the whole payload once the last segment is in
+    pub fn push(&mut self, frame: &[u8]) -> Result<Option<(Header, &[u8])>, DecodeError> {
+        let (header, payload, payload_crc) = decode_segment(frame)?;
+        if header.segment == 0 {
+            // Also drops whatever was left of an earlier message
+            self.first = Some(header);
+            self.next_segment = 0;
+            self.payload.clear();
+        }
+        let continues = self.first.is_some_and(|first| {
+            header.segment == self.next_segment
+                && header.sequence == first.sequence
+                && header.source == first.source
+                && header.message_type == first.message_type
+        });
+        if !continues || self.payload.extend_from_slice(payload).is_err() {
+            self.first = None;
+            return Err(DecodeError::UnexpectedSegment);
+        }
+        self.next_segment += 1;
+        if header.more {
+            return Ok(None);
+        }
+        let first = self.first.take().unwrap();
+        if payload_crc != Some(crc16(self.payload.iter().copied())) {
+            return Err(DecodeError::BadPayloadCrc);
+        }
+        Ok(Some((first, &self.payload)))
+    }
+}
+
+// Queued for forwarding to the comma device
+#[derive(Clone)]
+pub struct Message {
+    pub id: StandardId,
+    pub message_type: MessageType,
+    pub source: Source,
+    pub payload: Vec<u8, MAX_MESSAGE_LENGTH>,
+    // When the data was received or produced
+    pub timestamp: Instant,
+}
+impl Message {
+    // Payload bytes past MAX_MESSAGE_LENGTH are dropped
+    pub fn new(id: u16, message_type: MessageType, source: Source, payload: impl AsRef<[u8]>) -> Self {
+        let payload = payload.as_ref();
+        Self {
+            id: StandardId::new(id).unwrap(),
+            message_type,
+            source,
+            payload: Vec::from_slice(&payload[..payload.len().min(MAX_MESSAGE_LENGTH)]).unwrap(),
+            timestamp: Instant::now(),
+        }
+    }
+    // For data that arrived before the message was put together
+    pub fn at(mut self, timestamp: Instant) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+}
@@ -0,0 +1,128 @@
+use defmt::*;
+use embassy_rp::gpio::Output;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_can::StandardId;
+use heapless::Vec;
+use mcp25xxfd::config::FIFOConfig;
+use mcp25xxfd::frame::Frame;
+use mcp25xxfd::registers::{self, PayloadSize};
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::protocol::{Message, MessageType, Source};
+use crate::{mcp, CanController, FORWARDING_QUEUE, RX_BATTERY_FIFO, TRANSMIT_FIFO};
+
+// [bus (0 = OBD, 1 = comma), result, device ID register, oscillator register (4 bytes each)]
+const SELF_TEST_FORWARDING_ID: u16 = 0x7B2;
+const LOOPBACK_TIMEOUT: Duration = Duration::from_millis(50);
+
+static SELF_TEST_FAILED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, PartialEq, Format)]
+pub enum SelfTestResult {
+    Passed = 0,
+    // Never came out of reset, or SPI reads come back as all zeros/ones (missing or miswired)
+    NoResponse = 1,
+    OscillatorNotReady = 2,
+    UnknownDevice = 3,
+    LoopbackFailed = 4,
+}
+
+pub struct SelfTestReport {
+    pub result: SelfTestResult,
+    device_id: u32,
+    oscillator: u32,
+}
+impl SelfTestReport {
+    pub const NO_RESPONSE: Self = Self {
+        result: SelfTestResult::NoResponse,
+        device_id: 0,
+        oscillator: 0,
+    };
+}
+
+// Checks the device ID and oscillator registers, then sends a frame to itself in internal loopback mode. Leaves the
+// controller in loopback mode with a scratch FIFO setup, so it has to be reconfigured afterwards.
+pub async fn run(controller: &mut CanController) -> SelfTestReport {
+    let mut report = SelfTestReport::NO_RESPONSE;
+    let (Ok(oscillator), Ok(device_id)) = (controller.read_register(mcp::OSC).await, controller.read_register(mcp::DEVID).await) else {
+        return report;
+    };
+    report.oscillator = oscillator;
+    report.device_id = device_id;
+    if oscillator == 0 || oscillator == u32::MAX {
+        return report;
+    }
+    if oscillator & mcp::OSC_OSCRDY == 0 {
+        report.result = SelfTestResult::OscillatorNotReady;
+        return report;
+    }
+    // MCP2517FD, MCP2518FD and MCP251863
+    if !matches!((device_id >> 4) & 0xF, 0 | 1 | 3) {
+        report.result = SelfTestResult::UnknownDevice;
+        return report;
+    }
+
+    report.result = match loopback(controller).await {
+        Ok(true) => SelfTestResult::Passed,
+        Ok(false) => SelfTestResult::LoopbackFailed,
+        Err(err) => {
+            error!("Loopback test error: {}", err);
+            SelfTestResult::LoopbackFailed
+        },
+    };
+    report
+}
+
+async fn loopback(controller: &mut CanController) -> Result<bool, mcp25xxfd::Error> {
+    controller.configure_fifo(FIFOConfig::<TRANSMIT_FIFO>::tx_with_size(1, PayloadSize::Bytes8)).await?;
+    controller.configure_fifo(FIFOConfig::<RX_BATTERY_FIFO>::rx_with_size(1, PayloadSize::Bytes8)).await?;
+    mcp::set_filter(controller, 0, RX_BATTERY_FIFO, StandardId::ZERO.into(), 0).await?;
+    controller.set_mode(registers::OperationMode::InternalLoopback).await?;
+
+    let pattern = [0x55, 0xAA, 0x00, 0xFF, 0x0F, 0xF0, 0x33, 0xCC];
+    let test_frame = Frame::new(StandardId::new(0x555).unwrap(), &pattern).unwrap();
+    controller.transmit::<TRANSMIT_FIFO>(&test_frame).await?;
+
+    let sent_at = Instant::now();
+    while sent_at.elapsed() < LOOPBACK_TIMEOUT {
+        if let Some((_, frame)) = controller.receive(Some(RX_BATTERY_FIFO)).await? {
+            return Ok(frame.raw_id() == 0x555 && frame.data() == pattern);
+        }
+        Timer::after_millis(1).await;
+    }
+    Ok(false)
+}
+
+pub fn failed() -> bool {
+    SELF_TEST_FAILED.load(Ordering::Relaxed)
+}
+
+// Logs the result and forwards it as a diagnostics frame. Failures also start the status LED blinking.
+pub async fn report(name: &str, bus: u8, report: &SelfTestReport) {
+    if report.result == SelfTestResult::Passed {
+        info!("{} controller passed self-test (device ID {:x})", name, report.device_id);
+    }
+    else {
+        error!("{} controller failed self-test: {} (device ID {:x}, OSC {:x})", name, report.result, report.device_id, report.oscillator);
+        SELF_TEST_FAILED.store(true, Ordering::Relaxed);
+    }
+    let mut forward_data: Vec<u8, 64> = Vec::new();
+    forward_data.extend_from_slice(&[bus, report.result as u8]).unwrap();
+    forward_data.extend_from_slice(&report.device_id.to_be_bytes()).unwrap();
+    forward_data.extend_from_slice(&report.oscillator.to_be_bytes()).unwrap();
+    FORWARDING_QUEUE.send(Message::new(SELF_TEST_FORWARDING_ID, MessageType::SelfTest, Source::bus(bus), forward_data)).await;
+}
+
+#[embassy_executor::task]
+pub async fn status_led_task(mut led: Output<'static>) {
+    loop {
+        if failed() {
+            led.toggle();
+            Timer::after_millis(250).await;
+        }
+        else {
+            led.set_low();
+            Timer::after_millis(500).await;
+        }
+    }
+}
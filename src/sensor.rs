@@ -0,0 +1,176 @@
+use defmt::*;
+use core::ops::RangeInclusive;
+
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::errors::{self, ErrorCode, Module};
+use crate::protocol::{Message, MessageType, Source, MAX_MESSAGE_LENGTH};
+use crate::{config, power, supervisor, FORWARDING_QUEUE};
+
+// Everything a sensor has in common: initialize it, sample it on a fixed interval, re-initialize it when it stops
+// answering and report the outage once, and forward whatever it has to say. A new sensor is a module implementing
+// Sensor, a sensor_task! line to give it a task and a spawn in main. How often each one is sampled, or whether it's
+// paused, is part of the configuration and picked up while it runs, and they all slow down while the node is idle. A
+// sensor can also keep answering with readings that can't be right, which ChannelCheck catches.
+
+// Indexes into config::DeviceConfig::sensor_intervals
+pub const SENSOR_ENVIRONMENT: u8 = 0;
+pub const SENSOR_MOTION: u8 = 1;
+pub const SENSOR_COUNT: u8 = 2;
+
+// Shortest configurable interval, anything faster would starve the rest of the I2C bus
+pub const MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+// Consecutive failed samples before the sensor gets re-initialized, in case it reset or lost its configuration
+const SAMPLE_RETRIES: u8 = 3;
+// How often to try bringing a dead sensor back
+const INIT_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+pub trait Sensor {
+    const NAME: &'static str;
+    // Reported as when it stops answering
+    const MODULE: Module;
+    const MESSAGE_TYPE: MessageType;
+    // SENSOR_ENVIRONMENT etc.
+    const CONFIG_INDEX: u8;
+    type Sample;
+
+    // Checked in with on every sample, see supervisor.rs. Up to the instance for sensors there can be more than one of.
+    fn task(&self) -> supervisor::Task;
+    async fn init(&mut self) -> bool;
+    // None if the sensor didn't answer
+    async fn sample(&mut self) -> Option<Self::Sample>;
+    // Fills in the payload to forward and returns the ID to forward it on, or None to skip this sample (unchanged,
+    // still aggregating...)
+    async fn encode(&mut self, sample: Self::Sample, payload: &mut Vec<u8, MAX_MESSAGE_LENGTH>) -> Option<u16>;
+    // Called when the sensor stops answering and when it comes back
+    fn fault(&mut self, _faulted: bool) {}
+    // Bit per channel that was stuck or out of range in the last sample, see ChannelCheck. A channel going bad gets the
+    // sensor re-initialized.
+    fn channel_faults(&self) -> u8 {
+        0
+    }
+}
+
+// Catches a channel that returns physically impossible readings, or the exact same reading for longer than any real
+// signal with noise on it would, which a sensor that still answers on the bus can do after a brown-out or with a
+// failed element
+pub struct ChannelCheck {
+    valid: RangeInclusive<f32>,
+    stuck_after: Duration,
+    // The reading and when it was first seen
+    last: Option<(f32, Instant)>,
+}
+impl ChannelCheck {
+    pub const fn new(valid: RangeInclusive<f32>, stuck_after: Duration) -> Self {
+        Self { valid, stuck_after, last: None }
+    }
+
+    // Whether the reading is faulted
+    pub fn check(&mut self, reading: f32) -> bool {
+        // Also catches NaN
+        if !self.valid.contains(&reading) {
+            return true;
+        }
+        match self.last {
+            Some((last, since)) if last == reading => since.elapsed() >= self.stuck_after,
+            _ => {
+                self.last = Some((reading, Instant::now()));
+                false
+            },
+        }
+    }
+
+    // For a channel that dropped out
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+pub async fn run<S: Sensor>(mut sensor: S) {
+    let mut next_sample = Instant::now();
+    let mut configured = false;
+    let mut failed_samples = 0;
+    let mut faulted = false;
+    let mut channel_faults = 0;
+    // Everything else carries on without the sensor, it may just be unplugged
+    loop {
+        supervisor::pet(sensor.task());
+        let interval = config::CONFIG.lock().await.sensor_intervals[S::CONFIG_INDEX as usize];
+        if interval == 0 {
+            // A paused sensor isn't a faulty one, and it may have been power cycled by the time it's resumed
+            configured = false;
+            if core::mem::replace(&mut faulted, false) {
+                sensor.fault(false);
+            }
+            Timer::after(supervisor::PET_INTERVAL).await;
+            continue;
+        }
+        let mut interval = Duration::from_millis(interval as u64);
+        if power::is_idle() {
+            interval = interval.max(power::IDLE_SAMPLE_INTERVAL);
+        }
+        if !configured {
+            configured = sensor.init().await;
+            if !configured {
+                // Only once per outage, not on every retry
+                if !core::mem::replace(&mut faulted, true) {
+                    sensor.fault(true);
+                    errors::report(Source::Sensors, S::MODULE, ErrorCode::SensorFailed, errors::Error::Sensor, 0).await;
+                }
+                Timer::after(INIT_RETRY_INTERVAL).await;
+                continue;
+            }
+            failed_samples = 0;
+        }
+        // Long intervals are waited out in steps to keep checking in, a shortened one applies straight away
+        let now = Instant::now();
+        next_sample = next_sample.min(now + interval);
+        if now < next_sample {
+            Timer::at(next_sample.min(now + supervisor::PET_INTERVAL)).await;
+            continue;
+        }
+        // Missed samples are skipped rather than caught up on
+        next_sample = (next_sample + interval).max(now);
+        let Some(sample) = sensor.sample().await else {
+            failed_samples += 1;
+            if failed_samples >= SAMPLE_RETRIES {
+                warn!("{} failed {} samples in a row, re-initializing", S::NAME, failed_samples);
+                configured = false;
+                if !core::mem::replace(&mut faulted, true) {
+                    sensor.fault(true);
+                    errors::report(Source::Sensors, S::MODULE, ErrorCode::SensorFailed, errors::Error::Sensor, 0).await;
+                }
+            }
+            continue;
+        };
+        failed_samples = 0;
+        if core::mem::replace(&mut faulted, false) {
+            info!("{} is back", S::NAME);
+            sensor.fault(false);
+        }
+        let mut payload: Vec<u8, MAX_MESSAGE_LENGTH> = Vec::new();
+        if let Some(forwarding_id) = sensor.encode(sample, &mut payload).await {
+            FORWARDING_QUEUE.send(Message::new(forwarding_id, S::MESSAGE_TYPE, Source::Sensors, payload)).await;
+        }
+        // Only when a channel goes bad, a channel that's still stuck afterwards stays flagged in the forwarded readings
+        let previous_channel_faults = core::mem::replace(&mut channel_faults, sensor.channel_faults());
+        if channel_faults & !previous_channel_faults != 0 {
+            warn!("{} channels {:b} stuck or out of range, re-initializing", S::NAME, channel_faults);
+            errors::report(Source::Sensors, S::MODULE, ErrorCode::ChannelFaulted, errors::Error::Sensor, channel_faults as u32).await;
+            configured = false;
+        }
+    }
+}
+
+// Declares the task for a sensor, embassy tasks can't be generic
+macro_rules! sensor_task {
+    ($name:ident, $sensor:ty) => {
+        #[embassy_executor::task]
+        pub async fn $name(sensor: $sensor) {
+            $crate::sensor::run(sensor).await
+        }
+    };
+}
+pub(crate) use sensor_task;
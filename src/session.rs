@@ -0,0 +1,95 @@
+use core::cell::Cell;
+
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::{e2e, protocol};
+
+// Version of the comma-link protocol implemented by this firmware
+pub const PROTOCOL_VERSION: u8 = 2;
+// Forwarded frames carry the protocol.rs header from this version on (FD sessions only, the header alone would fill
+// most of a classic frame)
+pub const FRAMED_PROTOCOL_VERSION: u8 = 2;
+
+// Capability bits exchanged in the session handshake
+pub const CAP_FD: u16 = 1 << 0;
+// Compress ECU data and raw frames where it helps (needs the framing, see protocol::compress())
+pub const CAP_COMPRESSION: u16 = 1 << 1;
+pub const CAP_ENCRYPTION: u16 = 1 << 2;
+pub const CAP_E2E: u16 = 1 << 3;
+// Prefix forwarded vehicle data with its arrival time (4 bytes, microseconds since boot)
+pub const CAP_TIMESTAMPS: u16 = 1 << 4;
+// Pack small messages into shared FD frames on the batch forwarding ID (needs CAP_FD)
+pub const CAP_BATCHING: u16 = 1 << 5;
+// Retransmit DTCs, errors and command responses until the host acknowledges them, see ack.rs (needs CAP_FD)
+pub const CAP_ACK: u16 = 1 << 6;
+// Forward everything on one CAN ID with a stream ID in front of each payload, see mux.rs (needs CAP_FD)
+pub const CAP_MUX: u16 = 1 << 7;
+pub const SUPPORTED_CAPABILITIES: u16 = CAP_FD | CAP_COMPRESSION | CAP_TIMESTAMPS | CAP_BATCHING | CAP_E2E | CAP_ACK | CAP_MUX;
+
+#[derive(Clone, Copy, Format)]
+pub struct Session {
+    pub version: u8,
+    pub capabilities: u16,
+    pub max_payload: u8,
+}
+impl Session {
+    // Behavior before (or without) a handshake, for hosts that predate it
+    pub const LEGACY: Self = Self {
+        version: 0,
+        // Timestamps, batching, compression and multiplexing change the payload layout and ACKs need the host to
+        // answer, so they're only used when asked for
+        capabilities: SUPPORTED_CAPABILITIES & !CAP_COMPRESSION & !CAP_TIMESTAMPS & !CAP_BATCHING & !CAP_ACK & !CAP_MUX,
+        max_payload: 64,
+    };
+
+    pub fn negotiate(host_version: u8, host_capabilities: u16, host_max_payload: u8) -> Self {
+        let mut capabilities = SUPPORTED_CAPABILITIES & host_capabilities;
+        if capabilities & CAP_FD == 0 {
+            // Not worth it with 8-byte frames, and ACKs and compression need the framing
+            capabilities &= !CAP_BATCHING & !CAP_ACK & !CAP_COMPRESSION & !CAP_MUX;
+        }
+        let max_payload = if capabilities & CAP_FD != 0 { host_max_payload.clamp(8, 64) } else { 8 };
+        if (max_payload as usize) < protocol::FRAMING_OVERHEAD {
+            // Too small for the framing header, so forwarded unframed as if the host predated it
+            capabilities &= !CAP_ACK & !CAP_COMPRESSION;
+        }
+        Self {
+            version: PROTOCOL_VERSION.min(host_version),
+            capabilities,
+            max_payload,
+        }
+    }
+    pub fn has(&self, capability: u16) -> bool {
+        self.capabilities & capability != 0
+    }
+    pub fn framed(&self) -> bool {
+        self.version >= FRAMED_PROTOCOL_VERSION && self.has(CAP_FD) && self.max_payload as usize >= protocol::FRAMING_OVERHEAD
+    }
+    // Room for the forwarded payload once framing or E2E protection is added. Framed messages carry their own CRC and
+    // sequence number, so E2E protection is only added for hosts that predate the framing.
+    pub fn max_forward_payload(&self) -> usize {
+        let header_length = if self.framed() {
+            protocol::FRAMING_OVERHEAD
+        }
+        else if self.has(CAP_E2E) {
+            e2e::E2E_HEADER_LENGTH
+        }
+        else {
+            0
+        };
+        (self.max_payload as usize).saturating_sub(header_length)
+    }
+}
+
+static SESSION: Mutex<CriticalSectionRawMutex, Cell<Session>> = Mutex::new(Cell::new(Session::LEGACY));
+
+pub fn current() -> Session {
+    SESSION.lock(|session| session.get())
+}
+
+pub fn start(session: Session) {
+    info!("Comma-link session started: {}", session);
+    SESSION.lock(|current| current.set(session));
+}
@@ -0,0 +1,77 @@
+use embassy_time::Timer;
+use embedded_hal_async::i2c::I2c;
+
+// Sensirion SHT4x temperature and humidity sensor, fitted instead of the BME280 on some units for its better humidity
+// accuracy. It has no pressure channel. Only the handful of commands needed here: single-shot high-precision
+// measurements, the serial number to detect it at boot and a soft reset.
+
+const ADDRESS: u8 = 0x44;
+
+const CMD_MEASURE_HIGH_PRECISION: u8 = 0xFD;
+const CMD_READ_SERIAL: u8 = 0x89;
+const CMD_SOFT_RESET: u8 = 0x94;
+
+// Worst case from the datasheet, 8.3 ms for a high-precision measurement and 1 ms for everything else
+const MEASUREMENT_TIME_MS: u64 = 10;
+const COMMAND_TIME_MS: u64 = 1;
+
+pub enum Error<E> {
+    I2c(E),
+    // A word didn't match its checksum
+    Crc,
+}
+
+// CRC-8 with polynomial 0x31 and initial value 0xFF, over each 2-byte word
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xFF;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x31 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+pub struct Sht4x<I2C> {
+    i2c: I2C,
+}
+impl<I2C: I2c> Sht4x<I2C> {
+    pub fn new(i2c: I2C) -> Self {
+        Self { i2c }
+    }
+
+    // Sends a command and reads back its two checksummed words
+    async fn command(&mut self, command: u8, wait_ms: u64) -> Result<[u16; 2], Error<I2C::Error>> {
+        self.i2c.write(ADDRESS, &[command]).await.map_err(Error::I2c)?;
+        Timer::after_millis(wait_ms).await;
+        let mut buf = [0u8; 6];
+        self.i2c.read(ADDRESS, &mut buf).await.map_err(Error::I2c)?;
+        if crc8(&buf[0..2]) != buf[2] || crc8(&buf[3..5]) != buf[5] {
+            return Err(Error::Crc);
+        }
+        Ok([u16::from_be_bytes([buf[0], buf[1]]), u16::from_be_bytes([buf[3], buf[4]])])
+    }
+
+    // Also how it's detected, nothing else answers this at its address
+    pub async fn serial(&mut self) -> Result<u32, Error<I2C::Error>> {
+        let [high, low] = self.command(CMD_READ_SERIAL, COMMAND_TIME_MS).await?;
+        Ok(((high as u32) << 16) | low as u32)
+    }
+
+    // Soft reset, then checks it's still there
+    pub async fn init(&mut self) -> Result<(), Error<I2C::Error>> {
+        self.i2c.write(ADDRESS, &[CMD_SOFT_RESET]).await.map_err(Error::I2c)?;
+        Timer::after_millis(COMMAND_TIME_MS).await;
+        self.serial().await.map(|_| ())
+    }
+
+    // (°C, %RH)
+    pub async fn measure(&mut self) -> Result<(f32, f32), Error<I2C::Error>> {
+        let [temperature, humidity] = self.command(CMD_MEASURE_HIGH_PRECISION, MEASUREMENT_TIME_MS).await?;
+        let temperature = -45.0 + 175.0 * temperature as f32 / 65535.0;
+        // Can read slightly outside 0-100 %RH at the extremes
+        let humidity = (-6.0 + 125.0 * humidity as f32 / 65535.0).clamp(0.0, 100.0);
+        Ok((temperature, humidity))
+    }
+}
@@ -0,0 +1,73 @@
+use heapless::Vec;
+
+use crate::polling::QUERY_COUNT;
+use crate::protocol::MAX_MESSAGE_LENGTH;
+use crate::vehicle::{Vehicle, VehicleProfile};
+
+// Decoded signals from the periodic query responses, forwarded alongside the raw EcuData
+pub const SIGNALS_FORWARDING_ID: u16 = 0x7D1;
+
+// A signal in a query response, generated by build.rs from the signals column of queries/<profile>.csv. Bits are
+// numbered from the most significant bit of the first byte after the DID and values are big endian.
+pub struct SignalDefinition {
+    // Only there to make the generated tables readable
+    #[allow(dead_code)]
+    pub name: &'static str,
+    pub start: u16,
+    // 1-32 bits
+    pub length: u8,
+    pub signed: bool,
+    pub scale: f32,
+    pub offset: f32,
+}
+
+// Signals of each periodic query, same order as polling::QUERIES
+const SIGNALS: &[&[SignalDefinition]] = Vehicle::SIGNALS;
+const _: () = assert!(SIGNALS.len() == QUERY_COUNT, "every query needs an entry in the signal table");
+
+// None if the response doesn't go that far
+pub fn decode(signal: &SignalDefinition, data: &[u8]) -> Option<f32> {
+    let start = signal.start as usize;
+    let end = start + signal.length as usize;
+    if end > data.len() * 8 {
+        return None;
+    }
+    let mut raw: u32 = 0;
+    for bit in start..end {
+        raw = (raw << 1) | ((data[bit / 8] >> (7 - bit % 8)) & 1) as u32;
+    }
+    let value = if signal.signed && signal.length < 32 {
+        // Sign extend
+        let shift = 32 - signal.length as u32;
+        ((raw << shift) as i32 >> shift) as f32
+    }
+    else if signal.signed {
+        raw as i32 as f32
+    }
+    else {
+        raw as f32
+    };
+    Some(value * signal.scale + signal.offset)
+}
+
+// [query index, (signal index, value as a big endian f32)...] for the signals the response covers, None if the query
+// has no signals
+pub fn encode(query: usize, data: &[u8]) -> Option<Vec<u8, MAX_MESSAGE_LENGTH>> {
+    let signals = SIGNALS.get(query)?;
+    if signals.is_empty() {
+        return None;
+    }
+    let mut payload: Vec<u8, MAX_MESSAGE_LENGTH> = Vec::new();
+    payload.push(query as u8).unwrap();
+    for (index, signal) in signals.iter().enumerate() {
+        let Some(value) = decode(signal, data) else {
+            continue;
+        };
+        if payload.len() + 5 > payload.capacity() {
+            break;
+        }
+        payload.push(index as u8).unwrap();
+        payload.extend_from_slice(&value.to_be_bytes()).unwrap();
+    }
+    Some(payload)
+}
@@ -0,0 +1,83 @@
+use defmt::Format;
+use heapless::Deque;
+use serde::{Deserialize, Serialize};
+
+// Optional smoothing of each sensor's readings before they're forwarded, configured per sensor, so noisy raw values
+// don't need to be smoothed downstream. A moving average evens out noise, a median also throws out one-off spikes.
+
+// Longest window that can be configured
+pub const MAX_WINDOW: usize = 9;
+
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub enum Method {
+    None,
+    MovingAverage,
+    Median,
+}
+impl Method {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Self::None),
+            1 => Some(Self::MovingAverage),
+            2 => Some(Self::Median),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Format, Serialize, Deserialize)]
+pub struct Smoothing {
+    pub method: Method,
+    // Readings, 1 to MAX_WINDOW
+    pub window: u8,
+}
+impl Smoothing {
+    pub const NONE: Self = Self { method: Method::None, window: 1 };
+}
+
+// The last readings of one channel
+pub struct Smoother {
+    readings: Deque<f32, MAX_WINDOW>,
+}
+impl Smoother {
+    pub const fn new() -> Self {
+        Self { readings: Deque::new() }
+    }
+
+    // Start over, for a channel that dropped out
+    pub fn reset(&mut self) {
+        self.readings.clear();
+    }
+
+    // The smoothed value, which follows the raw one until the window has filled
+    pub fn apply(&mut self, smoothing: Smoothing, reading: f32) -> f32 {
+        let window = (smoothing.window as usize).clamp(1, MAX_WINDOW);
+        if smoothing.method == Method::None || window == 1 {
+            self.readings.clear();
+            return reading;
+        }
+        while self.readings.len() >= window {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(reading).unwrap();
+        let count = self.readings.len();
+        match smoothing.method {
+            Method::None => reading,
+            Method::MovingAverage => self.readings.iter().sum::<f32>() / count as f32,
+            Method::Median => {
+                let mut sorted = [0.0; MAX_WINDOW];
+                for (slot, &reading) in sorted.iter_mut().zip(self.readings.iter()) {
+                    *slot = reading;
+                }
+                let sorted = &mut sorted[..count];
+                sorted.sort_unstable_by(f32::total_cmp);
+                if count % 2 == 1 {
+                    sorted[count / 2]
+                }
+                else {
+                    (sorted[count / 2 - 1] + sorted[count / 2]) / 2.0
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use embassy_time::Instant;
+use embedded_can::StandardId;
+use heapless::Vec;
+use mcp25xxfd::config::FIFOConfig;
+use mcp25xxfd::frame::Frame;
+use mcp25xxfd::registers::PayloadSize;
+use mcp25xxfd::Error;
+
+use crate::{mcp, subscriptions, CanController, OBD_MODE_FIFO_DEPTH};
+
+// Passive CAN tap for reverse engineering: every frame on the vehicle bus lands in SNIFFER_FIFO and gets forwarded
+// as-is. Its filter has the lowest number so it wins over the per-ECU filters.
+pub const SNIFFER_FIFO: u8 = 12;
+const SNIFFER_FILTER: u8 = 0;
+// [arrival time (4 bytes, microseconds since boot), ID (4 bytes, bit 31 set for extended IDs), data...]
+pub const SNIFFER_FORWARDING_ID: u16 = 0x7F1;
+
+// Only in sniffer mode, like the loopback query FIFO below it is only configured in loopback mode. There's no room in
+// message RAM for both, see obd_message_ram()
+pub async fn configure(controller: &mut CanController) -> Result<(), Error> {
+    controller.configure_fifo(FIFOConfig::<SNIFFER_FIFO>::rx_with_size(OBD_MODE_FIFO_DEPTH, PayloadSize::Bytes8)).await?;
+    // A mask of all zeros accepts both standard and extended IDs
+    mcp::set_filter(controller, SNIFFER_FILTER, SNIFFER_FIFO, StandardId::ZERO.into(), 0).await?;
+    mcp::enable_rx_overflow_interrupts(controller, &[SNIFFER_FIFO]).await?;
+    mcp::enable_rx_timestamps(controller, &[SNIFFER_FIFO]).await
+}
+
+// Timestamps are always included, unlike the session-dependent ones on decoded data
+pub fn encode(received_at: Instant, frame: &Frame) -> Vec<u8, 64> {
+    let mut forward_data: Vec<u8, 64> = Vec::new();
+    forward_data.extend_from_slice(&(received_at.as_micros() as u32).to_be_bytes()).unwrap();
+    let stream_frame = subscriptions::encode_stream_frame(frame.id(), frame.data());
+    let remaining = forward_data.capacity() - forward_data.len();
+    forward_data.extend_from_slice(&stream_frame[..stream_frame.len().min(remaining)]).unwrap();
+    forward_data
+}
@@ -0,0 +1,182 @@
+use core::fmt::{self, Write};
+
+use defmt::*;
+use embassy_time::{Duration, Instant, Ticker};
+use heapless::Vec;
+use portable_atomic::{AtomicU32, AtomicU8, Ordering};
+
+use crate::{ecu_errors, latency};
+use crate::memory::{self, QueuePeak};
+use crate::protocol::{Message, MessageType, Source, MAX_MESSAGE_LENGTH};
+use crate::FORWARDING_QUEUE;
+
+// [uptime seconds (4 bytes), ISO-TP transfers completed (2 bytes), ISO-TP transfers aborted (2 bytes), forwarding queue
+// drops (2 bytes), stack bytes never used (4 bytes), then peak and capacity of the forwarding queue, the OBD RX channel
+// and the command channel, peak and capacity of the OBD and comma controllers' transmissions in flight, number of ECUs
+// with latencies], followed by [ECU, min, average and max query latency (ms,
+// 2 bytes each)] for every ECU that answered since the last report, number of ECUs with failures, [ECU, aborted
+// transfers, timed out queries, negative responses (2 bytes each)] for every ECU with any since boot, then [bus (0 = OBD, 1 = comma), FIFO, frames
+// received (2 bytes), frames transmitted (2 bytes), most frames drained at once] for every FIFO that has seen traffic. Counts are since boot and
+// wrap around, see memory.rs for the margins, latency.rs for the latencies and ecu_errors.rs for the failures.
+pub const STATS_FORWARDING_ID: u16 = 0x7B6;
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+// Frame counts per controller FIFO, the TXQ is FIFO 0. The controller doesn't report how full an RX FIFO is, so its
+// high-water mark is the most frames drained from it in one go.
+pub struct BusStats {
+    received: [AtomicU32; 32],
+    transmitted: [AtomicU32; 32],
+    peak: [AtomicU8; 32],
+}
+impl BusStats {
+    const fn new() -> Self {
+        Self {
+            received: [const { AtomicU32::new(0) }; 32],
+            transmitted: [const { AtomicU32::new(0) }; 32],
+            peak: [const { AtomicU8::new(0) }; 32],
+        }
+    }
+
+    pub fn received(&self, fifo: u8) {
+        self.received[fifo as usize & 31].fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn transmitted(&self, fifo: u8) {
+        self.transmitted[fifo as usize & 31].fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn drained(&self, fifo: u8, count: usize) {
+        self.peak[fifo as usize & 31].fetch_max(count.min(u8::MAX as usize) as u8, Ordering::Relaxed);
+    }
+}
+
+pub static OBD: BusStats = BusStats::new();
+pub static COMMA: BusStats = BusStats::new();
+static ISOTP_COMPLETED: AtomicU32 = AtomicU32::new(0);
+static ISOTP_ABORTED: AtomicU32 = AtomicU32::new(0);
+
+pub fn isotp_completed() {
+    ISOTP_COMPLETED.fetch_add(1, Ordering::Relaxed);
+}
+// Timed out partway through or too long to reassemble
+pub fn isotp_aborted() {
+    ISOTP_ABORTED.fetch_add(1, Ordering::Relaxed);
+}
+
+// (completed, aborted)
+pub fn isotp_transfers() -> (u32, u32) {
+    (ISOTP_COMPLETED.load(Ordering::Relaxed), ISOTP_ABORTED.load(Ordering::Relaxed))
+}
+
+// Human-readable version of the stats frame, for the console
+pub fn summary(out: &mut impl Write) -> fmt::Result {
+    write!(
+        out,
+        "up {} s, ISO-TP {} completed / {} aborted, {} forwarding drops\r\n",
+        Instant::now().as_secs(),
+        ISOTP_COMPLETED.load(Ordering::Relaxed),
+        ISOTP_ABORTED.load(Ordering::Relaxed),
+        FORWARDING_QUEUE.dropped(),
+    )?;
+    write!(out, "{} bytes of stack never used\r\n", memory::stack_margin())?;
+    for (name, peak) in queue_peaks() {
+        write!(out, "  {} peaked at {}/{}\r\n", name, peak.get(), peak.capacity)?;
+    }
+    for (ecu, latency) in latency::get().iter().enumerate().filter(|(_, latency)| latency.samples > 0) {
+        write!(out, "  ECU {} latency {}/{}/{} ms min/avg/max\r\n", ecu, latency.min_ms, latency.avg_ms(), latency.max_ms)?;
+    }
+    for (ecu, errors) in ecu_errors::ECUS.iter().enumerate().filter(|(_, errors)| errors.any()) {
+        write!(
+            out,
+            "  ECU {}: {} aborted, {} timed out, {} negative responses\r\n",
+            ecu,
+            errors.aborted.load(Ordering::Relaxed),
+            errors.timed_out.load(Ordering::Relaxed),
+            errors.negative_responses.load(Ordering::Relaxed),
+        )?;
+    }
+    for (name, stats) in [("OBD", &OBD), ("Comma", &COMMA)] {
+        for fifo in 0..32 {
+            let received = stats.received[fifo].load(Ordering::Relaxed);
+            let transmitted = stats.transmitted[fifo].load(Ordering::Relaxed);
+            if received > 0 || transmitted > 0 {
+                let peak = stats.peak[fifo].load(Ordering::Relaxed);
+                write!(out, "  {} FIFO{}: {} received, {} transmitted, peak {}\r\n", name, fifo, received, transmitted, peak)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn queue_peaks() -> [(&'static str, &'static QueuePeak); 5] {
+    [
+        ("forwarding queue", &memory::FORWARDING_QUEUE_PEAK),
+        ("OBD RX channel", &memory::OBD_RX_PEAK),
+        ("command channel", &memory::COMMAND_PEAK),
+        ("OBD TX in flight", &memory::OBD_TX_PEAK),
+        ("Comma TX in flight", &memory::COMMA_TX_PEAK),
+    ]
+}
+
+#[embassy_executor::task]
+pub async fn stats_task() {
+    let mut ticker = Ticker::every(STATS_INTERVAL);
+    loop {
+        ticker.next().await;
+        let uptime = Instant::now().as_secs() as u32;
+        let completed = ISOTP_COMPLETED.load(Ordering::Relaxed);
+        let aborted = ISOTP_ABORTED.load(Ordering::Relaxed);
+        let dropped = FORWARDING_QUEUE.dropped();
+        let stack_margin = memory::stack_margin();
+        info!("Stats: up {} s, ISO-TP {} completed / {} aborted, {} forwarding drops", uptime, completed, aborted, dropped);
+        info!("  {} bytes of stack never used", stack_margin);
+
+        let mut forward_data: Vec<u8, MAX_MESSAGE_LENGTH> = Vec::new();
+        forward_data.extend_from_slice(&uptime.to_be_bytes()).unwrap();
+        forward_data.extend_from_slice(&(completed as u16).to_be_bytes()).unwrap();
+        forward_data.extend_from_slice(&(aborted as u16).to_be_bytes()).unwrap();
+        forward_data.extend_from_slice(&(dropped as u16).to_be_bytes()).unwrap();
+        forward_data.extend_from_slice(&stack_margin.to_be_bytes()).unwrap();
+        for (name, peak) in queue_peaks() {
+            info!("  {} peaked at {}/{}", name, peak.get(), peak.capacity);
+            forward_data.extend_from_slice(&[peak.get(), peak.capacity]).unwrap();
+        }
+        let latencies = latency::take();
+        forward_data.push(latencies.iter().filter(|latency| latency.samples > 0).count() as u8).unwrap();
+        for (ecu, latency) in latencies.iter().enumerate().filter(|(_, latency)| latency.samples > 0) {
+            info!("  ECU {} latency {}/{}/{} ms min/avg/max", ecu, latency.min_ms, latency.avg_ms(), latency.max_ms);
+            forward_data.push(ecu as u8).unwrap();
+            forward_data.extend_from_slice(&latency.min_ms.to_be_bytes()).unwrap();
+            forward_data.extend_from_slice(&latency.avg_ms().to_be_bytes()).unwrap();
+            forward_data.extend_from_slice(&latency.max_ms.to_be_bytes()).unwrap();
+        }
+        forward_data.push(ecu_errors::ECUS.iter().filter(|errors| errors.any()).count() as u8).unwrap();
+        for (ecu, errors) in ecu_errors::ECUS.iter().enumerate().filter(|(_, errors)| errors.any()) {
+            let aborted = errors.aborted.load(Ordering::Relaxed);
+            let timed_out = errors.timed_out.load(Ordering::Relaxed);
+            let negative_responses = errors.negative_responses.load(Ordering::Relaxed);
+            info!("  ECU {}: {} aborted, {} timed out, {} negative responses", ecu, aborted, timed_out, negative_responses);
+            forward_data.push(ecu as u8).unwrap();
+            forward_data.extend_from_slice(&(aborted as u16).to_be_bytes()).unwrap();
+            forward_data.extend_from_slice(&(timed_out as u16).to_be_bytes()).unwrap();
+            forward_data.extend_from_slice(&(negative_responses as u16).to_be_bytes()).unwrap();
+        }
+        for (bus, (name, stats)) in [("OBD", &OBD), ("Comma", &COMMA)].into_iter().enumerate() {
+            for fifo in 0..32 {
+                let received = stats.received[fifo].load(Ordering::Relaxed);
+                let transmitted = stats.transmitted[fifo].load(Ordering::Relaxed);
+                if received == 0 && transmitted == 0 {
+                    continue;
+                }
+                let peak = stats.peak[fifo].load(Ordering::Relaxed);
+                info!("  {} FIFO{}: {} received, {} transmitted, peak {}", name, fifo, received, transmitted, peak);
+                if forward_data.len() + 7 > forward_data.capacity() {
+                    continue;
+                }
+                forward_data.extend_from_slice(&[bus as u8, fifo as u8]).unwrap();
+                forward_data.extend_from_slice(&(received as u16).to_be_bytes()).unwrap();
+                forward_data.extend_from_slice(&(transmitted as u16).to_be_bytes()).unwrap();
+                forward_data.push(peak).unwrap();
+            }
+        }
+        FORWARDING_QUEUE.send(Message::new(STATS_FORWARDING_ID, MessageType::Statistics, Source::Firmware, forward_data)).await;
+    }
+}
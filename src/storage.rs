@@ -0,0 +1,49 @@
+use core::cell::RefCell;
+
+use embassy_rp::flash::{Blocking, Error, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+pub type FlashMutex = Mutex<NoopRawMutex, RefCell<Flash<'static, FLASH, Blocking, FLASH_SIZE>>>;
+
+// Sectors in the STORAGE region at the end of flash (see memory.x), as offsets from the start of flash
+pub const STORAGE_OFFSET: u32 = 0x1F_0000;
+pub const DTC_SECTOR: u32 = STORAGE_OFFSET;
+// Two configuration slots, see config::save
+pub const CONFIG_SECTORS: [u32; 2] = [STORAGE_OFFSET + ERASE_SIZE as u32, STORAGE_OFFSET + 2 * ERASE_SIZE as u32];
+// Last panic or hard fault, see crash.rs
+pub const CRASH_SECTOR: u32 = STORAGE_OFFSET + 3 * ERASE_SIZE as u32;
+// Vehicle bus traffic leading up to the last crash or bus-off, see blackbox.rs
+pub const BLACKBOX_SECTOR: u32 = STORAGE_OFFSET + 4 * ERASE_SIZE as u32;
+
+pub fn read(flash: &FlashMutex, offset: u32, buf: &mut [u8]) -> Result<(), Error> {
+    flash.lock(|flash| flash.borrow_mut().blocking_read(offset, buf))
+}
+
+// Programs erased flash without erasing it first, so it can only clear bits
+pub fn write(flash: &FlashMutex, offset: u32, data: &[u8]) -> Result<(), Error> {
+    flash.lock(|flash| flash.borrow_mut().blocking_write(offset, data))
+}
+
+pub fn erase(flash: &FlashMutex, sector: u32) -> Result<(), Error> {
+    flash.lock(|flash| flash.borrow_mut().blocking_erase(sector, sector + ERASE_SIZE as u32))
+}
+
+// Erases the sector and writes `data` at its start
+pub fn write_sector(flash: &FlashMutex, sector: u32, data: &[u8]) -> Result<(), Error> {
+    flash.lock(|flash| {
+        let mut flash = flash.borrow_mut();
+        flash.blocking_erase(sector, sector + ERASE_SIZE as u32)?;
+        flash.blocking_write(sector, data)
+    })
+}
+
+// For the crash handlers, which run with the rest of the firmware stopped, so the flash is taken over directly rather
+// than through the mutex
+pub fn crash_write_sector(sector: u32, data: &[u8]) -> Result<(), Error> {
+    let mut flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(unsafe { FLASH::steal() });
+    flash.blocking_erase(sector, sector + ERASE_SIZE as u32)?;
+    flash.blocking_write(sector, data)
+}
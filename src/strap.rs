@@ -0,0 +1,56 @@
+use defmt::*;
+use embassy_rp::gpio::{Input, Pull};
+use embassy_time::Timer;
+
+use crate::board;
+use crate::config::{BitRates, DataBitRate, DeviceConfig, NominalBitRate};
+
+// Bit rates picked with jumpers on the two strap pins, so that the same firmware image can be installed on different
+// buses. A jumper pulls its pin to ground and sets its bit, no jumpers leaves the stored configuration alone.
+#[derive(Clone, Copy, Format)]
+pub struct BusProfile {
+    pub obd: BitRates,
+    pub comma: BitRates,
+}
+
+// Indexed by the strap value, the data rates only matter for FD frames with bit-rate switching
+const PROFILES: [Option<BusProfile>; 4] = [
+    None,
+    // 500 kbit/s with a 2 Mbit/s data phase on both buses, the defaults
+    Some(BusProfile {
+        obd: BitRates::DEFAULT,
+        comma: BitRates::DEFAULT,
+    }),
+    // 500 kbit/s with a 5 Mbit/s data phase on both buses
+    Some(BusProfile {
+        obd: BitRates { nominal: NominalBitRate::Kbps500, data: DataBitRate::Mbps5 },
+        comma: BitRates { nominal: NominalBitRate::Kbps500, data: DataBitRate::Mbps5 },
+    }),
+    // 250 kbit/s vehicle bus, the comma bus stays at the defaults
+    Some(BusProfile {
+        obd: BitRates { nominal: NominalBitRate::Kbps250, data: DataBitRate::Mbps2 },
+        comma: BitRates::DEFAULT,
+    }),
+];
+
+// Only read at boot, moving a jumper takes a reset
+pub async fn read(strap0: board::pins::Strap0, strap1: board::pins::Strap1) -> u8 {
+    let strap0 = Input::new(strap0, Pull::Up);
+    let strap1 = Input::new(strap1, Pull::Up);
+    // Give the pull-ups time to charge the pins before sampling
+    Timer::after_micros(100).await;
+    (strap0.is_low() as u8) | (strap1.is_low() as u8) << 1
+}
+
+// Overrides the stored bit rates (and bit rate detection) with the strapped profile. Bit rates set at runtime still
+// apply until the next reset.
+pub fn apply(config: &mut DeviceConfig, strap: u8) {
+    let Some(profile) = PROFILES[strap as usize & 0b11] else {
+        info!("No bus profile strapped, using the stored bit rates");
+        return;
+    };
+    info!("Bus profile {} strapped: {}", strap, profile);
+    config.obd_bit_rates = profile.obd;
+    config.comma_bit_rates = profile.comma;
+    config.obd_detect_bit_rate = false;
+}
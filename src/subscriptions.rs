@@ -0,0 +1,107 @@
+use defmt::*;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use embedded_can::Id;
+use heapless::Vec;
+
+use crate::{mcp, CanController};
+
+// Host-requested raw frame streams: the OBD controller gets temporary filters for the requested IDs into a dedicated
+// FIFO, everything matching is forwarded raw, and the filters are torn down again once the TTL runs out
+pub const SUBSCRIPTION_FIFO: u8 = 10;
+pub const MAX_SUBSCRIBED_IDS: usize = 4;
+const FIRST_SUBSCRIPTION_FILTER: u8 = 24;
+pub const STREAM_FORWARDING_ID: u16 = 0x7F0;
+
+// None tears down the active subscription
+pub static SUBSCRIPTION_REQUESTS: Signal<CriticalSectionRawMutex, Option<Subscription>> = Signal::new();
+
+// Host-managed capture filters stay in place until removed, for pulling in a new ECU's traffic (or a whole ID block)
+// without a TTL. They feed the same FIFO and forwarding ID as subscriptions.
+pub const MAX_CAPTURE_FILTERS: u8 = 4;
+const FIRST_CAPTURE_FILTER: u8 = FIRST_SUBSCRIPTION_FILTER + MAX_SUBSCRIBED_IDS as u8;
+pub static CAPTURE_REQUESTS: Channel<CriticalSectionRawMutex, CaptureRequest, 4> = Channel::new();
+
+#[derive(Format)]
+pub enum CaptureRequest {
+    Add {
+        slot: u8,
+        id: Id,
+        mask: u32,
+    },
+    Remove {
+        slot: u8,
+    },
+}
+
+#[derive(Format)]
+pub struct Subscription {
+    pub ids: Vec<Id, MAX_SUBSCRIBED_IDS>,
+    pub ttl: Duration,
+}
+
+// [ID (4 bytes, bit 31 set for extended IDs), data...]
+pub fn encode_stream_frame(id: Id, data: &[u8]) -> Vec<u8, 64> {
+    let raw_id = match id {
+        Id::Standard(id) => id.as_raw() as u32,
+        Id::Extended(id) => id.as_raw() | 0x8000_0000,
+    };
+    let mut forward_data = Vec::new();
+    forward_data.extend_from_slice(&raw_id.to_be_bytes()).unwrap();
+    forward_data.extend_from_slice(&data[..data.len().min(60)]).unwrap();
+    forward_data
+}
+
+#[embassy_executor::task]
+pub async fn subscription_task(obd_controller: &'static Mutex<CriticalSectionRawMutex, CanController>) {
+    let mut request = SUBSCRIPTION_REQUESTS.wait().await;
+    loop {
+        {
+            let mut obd_controller = obd_controller.lock().await;
+            for filter in FIRST_SUBSCRIPTION_FILTER..FIRST_SUBSCRIPTION_FILTER + MAX_SUBSCRIBED_IDS as u8 {
+                if let Err(err) = mcp::disable_filter(&mut obd_controller, filter).await {
+                    error!("Unable to disable subscription filter {}: {}", filter, err);
+                }
+            }
+            if let Some(subscription) = &request {
+                info!("Streaming raw frames for {} for {} s", subscription.ids, subscription.ttl.as_secs());
+                for (filter, id) in (FIRST_SUBSCRIPTION_FILTER..).zip(subscription.ids.iter()) {
+                    if let Err(err) = mcp::set_filter(&mut obd_controller, filter, SUBSCRIPTION_FIFO, *id, mcp::MASK_EXACT).await {
+                        error!("Unable to program subscription filter {}: {}", filter, err);
+                    }
+                }
+            }
+        }
+
+        request = match &request {
+            Some(subscription) => match embassy_time::with_timeout(subscription.ttl, SUBSCRIPTION_REQUESTS.wait()).await {
+                Ok(new_request) => new_request,
+                Err(_) => {
+                    info!("Raw frame subscription expired");
+                    None
+                },
+            },
+            None => SUBSCRIPTION_REQUESTS.wait().await,
+        };
+    }
+}
+
+// Applies capture filter changes while the controller stays in normal mode
+#[embassy_executor::task]
+pub async fn capture_task(obd_controller: &'static Mutex<CriticalSectionRawMutex, CanController>) {
+    loop {
+        let request = CAPTURE_REQUESTS.receive().await;
+        info!("Capture filter change: {}", request);
+        let mut obd_controller = obd_controller.lock().await;
+        let result = match request {
+            CaptureRequest::Add { slot, id, mask } => mcp::set_filter(&mut obd_controller, FIRST_CAPTURE_FILTER + slot, SUBSCRIPTION_FIFO, id, mask).await,
+            CaptureRequest::Remove { slot } => mcp::disable_filter(&mut obd_controller, FIRST_CAPTURE_FILTER + slot).await,
+        };
+        if let Err(err) = result {
+            error!("Unable to update capture filter: {}", err);
+        }
+    }
+}
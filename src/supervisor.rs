@@ -0,0 +1,88 @@
+use defmt::*;
+use embassy_rp::pac;
+use embassy_time::{Duration, Instant};
+use portable_atomic::{AtomicU32, AtomicU8, Ordering};
+
+// The long-running loops check in here at least every PET_INTERVAL, and boot_confirm_task only feeds the hardware
+// watchdog while every task that has checked in at least once keeps doing so. A loop stuck on a lock or a wedged SPI
+// transfer then resets the chip instead of leaving a node that looks alive but forwards nothing. Which task stalled is
+// kept in a watchdog scratch register across the reset and reported in the heartbeat.
+
+#[derive(Clone, Copy, Format)]
+pub enum Task {
+    ObdReceive = 0,
+    ObdInterrupt = 1,
+    ObdSender = 2,
+    CommaInterrupt = 3,
+    Forwarder = 4,
+    Environment = 5,
+    Motion = 6,
+    OutsideEnvironment = 7,
+}
+const TASK_COUNT: usize = 8;
+const TASKS: [Task; TASK_COUNT] = [
+    Task::ObdReceive,
+    Task::ObdInterrupt,
+    Task::ObdSender,
+    Task::CommaInterrupt,
+    Task::Forwarder,
+    Task::Environment,
+    Task::Motion,
+    Task::OutsideEnvironment,
+];
+
+// How often an idle loop has to wake up just to check in
+pub const PET_INTERVAL: Duration = Duration::from_secs(1);
+// Generous, the slowest loops (the environment sensor and IMU retrying) check in every 10 s
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+// Magic in the upper bytes, task in the lowest, see boot.rs for the reset reason in scratch 7
+const STALL_SCRATCH_MAGIC: u32 = 0x5354_4C00; // "STL"
+// Reported when no task stalled before the last reset
+pub const NO_STALL: u8 = 0xFF;
+// Reported in crash records when no task has checked in yet
+pub const NO_TASK: u8 = 0xFF;
+
+// Milliseconds since boot of each task's last check in, plus one so 0 means it never has
+static LAST_PET: [AtomicU32; TASK_COUNT] = [const { AtomicU32::new(0) }; TASK_COUNT];
+static STALLED_BEFORE_RESET: AtomicU8 = AtomicU8::new(NO_STALL);
+// Each loop checks in at the top of its iteration, so this is usually the task that's running
+static LAST_CHECKED_IN: AtomicU8 = AtomicU8::new(NO_TASK);
+
+pub fn pet(task: Task) {
+    LAST_PET[task as usize].store(Instant::now().as_millis() as u32 + 1, Ordering::Relaxed);
+    LAST_CHECKED_IN.store(task as u8, Ordering::Relaxed);
+}
+
+// For crash.rs
+pub fn last_checked_in() -> u8 {
+    LAST_CHECKED_IN.load(Ordering::Relaxed)
+}
+
+// The first task found to have stopped checking in, if any
+pub fn stalled() -> Option<Task> {
+    let now = Instant::now().as_millis() as u32 + 1;
+    TASKS.into_iter().find(|&task| {
+        let last_pet = LAST_PET[task as usize].load(Ordering::Relaxed);
+        last_pet != 0 && now.wrapping_sub(last_pet) > STALL_TIMEOUT.as_millis() as u32
+    })
+}
+
+// Call before letting the watchdog run out
+pub fn record_stall(task: Task) {
+    error!("{} stopped responding, letting the watchdog reset", task);
+    pac::WATCHDOG.scratch6().write_value(STALL_SCRATCH_MAGIC | task as u32);
+}
+
+// Call once at boot, alongside boot::read_reset_reason()
+pub fn read_stall() {
+    let scratch = pac::WATCHDOG.scratch6().read();
+    pac::WATCHDOG.scratch6().write_value(0);
+    if scratch & 0xFFFF_FF00 == STALL_SCRATCH_MAGIC {
+        warn!("Reset after task {} stalled", scratch as u8);
+        STALLED_BEFORE_RESET.store(scratch as u8, Ordering::Relaxed);
+    }
+}
+
+pub fn stalled_before_reset() -> u8 {
+    STALLED_BEFORE_RESET.load(Ordering::Relaxed)
+}
@@ -0,0 +1,84 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use embedded_can::Id;
+use heapless::Vec;
+use mcp25xxfd::Error;
+
+use crate::memory::{self, QueuePeak};
+use crate::{mcp, CanController};
+
+// How long a transmitted frame can go without showing up in the TX event FIFO before it's counted as lost
+pub const TX_CONFIRM_TIMEOUT: Duration = Duration::from_millis(50);
+pub const TX_EVENT_FIFO_DEPTH: u8 = 8;
+
+// Matches TX events against what was queued for transmission. The driver doesn't let us pick the sequence number
+// stored with each TX object, so events are matched to the oldest pending frame with the same ID instead. Frames
+// from the TXQ and the TX FIFO can complete out of order, so anything unmatched is only counted as lost once it
+// times out.
+pub struct TxTracker(Mutex<CriticalSectionRawMutex, RefCell<TrackerState>>, &'static QueuePeak);
+struct TrackerState {
+    pending: Vec<(Id, Instant), 16>,
+    confirmed: u32,
+    unconfirmed: u32,
+}
+
+pub static OBD_TX: TxTracker = TxTracker::new(&memory::OBD_TX_PEAK);
+pub static COMMA_TX: TxTracker = TxTracker::new(&memory::COMMA_TX_PEAK);
+
+impl TxTracker {
+    pub const fn new(in_flight_peak: &'static QueuePeak) -> Self {
+        Self(Mutex::new(RefCell::new(TrackerState {
+            pending: Vec::new(),
+            confirmed: 0,
+            unconfirmed: 0,
+        })), in_flight_peak)
+    }
+
+    // Call after a frame was successfully queued for transmission
+    pub fn record(&self, id: Id) {
+        self.0.lock(|state| {
+            let mut state = state.borrow_mut();
+            if state.pending.is_full() {
+                state.pending.remove(0);
+                state.unconfirmed += 1;
+            }
+            state.pending.push((id, Instant::now())).ok();
+            self.1.record(state.pending.len());
+        });
+    }
+
+    // Drains the controller's TX event FIFO and expires anything that's been pending for too long
+    pub async fn service(&self, controller: &mut CanController) -> Result<(), Error> {
+        while let Some(id) = mcp::read_tx_event(controller).await? {
+            self.0.lock(|state| {
+                let mut state = state.borrow_mut();
+                if let Some(position) = state.pending.iter().position(|(pending_id, _)| *pending_id == id) {
+                    state.pending.remove(position);
+                    state.confirmed += 1;
+                }
+            });
+        }
+        self.0.lock(|state| {
+            let mut state = state.borrow_mut();
+            let before = state.pending.len();
+            state.pending.retain(|(_, queued)| queued.elapsed() <= TX_CONFIRM_TIMEOUT);
+            state.unconfirmed += (before - state.pending.len()) as u32;
+        });
+        Ok(())
+    }
+
+    pub fn is_pending(&self, id: Id) -> bool {
+        self.0.lock(|state| state.borrow().pending.iter().any(|(pending_id, _)| *pending_id == id))
+    }
+
+    // (confirmed, unconfirmed) since boot
+    pub fn counts(&self) -> (u32, u32) {
+        self.0.lock(|state| {
+            let state = state.borrow();
+            (state.confirmed, state.unconfirmed)
+        })
+    }
+}
@@ -0,0 +1,58 @@
+use crate::config::EcuAddress;
+use crate::polling::{QueryDefinition, ECU_COUNT};
+use crate::signals::SignalDefinition;
+
+// The other profiles share its BMS decoding
+#[cfg_attr(not(feature = "ioniq5"), allow(dead_code))]
+mod ioniq5;
+#[cfg(any(feature = "kona-ev", feature = "niro-ev"))]
+mod kona_ev;
+#[cfg(feature = "niro-ev")]
+mod niro_ev;
+
+// Everything the firmware needs to know about a particular car. The OBD tasks only go through Vehicle, the profile
+// picked at compile time with exactly one of the ioniq5 (default), kona-ev or niro-ev features.
+pub trait VehicleProfile {
+    const NAME: &'static str;
+    // Diagnostic addresses in polling::ECU_BMS etc. order. ECUs a car doesn't have keep an address but get no queries.
+    const ECUS: [EcuAddress; ECU_COUNT as usize];
+    // Default periodic queries, generated from queries/<profile>.csv by build.rs
+    const QUERIES: &'static [QueryDefinition];
+    // Signals decoded from each query's response before forwarding, same order as QUERIES, also from build.rs
+    const SIGNALS: &'static [&'static [SignalDefinition]];
+    // Depth of each ECU's response FIFO, same order as ECUS. Sized to the longest ISO-TP response the ECU sends, since
+    // its consecutive frames come in back to back; ECUs without queries get the minimum. Checked against the message RAM
+    // budget in main.rs.
+    const RESPONSE_FIFO_DEPTHS: [u8; ECU_COUNT as usize];
+    // DID of the BMS response that decode_bms_status understands
+    const BMS_STATUS_DID: [u8; 2];
+
+    // Takes the response data after the DID, None if it's too short to tell whether the HV battery is connected
+    fn decode_bms_status(data: &[u8]) -> Option<BmsStatus>;
+    // Fills in a plausible BMS status response with the HV battery connected, for the loopback ECUs
+    fn simulate_bms_status(data: &mut [u8]);
+}
+
+pub struct BmsStatus {
+    // Current is flowing, so the car is on
+    pub hv_connected: bool,
+    // Volts, None if the response doesn't go that far
+    pub cell_voltage_delta: Option<f32>,
+    pub aux_battery_voltage: Option<f32>,
+}
+
+#[cfg(not(any(feature = "ioniq5", feature = "kona-ev", feature = "niro-ev")))]
+compile_error!("Enable a vehicle profile feature: ioniq5, kona-ev or niro-ev");
+#[cfg(any(
+    all(feature = "ioniq5", feature = "kona-ev"),
+    all(feature = "ioniq5", feature = "niro-ev"),
+    all(feature = "kona-ev", feature = "niro-ev"),
+))]
+compile_error!("Only one vehicle profile feature can be enabled (build with --no-default-features to switch)");
+
+#[cfg(feature = "ioniq5")]
+pub type Vehicle = ioniq5::Ioniq5;
+#[cfg(feature = "kona-ev")]
+pub type Vehicle = kona_ev::KonaEv;
+#[cfg(feature = "niro-ev")]
+pub type Vehicle = niro_ev::NiroEv;
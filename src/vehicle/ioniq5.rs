@@ -0,0 +1,62 @@
+use crate::config::EcuAddress;
+use crate::polling::{QueryDefinition, ECU_COUNT};
+use crate::signals::SignalDefinition;
+use crate::vehicle::{BmsStatus, VehicleProfile};
+
+// Offsets into the BMS 0x0101 response data
+const BMS_CURRENT_OFFSET: usize = 10; // 2 bytes
+const BMS_MAX_CELL_VOLTAGE_OFFSET: usize = 23; // 0.02 V/bit
+const BMS_MIN_CELL_VOLTAGE_OFFSET: usize = 25; // 0.02 V/bit
+const BMS_AUX_BATTERY_VOLTAGE_OFFSET: usize = 29; // 0.1 V/bit
+
+// The Kona Electric and Niro EV use the same layout
+pub fn decode_bms_status(data: &[u8]) -> Option<BmsStatus> {
+    let current = data.get(BMS_CURRENT_OFFSET..BMS_CURRENT_OFFSET + 2)?;
+    let cell_voltage_delta = match (data.get(BMS_MAX_CELL_VOLTAGE_OFFSET), data.get(BMS_MIN_CELL_VOLTAGE_OFFSET)) {
+        (Some(&max_cell), Some(&min_cell)) => Some((max_cell as f32 - min_cell as f32) * 0.02),
+        _ => None,
+    };
+    Some(BmsStatus {
+        hv_connected: current != [0x00, 0x00],
+        cell_voltage_delta,
+        aux_battery_voltage: data.get(BMS_AUX_BATTERY_VOLTAGE_OFFSET).map(|&aux_battery| aux_battery as f32 * 0.1),
+    })
+}
+
+pub fn simulate_bms_status(data: &mut [u8]) {
+    data[BMS_CURRENT_OFFSET..BMS_CURRENT_OFFSET + 2].copy_from_slice(&[0x00, 0x64]);
+    data[BMS_MAX_CELL_VOLTAGE_OFFSET] = 0xC8; // 4.00 V
+    data[BMS_MIN_CELL_VOLTAGE_OFFSET] = 0xC7; // 3.98 V
+    data[BMS_AUX_BATTERY_VOLTAGE_OFFSET] = 0x7D; // 12.5 V
+}
+
+pub struct Ioniq5;
+impl VehicleProfile for Ioniq5 {
+    const NAME: &'static str = "Ioniq 5";
+
+    const ECUS: [EcuAddress; ECU_COUNT as usize] = [
+        EcuAddress { request_id: 0x7E4, target: 0x14 },
+        EcuAddress { request_id: 0x7A0, target: 0xA0 },
+        EcuAddress { request_id: 0x7B3, target: 0xB3 },
+        EcuAddress { request_id: 0x730, target: 0x30 },
+        EcuAddress { request_id: 0x7E5, target: 0x15 },
+        EcuAddress { request_id: 0x744, target: 0x44 },
+        EcuAddress { request_id: 0x7C6, target: 0xC6 },
+        EcuAddress { request_id: 0x770, target: 0x70 },
+    ];
+
+    const QUERIES: &'static [QueryDefinition] = include!(concat!(env!("OUT_DIR"), "/queries/ioniq5.rs"));
+    const SIGNALS: &'static [&'static [SignalDefinition]] = include!(concat!(env!("OUT_DIR"), "/signals/ioniq5.rs"));
+
+    // BMS, TPMS, HVAC, ADAS, ICCU, VCMS, dash, IGPM
+    const RESPONSE_FIFO_DEPTHS: [u8; ECU_COUNT as usize] = [12, 6, 8, 1, 10, 12, 6, 8];
+
+    const BMS_STATUS_DID: [u8; 2] = [0x01, 0x01];
+
+    fn decode_bms_status(data: &[u8]) -> Option<BmsStatus> {
+        decode_bms_status(data)
+    }
+    fn simulate_bms_status(data: &mut [u8]) {
+        simulate_bms_status(data)
+    }
+}
@@ -0,0 +1,37 @@
+use crate::config::EcuAddress;
+use crate::polling::{QueryDefinition, ECU_COUNT};
+use crate::signals::SignalDefinition;
+use crate::vehicle::{ioniq5, BmsStatus, VehicleProfile};
+
+// There's no ICCU, the OBC answers in its place, and the VMCU stands in for the VCMS. The BMS status response has the
+// same layout as the Ioniq 5's.
+pub struct KonaEv;
+impl VehicleProfile for KonaEv {
+    const NAME: &'static str = "Kona Electric";
+
+    const ECUS: [EcuAddress; ECU_COUNT as usize] = [
+        EcuAddress { request_id: 0x7E4, target: 0x14 },
+        EcuAddress { request_id: 0x7A0, target: 0xA0 },
+        EcuAddress { request_id: 0x7B3, target: 0xB3 },
+        EcuAddress { request_id: 0x7D0, target: 0xD0 },
+        EcuAddress { request_id: 0x7E5, target: 0x15 },
+        EcuAddress { request_id: 0x7E2, target: 0x12 },
+        EcuAddress { request_id: 0x7C6, target: 0xC6 },
+        EcuAddress { request_id: 0x770, target: 0x70 },
+    ];
+
+    const QUERIES: &'static [QueryDefinition] = include!(concat!(env!("OUT_DIR"), "/queries/kona_ev.rs"));
+    const SIGNALS: &'static [&'static [SignalDefinition]] = include!(concat!(env!("OUT_DIR"), "/signals/kona_ev.rs"));
+
+    // BMS, TPMS, HVAC, ADAS, OBC, VMCU, dash, IGPM
+    const RESPONSE_FIFO_DEPTHS: [u8; ECU_COUNT as usize] = [12, 6, 8, 1, 1, 8, 6, 8];
+
+    const BMS_STATUS_DID: [u8; 2] = [0x01, 0x01];
+
+    fn decode_bms_status(data: &[u8]) -> Option<BmsStatus> {
+        ioniq5::decode_bms_status(data)
+    }
+    fn simulate_bms_status(data: &mut [u8]) {
+        ioniq5::simulate_bms_status(data)
+    }
+}
@@ -0,0 +1,23 @@
+use crate::config::EcuAddress;
+use crate::polling::{QueryDefinition, ECU_COUNT};
+use crate::signals::SignalDefinition;
+use crate::vehicle::kona_ev::KonaEv;
+use crate::vehicle::{BmsStatus, VehicleProfile};
+
+// Same platform and diagnostic layout as the Kona Electric
+pub struct NiroEv;
+impl VehicleProfile for NiroEv {
+    const NAME: &'static str = "Niro EV";
+    const ECUS: [EcuAddress; ECU_COUNT as usize] = KonaEv::ECUS;
+    const QUERIES: &'static [QueryDefinition] = KonaEv::QUERIES;
+    const SIGNALS: &'static [&'static [SignalDefinition]] = KonaEv::SIGNALS;
+    const RESPONSE_FIFO_DEPTHS: [u8; ECU_COUNT as usize] = KonaEv::RESPONSE_FIFO_DEPTHS;
+    const BMS_STATUS_DID: [u8; 2] = KonaEv::BMS_STATUS_DID;
+
+    fn decode_bms_status(data: &[u8]) -> Option<BmsStatus> {
+        KonaEv::decode_bms_status(data)
+    }
+    fn simulate_bms_status(data: &mut [u8]) {
+        KonaEv::simulate_bms_status(data)
+    }
+}
@@ -0,0 +1,41 @@
+use micromath::F32Ext;
+
+// Derived values forwarded alongside the raw environment readings, so every consumer doesn't need its own copy of the
+// formulas. Inputs are the corrected readings: °C, %RH and Pa.
+
+// Standard sea level pressure and density
+const SEA_LEVEL_PASCALS: f32 = 101_325.0;
+const SEA_LEVEL_DENSITY: f32 = 1.225;
+// Specific gas constants for dry air and water vapor, J/(kg·K)
+const R_DRY: f32 = 287.058;
+const R_VAPOR: f32 = 461.495;
+const KELVIN: f32 = 273.15;
+
+// Magnus formula coefficients (Sonntag 1990), good to a few tenths of a degree from -45 to 60 °C
+const MAGNUS_B: f32 = 17.62;
+const MAGNUS_C: f32 = 243.12;
+
+// Saturation vapor pressure in Pa
+fn saturation_vapor_pressure(temperature: f32) -> f32 {
+    611.2 * ((MAGNUS_B * temperature) / (MAGNUS_C + temperature)).exp()
+}
+
+// °C
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    // ln(0) would run off to -inf, dry enough air is as good as 0.1 %
+    let gamma = (humidity.max(0.1) / 100.0).ln() + (MAGNUS_B * temperature) / (MAGNUS_C + temperature);
+    MAGNUS_C * gamma / (MAGNUS_B - gamma)
+}
+
+// m, the altitude in the standard atmosphere with this pressure
+pub fn pressure_altitude(pressure: f32) -> f32 {
+    44_330.8 * (1.0 - (pressure / SEA_LEVEL_PASCALS).powf(0.190_263))
+}
+
+// m, the altitude in the standard atmosphere with the same air density, which is what engines and aerodynamics feel
+pub fn density_altitude(pressure: f32, temperature: f32, humidity: f32) -> f32 {
+    let vapor_pressure = saturation_vapor_pressure(temperature) * humidity.clamp(0.0, 100.0) / 100.0;
+    let kelvin = temperature + KELVIN;
+    let density = (pressure - vapor_pressure) / (R_DRY * kelvin) + vapor_pressure / (R_VAPOR * kelvin);
+    44_330.8 * (1.0 - (density / SEA_LEVEL_DENSITY).powf(0.234_969))
+}